@@ -14,47 +14,1260 @@
 
 #![warn(clippy::all)]
 
-use log::{debug, info};
-use std::sync::mpsc;
+use crate::backoff::Backoff;
+use crate::counters;
+use crate::mqtt;
+use crate::ratelimit::{Pending, TokenBucket};
+use crate::sink::Sink;
+use crate::spool::Spool;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{debug, error, info, warn};
+use reqwest::{header, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+// How long shutdown waits for the broker to acknowledge MQTT publishes.
+const SHUTDOWN_ACK_WAIT: Duration = Duration::from_secs(5);
+// The pause for a throttle notice that doesn't say how long it lasts.
+const DEFAULT_THROTTLE: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum Transport {
+    // One HTTPS request per metric (or per group batch).
+    Rest,
+    // Publishes to `{user}/feeds/{feed}/json` over a persistent connection.
+    Mqtt(Box<mqtt::Options>),
+}
 
 #[derive(Debug)]
 pub struct CallParams {
+    pub transport: Transport,
     pub base_url: String,
     pub io_user: String,
     pub io_key: String,
+    pub spool: Option<Spool>,
+    // Data points per minute allowed by the Adafruit IO plan.
+    pub rate_limit_per_minute: u32,
+    // Feed name prefixes, highest priority first, for when over budget.
+    pub feed_priority: Vec<String>,
+    // How long to collect live metrics before sending them as group batches;
+    // zero sends every metric on its own.
+    pub batch_window: Duration,
 }
 
-#[derive(Debug)]
+// A measurement, or a point on a location feed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    // Listed before Float so that whole numbers read back from the spool stay
+    // integers.
+    Int(i64),
+    Float(f64),
+    Text(String),
+    Location(Location),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Location {
+    pub lat: f64,
+    pub lon: f64,
+    // Elevation in metres.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ele: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Metric {
     pub feed: String,
-    pub value: f32,
+    pub value: Value,
+    // When the measurement was taken. Spool records written before this
+    // field existed get the time they are read back.
+    #[serde(default = "Utc::now")]
+    pub created_at: DateTime<Utc>,
+    // Where it was taken, if that matters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+}
+
+impl Metric {
+    // A metric taken now, with no location.
+    pub fn new(feed: impl Into<String>, value: impl Into<Value>) -> Metric {
+        Metric {
+            feed: feed.into(),
+            value: value.into(),
+            created_at: Utc::now(),
+            location: None,
+        }
+    }
+
+    // The location of a location feed, or the metadata of any other.
+    pub fn location(&self) -> Option<&Location> {
+        match &self.value {
+            Value::Location(l) => Some(l),
+            _ => self.location.as_ref(),
+        }
+    }
+}
+
+impl Value {
+    // The value as a number, if it is one.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(i) => Some(*i as f64),
+            Value::Float(f) => Some(*f),
+            _ => None,
+        }
+    }
+
+    // False for NaN and infinities, which no sink can store meaningfully.
+    pub fn is_finite(&self) -> bool {
+        match self {
+            Value::Float(f) => f.is_finite(),
+            Value::Location(l) => l.lat.is_finite() && l.lon.is_finite(),
+            Value::Int(_) | Value::Text(_) => true,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Text(s) => f.write_str(s),
+            Value::Location(l) => match l.ele {
+                Some(ele) => write!(f, "{},{},{}", l.lat, l.lon, ele),
+                None => write!(f, "{},{}", l.lat, l.lon),
+            },
+        }
+    }
+}
+
+// Sensor readings are f32; going through the shortest decimal keeps 21.1
+// from becoming 21.100000381469727.
+impl From<f32> for Value {
+    fn from(x: f32) -> Value {
+        Value::Float(x.to_string().parse().unwrap_or(x as f64))
+    }
+}
+
+impl From<f64> for Value {
+    fn from(x: f64) -> Value {
+        Value::Float(x)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Value {
+        Value::Int(i)
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::Text(s)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::Text(s.to_owned())
+    }
+}
+
+impl From<Location> for Value {
+    fn from(l: Location) -> Value {
+        Value::Location(l)
+    }
+}
+
+// What became of a single POST.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Sent,
+    // Transport error, 5xx or 429; try again later, no sooner than the
+    // server's Retry-After if it gave one.
+    Retry(Option<Duration>),
+    // The request itself is bad (e.g. 404 unknown feed, 422 invalid value);
+    // retrying won't help.
+    Rejected,
+    // 401/403: the credentials are wrong.
+    Unauthorized,
+}
+
+enum Link {
+    Rest(reqwest::blocking::Client),
+    Mqtt(mqtt::Publisher),
+}
+
+// The Adafruit IO sink. Sends spooled backlog first, in order, then live
+// metrics by priority, at no more than the configured rate.
+pub struct Sender {
+    link: Link,
+    params: CallParams,
+    spool: Option<Spool>,
+    pending: Pending,
+    bucket: TokenBucket,
+    backoff: Backoff,
+    retry_at: Option<Instant>,
+    // MQTT publishes the broker hasn't acknowledged yet, oldest first.
+    in_flight: VecDeque<Metric>,
+}
+
+impl Sender {
+    pub fn new(mut params: CallParams) -> Sender {
+        debug!("Adafruit IO parameters {:?}", params);
+        let link = match &params.transport {
+            Transport::Rest => Link::Rest(reqwest::blocking::Client::new()),
+            Transport::Mqtt(options) => {
+                let mut options = options.as_ref().clone();
+                // Adafruit IO reports rejected and throttled publishes here.
+                options
+                    .subscriptions
+                    .push(format!("{}/errors", params.io_user));
+                options
+                    .subscriptions
+                    .push(format!("{}/throttle", params.io_user));
+                Link::Mqtt(mqtt::Publisher::connect(&options))
+            }
+        };
+        Sender {
+            link,
+            spool: params.spool.take(),
+            pending: Pending::new(params.feed_priority.clone()),
+            bucket: TokenBucket::new(params.rate_limit_per_minute, Instant::now()),
+            backoff: Backoff::new(INITIAL_BACKOFF, MAX_BACKOFF),
+            retry_at: None,
+            in_flight: VecDeque::new(),
+            params,
+        }
+    }
+
+    // Sends live metrics, one request per group where possible. On a retryable
+    // failure, returns the metrics that weren't sent and why.
+    fn send_live(&mut self, batch: Vec<Metric>) -> Result<(), (Vec<Metric>, Outcome)> {
+        if let Link::Mqtt(_) = self.link {
+            // No per-request overhead to save.
+            return self.send_each(batch);
+        }
+        let mut jobs = plan_groups(batch).into_iter();
+        while let Some((group, metrics)) = jobs.next() {
+            let result = match group {
+                Some(g) if metrics.len() > 1 => match self.send_group(&g, &metrics) {
+                    Outcome::Sent => Ok(()),
+                    Outcome::Rejected => {
+                        warn!("Group {} rejected, falling back to single POSTs", g);
+                        self.send_each(metrics)
+                    }
+                    outcome => Err((metrics, outcome)),
+                },
+                _ => self.send_each(metrics),
+            };
+            if let Err((mut unsent, outcome)) = result {
+                unsent.extend(jobs.flat_map(|(_, ms)| ms));
+                return Err((unsent, outcome));
+            }
+        }
+        Ok(())
+    }
+
+    fn send_each(&mut self, metrics: Vec<Metric>) -> Result<(), (Vec<Metric>, Outcome)> {
+        let mut metrics = metrics.into_iter();
+        while let Some(m) = metrics.next() {
+            match self.send_one(&m) {
+                Outcome::Sent | Outcome::Rejected => {}
+                outcome => {
+                    let mut unsent = vec![m];
+                    unsent.extend(metrics);
+                    return Err((unsent, outcome));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn send_one(&mut self, m: &Metric) -> Outcome {
+        let outcome = match &mut self.link {
+            Link::Rest(client) => post(client, &self.params, m),
+            Link::Mqtt(publisher) => publish(publisher, &self.params, m),
+        };
+        match (&self.link, &outcome) {
+            // Counted once the broker acknowledges it.
+            (Link::Mqtt(_), Outcome::Sent) => self.in_flight.push_back(m.clone()),
+            _ => count(&outcome, 1),
+        }
+        outcome
+    }
+
+    // Retires the MQTT publishes the broker has acknowledged, committing them
+    // in the spool, and reads its notices. Returns how long to pause if it is
+    // throttling us.
+    fn check_mqtt(&mut self) -> Option<Duration> {
+        let publisher = match &mut self.link {
+            Link::Mqtt(p) => p,
+            Link::Rest(_) => return None,
+        };
+        let unacked = publisher.unacked() as usize;
+        let notices = publisher.received();
+        let acked = self.in_flight.len().saturating_sub(unacked);
+        self.in_flight.drain(..acked);
+        count(&Outcome::Sent, acked as u64);
+        if let Some(spool) = &mut self.spool {
+            if let Err(e) = spool.commit(acked) {
+                error!("Spool update failed: {}", e);
+            }
+        }
+        let mut throttle = None;
+        for notice in notices {
+            let text = String::from_utf8_lossy(&notice.payload);
+            if notice.topic.ends_with("/throttle") {
+                warn!("Adafruit IO throttled publishing: {}", text);
+                throttle = Some(throttle_delay(&text));
+            } else {
+                warn!("Adafruit IO rejected a publish: {}", text);
+            }
+            counters::inc("adafruit_send_failures");
+        }
+        throttle
+    }
+
+    fn send_group(&mut self, group: &str, metrics: &[Metric]) -> Outcome {
+        let outcome = match &mut self.link {
+            Link::Rest(client) => post_group(client, &self.params, group, metrics),
+            Link::Mqtt(_) => unreachable!("MQTT metrics are not grouped"),
+        };
+        count(&outcome, metrics.len() as u64);
+        outcome
+    }
+
+    // Whether the live metrics already waiting will use up the tokens there
+    // are, so that newer points should replace older ones.
+    fn over_budget(&mut self) -> bool {
+        self.pending.len() as f64 >= self.bucket.available(Instant::now()).floor()
+    }
+
+    fn retry_later(&mut self, retry_after: Option<Duration>) -> Option<Instant> {
+        let t = Instant::now() + self.backoff.next_delay(retry_after);
+        self.retry_at = Some(t);
+        Some(t)
+    }
+
+    // Sends the spool's metrics in order, committing each once delivered.
+    fn flush_spool(&mut self) -> Option<Instant> {
+        loop {
+            let spool = self.spool.as_mut().unwrap();
+            let first = match spool.front() {
+                Ok(Some(m)) => m,
+                Ok(None) => {
+                    self.backoff.reset();
+                    return None;
+                }
+                Err(e) => {
+                    error!("Spool read failed: {}", e);
+                    return self.retry_later(None);
+                }
+            };
+            // Give the rest of a burst of readings a chance to arrive.
+            let age = (Utc::now() - first.created_at).to_std().unwrap_or_default();
+            if age < self.params.batch_window {
+                return Some(Instant::now() + (self.params.batch_window - age));
+            }
+
+            // Only what can share one request, so that a failure leaves the
+            // unsent metrics at the end.
+            let now = Instant::now();
+            let max_batch = if self.params.batch_window.is_zero() {
+                1
+            } else {
+                usize::MAX
+            };
+            let mut batch: Vec<Metric> = Vec::new();
+            while batch.len() < max_batch {
+                let m = match spool.front() {
+                    Ok(Some(m)) => m,
+                    _ => break,
+                };
+                if batch.first().is_some_and(|first| !same_job(first, &m))
+                    || !self.bucket.try_take(now)
+                {
+                    break;
+                }
+                spool.take();
+                batch.push(m);
+            }
+            if batch.is_empty() {
+                debug!("Rate limited with {} bytes spooled", spool.queued_bytes());
+                return Some(self.bucket.next_available(now));
+            }
+
+            let taken = batch.len();
+            let result = self.send_live(batch);
+            let unsent = result.as_ref().map_or_else(|(ms, _)| ms.len(), |()| 0);
+            // MQTT publishes stay taken until the broker acknowledges them.
+            let delivered = match self.link {
+                Link::Rest(_) => taken - unsent,
+                Link::Mqtt(_) => 0,
+            };
+            let spool = self.spool.as_mut().unwrap();
+            spool.untake(unsent);
+            if let Err(e) = spool.commit(delivered) {
+                error!("Spool update failed: {}", e);
+                return self.retry_later(None);
+            }
+            match result {
+                Ok(()) => self.backoff.reset(),
+                Err((_, Outcome::Retry(retry_after))) => return self.retry_later(retry_after),
+                // Keep the data; it can be sent once the key is fixed.
+                Err(_) => return self.retry_later(None),
+            }
+        }
+    }
+
+    // Sends live metrics by priority, in group batches.
+    fn flush_pending(&mut self) -> Option<Instant> {
+        loop {
+            let now = Instant::now();
+            let oldest = match self.pending.oldest() {
+                Some(t) => t,
+                None => {
+                    self.backoff.reset();
+                    return None;
+                }
+            };
+            // Give the rest of a burst of readings a chance to arrive.
+            let due = oldest + self.params.batch_window;
+            if now < due {
+                return Some(due);
+            }
+
+            let max_batch = if self.params.batch_window.is_zero() {
+                1
+            } else {
+                usize::MAX
+            };
+            let mut batch = Vec::new();
+            while batch.len() < max_batch && !self.pending.is_empty() && self.bucket.try_take(now) {
+                batch.push(self.pending.pop().unwrap());
+            }
+            if batch.is_empty() {
+                debug!(
+                    "Rate limited with {} metrics pending ({} coalesced so far)",
+                    self.pending.len(),
+                    self.pending.coalesced()
+                );
+                return Some(self.bucket.next_available(now));
+            }
+
+            match self.send_live(batch) {
+                Ok(()) => self.backoff.reset(),
+                Err((unsent, Outcome::Unauthorized)) => {
+                    warn!("Dropping {} metrics", unsent.len());
+                }
+                Err((unsent, outcome)) => {
+                    for m in unsent {
+                        let coalesce = self.over_budget();
+                        self.pending.requeue(m, coalesce);
+                    }
+                    let retry_after = match outcome {
+                        Outcome::Retry(retry_after) => retry_after,
+                        _ => None,
+                    };
+                    return self.retry_later(retry_after);
+                }
+            }
+        }
+    }
+}
+
+impl Sink for Sender {
+    fn name(&self) -> &'static str {
+        "Adafruit IO"
+    }
+
+    fn send(&mut self, m: Metric) {
+        // With a spool, every point is kept on disk, in order, until it has
+        // been delivered.
+        match &mut self.spool {
+            Some(spool) => {
+                if let Err(e) = spool.push(&m) {
+                    error!("Spool write failed, dropping {}: {}", m.feed, e);
+                }
+            }
+            None => {
+                let coalesce = self.over_budget();
+                self.pending.push(m, coalesce);
+            }
+        }
+    }
+
+    // Sends whatever the rate limit allows.
+    fn flush(&mut self) -> Option<Instant> {
+        if let Some(pause) = self.check_mqtt() {
+            return self.retry_later(Some(pause));
+        }
+        if let Some(t) = self.retry_at {
+            if Instant::now() < t {
+                return Some(t);
+            }
+            self.retry_at = None;
+        }
+        if self.spool.is_some() {
+            self.flush_spool()
+        } else {
+            self.flush_pending()
+        }
+    }
+
+    fn shutdown(&mut self) {
+        // One last attempt regardless of the retry timer.
+        self.retry_at = None;
+        self.flush();
+        // Give the broker a moment to acknowledge what has been published.
+        // A spool replays the rest next time: it may never have got there.
+        let deadline = Instant::now() + SHUTDOWN_ACK_WAIT;
+        while !self.in_flight.is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+            self.check_mqtt();
+        }
+        if self.spool.is_none() && !self.in_flight.is_empty() {
+            warn!("Dropping {} unacknowledged metrics", self.in_flight.len());
+        }
+        if !self.pending.is_empty() {
+            warn!("Dropping {} unsent metrics", self.pending.len());
+        }
+        if let Some(spool) = &mut self.spool {
+            if let Err(e) = spool.sync() {
+                error!("Spool cursor not saved: {}", e);
+            }
+            let stats = spool.stats();
+            info!(
+                "Spool has {} bytes unsent; dropped {} (overflow), {} (expired), {} (corrupt)",
+                spool.queued_bytes(),
+                stats.dropped_overflow,
+                stats.dropped_expired,
+                stats.dropped_corrupt
+            );
+        }
+    }
+}
+
+fn count(outcome: &Outcome, points: u64) {
+    match outcome {
+        Outcome::Sent => counters::add("adafruit_points_sent", points),
+        _ => counters::inc("adafruit_send_failures"),
+    }
 }
 
-pub fn aio_sender(params: CallParams, rx: mpsc::Receiver<Metric>) {
-    info!("aio_sender starting");
-    debug!("aio_sender parameters {:?}", params);
-    let client = reqwest::blocking::Client::new();
-    while let Ok(m) = rx.recv() {
-        debug!("Received {:?}", m);
-        let url = format!(
-            "{}/{}/feeds/{}/data",
-            params.base_url, params.io_user, m.feed
+// Splits a batch by Adafruit IO group, taken from the feed name ("mbr" for
+// "mbr.temperature"), preserving order. Ungrouped feeds go on their own. A
+// group POST carries one timestamp and location for all its feeds, so only
+// metrics that agree on those share one.
+fn plan_groups(batch: Vec<Metric>) -> Vec<(Option<String>, Vec<Metric>)> {
+    let mut jobs: Vec<(Option<String>, Vec<Metric>)> = Vec::new();
+    for m in batch {
+        match m.feed.split_once('.') {
+            Some((group, _)) => {
+                let job = jobs
+                    .iter_mut()
+                    .find(|(g, ms)| g.is_some() && same_job(&ms[0], &m));
+                match job {
+                    Some((_, ms)) => ms.push(m),
+                    None => jobs.push((Some(group.to_owned()), vec![m])),
+                }
+            }
+            None => jobs.push((None, vec![m])),
+        }
+    }
+    jobs
+}
+
+// Whether two metrics can go in one group POST: the same group, time and
+// location.
+fn same_job(a: &Metric, b: &Metric) -> bool {
+    fn group(m: &Metric) -> Option<&str> {
+        m.feed.split_once('.').map(|(g, _)| g)
+    }
+    group(a).is_some()
+        && group(a) == group(b)
+        && created_at(a) == created_at(b)
+        && a.location() == b.location()
+}
+
+// Adafruit IO keeps whole seconds.
+fn created_at(m: &Metric) -> String {
+    m.created_at.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+// The JSON form of a data point: value, created_at and any lat/lon/ele.
+fn data_point(m: &Metric) -> serde_json::Map<String, serde_json::Value> {
+    let mut point = serde_json::Map::new();
+    point.insert("value".into(), m.value.to_string().into());
+    point.insert("created_at".into(), created_at(m).into());
+    if let Some(l) = m.location() {
+        point.extend(location_fields(l));
+    }
+    point
+}
+
+fn location_fields(l: &Location) -> serde_json::Map<String, serde_json::Value> {
+    let mut fields = serde_json::Map::new();
+    fields.insert("lat".into(), l.lat.into());
+    fields.insert("lon".into(), l.lon.into());
+    if let Some(ele) = l.ele {
+        fields.insert("ele".into(), ele.into());
+    }
+    fields
+}
+
+// POSTs a single metric and classifies the result.
+fn post(client: &reqwest::blocking::Client, params: &CallParams, m: &Metric) -> Outcome {
+    let url = format!(
+        "{}/{}/feeds/{}/data",
+        params.base_url, params.io_user, m.feed
+    );
+    debug!("POSTing to {}", url);
+    let mut form = reqwest::blocking::multipart::Form::new();
+    for (name, value) in data_point(m) {
+        let text = match value {
+            serde_json::Value::String(s) => s,
+            v => v.to_string(),
+        };
+        form = form.text(name, text);
+    }
+    let resp = client
+        .post(url)
+        .header("X-AIO-Key", params.io_key.as_bytes())
+        .multipart(form)
+        .send();
+    match resp {
+        Ok(r) => classify(r, params, &format!("{} = {}", m.feed, m.value)),
+        Err(e) => {
+            debug!("POST failed: {:?}", e);
+            Outcome::Retry(None)
+        }
+    }
+}
+
+// Queues a metric on the MQTT connection. It isn't sent until the broker
+// acknowledges it (see `Sender::check_mqtt`), and problems are reported
+// asynchronously on the errors/throttle topics, so only a dead connection
+// counts as a failure here.
+fn publish(publisher: &mut mqtt::Publisher, params: &CallParams, m: &Metric) -> Outcome {
+    // The /json topic takes the same fields as the REST API.
+    let topic = format!("{}/feeds/{}/json", params.io_user, m.feed);
+    debug!("Publishing to {}", topic);
+    let payload = serde_json::Value::Object(data_point(m)).to_string();
+    if publisher.publish(&topic, payload.as_bytes(), false) {
+        Outcome::Sent
+    } else {
+        Outcome::Retry(None)
+    }
+}
+
+// Adafruit IO's throttle notices end e.g. "28 seconds until throttle
+// released".
+fn throttle_delay(text: &str) -> Duration {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    words
+        .windows(2)
+        .find(|w| w[1].starts_with("second"))
+        .and_then(|w| w[0].parse().ok())
+        .map_or(DEFAULT_THROTTLE, Duration::from_secs)
+}
+
+// POSTs several metrics of one group in a single request.
+fn post_group(
+    client: &reqwest::blocking::Client,
+    params: &CallParams,
+    group: &str,
+    metrics: &[Metric],
+) -> Outcome {
+    let url = format!(
+        "{}/{}/groups/{}/data",
+        params.base_url, params.io_user, group
+    );
+    debug!("POSTing {} metrics to {}", metrics.len(), url);
+    let feeds: Vec<serde_json::Value> = metrics
+        .iter()
+        .map(|m| {
+            let key = m.feed.split_once('.').map_or(m.feed.as_str(), |(_, k)| k);
+            serde_json::json!({ "key": key, "value": m.value.to_string() })
+        })
+        .collect();
+    let mut body = serde_json::json!({ "feeds": feeds, "created_at": created_at(&metrics[0]) });
+    if let Some(l) = metrics[0].location() {
+        body["location"] = location_fields(l).into();
+    }
+    let resp = client
+        .post(url)
+        .header("X-AIO-Key", params.io_key.as_bytes())
+        .json(&body)
+        .send();
+    match resp {
+        Ok(r) => classify(r, params, &format!("group {}", group)),
+        Err(e) => {
+            debug!("POST failed: {:?}", e);
+            Outcome::Retry(None)
+        }
+    }
+}
+
+fn classify(r: reqwest::blocking::Response, params: &CallParams, what: &str) -> Outcome {
+    let status = r.status();
+    if status.is_success() {
+        debug!("POST succeeded: {:?}", status);
+        return Outcome::Sent;
+    }
+    let retry_after = r
+        .headers()
+        .get(header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs);
+    let body = r.text().unwrap_or_default();
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            error!(
+                "Adafruit IO rejected the credentials for user {} ({}); check IO_USERNAME and IO_KEY: {}",
+                params.io_user, status, body
+            );
+            Outcome::Unauthorized
+        }
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::REQUEST_TIMEOUT => {
+            warn!("Adafruit IO throttled {} ({})", what, status);
+            Outcome::Retry(retry_after)
+        }
+        s if s.is_server_error() => {
+            warn!("Adafruit IO error for {} ({}): {}", what, status, body);
+            Outcome::Retry(retry_after)
+        }
+        _ => {
+            warn!("Adafruit IO rejected {} ({}): {}", what, status, body);
+            Outcome::Rejected
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spool;
+    use crate::test_broker;
+    use crate::test_server::{self, Response};
+    use chrono::TimeZone;
+    use std::env;
+    use std::fs;
+    use std::thread;
+
+    fn params(base_url: &str) -> CallParams {
+        CallParams {
+            transport: Transport::Rest,
+            base_url: base_url.to_owned(),
+            io_user: "user".to_owned(),
+            io_key: "key".to_owned(),
+            spool: None,
+            rate_limit_per_minute: 60,
+            feed_priority: Vec::new(),
+            batch_window: Duration::ZERO,
+        }
+    }
+
+    fn metric(feed: &str) -> Metric {
+        let mut m = Metric::new(feed, 1.5);
+        m.created_at = Utc.with_ymd_and_hms(2022, 10, 18, 6, 30, 0).unwrap();
+        m
+    }
+
+    fn post_once(response: Response) -> Outcome {
+        let (url, server) = test_server::serve(vec![response]);
+        let client = reqwest::blocking::Client::new();
+        let outcome = post(&client, &params(&url), &metric("mbr.temperature"));
+        let requests = server.join().unwrap();
+        assert_eq!("POST", requests[0].method);
+        assert_eq!("/user/feeds/mbr.temperature/data", requests[0].path);
+        assert_eq!(Some("key"), requests[0].header("X-AIO-Key"));
+        outcome
+    }
+
+    #[test]
+    fn posts_carry_time_and_location() {
+        let (url, server) = test_server::serve(vec![Response::status(200)]);
+        let client = reqwest::blocking::Client::new();
+        let mut m = metric("car.speed");
+        m.location = Some(Location {
+            lat: 37.5,
+            lon: -122.25,
+            ele: None,
+        });
+        assert_eq!(Outcome::Sent, post(&client, &params(&url), &m));
+        let body = String::from_utf8(server.join().unwrap()[0].body.clone()).unwrap();
+        for (name, value) in [
+            ("value", "1.5"),
+            ("created_at", "2022-10-18T06:30:00Z"),
+            ("lat", "37.5"),
+            ("lon", "-122.25"),
+        ] {
+            let field = format!("name=\"{}\"\r\n\r\n{}\r\n", name, value);
+            assert!(body.contains(&field), "{} missing from {}", name, body);
+        }
+        assert!(!body.contains("name=\"ele\""));
+    }
+
+    #[test]
+    fn values_keep_their_type_through_json() {
+        let values = [
+            Value::Int(42),
+            Value::Float(42.0),
+            Value::Float(67123.45),
+            Value::Text("light rain".to_owned()),
+            Value::Location(Location {
+                lat: 1.0,
+                lon: 2.0,
+                ele: Some(3.0),
+            }),
+        ];
+        for v in values {
+            let json = serde_json::to_string(&v).unwrap();
+            assert_eq!(v, serde_json::from_str::<Value>(&json).unwrap());
+        }
+        assert_eq!("67123.45", Value::Float(67123.45).to_string());
+        assert_eq!(Value::Float(21.1), Value::from(21.1f32));
+
+        // As spooled before metrics had a time.
+        let old: Metric = serde_json::from_str(r#"{"feed":"a","value":21.5}"#).unwrap();
+        assert_eq!(Value::Float(21.5), old.value);
+    }
+
+    #[test]
+    fn groups_split_on_time_and_location() {
+        let later = {
+            let mut m = metric("mbr.humidity");
+            m.created_at += chrono::Duration::seconds(1);
+            m
+        };
+        let elsewhere = {
+            let mut m = metric("mbr.pressure");
+            m.location = Some(Location {
+                lat: 1.0,
+                lon: 2.0,
+                ele: None,
+            });
+            m
+        };
+        let jobs = plan_groups(vec![
+            metric("mbr.temperature"),
+            later,
+            elsewhere,
+            metric("mbr.lux"),
+        ]);
+        let feeds: Vec<Vec<&str>> = jobs
+            .iter()
+            .map(|(_, ms)| ms.iter().map(|m| m.feed.as_str()).collect())
+            .collect();
+        assert_eq!(
+            vec![
+                vec!["mbr.temperature", "mbr.lux"],
+                vec!["mbr.humidity"],
+                vec!["mbr.pressure"]
+            ],
+            feeds
+        );
+    }
+
+    #[test]
+    fn success_is_sent() {
+        assert_eq!(Outcome::Sent, post_once(Response::status(200)));
+    }
+
+    #[test]
+    fn server_errors_and_throttling_are_retried() {
+        assert_eq!(Outcome::Retry(None), post_once(Response::status(503)));
+        assert_eq!(
+            Outcome::Retry(Some(Duration::from_secs(7))),
+            post_once(Response::status(429).with_header("Retry-After", "7"))
+        );
+    }
+
+    #[test]
+    fn validation_errors_are_rejected() {
+        assert_eq!(Outcome::Rejected, post_once(Response::status(404)));
+        assert_eq!(
+            Outcome::Rejected,
+            post_once(Response::status(422).with_body("{\"error\":\"bad value\"}"))
         );
-        debug!("POSTing to {}", url);
-        let form = reqwest::blocking::multipart::Form::new().text("value", m.value.to_string());
-        let resp = client
-            .post(url)
-            .header("X-AIO-Key", params.io_key.as_bytes())
-            .multipart(form)
-            .send();
-        match resp {
-            Ok(r) => {
-                debug!("POST succeeded: {:?}", r.status());
+    }
+
+    #[test]
+    fn auth_failures_are_reported() {
+        assert_eq!(Outcome::Unauthorized, post_once(Response::status(401)));
+        assert_eq!(Outcome::Unauthorized, post_once(Response::status(403)));
+    }
+
+    #[test]
+    fn transport_errors_are_retried() {
+        // Nothing listens on port 1.
+        let client = reqwest::blocking::Client::new();
+        let outcome = post(&client, &params("http://127.0.0.1:1"), &metric("x"));
+        assert_eq!(Outcome::Retry(None), outcome);
+    }
+
+    fn sender(params: CallParams) -> Sender {
+        let mut sender = Sender::new(params);
+        sender.backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(5));
+        sender
+    }
+
+    // Pumps until the sender has nothing left or is waiting on the rate limit.
+    fn pump_until_idle(sender: &mut Sender, max_rounds: usize) {
+        for _ in 0..max_rounds {
+            match sender.flush() {
+                None => return,
+                Some(_) if sender.retry_at.is_none() => return,
+                Some(t) => thread::sleep(t.saturating_duration_since(Instant::now())),
             }
-            _ => {
-                debug!("POST failed: {:?}", resp.err());
+        }
+    }
+
+    #[test]
+    fn live_metrics_are_retried() {
+        let (url, server) = test_server::serve(vec![
+            Response::status(500),
+            Response::status(429),
+            Response::status(200),
+        ]);
+        let mut sender = sender(params(&url));
+        sender.send(metric("x"));
+        pump_until_idle(&mut sender, 10);
+        assert!(sender.pending.is_empty());
+        assert_eq!(3, server.join().unwrap().len());
+    }
+
+    #[test]
+    fn rate_limit_sends_priority_feeds_first() {
+        let (url, server) = test_server::serve(vec![Response::status(200), Response::status(200)]);
+        let mut p = params(&url);
+        p.rate_limit_per_minute = 2;
+        p.feed_priority = vec!["mbr.".to_owned()];
+        let mut sender = sender(p);
+        sender.send(metric("mbr-sgp30.raw-h2"));
+        sender.send(metric("mbr.temperature"));
+        sender.send(metric("mbr-sgp30.raw-h2"));
+        sender.send(metric("mbr.co2"));
+        sender.send(metric("mbr-sgp30.raw-ethanol"));
+
+        // Two tokens: both mbr.* feeds go, the rest wait for the next token.
+        let wake = sender.flush().unwrap();
+        assert!(wake > Instant::now() + Duration::from_secs(20));
+        let paths: Vec<String> = server.join().unwrap().into_iter().map(|r| r.path).collect();
+        assert_eq!(
+            vec![
+                "/user/feeds/mbr.temperature/data",
+                "/user/feeds/mbr.co2/data"
+            ],
+            paths
+        );
+        assert_eq!(2, sender.pending.len());
+        assert_eq!(1, sender.pending.coalesced());
+    }
+
+    #[test]
+    fn points_under_budget_all_go_out() {
+        let (url, server) = test_server::serve(vec![
+            Response::status(200),
+            Response::status(200),
+            Response::status(200),
+        ]);
+        let mut sender = sender(params(&url));
+        for _ in 0..3 {
+            sender.send(metric("mbr.temperature"));
+        }
+        assert_eq!(None, sender.flush());
+        assert_eq!(3, server.join().unwrap().len());
+        assert_eq!(0, sender.pending.coalesced());
+    }
+
+    #[test]
+    fn spool_drops_rejected_and_keeps_retryable() {
+        let dir = env::temp_dir().join(format!("iot-central-aio-drain-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let limits = spool::Limits {
+            max_bytes: 1 << 20,
+            max_age: Duration::from_secs(3600),
+            segment_bytes: 1 << 16,
+        };
+        let mut spool = Spool::open(&dir, limits).unwrap();
+        for feed in ["a", "b", "c"] {
+            spool.push(&metric(feed)).unwrap();
+        }
+
+        let (url, server) = test_server::serve(vec![
+            Response::status(422),
+            Response::status(503).with_header("Retry-After", "2"),
+        ]);
+        let mut p = params(&url);
+        p.spool = Some(spool);
+        let mut sender = sender(p);
+        sender.send(metric("d"));
+        let wake = sender.flush().unwrap();
+        assert!(wake >= Instant::now() + Duration::from_secs(1));
+        server.join().unwrap();
+
+        // "a" was rejected and dropped; "b" is still at the front, and the
+        // live metric went to the back of the spool.
+        let spool = sender.spool.as_mut().unwrap();
+        let mut feeds = Vec::new();
+        while let Some(m) = spool.front().unwrap() {
+            feeds.push(m.feed);
+            spool.take();
+            spool.commit(1).unwrap();
+        }
+        assert_eq!(vec!["b", "c", "d"], feeds);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn live_metrics_are_spooled_until_delivered() {
+        let dir = env::temp_dir().join(format!("iot-central-aio-live-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let limits = spool::Limits {
+            max_bytes: 1 << 20,
+            max_age: Duration::from_secs(3600),
+            segment_bytes: 1 << 16,
+        };
+        let (url, server) = test_server::serve(vec![
+            Response::status(503),
+            Response::status(200),
+            Response::status(200),
+        ]);
+        let mut p = params(&url);
+        p.spool = Some(Spool::open(&dir, limits).unwrap());
+        let mut sender = sender(p);
+        sender.send(metric("a"));
+        sender.send(metric("b"));
+        assert!(sender.flush().is_some());
+        assert!(sender.pending.is_empty());
+        assert!(sender.spool.as_ref().unwrap().queued_bytes() > 0);
+
+        pump_until_idle(&mut sender, 10);
+        assert_eq!(0, sender.spool.as_ref().unwrap().queued_bytes());
+        assert_eq!(3, server.join().unwrap().len());
+        drop(sender);
+        let mut spool = Spool::open(&dir, limits).unwrap();
+        assert!(spool.front().unwrap().is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn batches_are_sent_per_group() {
+        let (url, server) = test_server::serve(vec![Response::status(200), Response::status(200)]);
+        let mut p = params(&url);
+        p.batch_window = Duration::from_millis(20);
+        let mut sender = sender(p);
+        sender.send(metric("mbr.temperature"));
+        sender.send(metric("weather.temp"));
+        sender.send(metric("mbr.humidity"));
+
+        // Nothing goes out until the window has passed.
+        let due = sender.flush().unwrap();
+        assert_eq!(3, sender.pending.len());
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+        assert_eq!(None, sender.flush());
+
+        let requests = server.join().unwrap();
+        assert_eq!("/user/groups/mbr/data", requests[0].path);
+        let body: serde_json::Value = serde_json::from_slice(&requests[0].body).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "feeds": [
+                    {"key": "temperature", "value": "1.5"},
+                    {"key": "humidity", "value": "1.5"},
+                ],
+                "created_at": "2022-10-18T06:30:00Z",
+            }),
+            body
+        );
+        // A group of one is just a normal POST.
+        assert_eq!("/user/feeds/weather.temp/data", requests[1].path);
+    }
+
+    #[test]
+    fn spooled_runs_are_sent_per_group() {
+        let dir = env::temp_dir().join(format!("iot-central-aio-runs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let limits = spool::Limits {
+            max_bytes: 1 << 20,
+            max_age: Duration::from_secs(3600),
+            segment_bytes: 1 << 16,
+        };
+        let (url, server) = test_server::serve(vec![
+            Response::status(200),
+            Response::status(200),
+            Response::status(200),
+        ]);
+        let mut p = params(&url);
+        p.spool = Some(Spool::open(&dir, limits).unwrap());
+        p.batch_window = Duration::from_millis(1);
+        let mut sender = sender(p);
+        for feed in ["mbr.temperature", "mbr.humidity", "weather.temp", "mbr.lux"] {
+            sender.send(metric(feed));
+        }
+        assert_eq!(None, sender.flush());
+
+        // In spool order, so "mbr.lux" can't join the first group.
+        let paths: Vec<String> = server.join().unwrap().into_iter().map(|r| r.path).collect();
+        assert_eq!(
+            vec![
+                "/user/groups/mbr/data",
+                "/user/feeds/weather.temp/data",
+                "/user/feeds/mbr.lux/data"
+            ],
+            paths
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejected_group_falls_back_to_single_posts() {
+        let (url, server) = test_server::serve(vec![
+            Response::status(404),
+            Response::status(200),
+            Response::status(200),
+        ]);
+        let mut p = params(&url);
+        p.batch_window = Duration::from_millis(1);
+        let mut sender = sender(p);
+        sender.send(metric("mbr.temperature"));
+        sender.send(metric("mbr.humidity"));
+        thread::sleep(Duration::from_millis(2));
+        assert_eq!(None, sender.flush());
+
+        let paths: Vec<String> = server.join().unwrap().into_iter().map(|r| r.path).collect();
+        assert_eq!(
+            vec![
+                "/user/groups/mbr/data",
+                "/user/feeds/mbr.temperature/data",
+                "/user/feeds/mbr.humidity/data"
+            ],
+            paths
+        );
+    }
+
+    fn mqtt_params(broker: &test_broker::Broker) -> CallParams {
+        let mut p = params("http://unused");
+        p.transport = Transport::Mqtt(Box::new(mqtt::Options {
+            host: "127.0.0.1".to_owned(),
+            port: broker.port(),
+            tls: false,
+            client_id: "iot-central-test".to_owned(),
+            username: Some("user".to_owned()),
+            password: Some("key".to_owned()),
+            keep_alive: Duration::from_secs(5),
+            will: None,
+            birth: None,
+            subscriptions: Vec::new(),
+        }));
+        p
+    }
+
+    #[test]
+    fn mqtt_publishes_to_feed_topics() {
+        let broker = test_broker::Broker::start();
+        let mut p = mqtt_params(&broker);
+        p.batch_window = Duration::from_millis(1);
+        let mut sender = sender(p);
+        sender.send(metric("mbr.temperature"));
+        sender.send(metric("mbr.humidity"));
+        // Retries (with a short backoff) until the connection is up.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while sender.flush().is_some() {
+            assert!(Instant::now() < deadline, "never published");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let topics = [broker.next_publish(), broker.next_publish()];
+        assert_eq!("user/feeds/mbr.temperature/json", topics[0].topic);
+        let payload: serde_json::Value = serde_json::from_slice(&topics[0].payload).unwrap();
+        assert_eq!(
+            serde_json::json!({"value": "1.5", "created_at": "2022-10-18T06:30:00Z"}),
+            payload
+        );
+        assert_eq!("user/feeds/mbr.humidity/json", topics[1].topic);
+        assert_eq!(Some("key".to_owned()), broker.connects()[0].password);
+        assert_eq!(vec!["user/errors", "user/throttle"], broker.subscriptions());
+
+        // Sent once acknowledged.
+        while !sender.in_flight.is_empty() {
+            assert!(Instant::now() < deadline, "never acknowledged");
+            thread::sleep(Duration::from_millis(10));
+            sender.check_mqtt();
+        }
+
+        // A throttle notice pauses sending for as long as it says.
+        broker.publish(
+            "user/throttle",
+            b"user data rate limit reached, 30 seconds until throttle released",
+        );
+        let resume = loop {
+            if let Some(due) = sender.flush() {
+                break due;
             }
+            assert!(Instant::now() < deadline, "never throttled");
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert!(resume > Instant::now() + Duration::from_secs(29));
+        assert!(resume <= Instant::now() + Duration::from_secs(30));
+        sender.send(metric("mbr.temperature"));
+        assert_eq!(Some(resume), sender.flush());
+        assert_eq!(1, sender.pending.len());
+    }
+
+    #[test]
+    fn mqtt_publishes_leave_the_spool_once_acknowledged() {
+        let dir = env::temp_dir().join(format!("iot-central-aio-mqtt-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let limits = spool::Limits {
+            max_bytes: 1 << 20,
+            max_age: Duration::from_secs(3600),
+            segment_bytes: 1 << 16,
+        };
+        let broker = test_broker::Broker::start();
+        let mut p = mqtt_params(&broker);
+        p.spool = Some(Spool::open(&dir, limits).unwrap());
+        let mut sender = sender(p);
+        sender.send(metric("mbr.temperature"));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while sender.in_flight.is_empty() {
+            assert!(Instant::now() < deadline, "never published");
+            thread::sleep(Duration::from_millis(10));
+            sender.flush();
+        }
+        broker.next_publish();
+
+        // Published, but only committed once the PUBACK is seen.
+        assert!(sender.spool.as_ref().unwrap().queued_bytes() > 0);
+        while !sender.in_flight.is_empty() {
+            assert!(Instant::now() < deadline, "never acknowledged");
+            thread::sleep(Duration::from_millis(10));
+            sender.check_mqtt();
         }
+        assert_eq!(0, sender.spool.as_ref().unwrap().queued_bytes());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn throttle_notices_give_the_pause() {
+        assert_eq!(
+            Duration::from_secs(28),
+            throttle_delay("user data rate limit reached, 28 seconds until throttle released")
+        );
+        assert_eq!(DEFAULT_THROTTLE, throttle_delay("slow down"));
     }
-    info!("aio_sender finished");
 }
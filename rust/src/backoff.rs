@@ -0,0 +1,102 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Exponential backoff with "equal jitter": each delay is somewhere between
+/// half and all of the current step, and the step doubles up to `max`.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    /// Returns the delay before the next attempt. A server-provided minimum
+    /// (e.g. from `Retry-After`) takes precedence if it is longer.
+    pub fn next_delay(&mut self, at_least: Option<Duration>) -> Duration {
+        let half = self.current / 2;
+        let delay = half + jitter(self.current - half);
+        self.current = (self.current * 2).min(self.max);
+        delay.max(at_least.unwrap_or_default())
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+// A pseudo-random duration in [0, range]; good enough to spread retries.
+// RandomState is seeded randomly per process, so devices that start together
+// still differ, and hashing the full timestamp and thread varies it per call.
+fn jitter(range: Duration) -> Duration {
+    let mut hasher = RandomState::new().build_hasher();
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    thread::current().id().hash(&mut hasher);
+    range.mul_f64(hasher.finish() as f64 / u64::MAX as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_grow_and_are_capped() {
+        let mut b = Backoff::new(Duration::from_secs(1), Duration::from_secs(4));
+        let d1 = b.next_delay(None);
+        assert!(d1 >= Duration::from_millis(500) && d1 <= Duration::from_secs(1));
+        let d2 = b.next_delay(None);
+        assert!(d2 >= Duration::from_secs(1) && d2 <= Duration::from_secs(2));
+        for _ in 0..5 {
+            assert!(b.next_delay(None) <= Duration::from_secs(4));
+        }
+        b.reset();
+        assert!(b.next_delay(None) <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn jitter_is_spread_out() {
+        let range = Duration::from_secs(1);
+        let samples: Vec<Duration> = (0..100).map(|_| jitter(range)).collect();
+        assert!(samples.iter().all(|&d| d <= range));
+        let low = samples.iter().filter(|&&d| d < range / 2).count();
+        assert!((20..=80).contains(&low), "{} of 100 in the lower half", low);
+    }
+
+    #[test]
+    fn server_minimum_wins() {
+        let mut b = Backoff::new(Duration::from_secs(1), Duration::from_secs(4));
+        assert_eq!(
+            Duration::from_secs(30),
+            b.next_delay(Some(Duration::from_secs(30)))
+        );
+    }
+}
@@ -0,0 +1,1323 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+use crate::adafruit;
+use crate::filelog;
+use crate::mqtt;
+use crate::sensor::{self, ClimateSource, LightSource, ParticulateSource};
+use crate::spool;
+
+use serde::Deserialize;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Used when no --config is given; matches the historical hard-coded settings.
+const DEFAULT_CONFIG: &str = r#"
+[adafruit]
+io_user = "${IO_USERNAME}"
+io_key = "${IO_KEY}"
+"#;
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub adafruit: Option<AdafruitConfig>,
+    pub influxdb: Option<InfluxConfig>,
+    pub prometheus: Option<PrometheusConfig>,
+    pub homeassistant: Option<HomeAssistantConfig>,
+    pub history: Option<HistoryConfig>,
+    pub file_log: Option<FileLogConfig>,
+    #[serde(default)]
+    pub sensor: SensorConfig,
+    pub finance: Option<FinanceConfig>,
+    pub weather: Option<WeatherConfig>,
+    pub spool: Option<SpoolConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    Rest,
+    Mqtt,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AdafruitConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default = "default_transport")]
+    pub transport: TransportKind,
+    #[serde(default = "default_aio_base_url")]
+    pub base_url: String,
+    pub io_user: String,
+    pub io_key: String,
+    // The free plan allows 30 data points per minute.
+    #[serde(default = "default_rate_limit_per_minute")]
+    pub rate_limit_per_minute: u32,
+    // Only used without a spool, which sends everything in order.
+    #[serde(default)]
+    pub feed_priority: Vec<String>,
+    // Zero disables group batching.
+    #[serde(default = "default_batch_window_ms")]
+    pub batch_window_ms: u64,
+    #[serde(default)]
+    pub mqtt: MqttConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    #[serde(default = "default_mqtt_host")]
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_true")]
+    pub tls: bool,
+    #[serde(default = "default_mqtt_keep_alive_secs")]
+    pub keep_alive_secs: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct InfluxConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    // e.g. "http://localhost:8086"
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    pub token: String,
+    #[serde(default = "default_influx_batch_size")]
+    pub batch_size: usize,
+    #[serde(default = "default_influx_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PrometheusConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default = "default_prometheus_listen")]
+    pub listen: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HomeAssistantConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    pub host: String,
+    #[serde(default = "default_homeassistant_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub tls: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "default_mqtt_keep_alive_secs")]
+    pub keep_alive_secs: u64,
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+    #[serde(default = "default_node_id")]
+    pub node_id: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct HistoryConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    pub path: PathBuf,
+    #[serde(default = "default_raw_retention_days")]
+    pub raw_retention_days: u64,
+    #[serde(default = "default_hourly_retention_days")]
+    pub hourly_retention_days: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileLogFormat {
+    Csv,
+    Jsonl,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileLogRotation {
+    Daily,
+    Size,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct FileLogConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    pub dir: PathBuf,
+    #[serde(default = "default_file_log_prefix")]
+    pub prefix: String,
+    #[serde(default = "default_file_log_format")]
+    pub format: FileLogFormat,
+    #[serde(default = "default_file_log_rotation")]
+    pub rotation: FileLogRotation,
+    // Only used with size rotation.
+    #[serde(default = "default_file_log_max_bytes")]
+    pub max_bytes: u64,
+    #[serde(default)]
+    pub gzip: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SensorConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    // Local altitude in meters, used for the sea-level pressure correction.
+    #[serde(default = "default_altitude")]
+    pub altitude: f32,
+    #[serde(default = "default_sample_period_ms")]
+    pub sample_period_ms: u64,
+    #[serde(default = "default_sensor_update_period_secs")]
+    pub update_period_secs: u64,
+    // Which sensor provides mbr.temperature, mbr.humidity and the SGP30's
+    // humidity compensation. mbr.pressure comes from the BME680 if it is
+    // chosen here, and from the BME280 otherwise.
+    #[serde(default = "default_climate_source")]
+    pub climate_source: ClimateSource,
+    #[serde(default)]
+    pub bme280: Bme280Config,
+    pub bme680: Option<Bme680Config>,
+    #[serde(default)]
+    pub sgp30: Sgp30Config,
+    pub sht4x: Option<ShtConfig>,
+    pub sht3x: Option<ShtConfig>,
+    // Which sensor provides mbr.lux and mbr.lux-db.
+    #[serde(default = "default_light_source")]
+    pub light_source: LightSource,
+    // Darker readings, including none at all, are published as this.
+    #[serde(default = "default_dark_floor_lux")]
+    pub dark_floor_lux: f32,
+    #[serde(default)]
+    pub tsl2591: Tsl2591Config,
+    pub veml7700: Option<Veml7700Config>,
+    pub scd4x: Option<Co2SensorConfig>,
+    pub scd30: Option<Co2SensorConfig>,
+    // Which sensor provides mbr.pm1, mbr.pm25 and mbr.pm10, if either is
+    // enabled.
+    #[serde(default = "default_particulate_source")]
+    pub particulate_source: ParticulateSource,
+    pub pmsa003i: Option<Pmsa003iConfig>,
+    pub pms5003: Option<Pms5003Config>,
+    #[serde(default)]
+    pub sim: SimConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Bme280Config {
+    // Off when a BME680 takes its place.
+    pub enabled: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tsl2591Config {
+    // Off when a VEML7700 takes its place.
+    pub enabled: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sgp30Config {
+    // CO₂eq and TVOC are not published for this long after init. The sensor
+    // reports a fixed 400 ppm / 0 ppb for the first 15 s, and drifts for a
+    // while after that.
+    pub warmup_secs: u64,
+    // Where the eCO₂/TVOC baseline is kept across restarts. Not kept if unset.
+    pub baseline_file: Option<PathBuf>,
+}
+
+// The simulated room, when built with the "sim" feature instead of "ftdi" or
+// "rpi".
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+#[cfg_attr(not(feature = "sim"), allow(dead_code))]
+pub struct SimConfig {
+    // Warmest at 15:00, coolest at 03:00.
+    pub temperature_mean_c: f64,
+    pub temperature_swing_c: f64,
+    // Driest when warmest.
+    pub humidity_mean_pct: f64,
+    pub humidity_swing_pct: f64,
+    pub pressure_hpa: f64,
+    pub co2_baseline_ppm: f64,
+    // A spike of up to co2_spike_ppm above the baseline lasts co2_spike_mins,
+    // once every co2_spike_every_mins. TVOC and PM2.5 follow CO₂.
+    pub co2_spike_ppm: f64,
+    pub co2_spike_every_mins: u64,
+    pub co2_spike_mins: u64,
+    pub tvoc_baseline_ppb: f64,
+    pub pm25_baseline_ugm3: f64,
+    // Local time.
+    pub daylight_lux: f64,
+    pub night_lux: f64,
+    pub sunrise_hour: f64,
+    pub sunset_hour: f64,
+    // Simulated seconds per real second.
+    pub time_scale: f64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct FinanceConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default = "default_finance_base_url")]
+    pub base_url: String,
+    pub api_key: String,
+    pub symbols: Vec<String>,
+    #[serde(default = "default_remote_update_period_secs")]
+    pub update_period_secs: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct WeatherConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default = "default_weather_base_url")]
+    pub base_url: String,
+    pub api_key: String,
+    pub lat: String,
+    pub lon: String,
+    #[serde(default = "default_units")]
+    pub units: String,
+    #[serde(default = "default_remote_update_period_secs")]
+    pub update_period_secs: u64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SpoolConfig {
+    pub dir: PathBuf,
+    #[serde(default = "default_spool_max_bytes")]
+    pub max_bytes: u64,
+    #[serde(default = "default_spool_max_age_secs")]
+    pub max_age_secs: u64,
+    #[serde(default = "default_spool_segment_bytes")]
+    pub segment_bytes: u64,
+}
+
+impl Default for SensorConfig {
+    fn default() -> Self {
+        SensorConfig {
+            enabled: true,
+            altitude: default_altitude(),
+            sample_period_ms: default_sample_period_ms(),
+            update_period_secs: default_sensor_update_period_secs(),
+            climate_source: default_climate_source(),
+            bme280: Bme280Config::default(),
+            bme680: None,
+            sgp30: Sgp30Config::default(),
+            sht4x: None,
+            sht3x: None,
+            light_source: default_light_source(),
+            dark_floor_lux: default_dark_floor_lux(),
+            tsl2591: Tsl2591Config::default(),
+            veml7700: None,
+            scd4x: None,
+            scd30: None,
+            particulate_source: default_particulate_source(),
+            pmsa003i: None,
+            pms5003: None,
+            sim: SimConfig::default(),
+        }
+    }
+}
+
+// A BME680 or BME688, which adds a gas sensor to the BME280's readings.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Bme680Config {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    // 0x77, or 0x76 if SDO is grounded.
+    #[serde(default = "default_bme680_address")]
+    pub address: u8,
+    // Up to 10 steps of 200-400 °C for 1-4032 ms; each sample uses the next.
+    // The air quality score is computed from the first step.
+    #[serde(default = "default_heater_profile")]
+    pub heater_profile: Vec<HeaterStepConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct HeaterStepConfig {
+    pub temperature_c: u16,
+    pub duration_ms: u16,
+}
+
+// An SHT4x or SHT3x temperature/humidity sensor.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ShtConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    // 0x44, or 0x45 (0x46 for some SHT4x parts).
+    #[serde(default = "default_sht_address")]
+    pub address: u8,
+    // Pulse the heater at most this often while humidity is above 80%, to
+    // keep condensation from skewing readings. Off if unset.
+    pub heater_interval_secs: Option<u64>,
+}
+
+// An SCD4x or SCD30 NDIR CO₂ sensor.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Co2SensorConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    // Left as the sensor has it if unset.
+    pub automatic_self_calibration: Option<bool>,
+    // Recalibrate to this CO₂ level (400-2000 ppm) a few minutes after
+    // startup. Only set it while the sensor is in air of known CO₂, e.g.
+    // outdoors at about 420 ppm, and remove it afterwards.
+    pub forced_recalibration_ppm: Option<u16>,
+}
+
+// A PMSA003I particulate matter sensor on the I2C bus.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Pmsa003iConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+// A VEML7700 ambient light sensor on the I2C bus.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Veml7700Config {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+// A PMS5003 particulate matter sensor on a serial port.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Pms5003Config {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default = "default_pms5003_port")]
+    pub port: PathBuf,
+}
+
+impl Default for Bme280Config {
+    fn default() -> Self {
+        Bme280Config { enabled: true }
+    }
+}
+
+impl Default for Tsl2591Config {
+    fn default() -> Self {
+        Tsl2591Config { enabled: true }
+    }
+}
+
+impl Default for Sgp30Config {
+    fn default() -> Self {
+        Sgp30Config {
+            warmup_secs: 300,
+            baseline_file: None,
+        }
+    }
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            temperature_mean_c: 21.0,
+            temperature_swing_c: 3.0,
+            humidity_mean_pct: 45.0,
+            humidity_swing_pct: 10.0,
+            pressure_hpa: 1000.0,
+            co2_baseline_ppm: 450.0,
+            co2_spike_ppm: 800.0,
+            co2_spike_every_mins: 180,
+            co2_spike_mins: 45,
+            tvoc_baseline_ppb: 30.0,
+            pm25_baseline_ugm3: 5.0,
+            daylight_lux: 800.0,
+            night_lux: 0.5,
+            sunrise_hour: 7.0,
+            sunset_hour: 19.0,
+            time_scale: 1.0,
+        }
+    }
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            host: default_mqtt_host(),
+            port: default_mqtt_port(),
+            tls: true,
+            keep_alive_secs: default_mqtt_keep_alive_secs(),
+        }
+    }
+}
+
+impl AdafruitConfig {
+    pub fn batch_window(&self) -> Duration {
+        Duration::from_millis(self.batch_window_ms)
+    }
+
+    pub fn transport(&self) -> adafruit::Transport {
+        match self.transport {
+            TransportKind::Rest => adafruit::Transport::Rest,
+            TransportKind::Mqtt => adafruit::Transport::Mqtt(Box::new(mqtt::Options {
+                host: self.mqtt.host.clone(),
+                port: self.mqtt.port,
+                tls: self.mqtt.tls,
+                client_id: format!("iot-central-{}", std::process::id()),
+                username: Some(self.io_user.clone()),
+                password: Some(self.io_key.clone()),
+                keep_alive: Duration::from_secs(self.mqtt.keep_alive_secs),
+                will: None,
+                birth: None,
+                subscriptions: Vec::new(),
+            })),
+        }
+    }
+}
+
+impl InfluxConfig {
+    pub fn flush_interval(&self) -> Duration {
+        Duration::from_millis(self.flush_interval_ms)
+    }
+}
+
+impl HomeAssistantConfig {
+    pub fn mqtt(&self) -> mqtt::Options {
+        mqtt::Options {
+            host: self.host.clone(),
+            port: self.port,
+            tls: self.tls,
+            client_id: format!("{}-{}", self.node_id, std::process::id()),
+            username: self.username.clone(),
+            password: self.password.clone(),
+            keep_alive: Duration::from_secs(self.keep_alive_secs),
+            will: None,
+            birth: None,
+            subscriptions: Vec::new(),
+        }
+    }
+}
+
+impl HistoryConfig {
+    pub fn raw_retention(&self) -> Duration {
+        Duration::from_secs(self.raw_retention_days * 24 * 60 * 60)
+    }
+
+    pub fn hourly_retention(&self) -> Duration {
+        Duration::from_secs(self.hourly_retention_days * 24 * 60 * 60)
+    }
+}
+
+impl FileLogConfig {
+    pub fn format(&self) -> filelog::Format {
+        match self.format {
+            FileLogFormat::Csv => filelog::Format::Csv,
+            FileLogFormat::Jsonl => filelog::Format::Jsonl,
+        }
+    }
+
+    pub fn rotation(&self) -> filelog::Rotation {
+        match self.rotation {
+            FileLogRotation::Daily => filelog::Rotation::Daily,
+            FileLogRotation::Size => filelog::Rotation::Size(self.max_bytes),
+        }
+    }
+}
+
+impl SensorConfig {
+    pub fn sample_period(&self) -> Duration {
+        Duration::from_millis(self.sample_period_ms)
+    }
+
+    pub fn update_period(&self) -> Duration {
+        Duration::from_secs(self.update_period_secs)
+    }
+}
+
+impl Sgp30Config {
+    pub fn warmup(&self) -> Duration {
+        Duration::from_secs(self.warmup_secs)
+    }
+}
+
+impl Bme680Config {
+    pub fn settings(&self) -> sensor::Bme680Settings {
+        sensor::Bme680Settings {
+            address: self.address,
+            heater_profile: self
+                .heater_profile
+                .iter()
+                .map(|s| sensor::HeaterStep {
+                    temperature_c: s.temperature_c,
+                    duration_ms: s.duration_ms,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl ShtConfig {
+    pub fn settings(&self) -> sensor::ShtSettings {
+        sensor::ShtSettings {
+            address: self.address,
+            heater_interval: self.heater_interval_secs.map(Duration::from_secs),
+        }
+    }
+}
+
+impl Co2SensorConfig {
+    pub fn calibration(&self) -> sensor::Co2Calibration {
+        sensor::Co2Calibration {
+            automatic_self_calibration: self.automatic_self_calibration,
+            forced_recalibration_ppm: self.forced_recalibration_ppm,
+        }
+    }
+}
+
+#[cfg(feature = "sim")]
+impl SimConfig {
+    pub fn waveforms(&self) -> sensor::sim::Waveforms {
+        sensor::sim::Waveforms {
+            temperature_mean: self.temperature_mean_c,
+            temperature_swing: self.temperature_swing_c,
+            humidity_mean: self.humidity_mean_pct,
+            humidity_swing: self.humidity_swing_pct,
+            pressure: self.pressure_hpa,
+            co2_baseline: self.co2_baseline_ppm,
+            co2_spike: self.co2_spike_ppm,
+            co2_spike_every: Duration::from_secs(self.co2_spike_every_mins * 60),
+            co2_spike_length: Duration::from_secs(self.co2_spike_mins * 60),
+            tvoc_baseline: self.tvoc_baseline_ppb,
+            pm25_baseline: self.pm25_baseline_ugm3,
+            daylight: self.daylight_lux,
+            night_light: self.night_lux,
+            sunrise_hour: self.sunrise_hour,
+            sunset_hour: self.sunset_hour,
+            time_scale: self.time_scale,
+        }
+    }
+}
+
+impl FinanceConfig {
+    pub fn update_period(&self) -> Duration {
+        Duration::from_secs(self.update_period_secs)
+    }
+}
+
+impl WeatherConfig {
+    pub fn update_period(&self) -> Duration {
+        Duration::from_secs(self.update_period_secs)
+    }
+}
+
+impl SpoolConfig {
+    pub fn limits(&self) -> spool::Limits {
+        spool::Limits {
+            max_bytes: self.max_bytes,
+            max_age: Duration::from_secs(self.max_age_secs),
+            segment_bytes: self.segment_bytes,
+        }
+    }
+}
+
+fn default_transport() -> TransportKind {
+    TransportKind::Rest
+}
+
+fn default_mqtt_host() -> String {
+    "io.adafruit.com".to_owned()
+}
+
+fn default_mqtt_port() -> u16 {
+    8883
+}
+
+fn default_mqtt_keep_alive_secs() -> u64 {
+    60
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_aio_base_url() -> String {
+    "https://io.adafruit.com/api/v2".to_owned()
+}
+
+fn default_finance_base_url() -> String {
+    "https://finnhub.io/api/v1/quote".to_owned()
+}
+
+fn default_weather_base_url() -> String {
+    "https://api.openweathermap.org/data/2.5/onecall".to_owned()
+}
+
+fn default_units() -> String {
+    "metric".to_owned()
+}
+
+fn default_rate_limit_per_minute() -> u32 {
+    30
+}
+
+fn default_batch_window_ms() -> u64 {
+    1000
+}
+
+fn default_altitude() -> f32 {
+    100.0
+}
+
+fn default_sample_period_ms() -> u64 {
+    1000
+}
+
+fn default_climate_source() -> ClimateSource {
+    ClimateSource::Bme280
+}
+
+fn default_light_source() -> LightSource {
+    LightSource::Tsl2591
+}
+
+fn default_particulate_source() -> ParticulateSource {
+    ParticulateSource::Pmsa003i
+}
+
+// About the least the TSL2591 can resolve.
+fn default_dark_floor_lux() -> f32 {
+    0.0002
+}
+
+fn default_bme680_address() -> u8 {
+    sensor::BME680_DEFAULT_ADDRESS
+}
+
+// Bosch's default: 320 °C for 150 ms.
+fn default_heater_profile() -> Vec<HeaterStepConfig> {
+    vec![HeaterStepConfig {
+        temperature_c: 320,
+        duration_ms: 150,
+    }]
+}
+
+fn default_sht_address() -> u8 {
+    0x44
+}
+
+fn default_pms5003_port() -> PathBuf {
+    PathBuf::from("/dev/serial0")
+}
+
+fn default_sensor_update_period_secs() -> u64 {
+    60
+}
+
+fn default_remote_update_period_secs() -> u64 {
+    10 * 60
+}
+
+fn default_influx_batch_size() -> usize {
+    500
+}
+
+fn default_influx_flush_interval_ms() -> u64 {
+    10_000
+}
+
+fn default_prometheus_listen() -> String {
+    "0.0.0.0:9184".to_owned()
+}
+
+fn default_homeassistant_port() -> u16 {
+    1883
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_owned()
+}
+
+fn default_node_id() -> String {
+    "iot-central".to_owned()
+}
+
+fn default_raw_retention_days() -> u64 {
+    30
+}
+
+fn default_hourly_retention_days() -> u64 {
+    365
+}
+
+fn default_file_log_prefix() -> String {
+    "metrics".to_owned()
+}
+
+fn default_file_log_format() -> FileLogFormat {
+    FileLogFormat::Csv
+}
+
+fn default_file_log_rotation() -> FileLogRotation {
+    FileLogRotation::Daily
+}
+
+fn default_file_log_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_spool_max_bytes() -> u64 {
+    16 * 1024 * 1024
+}
+
+fn default_spool_max_age_secs() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_spool_segment_bytes() -> u64 {
+    1024 * 1024
+}
+
+/// Loads the configuration from `path`, or the built-in defaults if `None`.
+pub fn load(path: Option<&Path>) -> Result<Config, Box<dyn Error>> {
+    match path {
+        Some(p) => {
+            let text = fs::read_to_string(p)
+                .map_err(|e| format!("unable to read config file {}: {}", p.display(), e))?;
+            parse(&text).map_err(|e| format!("invalid config file {}: {}", p.display(), e).into())
+        }
+        None => parse(DEFAULT_CONFIG),
+    }
+}
+
+/// Parses a TOML configuration, expanding `${VAR}` references in string values
+/// from the environment.
+pub fn parse(text: &str) -> Result<Config, Box<dyn Error>> {
+    let mut value: toml::Value = text.parse()?;
+    interpolate(&mut value, &mut Vec::new())?;
+    let config: Config = value.try_into()?;
+    validate(&config)?;
+    Ok(config)
+}
+
+fn validate(config: &Config) -> Result<(), Box<dyn Error>> {
+    if config.sensor.sample_period_ms == 0 {
+        return Err("sensor.sample_period_ms must be greater than zero".into());
+    }
+    if config.sensor.sgp30.warmup_secs < 15 {
+        return Err("sensor.sgp30.warmup_secs must be at least 15".into());
+    }
+    let sht4x = config.sensor.sht4x.as_ref().filter(|c| c.enabled);
+    let sht3x = config.sensor.sht3x.as_ref().filter(|c| c.enabled);
+    for (name, sht, addresses) in [
+        ("sht4x", sht4x, &[0x44, 0x45, 0x46][..]),
+        ("sht3x", sht3x, &[0x44, 0x45][..]),
+    ] {
+        if sht.is_some_and(|c| !addresses.contains(&c.address)) {
+            return Err(format!("sensor.{}.address is not one the sensor can have", name).into());
+        }
+        if sht.is_some_and(|c| c.heater_interval_secs.is_some_and(|secs| secs < 60)) {
+            return Err(format!("sensor.{}.heater_interval_secs must be at least 60", name).into());
+        }
+    }
+    if let (Some(a), Some(b)) = (sht4x, sht3x) {
+        if a.address == b.address {
+            return Err("sensor.sht4x and sensor.sht3x must have different addresses".into());
+        }
+    }
+    let bme680 = config.sensor.bme680.as_ref().filter(|c| c.enabled);
+    if let Some(b) = bme680 {
+        if b.address != 0x76 && b.address != 0x77 {
+            return Err("sensor.bme680.address must be 0x76 or 0x77".into());
+        }
+        if b.address == 0x77 && config.sensor.bme280.enabled {
+            return Err(
+                "sensor.bme680 is at the BME280's address; disable sensor.bme280 or use 0x76"
+                    .into(),
+            );
+        }
+        if b.heater_profile.is_empty() || b.heater_profile.len() > 10 {
+            return Err("sensor.bme680.heater_profile must have 1-10 steps".into());
+        }
+        for step in &b.heater_profile {
+            if !(200..=400).contains(&step.temperature_c) || !(1..=4032).contains(&step.duration_ms)
+            {
+                return Err("sensor.bme680.heater_profile steps must be 200-400 °C for \
+                            1-4032 ms"
+                    .into());
+            }
+        }
+    }
+    let dark_floor_lux = config.sensor.dark_floor_lux;
+    if !(dark_floor_lux > 0. && dark_floor_lux.is_finite()) {
+        return Err("sensor.dark_floor_lux must be greater than zero".into());
+    }
+    let source_enabled = match config.sensor.climate_source {
+        ClimateSource::Bme280 => config.sensor.bme280.enabled,
+        ClimateSource::Bme680 => bme680.is_some(),
+        ClimateSource::Sht4x => sht4x.is_some(),
+        ClimateSource::Sht3x => sht3x.is_some(),
+    };
+    if !source_enabled {
+        return Err(format!(
+            "sensor.climate_source is {0}, but sensor.{0} is not enabled",
+            config.sensor.climate_source.sensor_name()
+        )
+        .into());
+    }
+    let light_source_enabled = match config.sensor.light_source {
+        LightSource::Tsl2591 => config.sensor.tsl2591.enabled,
+        LightSource::Veml7700 => config.sensor.veml7700.as_ref().is_some_and(|c| c.enabled),
+    };
+    if !light_source_enabled {
+        return Err(format!(
+            "sensor.light_source is {0}, but sensor.{0} is not enabled",
+            config.sensor.light_source.sensor_name()
+        )
+        .into());
+    }
+    let pmsa003i = config.sensor.pmsa003i.as_ref().is_some_and(|c| c.enabled);
+    let pms5003 = config.sensor.pms5003.as_ref().is_some_and(|c| c.enabled);
+    let particulate_source_enabled = match config.sensor.particulate_source {
+        ParticulateSource::Pmsa003i => pmsa003i,
+        ParticulateSource::Pms5003 => pms5003,
+    };
+    if (pmsa003i || pms5003) && !particulate_source_enabled {
+        return Err(format!(
+            "sensor.particulate_source is {0}, but sensor.{0} is not enabled",
+            config.sensor.particulate_source.sensor_name()
+        )
+        .into());
+    }
+    for (name, co2) in [
+        ("scd4x", &config.sensor.scd4x),
+        ("scd30", &config.sensor.scd30),
+    ] {
+        let frc = co2.as_ref().and_then(|c| c.forced_recalibration_ppm);
+        if frc.is_some_and(|ppm| !(400..=2000).contains(&ppm)) {
+            return Err(format!(
+                "sensor.{}.forced_recalibration_ppm must be within 400-2000",
+                name
+            )
+            .into());
+        }
+    }
+    let sim = &config.sensor.sim;
+    if sim.co2_baseline_ppm <= 0.0 {
+        return Err("sensor.sim.co2_baseline_ppm must be greater than zero".into());
+    }
+    if sim.co2_spike_mins > sim.co2_spike_every_mins {
+        return Err("sensor.sim.co2_spike_mins must not exceed co2_spike_every_mins".into());
+    }
+    if !(0.0 <= sim.sunrise_hour && sim.sunrise_hour <= sim.sunset_hour && sim.sunset_hour <= 24.0)
+    {
+        return Err("sensor.sim.sunrise_hour must come before sunset_hour, within 0-24".into());
+    }
+    if sim.time_scale < 0.0 {
+        return Err("sensor.sim.time_scale must not be negative".into());
+    }
+    let adafruit = config.adafruit.as_ref().filter(|a| a.enabled);
+    let influxdb = config.influxdb.as_ref().filter(|i| i.enabled);
+    let prometheus = config.prometheus.as_ref().filter(|p| p.enabled);
+    let homeassistant = config.homeassistant.as_ref().filter(|h| h.enabled);
+    let history = config.history.as_ref().filter(|h| h.enabled);
+    let file_log = config.file_log.as_ref().filter(|f| f.enabled);
+    if adafruit.is_none()
+        && influxdb.is_none()
+        && prometheus.is_none()
+        && homeassistant.is_none()
+        && history.is_none()
+        && file_log.is_none()
+    {
+        return Err("no sinks are enabled".into());
+    }
+    if adafruit.is_some_and(|a| a.rate_limit_per_minute == 0) {
+        return Err("adafruit.rate_limit_per_minute must be greater than zero".into());
+    }
+    if influxdb.is_some_and(|i| i.batch_size == 0) {
+        return Err("influxdb.batch_size must be greater than zero".into());
+    }
+    if let Some(h) = homeassistant {
+        // Home Assistant only accepts these in object and node IDs.
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+        if h.node_id.is_empty() || !h.node_id.chars().all(valid) {
+            return Err("homeassistant.node_id may only contain letters, digits, _ and -".into());
+        }
+    }
+    if file_log.is_some_and(|f| f.rotation == FileLogRotation::Size && f.max_bytes == 0) {
+        return Err("file_log.max_bytes must be greater than zero".into());
+    }
+    if let Some(s) = &config.spool {
+        if s.segment_bytes == 0 || s.segment_bytes > s.max_bytes {
+            return Err("spool.segment_bytes must be between 1 and spool.max_bytes".into());
+        }
+    }
+    if let Some(f) = &config.finance {
+        if f.enabled && f.symbols.is_empty() {
+            return Err("finance.symbols must not be empty".into());
+        }
+    }
+    Ok(())
+}
+
+fn interpolate(value: &mut toml::Value, path: &mut Vec<String>) -> Result<(), Box<dyn Error>> {
+    match value {
+        toml::Value::String(s) => {
+            *s = expand_env(s).map_err(|e| format!("{}: {}", path.join("."), e))?;
+        }
+        toml::Value::Array(a) => {
+            for (i, v) in a.iter_mut().enumerate() {
+                path.push(i.to_string());
+                interpolate(v, path)?;
+                path.pop();
+            }
+        }
+        toml::Value::Table(t) => {
+            // Disabled sections shouldn't require their secrets to be defined.
+            if t.get("enabled").and_then(toml::Value::as_bool) == Some(false) {
+                return Ok(());
+            }
+            for (k, v) in t.iter_mut() {
+                path.push(k.clone());
+                interpolate(v, path)?;
+                path.pop();
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// Replaces every `${NAME}` with the value of environment variable NAME.
+fn expand_env(s: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| format!("unterminated variable reference in \"{}\"", s))?;
+        let name = &after[..end];
+        let var =
+            env::var(name).map_err(|_| format!("environment variable {} is not defined", name))?;
+        out.push_str(&var);
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minimal_config_uses_defaults() {
+        let c = parse("[adafruit]\nio_user = \"u\"\nio_key = \"k\"\n").unwrap();
+        assert_eq!(
+            "https://io.adafruit.com/api/v2",
+            c.adafruit.unwrap().base_url
+        );
+        assert!(c.sensor.enabled);
+        assert_eq!(100.0, c.sensor.altitude);
+        assert_eq!(Duration::from_secs(60), c.sensor.update_period());
+        assert!(c.sensor.sgp30.baseline_file.is_none());
+        assert_eq!(Duration::from_secs(300), c.sensor.sgp30.warmup());
+        assert!(c.finance.is_none());
+        assert!(c.weather.is_none());
+    }
+
+    #[test]
+    fn env_vars_are_interpolated() {
+        env::set_var("IOT_CENTRAL_TEST_KEY", "secret");
+        let c = parse("[adafruit]\nio_user = \"u\"\nio_key = \"a-${IOT_CENTRAL_TEST_KEY}-b\"\n")
+            .unwrap();
+        assert_eq!("a-secret-b", c.adafruit.unwrap().io_key);
+    }
+
+    #[test]
+    fn mqtt_transport_uses_adafruit_credentials() {
+        let c =
+            parse("[adafruit]\ntransport = \"mqtt\"\nio_user = \"u\"\nio_key = \"k\"\n").unwrap();
+        match c.adafruit.unwrap().transport() {
+            adafruit::Transport::Mqtt(o) => {
+                assert_eq!(
+                    ("io.adafruit.com", 8883, true),
+                    (o.host.as_str(), o.port, o.tls)
+                );
+                assert_eq!(Some("k".to_owned()), o.password);
+            }
+            t => panic!("unexpected transport {:?}", t),
+        }
+        assert!(parse(
+            "[adafruit]\ntransport = \"carrier-pigeon\"\nio_user = \"u\"\nio_key = \"k\"\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn disabled_sections_are_not_interpolated() {
+        let c = parse(
+            "[adafruit]\nio_user = \"u\"\nio_key = \"k\"\n\
+             [finance]\nenabled = false\napi_key = \"${IOT_CENTRAL_TEST_UNDEFINED}\"\n\
+             symbols = []\n",
+        )
+        .unwrap();
+        assert!(!c.finance.unwrap().enabled);
+    }
+
+    #[test]
+    fn undefined_env_var_is_an_error() {
+        let e = parse("[adafruit]\nio_user = \"u\"\nio_key = \"${IOT_CENTRAL_TEST_UNDEFINED}\"\n")
+            .unwrap_err();
+        assert!(e.to_string().contains("adafruit.io_key"));
+        assert!(e.to_string().contains("IOT_CENTRAL_TEST_UNDEFINED"));
+    }
+
+    #[test]
+    fn missing_and_unknown_keys_are_errors() {
+        assert!(parse("[adafruit]\nio_user = \"u\"\n").is_err());
+        assert!(parse("[adafruit]\nio_user = \"u\"\nio_key = \"k\"\nbogus = 1\n").is_err());
+        assert!(
+            parse("[adafruit]\nio_user = \"u\"\nio_key = \"k\"\n[weather]\napi_key = \"x\"\n")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn influxdb_alone_is_enough() {
+        let c = parse(
+            "[influxdb]\nurl = \"http://localhost:8086\"\norg = \"o\"\nbucket = \"b\"\n\
+             token = \"t\"\n",
+        )
+        .unwrap();
+        assert!(c.adafruit.is_none());
+        let i = c.influxdb.unwrap();
+        assert_eq!(500, i.batch_size);
+        assert_eq!(Duration::from_secs(10), i.flush_interval());
+    }
+
+    #[test]
+    fn homeassistant_node_id_is_checked() {
+        let c = parse("[homeassistant]\nhost = \"broker\"\n").unwrap();
+        let o = c.homeassistant.unwrap().mqtt();
+        assert_eq!(("broker", 1883, false), (o.host.as_str(), o.port, o.tls));
+        assert!(parse("[homeassistant]\nhost = \"broker\"\nnode_id = \"a/b\"\n").is_err());
+    }
+
+    #[test]
+    fn sim_waveforms_are_checked() {
+        let base = "[prometheus]\n[sensor.sim]\n";
+        let c = parse(&format!("{}time_scale = 60.0\n", base)).unwrap();
+        assert_eq!(60.0, c.sensor.sim.time_scale);
+        assert_eq!(450.0, c.sensor.sim.co2_baseline_ppm);
+        assert!(parse(&format!("{}co2_spike_mins = 200\n", base)).is_err());
+        assert!(parse(&format!("{}sunrise_hour = 20.0\n", base)).is_err());
+        assert!(parse(&format!("{}lux = 1.0\n", base)).is_err());
+    }
+
+    #[test]
+    fn sgp30_options_are_checked() {
+        let c = parse("[prometheus]\n[sensor.sgp30]\nbaseline_file = \"/tmp/b.json\"\n").unwrap();
+        assert_eq!(
+            Some(PathBuf::from("/tmp/b.json")),
+            c.sensor.sgp30.baseline_file
+        );
+        assert!(parse("[prometheus]\n[sensor.sgp30]\nwarmup_secs = 10\n").is_err());
+        assert!(parse("[prometheus]\n[sensor.sgp30]\nbaseline = \"/tmp/b.json\"\n").is_err());
+    }
+
+    #[test]
+    fn co2_sensors_are_optional() {
+        let c = parse("[prometheus]\n").unwrap();
+        assert!(c.sensor.scd4x.is_none());
+        let c =
+            parse("[prometheus]\n[sensor.scd4x]\nautomatic_self_calibration = false\n").unwrap();
+        let scd4x = c.sensor.scd4x.unwrap();
+        assert!(scd4x.enabled);
+        assert_eq!(Some(false), scd4x.calibration().automatic_self_calibration);
+        assert!(parse("[prometheus]\n[sensor.scd30]\nforced_recalibration_ppm = 420\n").is_ok());
+        assert!(parse("[prometheus]\n[sensor.scd30]\nforced_recalibration_ppm = 4000\n").is_err());
+    }
+
+    #[test]
+    fn climate_source_must_be_enabled() {
+        let c = parse("[prometheus]\n").unwrap();
+        assert_eq!(ClimateSource::Bme280, c.sensor.climate_source);
+        assert!(c.sensor.sht4x.is_none());
+
+        let c = parse(
+            "[prometheus]\n[sensor]\nclimate_source = \"sht4x\"\n\
+             [sensor.sht4x]\nheater_interval_secs = 3600\n",
+        )
+        .unwrap();
+        let settings = c.sensor.sht4x.unwrap().settings();
+        assert_eq!(0x44, settings.address);
+        assert_eq!(Some(Duration::from_secs(3600)), settings.heater_interval);
+
+        assert!(parse("[prometheus]\n[sensor]\nclimate_source = \"sht3x\"\n").is_err());
+        assert!(parse(
+            "[prometheus]\n[sensor]\nclimate_source = \"sht3x\"\n\
+             [sensor.sht3x]\nenabled = false\n"
+        )
+        .is_err());
+        assert!(parse("[prometheus]\n[sensor]\nclimate_source = \"dht22\"\n").is_err());
+    }
+
+    #[test]
+    fn light_source_must_be_enabled() {
+        let c = parse("[prometheus]\n").unwrap();
+        assert_eq!(LightSource::Tsl2591, c.sensor.light_source);
+        assert!(c.sensor.tsl2591.enabled);
+        assert!(c.sensor.veml7700.is_none());
+
+        let c = parse(
+            "[prometheus]\n[sensor]\nlight_source = \"veml7700\"\n\
+             [sensor.tsl2591]\nenabled = false\n[sensor.veml7700]\n",
+        )
+        .unwrap();
+        assert!(!c.sensor.tsl2591.enabled);
+        assert!(c.sensor.veml7700.unwrap().enabled);
+
+        assert!(parse("[prometheus]\n[sensor]\nlight_source = \"veml7700\"\n").is_err());
+        assert!(parse("[prometheus]\n[sensor.tsl2591]\nenabled = false\n").is_err());
+    }
+
+    #[test]
+    fn dark_floor_is_positive() {
+        let c = parse("[prometheus]\n").unwrap();
+        assert_eq!(0.0002, c.sensor.dark_floor_lux);
+        let c = parse("[prometheus]\n[sensor]\ndark_floor_lux = 0.01\n").unwrap();
+        assert_eq!(0.01, c.sensor.dark_floor_lux);
+        assert!(parse("[prometheus]\n[sensor]\ndark_floor_lux = 0.0\n").is_err());
+        assert!(parse("[prometheus]\n[sensor]\ndark_floor_lux = -1.0\n").is_err());
+        assert!(parse("[prometheus]\n[sensor.tsl2591]\ndark_floor_lux = 0.01\n").is_err());
+    }
+
+    #[test]
+    fn bme680_can_replace_the_bme280() {
+        let c = parse(
+            "[prometheus]\n[sensor]\nclimate_source = \"bme680\"\n\
+             [sensor.bme280]\nenabled = false\n[sensor.bme680]\n",
+        )
+        .unwrap();
+        let settings = c.sensor.bme680.unwrap().settings();
+        assert_eq!(0x77, settings.address);
+        assert_eq!(1, settings.heater_profile.len());
+        assert_eq!(320, settings.heater_profile[0].temperature_c);
+
+        // Both at 0x77.
+        assert!(parse("[prometheus]\n[sensor.bme680]\n").is_err());
+        assert!(parse("[prometheus]\n[sensor.bme680]\naddress = 0x76\n").is_ok());
+        // The BME280 is the default climate source.
+        assert!(parse("[prometheus]\n[sensor.bme280]\nenabled = false\n").is_err());
+    }
+
+    #[test]
+    fn bme680_heater_profile_is_checked() {
+        let base = "[prometheus]\n[sensor.bme680]\naddress = 0x76\n";
+        let c = parse(&format!(
+            "{}heater_profile = [{{ temperature_c = 200, duration_ms = 100 }}, \
+             {{ temperature_c = 400, duration_ms = 4032 }}]\n",
+            base
+        ))
+        .unwrap();
+        assert_eq!(2, c.sensor.bme680.unwrap().heater_profile.len());
+        assert!(parse(&format!("{}heater_profile = []\n", base)).is_err());
+        assert!(parse(&format!(
+            "{}heater_profile = [{{ temperature_c = 450, duration_ms = 100 }}]\n",
+            base
+        ))
+        .is_err());
+        assert!(parse(&format!(
+            "{}heater_profile = [{{ temperature_c = 300, duration_ms = 0 }}]\n",
+            base
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn sht_options_are_checked() {
+        assert!(parse("[prometheus]\n[sensor.sht3x]\naddress = 0x45\n").is_ok());
+        assert!(parse("[prometheus]\n[sensor.sht3x]\naddress = 0x46\n").is_err());
+        assert!(parse("[prometheus]\n[sensor.sht4x]\nheater_interval_secs = 5\n").is_err());
+        // Both default to 0x44.
+        assert!(parse("[prometheus]\n[sensor.sht4x]\n[sensor.sht3x]\n").is_err());
+        assert!(parse("[prometheus]\n[sensor.sht4x]\n[sensor.sht3x]\naddress = 0x45\n").is_ok());
+    }
+
+    #[test]
+    fn particulate_sensors_are_optional() {
+        let c =
+            parse("[prometheus]\n[sensor]\nparticulate_source = \"pms5003\"\n[sensor.pms5003]\n")
+                .unwrap();
+        assert!(c.sensor.pmsa003i.is_none());
+        assert_eq!(
+            PathBuf::from("/dev/serial0"),
+            c.sensor.pms5003.unwrap().port
+        );
+        let c = parse("[prometheus]\n[sensor.pmsa003i]\nenabled = false\n").unwrap();
+        assert!(!c.sensor.pmsa003i.unwrap().enabled);
+    }
+
+    #[test]
+    fn particulate_source_must_be_enabled() {
+        let c = parse("[prometheus]\n[sensor.pmsa003i]\n[sensor.pms5003]\n").unwrap();
+        assert_eq!(ParticulateSource::Pmsa003i, c.sensor.particulate_source);
+
+        assert!(parse("[prometheus]\n[sensor.pms5003]\n").is_err());
+        assert!(parse(
+            "[prometheus]\n[sensor]\nparticulate_source = \"pms5003\"\n[sensor.pmsa003i]\n"
+        )
+        .is_err());
+        // Nothing to choose between.
+        assert!(parse("[prometheus]\n[sensor]\nparticulate_source = \"pms5003\"\n").is_ok());
+    }
+
+    #[test]
+    fn history_alone_is_enough() {
+        let c = parse("[history]\npath = \"/tmp/history.db\"\n").unwrap();
+        let h = c.history.unwrap();
+        assert_eq!(Duration::from_secs(30 * 24 * 60 * 60), h.raw_retention());
+    }
+
+    #[test]
+    fn at_least_one_sink_is_required() {
+        assert!(parse("[sensor]\naltitude = 10.0\n").is_err());
+        let e =
+            parse("[adafruit]\nenabled = false\nio_user = \"u\"\nio_key = \"k\"\n").unwrap_err();
+        assert!(e.to_string().contains("no sinks"));
+    }
+}
@@ -16,9 +16,6 @@
 
 use core::f32::consts;
 
-// Local altitude in meters.
-const ALTITUDE: f32 = 100.0;
-
 pub fn celsius_to_fahrenheit(celsius: f32) -> f32 {
     celsius * 1.8 + 32.0
 }
@@ -27,8 +24,9 @@ pub fn celsius_to_kelvin(celsius: f32) -> f32 {
     celsius + 273.15
 }
 
-pub fn raw_pressure_to_sealevel(raw_hpa: f32, celsius: f32) -> f32 {
-    raw_hpa * (1.0 - 0.0065 * ALTITUDE / (0.0065 + celsius_to_kelvin(celsius))).powf(-5.257)
+// altitude is the local altitude in meters.
+pub fn raw_pressure_to_sealevel(raw_hpa: f32, celsius: f32, altitude: f32) -> f32 {
+    raw_hpa * (1.0 - 0.0065 * altitude / (0.0065 + celsius_to_kelvin(celsius))).powf(-5.257)
 }
 
 pub fn hpa_to_inhg(hpa: f32) -> f32 {
@@ -67,19 +65,19 @@ mod tests {
     fn rp_to_s_works() {
         assert_eq!(
             1_012.0,
-            raw_pressure_to_sealevel(1000.0, 15.0).round()
+            raw_pressure_to_sealevel(1000.0, 15.0, 100.0).round()
         );
         assert_eq!(
             1_025.0,
-            raw_pressure_to_sealevel(1_013.25, 15.0).round()
+            raw_pressure_to_sealevel(1_013.25, 15.0, 100.0).round()
         );
         assert_eq!(
             1_010.0,
-            raw_pressure_to_sealevel(999.0, 40.0).round()
+            raw_pressure_to_sealevel(999.0, 40.0, 100.0).round()
         );
         assert_eq!(
             1_010.0,
-            raw_pressure_to_sealevel(999.0, 40.0).round()
+            raw_pressure_to_sealevel(999.0, 40.0, 100.0).round()
         );
     }
 
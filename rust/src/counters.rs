@@ -0,0 +1,41 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+//! Process-wide event counters (e.g. failed uploads), exported by the
+//! Prometheus endpoint when it is enabled.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+static COUNTERS: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(BTreeMap::new());
+
+pub fn add(name: &'static str, n: u64) {
+    *COUNTERS.lock().unwrap().entry(name).or_default() += n;
+}
+
+pub fn inc(name: &'static str) {
+    add(name, 1);
+}
+
+/// Current values, sorted by name.
+pub fn snapshot() -> Vec<(&'static str, u64)> {
+    COUNTERS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(k, v)| (*k, *v))
+        .collect()
+}
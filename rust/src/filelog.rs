@@ -0,0 +1,404 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+//! Appends every metric to a local CSV or JSON Lines file. The active file is
+//! `<prefix>.csv` (or `.jsonl`); on rotation it is renamed to
+//! `<prefix>-<start time>.csv` and optionally gzipped.
+
+use crate::adafruit::Metric;
+use crate::sink::Sink;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{debug, error, info, warn};
+use serde_json::json;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+const CSV_HEADER: &str = "created_at,feed,value,lat,lon,ele\n";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Csv,
+    Jsonl,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotation {
+    // At the first write after midnight UTC.
+    Daily,
+    // Before the file would grow past this many bytes.
+    Size(u64),
+}
+
+#[derive(Debug)]
+pub struct CallParams {
+    pub dir: PathBuf,
+    pub prefix: String,
+    pub format: Format,
+    pub rotation: Rotation,
+    // Compress rotated files.
+    pub gzip: bool,
+}
+
+struct Active {
+    writer: BufWriter<File>,
+    size: u64,
+    started: DateTime<Utc>,
+}
+
+// The file logger sink.
+pub struct FileLog {
+    params: CallParams,
+    active: Option<Active>,
+}
+
+impl FileLog {
+    pub fn new(params: CallParams) -> FileLog {
+        info!(
+            "Logging metrics to {}",
+            params.dir.join(active_name(&params)).display()
+        );
+        FileLog {
+            params,
+            active: None,
+        }
+    }
+
+    fn write(&mut self, m: &Metric, now: DateTime<Utc>) -> io::Result<()> {
+        let line = format_line(self.params.format, m);
+        if let Some(a) = &self.active {
+            let rotate = match self.params.rotation {
+                Rotation::Daily => a.started.date_naive() != now.date_naive(),
+                Rotation::Size(max) => a.size + line.len() as u64 > max,
+            };
+            if rotate && a.size > 0 {
+                self.rotate()?;
+            }
+        }
+        let a = match &mut self.active {
+            Some(a) => a,
+            None => self.active.insert(open_active(&self.params, now)?),
+        };
+        a.writer.write_all(line.as_bytes())?;
+        a.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut a = match self.active.take() {
+            Some(a) => a,
+            None => return Ok(()),
+        };
+        a.writer.flush()?;
+        drop(a.writer);
+
+        let ext = extension(self.params.format);
+        let stamp = a.started.format("%Y%m%dT%H%M%SZ");
+        let mut rotated = self
+            .params
+            .dir
+            .join(format!("{}-{}.{}", self.params.prefix, stamp, ext));
+        let mut n = 1;
+        while rotated.exists() || gz_path(&rotated).exists() {
+            rotated = self
+                .params
+                .dir
+                .join(format!("{}-{}-{}.{}", self.params.prefix, stamp, n, ext));
+            n += 1;
+        }
+        fs::rename(self.params.dir.join(active_name(&self.params)), &rotated)?;
+        debug!("Rotated metrics log to {}", rotated.display());
+        if self.params.gzip {
+            gzip(&rotated)?;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for FileLog {
+    fn name(&self) -> &'static str {
+        "file log"
+    }
+
+    fn send(&mut self, m: Metric) {
+        if !m.value.is_finite() {
+            warn!("Not logging non-finite {} = {}", m.feed, m.value);
+            return;
+        }
+        if let Err(e) = self.write(&m, Utc::now()) {
+            error!("Unable to log {} = {}: {}", m.feed, m.value, e);
+            // Start over with a fresh handle next time.
+            self.active = None;
+        }
+    }
+
+    fn flush(&mut self) -> Option<Instant> {
+        if let Some(a) = &mut self.active {
+            if let Err(e) = a.writer.flush() {
+                error!("Unable to write metrics log: {}", e);
+                self.active = None;
+            }
+        }
+        None
+    }
+
+    fn shutdown(&mut self) {
+        self.flush();
+    }
+}
+
+fn extension(format: Format) -> &'static str {
+    match format {
+        Format::Csv => "csv",
+        Format::Jsonl => "jsonl",
+    }
+}
+
+fn active_name(params: &CallParams) -> String {
+    format!("{}.{}", params.prefix, extension(params.format))
+}
+
+fn gz_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".gz");
+    PathBuf::from(name)
+}
+
+// Opens (or continues) the active file, writing the CSV header if it is new.
+fn open_active(params: &CallParams, now: DateTime<Utc>) -> io::Result<Active> {
+    fs::create_dir_all(&params.dir)?;
+    let path = params.dir.join(active_name(params));
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let meta = file.metadata()?;
+    let started = if meta.len() > 0 {
+        // Picking up where a previous run left off.
+        meta.created()
+            .or_else(|_| meta.modified())
+            .map(DateTime::<Utc>::from)
+            .unwrap_or(now)
+    } else {
+        now
+    };
+    let mut active = Active {
+        writer: BufWriter::new(file),
+        size: meta.len(),
+        started,
+    };
+    if active.size == 0 && params.format == Format::Csv {
+        active.writer.write_all(CSV_HEADER.as_bytes())?;
+        active.size = CSV_HEADER.len() as u64;
+    }
+    Ok(active)
+}
+
+fn format_line(format: Format, m: &Metric) -> String {
+    let created_at = m.created_at.to_rfc3339_opts(SecondsFormat::Secs, true);
+    let location = m.location();
+    match format {
+        Format::Csv => {
+            let (lat, lon, ele) = match location {
+                Some(l) => (
+                    l.lat.to_string(),
+                    l.lon.to_string(),
+                    l.ele.map(|e| e.to_string()).unwrap_or_default(),
+                ),
+                None => Default::default(),
+            };
+            format!(
+                "{},{},{},{},{},{}\n",
+                created_at,
+                csv_field(&m.feed),
+                csv_field(&m.value.to_string()),
+                lat,
+                lon,
+                ele
+            )
+        }
+        Format::Jsonl => {
+            let mut record = json!({
+                "created_at": created_at,
+                "feed": m.feed,
+                "value": m.value,
+            });
+            if let Some(l) = location {
+                record["lat"] = l.lat.into();
+                record["lon"] = l.lon.into();
+                if let Some(ele) = l.ele {
+                    record["ele"] = ele.into();
+                }
+            }
+            format!("{}\n", record)
+        }
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+// Replaces `path` with `path.gz`.
+fn gzip(path: &Path) -> io::Result<()> {
+    let gz = gz_path(path);
+    let mut encoder = GzEncoder::new(File::create(&gz)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adafruit::{Location, Value};
+    use chrono::TimeZone;
+    use flate2::read::GzDecoder;
+    use std::env;
+    use std::io::Read;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!(
+            "iot-central-filelog-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn params(dir: &Path, format: Format, rotation: Rotation, gzip: bool) -> CallParams {
+        CallParams {
+            dir: dir.to_owned(),
+            prefix: "metrics".to_owned(),
+            format,
+            rotation,
+            gzip,
+        }
+    }
+
+    fn metric(feed: &str, value: impl Into<Value>, at: DateTime<Utc>) -> Metric {
+        let mut m = Metric::new(feed, value);
+        m.created_at = at;
+        m
+    }
+
+    fn files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn lines_use_adafruit_feed_names() {
+        let at = Utc.with_ymd_and_hms(2022, 10, 18, 6, 30, 0).unwrap();
+        assert_eq!(
+            "2022-10-18T06:30:00Z,mbr-bme280.temperature,21.5,,,\n",
+            format_line(Format::Csv, &metric("mbr-bme280.temperature", 21.5, at))
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&format_line(Format::Jsonl, &metric("mbr.lux", 12.0, at)))
+                .unwrap();
+        assert_eq!("mbr.lux", json["feed"]);
+        assert_eq!(12.0, json["value"]);
+        assert_eq!("2022-10-18T06:30:00Z", json["created_at"]);
+        assert!(json.get("lat").is_none());
+    }
+
+    #[test]
+    fn text_and_locations_are_logged() {
+        let at = Utc.with_ymd_and_hms(2022, 10, 18, 6, 30, 0).unwrap();
+        let mut m = metric("weather.description", "rain, heavy", at);
+        m.location = Some(Location {
+            lat: 37.5,
+            lon: -122.25,
+            ele: None,
+        });
+        assert_eq!(
+            "2022-10-18T06:30:00Z,weather.description,\"rain, heavy\",37.5,-122.25,\n",
+            format_line(Format::Csv, &m)
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&format_line(Format::Jsonl, &m)).unwrap();
+        assert_eq!("rain, heavy", json["value"]);
+        assert_eq!(37.5, json["lat"]);
+        assert_eq!(-122.25, json["lon"]);
+        assert!(json.get("ele").is_none());
+    }
+
+    #[test]
+    fn rotates_daily_and_compresses() {
+        let dir = temp_dir("daily");
+        let mut log = FileLog::new(params(&dir, Format::Csv, Rotation::Daily, true));
+        let day1 = Utc.with_ymd_and_hms(2022, 10, 17, 23, 59, 0).unwrap();
+        let day2 = Utc.with_ymd_and_hms(2022, 10, 18, 0, 1, 0).unwrap();
+        log.write(&metric("mbr.temperature", 70.0, day1), day1)
+            .unwrap();
+        log.write(&metric("mbr.temperature", 71.0, day1), day1)
+            .unwrap();
+        log.write(&metric("mbr.temperature", 72.0, day2), day2)
+            .unwrap();
+        log.flush();
+
+        assert_eq!(
+            vec!["metrics-20221017T235900Z.csv.gz", "metrics.csv"],
+            files(&dir)
+        );
+        let mut old = String::new();
+        GzDecoder::new(File::open(dir.join("metrics-20221017T235900Z.csv.gz")).unwrap())
+            .read_to_string(&mut old)
+            .unwrap();
+        assert_eq!(3, old.lines().count());
+        assert!(old.starts_with(CSV_HEADER));
+        let new = fs::read_to_string(dir.join("metrics.csv")).unwrap();
+        assert_eq!(
+            "created_at,feed,value,lat,lon,ele\n2022-10-18T00:01:00Z,mbr.temperature,72,,,\n",
+            new
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = temp_dir("size");
+        let mut log = FileLog::new(params(&dir, Format::Jsonl, Rotation::Size(200), false));
+        let at = Utc.with_ymd_and_hms(2022, 10, 18, 12, 0, 0).unwrap();
+        for i in 0..5 {
+            log.write(&metric("mbr-sgp30.co2", 400.0 + f64::from(i), at), at)
+                .unwrap();
+        }
+        log.flush();
+
+        let names = files(&dir);
+        assert_eq!(3, names.len());
+        let mut lines = 0;
+        for name in &names {
+            let text = fs::read_to_string(dir.join(name)).unwrap();
+            assert!(text.len() <= 200);
+            lines += text.lines().count();
+        }
+        assert_eq!(5, lines);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
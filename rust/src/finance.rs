@@ -16,7 +16,7 @@
 
 use crate::adafruit;
 
-//use chrono::{offset::TimeZone, Local, Utc};
+use chrono::{TimeZone, Utc};
 use log::{debug, info};
 use serde::Deserialize;
 use std::sync::{mpsc, Arc, Condvar, Mutex};
@@ -26,6 +26,7 @@ use std::time::Duration;
 pub struct CallParams {
     pub shutdown: Arc<(Mutex<bool>, Condvar)>,
     pub tx: mpsc::Sender<adafruit::Metric>,
+    pub update_period: Duration,
     pub base_url: String,
     pub api_key: String,
     pub symbols: Vec<String>,
@@ -34,14 +35,16 @@ pub struct CallParams {
 #[derive(Deserialize, Debug, Default)]
 struct Quote {
     #[serde(rename = "c")]
-    current_price: f32,
+    current_price: f64,
+    // When the price was last updated, in Unix seconds.
+    #[serde(rename = "t")]
+    timestamp: i64,
 }
 
 pub fn finance_updater(params: CallParams) {
     info!("finance_updater starting");
     debug!("finance_updater parameters {:?}", params);
     let client = reqwest::blocking::Client::new();
-    let update_period = Duration::from_secs(10 * 60);
     loop {
         for symbol in &params.symbols {
             let url = format!("{}?symbol={}", params.base_url, symbol);
@@ -55,16 +58,16 @@ pub fn finance_updater(params: CallParams) {
                     debug!("GET finance (symbol: {}): {:?}", symbol, r.status());
                     let q: Quote = r.json().unwrap_or_default();
                     if q.current_price != 0.0 {
-                        params
-                            .tx
-                            .send(adafruit::Metric {
-                                feed: format!(
-                                    "finance.{}",
-                                    symbol.to_lowercase().replace(':', "-")
-                                ),
-                                value: q.current_price,
-                            })
-                            .unwrap();
+                        let mut m = adafruit::Metric::new(
+                            format!("finance.{}", symbol.to_lowercase().replace(':', "-")),
+                            q.current_price,
+                        );
+                        if q.timestamp > 0 {
+                            if let Some(t) = Utc.timestamp_opt(q.timestamp, 0).single() {
+                                m.created_at = t;
+                            }
+                        }
+                        params.tx.send(m).unwrap();
                     }
                 }
                 _ => {
@@ -76,9 +79,11 @@ pub fn finance_updater(params: CallParams) {
         // Wait for next update period, or  shutdown signal.
         let (lock, cvar) = &*params.shutdown;
         let shutdown = cvar
-            .wait_timeout_while(lock.lock().unwrap(), update_period, |&mut shutdown| {
-                !shutdown
-            })
+            .wait_timeout_while(
+                lock.lock().unwrap(),
+                params.update_period,
+                |&mut shutdown| !shutdown,
+            )
             .unwrap();
         if *shutdown.0 {
             break;
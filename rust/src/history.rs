@@ -0,0 +1,263 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+//! On-device history in SQLite. Every sample goes into `samples`, which is
+//! pruned after the raw retention period; `rollups` keeps per-feed hourly and
+//! daily min/max/mean/count (UTC buckets) for much longer.
+
+use crate::adafruit::Metric;
+use crate::sink::Sink;
+
+use chrono::Utc;
+use log::{debug, error, info, warn};
+use rusqlite::{params, Connection};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+// Lets a burst of readings share one transaction.
+const COMMIT_DELAY: Duration = Duration::from_secs(1);
+const PRUNE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+const HOUR: i64 = 60 * 60;
+const DAY: i64 = 24 * HOUR;
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    CREATE TABLE IF NOT EXISTS samples (
+        feed TEXT NOT NULL,
+        time INTEGER NOT NULL,
+        value REAL NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS samples_feed_time ON samples (feed, time);
+    CREATE TABLE IF NOT EXISTS rollups (
+        period TEXT NOT NULL,
+        feed TEXT NOT NULL,
+        start INTEGER NOT NULL,
+        min REAL NOT NULL,
+        max REAL NOT NULL,
+        mean REAL NOT NULL,
+        count INTEGER NOT NULL,
+        PRIMARY KEY (period, feed, start)
+    );
+";
+
+#[derive(Debug)]
+pub struct CallParams {
+    pub path: PathBuf,
+    // How long individual samples are kept.
+    pub raw_retention: Duration,
+    // How long hourly rollups are kept; daily rollups are kept forever.
+    pub hourly_retention: Duration,
+}
+
+// The SQLite history sink.
+pub struct History {
+    db: Connection,
+    params: CallParams,
+    // Samples waiting to be committed: feed, Unix time in seconds and value.
+    pending: Vec<(String, i64, f64)>,
+    first_pending: Option<Instant>,
+    next_prune: Instant,
+}
+
+impl History {
+    pub fn open(params: CallParams) -> rusqlite::Result<History> {
+        let db = Connection::open(&params.path)?;
+        db.execute_batch(SCHEMA)?;
+        info!("Recording history in {}", params.path.display());
+        Ok(History {
+            db,
+            params,
+            pending: Vec::new(),
+            first_pending: None,
+            next_prune: Instant::now(),
+        })
+    }
+
+    fn commit(&mut self) -> rusqlite::Result<()> {
+        let tx = self.db.transaction()?;
+        {
+            let mut insert =
+                tx.prepare_cached("INSERT INTO samples (feed, time, value) VALUES (?1, ?2, ?3)")?;
+            let mut rollup = tx.prepare_cached(
+                "INSERT INTO rollups (period, feed, start, min, max, mean, count)
+                 VALUES (?1, ?2, ?3, ?4, ?4, ?4, 1)
+                 ON CONFLICT (period, feed, start) DO UPDATE SET
+                     min = min(min, excluded.min),
+                     max = max(max, excluded.max),
+                     mean = (mean * count + excluded.mean) / (count + 1),
+                     count = count + 1",
+            )?;
+            for (feed, t, value) in &self.pending {
+                insert.execute(params![feed, t, value])?;
+                rollup.execute(params!["hour", feed, t - t.rem_euclid(HOUR), value])?;
+                rollup.execute(params!["day", feed, t - t.rem_euclid(DAY), value])?;
+            }
+        }
+        tx.commit()
+    }
+
+    fn commit_pending(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        if let Err(e) = self.commit() {
+            error!("Dropping {} history samples: {}", self.pending.len(), e);
+        }
+        self.pending.clear();
+        self.first_pending = None;
+    }
+
+    fn prune(&mut self, now: i64) -> rusqlite::Result<()> {
+        let raw_cutoff = now - self.params.raw_retention.as_secs() as i64;
+        let hourly_cutoff = now - self.params.hourly_retention.as_secs() as i64;
+        let samples = self
+            .db
+            .execute("DELETE FROM samples WHERE time < ?1", [raw_cutoff])?;
+        let hours = self.db.execute(
+            "DELETE FROM rollups WHERE period = 'hour' AND start < ?1",
+            [hourly_cutoff],
+        )?;
+        debug!("Pruned {} samples and {} hourly rollups", samples, hours);
+        Ok(())
+    }
+}
+
+impl Sink for History {
+    fn name(&self) -> &'static str {
+        "SQLite history"
+    }
+
+    fn send(&mut self, m: Metric) {
+        let value = match m.value.as_f64() {
+            Some(v) if v.is_finite() => v,
+            Some(_) => {
+                warn!("Not recording non-finite {} = {}", m.feed, m.value);
+                return;
+            }
+            // Only numbers can be rolled up.
+            None => return,
+        };
+        self.pending.push((m.feed, m.created_at.timestamp(), value));
+        self.first_pending.get_or_insert_with(Instant::now);
+    }
+
+    fn flush(&mut self) -> Option<Instant> {
+        let now = Instant::now();
+        if let Some(first) = self.first_pending {
+            if now < first + COMMIT_DELAY {
+                return Some(first + COMMIT_DELAY);
+            }
+            self.commit_pending();
+        }
+        if now >= self.next_prune {
+            if let Err(e) = self.prune(Utc::now().timestamp()) {
+                error!("History pruning failed: {}", e);
+            }
+            self.next_prune = now + PRUNE_PERIOD;
+        }
+        Some(self.next_prune)
+    }
+
+    fn shutdown(&mut self) {
+        self.commit_pending();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history() -> History {
+        History::open(CallParams {
+            path: ":memory:".into(),
+            raw_retention: Duration::from_secs(2 * DAY as u64),
+            hourly_retention: Duration::from_secs(30 * DAY as u64),
+        })
+        .unwrap()
+    }
+
+    fn record(h: &mut History, feed: &str, value: f64, t: i64) {
+        h.pending.push((feed.to_owned(), t, value));
+        h.commit().unwrap();
+        h.pending.clear();
+    }
+
+    fn rollup(h: &History, period: &str, feed: &str, start: i64) -> (f64, f64, f64, i64) {
+        h.db.query_row(
+            "SELECT min, max, mean, count FROM rollups
+             WHERE period = ?1 AND feed = ?2 AND start = ?3",
+            params![period, feed, start],
+            |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?)),
+        )
+        .unwrap()
+    }
+
+    fn count(h: &History, table: &str) -> i64 {
+        h.db.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |r| r.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn samples_roll_up_by_hour_and_day() {
+        let mut h = history();
+        let day = 19_000 * DAY;
+        record(&mut h, "mbr-sgp30.co2", 400.0, day + 10);
+        record(&mut h, "mbr-sgp30.co2", 800.0, day + 20);
+        record(&mut h, "mbr-sgp30.co2", 600.0, day + HOUR + 5);
+        record(&mut h, "mbr.temperature", 70.0, day + 30);
+
+        assert_eq!(4, count(&h, "samples"));
+        assert_eq!(
+            (400.0, 800.0, 600.0, 2),
+            rollup(&h, "hour", "mbr-sgp30.co2", day)
+        );
+        assert_eq!(
+            (600.0, 600.0, 600.0, 1),
+            rollup(&h, "hour", "mbr-sgp30.co2", day + HOUR)
+        );
+        assert_eq!(
+            (400.0, 800.0, 600.0, 3),
+            rollup(&h, "day", "mbr-sgp30.co2", day)
+        );
+    }
+
+    #[test]
+    fn pruning_keeps_rollups_longer_than_samples() {
+        let mut h = history();
+        let now = 19_000 * DAY;
+        record(&mut h, "mbr.lux", 10.0, now - 40 * DAY);
+        record(&mut h, "mbr.lux", 20.0, now - 10 * DAY);
+        record(&mut h, "mbr.lux", 30.0, now - HOUR);
+        h.prune(now).unwrap();
+
+        assert_eq!(1, count(&h, "samples"));
+        // Two hourly rollups survive, and all three daily ones.
+        assert_eq!(5, count(&h, "rollups"));
+    }
+
+    #[test]
+    fn flush_commits_after_delay() {
+        let mut h = history();
+        h.send(Metric::new("weather.temp", 5.0));
+        h.send(Metric::new("weather.temp", f64::NAN));
+        h.send(Metric::new("weather.description", "mist"));
+        assert!(h.flush().is_some());
+        assert_eq!(0, count(&h, "samples"));
+        h.shutdown();
+        assert_eq!(1, count(&h, "samples"));
+    }
+}
@@ -0,0 +1,718 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+use crate::adafruit::Metric;
+use crate::mqtt;
+use crate::sensor::{self, availability, ClimateSource, LightSource, ParticulateSource};
+use crate::sink::{FeedName, Sink};
+
+use log::debug;
+use serde_json::json;
+use std::collections::{BTreeMap, HashSet};
+use std::time::{Duration, Instant};
+
+// How often to check for sensors going offline or coming back.
+const AVAILABILITY_POLL: Duration = Duration::from_secs(1);
+
+const ONLINE: &[u8] = b"online";
+const OFFLINE: &[u8] = b"offline";
+
+#[derive(Debug)]
+pub struct CallParams {
+    // The broker connection; the will and birth are set from `node_id`.
+    pub mqtt: mqtt::Options,
+    // Home Assistant's discovery topic prefix, normally "homeassistant".
+    pub discovery_prefix: String,
+    // Identifies this device in Home Assistant and prefixes its state topics.
+    pub node_id: String,
+    // The sensor behind mbr.temperature and mbr.humidity.
+    pub climate_source: ClimateSource,
+    // The sensor behind mbr.lux.
+    pub light_source: LightSource,
+    // The sensor behind mbr.pm1, mbr.pm25 and mbr.pm10.
+    pub particulate_source: ParticulateSource,
+}
+
+// Stand for `CallParams::climate_source`, the sensor it implies for
+// mbr.pressure, `CallParams::light_source` and
+// `CallParams::particulate_source`, in `Quantity::primary`.
+const CLIMATE_SENSOR: &str = "<climate>";
+const PRESSURE_SENSOR: &str = "<pressure>";
+const LIGHT_SENSOR: &str = "<light>";
+const PARTICULATE_SENSOR: &str = "<particulate>";
+
+// How Home Assistant shows one quantity.
+struct Quantity {
+    quantity: &'static str,
+    // For the mbr.* feeds, the sensor behind them; None for what sensors
+    // publish under their own mbr-<sensor>.* feeds.
+    primary: Option<&'static str>,
+    name: &'static str,
+    device_class: Option<&'static str>,
+    unit: Option<&'static str>,
+}
+
+// The mbr.* feeds are in the units Adafruit IO has always had, and the
+// sensors' own feeds metric. A sensor's quantity numbered with a suffix, such
+// as gas-resistance-2 for the BME680's second heater step, is shown as the
+// unnumbered one.
+const QUANTITIES: &[Quantity] = &[
+    Quantity {
+        quantity: "temperature",
+        primary: Some(CLIMATE_SENSOR),
+        name: "Temperature",
+        device_class: Some("temperature"),
+        unit: Some("°F"),
+    },
+    Quantity {
+        quantity: "humidity",
+        primary: Some(CLIMATE_SENSOR),
+        name: "Humidity",
+        device_class: Some("humidity"),
+        unit: Some("%"),
+    },
+    Quantity {
+        quantity: "abs-humidity",
+        primary: Some(CLIMATE_SENSOR),
+        name: "Absolute Humidity",
+        device_class: None,
+        unit: Some("g/m³"),
+    },
+    Quantity {
+        quantity: "pressure",
+        primary: Some(PRESSURE_SENSOR),
+        name: "Sea Level Pressure",
+        device_class: Some("pressure"),
+        unit: Some("inHg"),
+    },
+    Quantity {
+        quantity: "lux",
+        primary: Some(LIGHT_SENSOR),
+        name: "Illuminance",
+        device_class: Some("illuminance"),
+        unit: Some("lx"),
+    },
+    Quantity {
+        quantity: "lux-db",
+        primary: Some(LIGHT_SENSOR),
+        name: "Illuminance Level",
+        device_class: None,
+        unit: Some("dB"),
+    },
+    Quantity {
+        quantity: "pm1",
+        primary: Some(PARTICULATE_SENSOR),
+        name: "PM1.0",
+        device_class: Some("pm1"),
+        unit: Some("µg/m³"),
+    },
+    Quantity {
+        quantity: "pm25",
+        primary: Some(PARTICULATE_SENSOR),
+        name: "PM2.5",
+        device_class: Some("pm25"),
+        unit: Some("µg/m³"),
+    },
+    Quantity {
+        quantity: "pm10",
+        primary: Some(PARTICULATE_SENSOR),
+        name: "PM10",
+        device_class: Some("pm10"),
+        unit: Some("µg/m³"),
+    },
+    Quantity {
+        quantity: "temperature",
+        primary: None,
+        name: "Temperature",
+        device_class: Some("temperature"),
+        unit: Some("°C"),
+    },
+    Quantity {
+        quantity: "humidity",
+        primary: None,
+        name: "Humidity",
+        device_class: Some("humidity"),
+        unit: Some("%"),
+    },
+    Quantity {
+        quantity: "pressure",
+        primary: None,
+        name: "Pressure",
+        device_class: Some("pressure"),
+        unit: Some("hPa"),
+    },
+    Quantity {
+        quantity: "gas-resistance",
+        primary: None,
+        name: "Gas Resistance",
+        device_class: None,
+        unit: Some("Ω"),
+    },
+    Quantity {
+        quantity: "air-quality",
+        primary: None,
+        name: "Air Quality",
+        device_class: None,
+        unit: Some("%"),
+    },
+    Quantity {
+        quantity: "co2",
+        primary: None,
+        name: "CO₂",
+        device_class: Some("carbon_dioxide"),
+        unit: Some("ppm"),
+    },
+    Quantity {
+        quantity: "tvoc",
+        primary: None,
+        name: "TVOC",
+        device_class: Some("volatile_organic_compounds_parts"),
+        unit: Some("ppb"),
+    },
+    Quantity {
+        quantity: "raw-h2",
+        primary: None,
+        name: "Raw H₂",
+        device_class: None,
+        unit: None,
+    },
+    Quantity {
+        quantity: "raw-ethanol",
+        primary: None,
+        name: "Raw Ethanol",
+        device_class: None,
+        unit: None,
+    },
+    Quantity {
+        quantity: "pm1",
+        primary: None,
+        name: "PM1.0",
+        device_class: Some("pm1"),
+        unit: Some("µg/m³"),
+    },
+    Quantity {
+        quantity: "pm25",
+        primary: None,
+        name: "PM2.5",
+        device_class: Some("pm25"),
+        unit: Some("µg/m³"),
+    },
+    Quantity {
+        quantity: "pm10",
+        primary: None,
+        name: "PM10",
+        device_class: Some("pm10"),
+        unit: Some("µg/m³"),
+    },
+    Quantity {
+        quantity: "particles-03um",
+        primary: None,
+        name: "Particles ≥0.3 µm",
+        device_class: None,
+        unit: Some("/0.1 L"),
+    },
+    Quantity {
+        quantity: "particles-05um",
+        primary: None,
+        name: "Particles ≥0.5 µm",
+        device_class: None,
+        unit: Some("/0.1 L"),
+    },
+    Quantity {
+        quantity: "particles-10um",
+        primary: None,
+        name: "Particles ≥1.0 µm",
+        device_class: None,
+        unit: Some("/0.1 L"),
+    },
+    Quantity {
+        quantity: "particles-25um",
+        primary: None,
+        name: "Particles ≥2.5 µm",
+        device_class: None,
+        unit: Some("/0.1 L"),
+    },
+    Quantity {
+        quantity: "particles-50um",
+        primary: None,
+        name: "Particles ≥5.0 µm",
+        device_class: None,
+        unit: Some("/0.1 L"),
+    },
+    Quantity {
+        quantity: "particles-100um",
+        primary: None,
+        name: "Particles ≥10 µm",
+        device_class: None,
+        unit: Some("/0.1 L"),
+    },
+    Quantity {
+        quantity: "lux",
+        primary: None,
+        name: "Illuminance",
+        device_class: Some("illuminance"),
+        unit: Some("lx"),
+    },
+    Quantity {
+        quantity: "full-spectrum",
+        primary: None,
+        name: "Full Spectrum",
+        device_class: None,
+        unit: None,
+    },
+    Quantity {
+        quantity: "infrared",
+        primary: None,
+        name: "Infrared",
+        device_class: None,
+        unit: None,
+    },
+    Quantity {
+        quantity: "white",
+        primary: None,
+        name: "White",
+        device_class: None,
+        unit: None,
+    },
+    Quantity {
+        quantity: "gain",
+        primary: None,
+        name: "Gain",
+        device_class: None,
+        unit: Some("x"),
+    },
+    Quantity {
+        quantity: "integration-time",
+        primary: None,
+        name: "Integration Time",
+        device_class: Some("duration"),
+        unit: Some("ms"),
+    },
+    Quantity {
+        quantity: "saturated",
+        primary: None,
+        name: "Saturated Samples",
+        device_class: None,
+        unit: None,
+    },
+    // 0 healthy, 1 degraded, 2 offline.
+    Quantity {
+        quantity: "health",
+        primary: None,
+        name: "Health",
+        device_class: None,
+        unit: None,
+    },
+];
+
+/// A Home Assistant sensor entity for one of our feeds.
+pub struct Entity {
+    // Which physical sensor it comes from, for availability, or one of the
+    // placeholders above. None for a sensor's health, which matters most
+    // while the sensor is offline.
+    sensor: Option<String>,
+    name: String,
+    device_class: Option<&'static str>,
+    unit: Option<&'static str>,
+}
+
+/// The entity for `feed`, if it is a sensor feed Home Assistant can show;
+/// weather, finance and unknown quantities have none.
+pub fn entity(feed: &str) -> Option<Entity> {
+    let name = FeedName::parse(feed);
+    if name.location != "mbr" {
+        return None;
+    }
+    let sensor = match name.sensor {
+        Some(sensor) => sensor,
+        None => {
+            let q = QUANTITIES
+                .iter()
+                .find(|q| q.primary.is_some() && q.quantity == name.quantity)?;
+            return Some(Entity {
+                sensor: q.primary.map(str::to_owned),
+                name: q.name.to_owned(),
+                device_class: q.device_class,
+                unit: q.unit,
+            });
+        }
+    };
+    let find = |quantity: &str| {
+        QUANTITIES
+            .iter()
+            .find(|q| q.primary.is_none() && q.quantity == quantity)
+    };
+    let (q, number) = match find(name.quantity) {
+        Some(q) => (q, None),
+        None => {
+            let (base, number) = name.quantity.rsplit_once('-')?;
+            number.parse::<u8>().ok()?;
+            (find(base)?, Some(number))
+        }
+    };
+    let mut entity_name = format!("{} {}", model(sensor), q.name);
+    if let Some(n) = number {
+        entity_name = format!("{} {}", entity_name, n);
+    }
+    Some(Entity {
+        sensor: (q.quantity != "health").then(|| sensor.to_owned()),
+        name: entity_name,
+        device_class: q.device_class,
+        unit: q.unit,
+    })
+}
+
+// The sensor's name as its maker writes it: "bme280" as "BME280", keeping the
+// lower-case x of families such as "sht4x".
+fn model(sensor: &str) -> String {
+    let upper = sensor.to_uppercase();
+    match upper.strip_suffix('X') {
+        Some(family) => format!("{}x", family),
+        None => upper,
+    }
+}
+
+// The Home Assistant sink. Announces each sensor feed through MQTT discovery
+// the first time it is seen, then publishes its state; feeds Home Assistant
+// has no entity for (weather, finance) are ignored.
+pub struct Bridge {
+    publisher: mqtt::Publisher,
+    params: CallParams,
+    // Feeds whose discovery config has been published.
+    discovered: HashSet<String>,
+    // Sensor availability as last published.
+    published: BTreeMap<&'static str, bool>,
+}
+
+impl Bridge {
+    pub fn new(mut params: CallParams) -> Bridge {
+        debug!("Home Assistant parameters {:?}", params);
+        let status = status_topic(&params.node_id);
+        params.mqtt.will = Some(mqtt::Message {
+            topic: status.clone(),
+            payload: OFFLINE.to_vec(),
+            retain: true,
+        });
+        params.mqtt.birth = Some(mqtt::Message {
+            topic: status,
+            payload: ONLINE.to_vec(),
+            retain: true,
+        });
+        Bridge {
+            publisher: mqtt::Publisher::connect(&params.mqtt),
+            params,
+            discovered: HashSet::new(),
+            published: BTreeMap::new(),
+        }
+    }
+
+    fn discovery_config(&self, feed: &str, entity: &Entity) -> serde_json::Value {
+        let node = &self.params.node_id;
+        let object_id = object_id(feed);
+        let mut availability = vec![json!({"topic": status_topic(node)})];
+        if let Some(sensor) = &entity.sensor {
+            let sensor = match sensor.as_str() {
+                CLIMATE_SENSOR => self.params.climate_source.sensor_name(),
+                PRESSURE_SENSOR => {
+                    sensor::pressure_source(self.params.climate_source).sensor_name()
+                }
+                LIGHT_SENSOR => self.params.light_source.sensor_name(),
+                PARTICULATE_SENSOR => self.params.particulate_source.sensor_name(),
+                s => s,
+            };
+            availability.push(json!({"topic": availability_topic(node, sensor)}));
+        }
+        let mut config = json!({
+            "name": entity.name,
+            "unique_id": format!("{}_{}", node, object_id),
+            "state_topic": state_topic(node, feed),
+            "state_class": "measurement",
+            "availability": availability,
+            "availability_mode": "all",
+            "device": {
+                "identifiers": [node],
+                "name": node,
+                "model": "iot-central",
+                "sw_version": env!("CARGO_PKG_VERSION"),
+            },
+        });
+        if let Some(c) = entity.device_class {
+            config["device_class"] = json!(c);
+        }
+        if let Some(u) = entity.unit {
+            config["unit_of_measurement"] = json!(u);
+        }
+        config
+    }
+
+    // Publishes sensors whose availability changed since last time.
+    fn publish_availability(&mut self) {
+        for (sensor, available) in availability::snapshot() {
+            if self.published.get(sensor) == Some(&available) {
+                continue;
+            }
+            let topic = availability_topic(&self.params.node_id, sensor);
+            let payload = if available { ONLINE } else { OFFLINE };
+            if self.publisher.publish(&topic, payload, true) {
+                debug!("{} is {}", sensor, String::from_utf8_lossy(payload));
+                self.published.insert(sensor, available);
+            }
+        }
+    }
+}
+
+impl Sink for Bridge {
+    fn name(&self) -> &'static str {
+        "Home Assistant"
+    }
+
+    fn send(&mut self, m: Metric) {
+        if !self.discovered.contains(&m.feed) {
+            let entity = match entity(&m.feed) {
+                Some(e) => e,
+                None => return,
+            };
+            let topic = format!(
+                "{}/sensor/{}/{}/config",
+                self.params.discovery_prefix,
+                self.params.node_id,
+                object_id(&m.feed)
+            );
+            let config = self.discovery_config(&m.feed, &entity).to_string();
+            if !self.publisher.publish(&topic, config.as_bytes(), true) {
+                debug!("Not connected; {} = {} not published", m.feed, m.value);
+                return;
+            }
+            self.discovered.insert(m.feed.clone());
+        }
+        let topic = state_topic(&self.params.node_id, &m.feed);
+        if !self
+            .publisher
+            .publish(&topic, m.value.to_string().as_bytes(), true)
+        {
+            debug!("Not connected; {} = {} not published", m.feed, m.value);
+        }
+    }
+
+    fn flush(&mut self) -> Option<Instant> {
+        self.publish_availability();
+        Some(Instant::now() + AVAILABILITY_POLL)
+    }
+
+    fn shutdown(&mut self) {
+        self.publish_availability();
+        // A clean disconnect doesn't trigger the will.
+        let status = status_topic(&self.params.node_id);
+        self.publisher.publish(&status, OFFLINE, true);
+    }
+}
+
+fn object_id(feed: &str) -> String {
+    feed.replace('.', "_")
+}
+
+fn status_topic(node: &str) -> String {
+    format!("{}/status", node)
+}
+
+fn availability_topic(node: &str, sensor: &str) -> String {
+    format!("{}/{}/availability", node, sensor)
+}
+
+fn state_topic(node: &str, feed: &str) -> String {
+    format!("{}/{}/state", node, object_id(feed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_broker;
+    use std::thread;
+
+    fn bridge(port: u16) -> Bridge {
+        let b = Bridge::new(CallParams {
+            mqtt: mqtt::Options {
+                host: "127.0.0.1".to_owned(),
+                port,
+                tls: false,
+                client_id: "ha-test".to_owned(),
+                username: None,
+                password: None,
+                keep_alive: Duration::from_secs(5),
+                will: None,
+                birth: None,
+                subscriptions: Vec::new(),
+            },
+            discovery_prefix: "homeassistant".to_owned(),
+            node_id: "node".to_owned(),
+            climate_source: ClimateSource::Sht4x,
+            light_source: LightSource::Veml7700,
+            particulate_source: ParticulateSource::Pms5003,
+        });
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !b.publisher.is_connected() {
+            assert!(Instant::now() < deadline, "never connected");
+            thread::sleep(Duration::from_millis(10));
+        }
+        b
+    }
+
+    // Skips messages on other topics, such as other tests' sensors.
+    fn next_on(broker: &test_broker::Broker, topic: &str) -> test_broker::Message {
+        loop {
+            let m = broker.next_publish();
+            if m.topic == topic {
+                return m;
+            }
+        }
+    }
+
+    fn metric(feed: &str, value: f32) -> Metric {
+        Metric::new(feed, value)
+    }
+
+    #[test]
+    fn announces_then_publishes_state() {
+        let broker = test_broker::Broker::start();
+        let mut b = bridge(broker.port());
+        let will = broker.connects()[0].will.clone().unwrap();
+        assert_eq!(
+            ("node/status", OFFLINE),
+            (will.topic.as_str(), &will.payload[..])
+        );
+        assert_eq!(ONLINE, &next_on(&broker, "node/status").payload[..]);
+
+        b.send(metric("weather.temp", 5.0));
+        b.send(metric("mbr-bme280.temperature", 21.5));
+        b.send(metric("mbr-bme280.temperature", 21.75));
+        let config = next_on(
+            &broker,
+            "homeassistant/sensor/node/mbr-bme280_temperature/config",
+        );
+        assert!(config.retain);
+        let config: serde_json::Value = serde_json::from_slice(&config.payload).unwrap();
+        assert_eq!("temperature", config["device_class"]);
+        assert_eq!("°C", config["unit_of_measurement"]);
+        assert_eq!("node/mbr-bme280_temperature/state", config["state_topic"]);
+        assert_eq!(
+            "node/bme280/availability",
+            config["availability"][1]["topic"]
+        );
+        let state = "node/mbr-bme280_temperature/state";
+        assert_eq!(b"21.5".to_vec(), next_on(&broker, state).payload);
+        // Announced only once.
+        let next = broker.next_publish();
+        assert_eq!(
+            (state, b"21.75".to_vec()),
+            (next.topic.as_str(), next.payload)
+        );
+
+        b.shutdown();
+        assert_eq!(OFFLINE, &next_on(&broker, "node/status").payload[..]);
+    }
+
+    #[test]
+    fn primary_feeds_follow_their_sources() {
+        let broker = test_broker::Broker::start();
+        let mut b = bridge(broker.port());
+        b.send(metric("mbr.humidity", 40.0));
+        let config = next_on(&broker, "homeassistant/sensor/node/mbr_humidity/config");
+        let config: serde_json::Value = serde_json::from_slice(&config.payload).unwrap();
+        assert_eq!(
+            "node/sht4x/availability",
+            config["availability"][1]["topic"]
+        );
+        b.send(metric("mbr.pressure", 29.9));
+        let config = next_on(&broker, "homeassistant/sensor/node/mbr_pressure/config");
+        let config: serde_json::Value = serde_json::from_slice(&config.payload).unwrap();
+        assert_eq!(
+            "node/bme280/availability",
+            config["availability"][1]["topic"]
+        );
+        b.send(metric("mbr.lux", 120.0));
+        let config = next_on(&broker, "homeassistant/sensor/node/mbr_lux/config");
+        let config: serde_json::Value = serde_json::from_slice(&config.payload).unwrap();
+        assert_eq!(
+            "node/veml7700/availability",
+            config["availability"][1]["topic"]
+        );
+        b.send(metric("mbr.pm25", 6.0));
+        let config = next_on(&broker, "homeassistant/sensor/node/mbr_pm25/config");
+        let config: serde_json::Value = serde_json::from_slice(&config.payload).unwrap();
+        assert_eq!(
+            "node/pms5003/availability",
+            config["availability"][1]["topic"]
+        );
+    }
+
+    #[test]
+    fn entities_follow_the_feed_name() {
+        let e = entity("mbr-bme680.gas-resistance-2").unwrap();
+        assert_eq!("BME680 Gas Resistance 2", e.name);
+        assert_eq!(Some("Ω"), e.unit);
+        assert_eq!(Some("bme680"), e.sensor.as_deref());
+        let e = entity("mbr-pmsa003i.particles-100um").unwrap();
+        assert_eq!("PMSA003I Particles ≥10 µm", e.name);
+        let e = entity("mbr-scd4x.co2").unwrap();
+        assert_eq!("SCD4x CO₂", e.name);
+        assert_eq!(Some("carbon_dioxide"), e.device_class);
+        // The mbr.* feeds keep their units and follow their source.
+        let e = entity("mbr.temperature").unwrap();
+        assert_eq!(Some("°F"), e.unit);
+        assert_eq!(Some(CLIMATE_SENSOR), e.sensor.as_deref());
+        // Health stays available while the sensor is offline.
+        let e = entity("mbr-sht4x.health").unwrap();
+        assert_eq!("SHT4x Health", e.name);
+        assert_eq!(None, e.sensor);
+
+        for feed in [
+            "weather.temp",
+            "finance.binance-btcusdt",
+            "mbr-bme280.colour",
+            "mbr-bme680.gas-resistance-x",
+            "mbr.co2",
+        ] {
+            assert!(entity(feed).is_none(), "{}", feed);
+        }
+    }
+
+    #[test]
+    fn health_is_available_while_the_sensor_is_not() {
+        let broker = test_broker::Broker::start();
+        let mut b = bridge(broker.port());
+        b.send(Metric::new("mbr-scd30.health", 2i64));
+        let config = next_on(&broker, "homeassistant/sensor/node/mbr-scd30_health/config");
+        let config: serde_json::Value = serde_json::from_slice(&config.payload).unwrap();
+        assert_eq!(json!([{"topic": "node/status"}]), config["availability"]);
+        assert_eq!(
+            b"2".to_vec(),
+            next_on(&broker, "node/mbr-scd30_health/state").payload
+        );
+    }
+
+    #[test]
+    fn sensor_availability_follows_the_registry() {
+        let broker = test_broker::Broker::start();
+        let mut b = bridge(broker.port());
+        availability::set("test-sensor", true);
+        b.flush();
+        let topic = "node/test-sensor/availability";
+        assert_eq!(ONLINE, &next_on(&broker, topic).payload[..]);
+        availability::set("test-sensor", false);
+        b.flush();
+        assert_eq!(OFFLINE, &next_on(&broker, topic).payload[..]);
+    }
+}
@@ -0,0 +1,399 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+use crate::adafruit::{Metric, Value};
+use crate::backoff::Backoff;
+use crate::counters;
+use crate::sink::{FeedName, Sink};
+
+use log::{debug, error, warn};
+use reqwest::{header, StatusCode};
+use std::time::{Duration, Instant};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+// Lines kept while InfluxDB is unreachable; the oldest go first beyond this.
+const MAX_BUFFERED: usize = 10_000;
+
+#[derive(Debug)]
+pub struct CallParams {
+    // e.g. "http://localhost:8086"
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    pub token: String,
+    // Lines per write request.
+    pub batch_size: usize,
+    // How long a line may wait for the batch to fill up.
+    pub flush_interval: Duration,
+}
+
+// What became of a single write.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Written,
+    // Transport error, auth failure, 5xx or 429; keep the lines and try again.
+    Retry(Option<Duration>),
+    // InfluxDB refused the data itself (e.g. 400 bad line); retrying won't help.
+    Rejected,
+}
+
+// The InfluxDB v2 sink. Buffers metrics as line protocol and writes them in
+// batches.
+pub struct Writer {
+    client: reqwest::blocking::Client,
+    params: CallParams,
+    lines: Vec<String>,
+    // When the oldest buffered line arrived.
+    first_at: Option<Instant>,
+    backoff: Backoff,
+    retry_at: Option<Instant>,
+}
+
+impl Writer {
+    pub fn new(params: CallParams) -> Writer {
+        debug!("InfluxDB parameters {:?}", params);
+        Writer {
+            client: reqwest::blocking::Client::new(),
+            params,
+            lines: Vec::new(),
+            first_at: None,
+            backoff: Backoff::new(INITIAL_BACKOFF, MAX_BACKOFF),
+            retry_at: None,
+        }
+    }
+
+    fn write(&self, lines: &[String]) -> Outcome {
+        let url = format!("{}/api/v2/write", self.params.url);
+        debug!("Writing {} lines to {}", lines.len(), url);
+        let resp = self
+            .client
+            .post(url)
+            .query(&[
+                ("org", self.params.org.as_str()),
+                ("bucket", self.params.bucket.as_str()),
+                ("precision", "ms"),
+            ])
+            .header(
+                header::AUTHORIZATION,
+                format!("Token {}", self.params.token),
+            )
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(lines.join("\n"))
+            .send();
+        let r = match resp {
+            Ok(r) => r,
+            Err(e) => {
+                debug!("InfluxDB write failed: {:?}", e);
+                counters::inc("influxdb_write_failures");
+                return Outcome::Retry(None);
+            }
+        };
+        let status = r.status();
+        if status.is_success() {
+            counters::add("influxdb_lines_written", lines.len() as u64);
+            return Outcome::Written;
+        }
+        counters::inc("influxdb_write_failures");
+        let retry_after = r
+            .headers()
+            .get(header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse().ok())
+            .map(Duration::from_secs);
+        let body = r.text().unwrap_or_default();
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                error!("InfluxDB rejected the token ({}): {}", status, body);
+                Outcome::Retry(None)
+            }
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+                Outcome::Retry(retry_after)
+            }
+            s if s.is_server_error() => Outcome::Retry(None),
+            _ => {
+                error!(
+                    "InfluxDB rejected {} lines ({}): {}",
+                    lines.len(),
+                    status,
+                    body
+                );
+                Outcome::Rejected
+            }
+        }
+    }
+}
+
+impl Sink for Writer {
+    fn name(&self) -> &'static str {
+        "InfluxDB"
+    }
+
+    fn send(&mut self, m: Metric) {
+        let line = match line(&m) {
+            Some(l) => l,
+            None => {
+                warn!("Not writing non-finite {} = {}", m.feed, m.value);
+                return;
+            }
+        };
+        if self.lines.len() >= MAX_BUFFERED {
+            warn!("InfluxDB buffer full; dropping the oldest line");
+            self.lines.remove(0);
+        }
+        self.lines.push(line);
+        self.first_at.get_or_insert_with(Instant::now);
+    }
+
+    // Writes full batches, and partial ones once they have waited long enough.
+    fn flush(&mut self) -> Option<Instant> {
+        let now = Instant::now();
+        if let Some(t) = self.retry_at {
+            if now < t {
+                return Some(t);
+            }
+            self.retry_at = None;
+        }
+        let due = self.first_at? + self.params.flush_interval;
+        while !self.lines.is_empty() {
+            let n = self.lines.len().min(self.params.batch_size);
+            if n < self.params.batch_size && now < due {
+                return Some(due);
+            }
+            match self.write(&self.lines[..n]) {
+                Outcome::Written | Outcome::Rejected => {
+                    self.backoff.reset();
+                    self.lines.drain(..n);
+                }
+                Outcome::Retry(retry_after) => {
+                    let t = Instant::now() + self.backoff.next_delay(retry_after);
+                    self.retry_at = Some(t);
+                    return Some(t);
+                }
+            }
+        }
+        self.first_at = None;
+        None
+    }
+
+    fn shutdown(&mut self) {
+        // One last attempt, without waiting for the batch to fill.
+        self.retry_at = None;
+        self.params.flush_interval = Duration::ZERO;
+        self.flush();
+        if !self.lines.is_empty() {
+            warn!("Dropping {} unwritten InfluxDB lines", self.lines.len());
+        }
+    }
+}
+
+// Formats a metric as line protocol, e.g. "mbr,sensor=bme280 temperature=21.5
+// 1666000000000" for "mbr-bme280.temperature". Integers get an "i" suffix,
+// text is quoted, and a location adds lat/lon/ele fields. None if the value
+// can't be represented.
+fn line(m: &Metric) -> Option<String> {
+    if !m.value.is_finite() {
+        return None;
+    }
+    let name = FeedName::parse(&m.feed);
+    let mut out = escape(name.location, &[',', ' ']);
+    if let Some(sensor) = name.sensor {
+        out.push_str(",sensor=");
+        out.push_str(&escape(sensor, &[',', '=', ' ']));
+    }
+    let quantity = escape(name.quantity, &[',', '=', ' ']);
+    let mut fields = match &m.value {
+        Value::Int(i) => vec![format!("{}={}i", quantity, i)],
+        Value::Float(f) => vec![format!("{}={}", quantity, f)],
+        Value::Text(t) => vec![format!("{}=\"{}\"", quantity, escape(t, &['"']))],
+        Value::Location(_) => Vec::new(),
+    };
+    if let Some(l) = m
+        .location()
+        .filter(|l| l.lat.is_finite() && l.lon.is_finite())
+    {
+        fields.push(format!("lat={},lon={}", l.lat, l.lon));
+        if let Some(ele) = l.ele.filter(|e| e.is_finite()) {
+            fields.push(format!("ele={}", ele));
+        }
+    }
+    out.push_str(&format!(
+        " {} {}",
+        fields.join(","),
+        m.created_at.timestamp_millis()
+    ));
+    Some(out)
+}
+
+fn escape(s: &str, special: &[char]) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '\\' || special.contains(&c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adafruit::Location;
+    use crate::test_server::{self, Response};
+    use chrono::{TimeZone, Utc};
+    use std::thread;
+
+    fn params(url: &str, batch_size: usize) -> CallParams {
+        CallParams {
+            url: url.to_owned(),
+            org: "home".to_owned(),
+            bucket: "iot".to_owned(),
+            token: "secret".to_owned(),
+            batch_size,
+            flush_interval: Duration::from_secs(60),
+        }
+    }
+
+    fn metric(feed: &str, value: impl Into<Value>) -> Metric {
+        let mut m = Metric::new(feed, value);
+        m.created_at = Utc.timestamp_millis_opt(1_666_000_000_123).unwrap();
+        m
+    }
+
+    fn writer(params: CallParams) -> Writer {
+        let mut writer = Writer::new(params);
+        writer.backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(5));
+        writer
+    }
+
+    fn body(r: &test_server::Request) -> Vec<String> {
+        String::from_utf8(r.body.clone())
+            .unwrap()
+            .lines()
+            .map(|l| l.to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn feeds_become_line_protocol() {
+        assert_eq!(
+            Some("mbr,sensor=bme280 temperature=21.5 1666000000123".to_owned()),
+            line(&metric("mbr-bme280.temperature", 21.5))
+        );
+        assert_eq!(
+            Some("weather temp=7 1666000000123".to_owned()),
+            line(&metric("weather.temp", 7.0))
+        );
+        assert_eq!(
+            Some("my\\ place,sensor=a\\=b x\\,y=1 1666000000123".to_owned()),
+            line(&metric("my place-a=b.x,y", 1.0))
+        );
+        assert_eq!(None, line(&metric("mbr.lux-db", f32::NEG_INFINITY)));
+    }
+
+    #[test]
+    fn values_keep_their_type() {
+        assert_eq!(
+            Some("mbr count=3i 1666000000123".to_owned()),
+            line(&metric("mbr.count", 3i64))
+        );
+        assert_eq!(
+            Some("weather description=\"a \\\"nice\\\" day\" 1666000000123".to_owned()),
+            line(&metric("weather.description", "a \"nice\" day"))
+        );
+        let here = Location {
+            lat: 37.5,
+            lon: -122.25,
+            ele: Some(10.0),
+        };
+        assert_eq!(
+            Some("car lat=37.5,lon=-122.25,ele=10 1666000000123".to_owned()),
+            line(&metric("car.position", here))
+        );
+        let mut m = metric("car.speed", 12.5);
+        m.location = Some(here);
+        assert_eq!(
+            Some("car speed=12.5,lat=37.5,lon=-122.25,ele=10 1666000000123".to_owned()),
+            line(&m)
+        );
+    }
+
+    #[test]
+    fn full_batches_are_written() {
+        let (url, server) = test_server::serve(vec![Response::status(204)]);
+        let mut w = writer(params(&url, 2));
+        w.send(metric("mbr.temperature", 20.0));
+        assert!(w.flush().is_some());
+        w.send(metric("mbr.humidity", 40.0));
+        assert_eq!(None, w.flush());
+
+        let requests = server.join().unwrap();
+        let r = &requests[0];
+        assert_eq!("POST", r.method);
+        assert_eq!("/api/v2/write?org=home&bucket=iot&precision=ms", r.path);
+        assert_eq!(Some("Token secret"), r.header("Authorization"));
+        let lines = body(r);
+        assert_eq!(2, lines.len());
+        assert!(lines[0].starts_with("mbr temperature=20 "));
+        assert!(lines[1].starts_with("mbr humidity=40 "));
+    }
+
+    #[test]
+    fn partial_batches_wait_for_the_interval() {
+        let (url, server) = test_server::serve(vec![Response::status(204)]);
+        let mut p = params(&url, 100);
+        p.flush_interval = Duration::from_millis(20);
+        let mut w = writer(p);
+        w.send(metric("mbr.temperature", 20.0));
+        let due = w.flush().unwrap();
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+        assert_eq!(None, w.flush());
+        assert_eq!(1, body(&server.join().unwrap()[0]).len());
+    }
+
+    #[test]
+    fn failed_writes_are_retried_and_bad_data_dropped() {
+        let (url, server) = test_server::serve(vec![
+            Response::status(503),
+            Response::status(204),
+            Response::status(400).with_body("{\"message\":\"bad line\"}"),
+        ]);
+        let mut w = writer(params(&url, 1));
+        w.send(metric("mbr.temperature", 20.0));
+        let retry = w.flush().unwrap();
+        assert_eq!(1, w.lines.len());
+        thread::sleep(retry.saturating_duration_since(Instant::now()));
+        assert_eq!(None, w.flush());
+
+        w.send(metric("mbr.humidity", 40.0));
+        assert_eq!(None, w.flush());
+        assert!(w.lines.is_empty());
+
+        let requests = server.join().unwrap();
+        assert_eq!(body(&requests[0]), body(&requests[1]));
+    }
+
+    #[test]
+    fn shutdown_writes_partial_batch() {
+        let (url, server) = test_server::serve(vec![Response::status(204)]);
+        let mut w = writer(params(&url, 100));
+        w.send(metric("mbr.temperature", 20.0));
+        w.shutdown();
+        assert_eq!(1, body(&server.join().unwrap()[0]).len());
+    }
+}
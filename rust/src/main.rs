@@ -19,78 +19,245 @@ extern crate reqwest;
 extern crate serde;
 
 mod adafruit;
+mod backoff;
+mod config;
 mod conversion;
+mod counters;
+mod filelog;
 mod finance;
+mod history;
+mod homeassistant;
+mod influx;
+mod mqtt;
+mod prometheus;
+mod ratelimit;
 mod sensor;
+mod sink;
+mod spool;
+#[cfg(test)]
+mod test_broker;
+#[cfg(test)]
+mod test_server;
 mod weather;
 
 use log::info;
 use std::env;
+use std::error::Error;
+use std::path::PathBuf;
 use std::sync::mpsc::channel;
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 
-const ENABLE_FINANCE_THREAD: bool = false;
-const ENABLE_WEATHER_THREAD: bool = false;
+// Parses `--config <path>` from the command line.
+fn config_path() -> Result<Option<PathBuf>, Box<dyn Error>> {
+    let mut args = env::args().skip(1);
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            path = Some(args.next().ok_or("--config requires a path")?.into());
+        } else if let Some(p) = arg.strip_prefix("--config=") {
+            path = Some(p.into());
+        } else {
+            return Err(format!("unrecognized argument: {}", arg).into());
+        }
+    }
+    Ok(path)
+}
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
 
+    let config = config::load(config_path()?.as_deref())?;
+
     let shutdown = Arc::new((Mutex::new(false), Condvar::new()));
     let (tx, rx) = channel();
 
-    // Start the Adafruit IO transmission agent.
-    let aio_params = adafruit::CallParams {
-        base_url: "https://io.adafruit.com/api/v2".to_owned(),
-        io_user: env::var("IO_USERNAME").expect("Adafruit IO_USERNAME is not defined."),
-        io_key: env::var("IO_KEY").expect("Adafruti IO_KEY is not defined."),
-    };
-    let aio_thread = thread::spawn(move || adafruit::aio_sender(aio_params, rx));
-
-    // Start the sensor thread.
-    let sensor_params = sensor::CallParams {
-        shutdown: shutdown.clone(),
-        tx: tx.clone(),
-    };
-    let sensor_thread = thread::spawn(move || sensor::sensor_updater(sensor_params));
-
-    let finance_thread = if ENABLE_FINANCE_THREAD {
+    let mut sinks: Vec<Box<dyn sink::Sink>> = Vec::new();
+
+    if let Some(adafruit) = config.adafruit.filter(|a| a.enabled) {
+        // Open the outbound spool, if configured.
+        let spool = match &config.spool {
+            Some(s) => Some(
+                spool::Spool::open(&s.dir, s.limits())
+                    .map_err(|e| format!("unable to open spool {}: {}", s.dir.display(), e))?,
+            ),
+            None => None,
+        };
+
+        // Set up the Adafruit IO transmission agent.
+        let aio_params = adafruit::CallParams {
+            transport: adafruit.transport(),
+            batch_window: adafruit.batch_window(),
+            base_url: adafruit.base_url,
+            io_user: adafruit.io_user,
+            io_key: adafruit.io_key,
+            spool,
+            rate_limit_per_minute: adafruit.rate_limit_per_minute,
+            feed_priority: adafruit.feed_priority,
+        };
+        sinks.push(Box::new(adafruit::Sender::new(aio_params)));
+    }
+
+    if let Some(influxdb) = config.influxdb.filter(|i| i.enabled) {
+        // Set up the InfluxDB writer.
+        let influx_params = influx::CallParams {
+            flush_interval: influxdb.flush_interval(),
+            url: influxdb.url,
+            org: influxdb.org,
+            bucket: influxdb.bucket,
+            token: influxdb.token,
+            batch_size: influxdb.batch_size,
+        };
+        sinks.push(Box::new(influx::Writer::new(influx_params)));
+    }
+
+    if let Some(prometheus) = config.prometheus.filter(|p| p.enabled) {
+        // Start the /metrics endpoint.
+        let prometheus_params = prometheus::CallParams {
+            listen: prometheus.listen,
+        };
+        sinks.push(Box::new(prometheus::Exporter::new(prometheus_params)?));
+    }
+
+    if let Some(homeassistant) = config.homeassistant.filter(|h| h.enabled) {
+        // Connect to Home Assistant's MQTT broker.
+        let homeassistant_params = homeassistant::CallParams {
+            mqtt: homeassistant.mqtt(),
+            discovery_prefix: homeassistant.discovery_prefix,
+            node_id: homeassistant.node_id,
+            climate_source: config.sensor.climate_source,
+            light_source: config.sensor.light_source,
+            particulate_source: config.sensor.particulate_source,
+        };
+        sinks.push(Box::new(homeassistant::Bridge::new(homeassistant_params)));
+    }
+
+    if let Some(history) = config.history.filter(|h| h.enabled) {
+        // Open the local history database.
+        let history_params = history::CallParams {
+            raw_retention: history.raw_retention(),
+            hourly_retention: history.hourly_retention(),
+            path: history.path,
+        };
+        let path = history_params.path.clone();
+        let history = history::History::open(history_params)
+            .map_err(|e| format!("unable to open history {}: {}", path.display(), e))?;
+        sinks.push(Box::new(history));
+    }
+
+    if let Some(file_log) = config.file_log.filter(|f| f.enabled) {
+        // Start the local file logger.
+        let file_log_params = filelog::CallParams {
+            format: file_log.format(),
+            rotation: file_log.rotation(),
+            dir: file_log.dir,
+            prefix: file_log.prefix,
+            gzip: file_log.gzip,
+        };
+        sinks.push(Box::new(filelog::FileLog::new(file_log_params)));
+    }
+
+    // Fan metrics out to every sink.
+    let dispatcher_thread = thread::spawn(move || sink::dispatcher(rx, sinks));
+
+    let mut producer_threads = Vec::new();
+
+    if config.sensor.enabled {
+        // Start the sensor thread.
+        let sensor_params = sensor::CallParams {
+            shutdown: shutdown.clone(),
+            tx: tx.clone(),
+            altitude: config.sensor.altitude,
+            sample_period: config.sensor.sample_period(),
+            update_period: config.sensor.update_period(),
+            sgp30_warmup: config.sensor.sgp30.warmup(),
+            sgp30_baseline_file: config.sensor.sgp30.baseline_file.clone(),
+            climate_source: config.sensor.climate_source,
+            bme280: config.sensor.bme280.enabled,
+            bme680: config
+                .sensor
+                .bme680
+                .as_ref()
+                .filter(|c| c.enabled)
+                .map(|c| c.settings()),
+            light_source: config.sensor.light_source,
+            dark_floor_lux: config.sensor.dark_floor_lux,
+            tsl2591: config.sensor.tsl2591.enabled,
+            veml7700: config.sensor.veml7700.as_ref().is_some_and(|c| c.enabled),
+            sht4x: config
+                .sensor
+                .sht4x
+                .as_ref()
+                .filter(|c| c.enabled)
+                .map(|c| c.settings()),
+            sht3x: config
+                .sensor
+                .sht3x
+                .as_ref()
+                .filter(|c| c.enabled)
+                .map(|c| c.settings()),
+            scd4x: config
+                .sensor
+                .scd4x
+                .as_ref()
+                .filter(|c| c.enabled)
+                .map(|c| c.calibration()),
+            scd30: config
+                .sensor
+                .scd30
+                .as_ref()
+                .filter(|c| c.enabled)
+                .map(|c| c.calibration()),
+            particulate_source: config.sensor.particulate_source,
+            pmsa003i: config.sensor.pmsa003i.as_ref().is_some_and(|c| c.enabled),
+            pms5003_port: config
+                .sensor
+                .pms5003
+                .as_ref()
+                .filter(|c| c.enabled)
+                .map(|c| c.port.clone()),
+            #[cfg(feature = "sim")]
+            sim: config.sensor.sim.waveforms(),
+        };
+        producer_threads.push((
+            "Sensor",
+            thread::spawn(move || sensor::sensor_updater(sensor_params)),
+        ));
+    }
+
+    if let Some(finance) = config.finance.filter(|f| f.enabled) {
         // Start the finance thread.
         let finance_params = finance::CallParams {
             shutdown: shutdown.clone(),
             tx: tx.clone(),
-            base_url: "https://finnhub.io/api/v1/quote".to_owned(),
-            api_key: env::var("FINHUB_API_KEY").expect("FINHUB_API_KEY is not defined."),
-            symbols: vec![
-                "DIA".into(),
-                "COINBASE:BTC-USD".into(),
-                "BITFINEX:USTUSD".into(),
-                "KRAKEN:USDTZUSD".into(),
-                "QQQ".into(),
-            ],
+            update_period: finance.update_period(),
+            base_url: finance.base_url,
+            api_key: finance.api_key,
+            symbols: finance.symbols,
         };
-        thread::spawn(move || finance::finance_updater(finance_params))
-    } else {
-        // Do nothing.
-        thread::spawn(move || return)
-    };
+        producer_threads.push((
+            "Finance",
+            thread::spawn(move || finance::finance_updater(finance_params)),
+        ));
+    }
 
-    let weather_thread = if ENABLE_WEATHER_THREAD {
+    if let Some(weather) = config.weather.filter(|w| w.enabled) {
         // Start the weather thread.
         let weather_params = weather::CallParams {
             shutdown: shutdown.clone(),
             tx: tx.clone(),
-            base_url: "https://api.openweathermap.org/data/2.5/onecall".to_owned(),
-            api_key: env::var("OPEN_WEATHER_KEY").expect("OPEN_WEATHER_KEY is not defined."),
-            lat: env::var("OPEN_WEATHER_LAT").expect("OPEN_WEATHER_LAT is not defined."),
-            lon: env::var("OPEN_WEATHER_LON").expect("OPEN_WEATHER_LON is not defined."),
-            units: "metric".to_owned(),
+            update_period: weather.update_period(),
+            base_url: weather.base_url,
+            api_key: weather.api_key,
+            lat: weather.lat,
+            lon: weather.lon,
+            units: weather.units,
         };
-        thread::spawn(move || weather::weather_updater(weather_params))
-    } else {
-        // Do nothing.
-        thread::spawn(move || return)
-    };
+        producer_threads.push((
+            "Weather",
+            thread::spawn(move || weather::weather_updater(weather_params)),
+        ));
+    }
 
     ctrlc::set_handler(move || {
         info!("Shutdown initiated...");
@@ -103,23 +270,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     })
     .unwrap();
 
-    info!("Waiting for Sensor thread...");
-    sensor_thread.join().expect("Failed to join sensor_thread.");
-
-    info!("Waiting for Finance thread...");
-    finance_thread
-        .join()
-        .expect("Failed to join finance_thread.");
+    for (name, handle) in producer_threads {
+        info!("Waiting for {} thread...", name);
+        handle
+            .join()
+            .unwrap_or_else(|_| panic!("Failed to join {} thread.", name));
+    }
 
-    info!("Waiting for Weather thread...");
-    weather_thread
-        .join()
-        .expect("Failed to join weather_thread.");
-
-    // Signal the consumer thread (Adafruit IO sender).
+    // Signal the dispatcher, which shuts down the sinks.
     drop(tx);
-    info!("Waiting for Adafruit IO thread...");
-    aio_thread.join().expect("Failed to join aio_thread.");
+    info!("Waiting for dispatcher thread...");
+    dispatcher_thread
+        .join()
+        .expect("Failed to join dispatcher thread.");
 
     Ok(())
 }
@@ -0,0 +1,385 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+//! A persistent MQTT connection for publishing. The connection is driven by
+//! a background thread that keeps it alive and reconnects with backoff.
+//! Publishes that were queued but not yet acknowledged are resent after a
+//! reconnect, so `unacked()` only falls once the broker has them.
+
+use crate::backoff::Backoff;
+
+use log::{debug, info, warn};
+use rumqttc::tokio_rustls::rustls;
+use rumqttc::{
+    Client, Event, LastWill, MqttOptions, Outgoing, Packet, QoS, TlsConfiguration, Transport,
+};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+// Requests buffered while the event loop catches up.
+const REQUEST_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub keep_alive: Duration,
+    // Published by the broker if the connection drops without a DISCONNECT.
+    pub will: Option<Message>,
+    // Published after every (re)connect, typically undoing the will.
+    pub birth: Option<Message>,
+    // Subscribed to after every (re)connect, as a clean session forgets them.
+    pub subscriptions: Vec<String>,
+}
+
+pub struct Publisher {
+    client: Client,
+    connected: Arc<AtomicBool>,
+    // QoS 1 publishes queued, and those the broker has acknowledged.
+    queued: Arc<AtomicU64>,
+    acked: Arc<AtomicU64>,
+    received: mpsc::Receiver<Message>,
+    stopping: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Publisher {
+    /// Starts connecting in the background; check `is_connected()` before
+    /// relying on delivery.
+    pub fn connect(options: &Options) -> Publisher {
+        let mut mqtt_options = MqttOptions::new(&options.client_id, &options.host, options.port);
+        mqtt_options.set_keep_alive(options.keep_alive);
+        if let Some(username) = &options.username {
+            mqtt_options.set_credentials(username, options.password.as_deref().unwrap_or_default());
+        }
+        if options.tls {
+            mqtt_options.set_transport(tls_transport());
+        }
+        if let Some(will) = &options.will {
+            mqtt_options.set_last_will(LastWill::new(
+                &will.topic,
+                will.payload.clone(),
+                QoS::AtLeastOnce,
+                will.retain,
+            ));
+        }
+
+        let (client, mut connection) = Client::new(mqtt_options, REQUEST_CAPACITY);
+        let connected = Arc::new(AtomicBool::new(false));
+        let stopping = Arc::new(AtomicBool::new(false));
+        let queued = Arc::new(AtomicU64::new(0));
+        let acked = Arc::new(AtomicU64::new(0));
+        let (received_tx, received) = mpsc::channel();
+        let server = format!("{}:{}", options.host, options.port);
+        let thread = {
+            let connected = connected.clone();
+            let stopping = stopping.clone();
+            let acked = acked.clone();
+            let birth = options.birth.clone();
+            let subscriptions = options.subscriptions.clone();
+            let mut loop_client = client.clone();
+            thread::spawn(move || {
+                let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+                for notification in connection.iter() {
+                    match notification {
+                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                            info!("MQTT connected to {}", server);
+                            connected.store(true, Ordering::SeqCst);
+                            backoff.reset();
+                            for topic in &subscriptions {
+                                if let Err(e) = loop_client.try_subscribe(topic, QoS::AtMostOnce) {
+                                    warn!("MQTT subscribe to {} failed: {}", topic, e);
+                                }
+                            }
+                            // QoS 0, so that `unacked()` only counts the
+                            // caller's publishes; it is sent again on the
+                            // next connect anyway.
+                            if let Some(b) = &birth {
+                                if let Err(e) = loop_client.try_publish(
+                                    &b.topic,
+                                    QoS::AtMostOnce,
+                                    b.retain,
+                                    b.payload.clone(),
+                                ) {
+                                    warn!("MQTT birth publish to {} failed: {}", b.topic, e);
+                                }
+                            }
+                        }
+                        Ok(Event::Incoming(Packet::PubAck(_))) => {
+                            acked.fetch_add(1, Ordering::SeqCst);
+                        }
+                        Ok(Event::Incoming(Packet::Publish(p))) => {
+                            debug!(
+                                "MQTT message on {}: {}",
+                                p.topic,
+                                String::from_utf8_lossy(&p.payload)
+                            );
+                            let _ = received_tx.send(Message {
+                                topic: p.topic,
+                                payload: p.payload.to_vec(),
+                                retain: p.retain,
+                            });
+                        }
+                        Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                        Ok(_) => {}
+                        Err(e) => {
+                            connected.store(false, Ordering::SeqCst);
+                            if stopping.load(Ordering::SeqCst) {
+                                break;
+                            }
+                            let delay = backoff.next_delay(None);
+                            warn!(
+                                "MQTT connection to {} failed, retrying in {:?}: {}",
+                                server, delay, e
+                            );
+                            sleep_unless_stopping(delay, &stopping);
+                        }
+                    }
+                }
+                connected.store(false, Ordering::SeqCst);
+                debug!("MQTT event loop for {} finished", server);
+            })
+        };
+
+        Publisher {
+            client,
+            connected,
+            queued,
+            acked,
+            received,
+            stopping,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    /// Queues a QoS 1 publish. Returns false if the broker is not connected
+    /// or the outgoing queue is full, so the caller can retry later. True
+    /// only means queued; see `unacked()`.
+    pub fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> bool {
+        if !self.is_connected() {
+            return false;
+        }
+        match self
+            .client
+            .try_publish(topic, QoS::AtLeastOnce, retain, payload.to_vec())
+        {
+            Ok(()) => {
+                self.queued.fetch_add(1, Ordering::SeqCst);
+                true
+            }
+            Err(e) => {
+                debug!("MQTT publish to {} failed: {}", topic, e);
+                false
+            }
+        }
+    }
+
+    /// The number of queued publishes the broker hasn't acknowledged yet.
+    /// The broker acknowledges them in order.
+    pub fn unacked(&self) -> u64 {
+        self.queued.load(Ordering::SeqCst) - self.acked.load(Ordering::SeqCst)
+    }
+
+    /// Messages received on the subscribed topics since the last call.
+    pub fn received(&mut self) -> Vec<Message> {
+        self.received.try_iter().collect()
+    }
+}
+
+impl Drop for Publisher {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        // Flushes queued publishes, then sends DISCONNECT.
+        let _ = self.client.try_disconnect();
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+fn sleep_unless_stopping(delay: Duration, stopping: &AtomicBool) {
+    let step = Duration::from_millis(100);
+    let mut remaining = delay;
+    while !remaining.is_zero() && !stopping.load(Ordering::SeqCst) {
+        let d = remaining.min(step);
+        thread::sleep(d);
+        remaining -= d;
+    }
+}
+
+fn tls_transport() -> Transport {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Transport::tls_with_config(TlsConfiguration::Rustls(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_broker;
+    use std::time::Instant;
+
+    fn options(port: u16) -> Options {
+        Options {
+            host: "127.0.0.1".to_owned(),
+            port,
+            tls: false,
+            client_id: "test".to_owned(),
+            username: Some("user".to_owned()),
+            password: Some("key".to_owned()),
+            keep_alive: Duration::from_secs(5),
+            will: None,
+            birth: None,
+            subscriptions: Vec::new(),
+        }
+    }
+
+    fn wait_for(mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn wait_connected(p: &Publisher) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !p.is_connected() {
+            assert!(Instant::now() < deadline, "never connected");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn publishes_with_credentials() {
+        let broker = test_broker::Broker::start();
+        let mut p = Publisher::connect(&options(broker.port()));
+        wait_connected(&p);
+        assert!(p.publish("user/feeds/x", b"1.5", false));
+
+        let msg = broker.next_publish();
+        wait_for(|| p.unacked() == 0);
+        assert_eq!(
+            ("user/feeds/x", &b"1.5"[..]),
+            (msg.topic.as_str(), &msg.payload[..])
+        );
+        let connect = broker.connects()[0].clone();
+        assert_eq!("test", connect.client_id);
+        assert_eq!(Some("user".to_owned()), connect.username);
+        assert_eq!(Some("key".to_owned()), connect.password);
+    }
+
+    #[test]
+    fn reconnects_after_broker_drops_connection() {
+        let broker = test_broker::Broker::start();
+        let mut p = Publisher::connect(&options(broker.port()));
+        wait_connected(&p);
+        broker.drop_connections();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while broker.connects().len() < 2 {
+            assert!(Instant::now() < deadline, "never reconnected");
+            thread::sleep(Duration::from_millis(10));
+        }
+        wait_connected(&p);
+        assert!(p.publish("user/feeds/y", b"2", false));
+        assert_eq!("user/feeds/y", broker.next_publish().topic);
+    }
+
+    #[test]
+    fn will_is_registered_and_birth_sent_on_each_connect() {
+        let broker = test_broker::Broker::start();
+        let mut o = options(broker.port());
+        o.will = Some(Message {
+            topic: "node/status".to_owned(),
+            payload: b"offline".to_vec(),
+            retain: true,
+        });
+        o.birth = Some(Message {
+            topic: "node/status".to_owned(),
+            payload: b"online".to_vec(),
+            retain: true,
+        });
+        let p = Publisher::connect(&o);
+        wait_connected(&p);
+        let will = broker.connects()[0].will.clone().unwrap();
+        assert_eq!(
+            ("node/status", &b"offline"[..], true),
+            (will.topic.as_str(), &will.payload[..], will.retain)
+        );
+        assert_eq!(b"online".to_vec(), broker.next_publish().payload);
+
+        // The broker publishes the will, and the birth follows the reconnect.
+        broker.drop_connections();
+        assert_eq!(b"offline".to_vec(), broker.next_publish().payload);
+        assert_eq!(b"online".to_vec(), broker.next_publish().payload);
+    }
+
+    #[test]
+    fn subscribes_again_after_reconnecting() {
+        let broker = test_broker::Broker::start();
+        let mut o = options(broker.port());
+        o.subscriptions = vec!["user/throttle".to_owned()];
+        let mut p = Publisher::connect(&o);
+        wait_for(|| broker.subscriptions().len() == 1);
+        broker.drop_connections();
+        wait_for(|| broker.subscriptions().len() == 2);
+        assert_eq!(vec!["user/throttle"; 2], broker.subscriptions());
+
+        broker.publish("user/throttle", b"slow down");
+        let mut received = Vec::new();
+        wait_for(|| {
+            received.extend(p.received());
+            !received.is_empty()
+        });
+        assert_eq!("user/throttle", received[0].topic);
+        assert_eq!(b"slow down".to_vec(), received[0].payload);
+    }
+
+    #[test]
+    fn refuses_to_publish_while_disconnected() {
+        // Nothing listens on port 1.
+        let mut p = Publisher::connect(&options(1));
+        assert!(!p.publish("user/feeds/x", b"1", false));
+    }
+}
@@ -0,0 +1,218 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+use crate::adafruit::Metric;
+use crate::counters;
+use crate::sink::{FeedName, Sink};
+
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use tiny_http::{Header, Response, Server};
+
+const PREFIX: &str = "iot_central_";
+
+#[derive(Debug)]
+pub struct CallParams {
+    // e.g. "0.0.0.0:9184"
+    pub listen: String,
+}
+
+type Latest = Arc<Mutex<BTreeMap<String, f64>>>;
+
+// The Prometheus sink. Keeps the latest value of every feed and serves them,
+// with the internal counters, on /metrics.
+pub struct Exporter {
+    latest: Latest,
+    server: Arc<Server>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Exporter {
+    pub fn new(params: CallParams) -> Result<Exporter, Box<dyn Error>> {
+        let server = Arc::new(
+            Server::http(&params.listen)
+                .map_err(|e| format!("unable to listen on {}: {}", params.listen, e))?,
+        );
+        info!("Serving Prometheus metrics on {}/metrics", params.listen);
+        let latest = Latest::default();
+        let thread = {
+            let server = server.clone();
+            let latest = latest.clone();
+            thread::spawn(move || serve(&server, &latest))
+        };
+        Ok(Exporter {
+            latest,
+            server,
+            thread: Some(thread),
+        })
+    }
+
+    #[cfg(test)]
+    fn addr(&self) -> std::net::SocketAddr {
+        self.server.server_addr().to_ip().unwrap()
+    }
+}
+
+impl Sink for Exporter {
+    fn name(&self) -> &'static str {
+        "Prometheus"
+    }
+
+    fn send(&mut self, m: Metric) {
+        let mut latest = self.latest.lock().unwrap();
+        match m.value.as_f64() {
+            Some(v) if v.is_finite() => {
+                latest.insert(m.feed, v);
+            }
+            // Better no sample than a misleading one.
+            Some(_) => {
+                latest.remove(&m.feed);
+            }
+            // Text and locations have no gauge.
+            None => {}
+        }
+    }
+
+    fn flush(&mut self) -> Option<Instant> {
+        None
+    }
+
+    fn shutdown(&mut self) {
+        self.server.unblock();
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+fn serve(server: &Server, latest: &Mutex<BTreeMap<String, f64>>) {
+    for request in server.incoming_requests() {
+        debug!("{} {}", request.method(), request.url());
+        let result = if request.url() == "/metrics" {
+            let body = render(&latest.lock().unwrap(), &counters::snapshot());
+            let content_type =
+                Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap();
+            request.respond(Response::from_string(body).with_header(content_type))
+        } else {
+            request.respond(Response::from_string("Not found\n").with_status_code(404))
+        };
+        if let Err(e) = result {
+            warn!("Prometheus response failed: {}", e);
+        }
+    }
+}
+
+// Formats the text exposition format: one gauge per quantity, labelled with
+// where it came from, e.g. iot_central_temperature{location="mbr",
+// sensor="bme280"} for "mbr-bme280.temperature".
+fn render(latest: &BTreeMap<String, f64>, counters: &[(&str, u64)]) -> String {
+    let mut gauges: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (feed, value) in latest {
+        let name = FeedName::parse(feed);
+        let mut labels = format!("location=\"{}\"", escape(name.location));
+        if let Some(sensor) = name.sensor {
+            labels.push_str(&format!(",sensor=\"{}\"", escape(sensor)));
+        }
+        gauges
+            .entry(metric_name(name.quantity))
+            .or_default()
+            .push(format!("{{{}}} {}", labels, value));
+    }
+
+    let mut out = String::new();
+    for (name, samples) in gauges {
+        out.push_str(&format!("# TYPE {} gauge\n", name));
+        for s in samples {
+            out.push_str(&format!("{}{}\n", name, s));
+        }
+    }
+    for (name, value) in counters {
+        let name = format!("{}_total", metric_name(name));
+        out.push_str(&format!("# TYPE {} counter\n{} {}\n", name, name, value));
+    }
+    out
+}
+
+fn metric_name(s: &str) -> String {
+    let sanitized: String = s
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{}{}", PREFIX, sanitized)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(feed: &str, value: f32) -> Metric {
+        Metric::new(feed, value)
+    }
+
+    #[test]
+    fn feeds_become_labelled_gauges() {
+        let latest = BTreeMap::from([
+            ("mbr-bme280.temperature".to_owned(), 21.5),
+            ("mbr.temperature".to_owned(), 21.25),
+            ("mbr-sgp30.raw-h2".to_owned(), 13000.0),
+        ]);
+        let text = render(&latest, &[("adafruit_send_failures", 3)]);
+        assert_eq!(
+            "# TYPE iot_central_raw_h2 gauge\n\
+             iot_central_raw_h2{location=\"mbr\",sensor=\"sgp30\"} 13000\n\
+             # TYPE iot_central_temperature gauge\n\
+             iot_central_temperature{location=\"mbr\",sensor=\"bme280\"} 21.5\n\
+             iot_central_temperature{location=\"mbr\"} 21.25\n\
+             # TYPE iot_central_adafruit_send_failures_total counter\n\
+             iot_central_adafruit_send_failures_total 3\n",
+            text
+        );
+    }
+
+    #[test]
+    fn serves_latest_values() {
+        let mut e = Exporter::new(CallParams {
+            listen: "127.0.0.1:0".to_owned(),
+        })
+        .unwrap();
+        e.send(metric("weather.temp", 7.0));
+        e.send(metric("weather.temp", 8.5));
+        e.send(metric("mbr.lux-db", f32::NEG_INFINITY));
+        e.send(Metric::new("weather.description", "clear sky"));
+
+        let base = format!("http://{}", e.addr());
+        let r = reqwest::blocking::get(format!("{}/metrics", base)).unwrap();
+        assert!(r.status().is_success());
+        let body = r.text().unwrap();
+        assert!(body.contains("iot_central_temp{location=\"weather\"} 8.5\n"));
+        assert!(!body.contains("lux_db"));
+        assert!(!body.contains("description"));
+
+        let r = reqwest::blocking::get(format!("{}/other", base)).unwrap();
+        assert_eq!(404, r.status().as_u16());
+        e.shutdown();
+    }
+}
@@ -0,0 +1,274 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+use crate::adafruit;
+
+use std::time::{Duration, Instant};
+
+/// Classic token bucket: holds up to a minute's worth of tokens and refills
+/// continuously at `per_minute`.
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(per_minute: u32, now: Instant) -> TokenBucket {
+        let capacity = f64::from(per_minute.max(1));
+        TokenBucket {
+            capacity,
+            per_sec: capacity / 60.0,
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.per_sec).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The tokens available now, including any fraction of the next.
+    pub fn available(&mut self, now: Instant) -> f64 {
+        self.refill(now);
+        self.tokens
+    }
+
+    /// When the next token will be available.
+    pub fn next_available(&mut self, now: Instant) -> Instant {
+        self.refill(now);
+        let missing = (1.0 - self.tokens).max(0.0);
+        now + Duration::from_secs_f64(missing / self.per_sec)
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    priority: usize,
+    seq: u64,
+    // When the feed first got a point waiting; survives coalescing.
+    since: Instant,
+    metric: adafruit::Metric,
+}
+
+/// Metrics waiting for a token. Over budget, a newer point for a queued feed
+/// replaces the older value but keeps its place; otherwise every point is
+/// kept. Feeds matching an earlier prefix in `priorities` go first; ties go
+/// in arrival order.
+#[derive(Debug)]
+pub struct Pending {
+    priorities: Vec<String>,
+    entries: Vec<Entry>,
+    next_seq: u64,
+    coalesced: u64,
+}
+
+impl Pending {
+    pub fn new(priorities: Vec<String>) -> Pending {
+        Pending {
+            priorities,
+            entries: Vec::new(),
+            next_seq: 0,
+            coalesced: 0,
+        }
+    }
+
+    fn priority(&self, feed: &str) -> usize {
+        self.priorities
+            .iter()
+            .position(|p| feed.starts_with(p.as_str()))
+            .unwrap_or(self.priorities.len())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Number of points replaced by a newer value before they were sent.
+    pub fn coalesced(&self) -> u64 {
+        self.coalesced
+    }
+
+    // When the longest-waiting point arrived.
+    pub fn oldest(&self) -> Option<Instant> {
+        self.entries.iter().map(|e| e.since).min()
+    }
+
+    /// Queues a metric, or with `coalesce` replaces the newest point waiting
+    /// for the same feed.
+    pub fn push(&mut self, metric: adafruit::Metric, coalesce: bool) {
+        if coalesce {
+            if let Some(e) = self
+                .entries
+                .iter_mut()
+                .filter(|e| e.metric.feed == metric.feed)
+                .max_by_key(|e| e.seq)
+            {
+                e.metric = metric;
+                self.coalesced += 1;
+                return;
+            }
+        }
+        self.entries.push(Entry {
+            priority: self.priority(&metric.feed),
+            seq: self.next_seq,
+            since: Instant::now(),
+            metric,
+        });
+        self.next_seq += 1;
+    }
+
+    /// Puts back a metric that could not be sent, unless `coalesce` is set and
+    /// a newer point for the same feed has arrived in the meantime.
+    pub fn requeue(&mut self, metric: adafruit::Metric, coalesce: bool) {
+        if coalesce && self.entries.iter().any(|e| e.metric.feed == metric.feed) {
+            self.coalesced += 1;
+            return;
+        }
+        self.entries.push(Entry {
+            priority: self.priority(&metric.feed),
+            // Ahead of everything else at the same priority.
+            seq: 0,
+            since: Instant::now(),
+            metric,
+        });
+    }
+
+    pub fn pop(&mut self) -> Option<adafruit::Metric> {
+        let i = self
+            .entries
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, e)| (e.priority, e.seq))
+            .map(|(i, _)| i)?;
+        Some(self.entries.remove(i).metric)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adafruit::Value;
+
+    fn metric(feed: &str, value: f32) -> adafruit::Metric {
+        adafruit::Metric::new(feed, value)
+    }
+
+    fn drain(p: &mut Pending) -> Vec<adafruit::Metric> {
+        std::iter::from_fn(|| p.pop()).collect()
+    }
+
+    #[test]
+    fn bucket_allows_burst_then_refills() {
+        let start = Instant::now();
+        let mut b = TokenBucket::new(30, start);
+        for _ in 0..30 {
+            assert!(b.try_take(start));
+        }
+        assert!(!b.try_take(start));
+        assert_eq!(start + Duration::from_secs(2), b.next_available(start));
+        assert!(b.try_take(start + Duration::from_secs(2)));
+        assert!(!b.try_take(start + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn bucket_does_not_overfill() {
+        let start = Instant::now();
+        let mut b = TokenBucket::new(2, start);
+        let later = start + Duration::from_secs(3600);
+        assert!(b.try_take(later));
+        assert!(b.try_take(later));
+        assert!(!b.try_take(later));
+    }
+
+    #[test]
+    fn pending_orders_by_priority_then_arrival() {
+        let mut p = Pending::new(vec!["mbr.".to_owned(), "mbr-bme280.".to_owned()]);
+        p.push(metric("mbr-sgp30.raw-h2", 1.0), true);
+        p.push(metric("mbr-bme280.temperature", 2.0), true);
+        p.push(metric("mbr.temperature", 3.0), true);
+        p.push(metric("mbr.humidity", 4.0), true);
+        let feeds: Vec<String> = drain(&mut p).into_iter().map(|m| m.feed).collect();
+        assert_eq!(
+            vec![
+                "mbr.temperature",
+                "mbr.humidity",
+                "mbr-bme280.temperature",
+                "mbr-sgp30.raw-h2"
+            ],
+            feeds
+        );
+    }
+
+    #[test]
+    fn pending_coalesces_same_feed() {
+        let mut p = Pending::new(Vec::new());
+        p.push(metric("a", 1.0), true);
+        p.push(metric("b", 2.0), true);
+        p.push(metric("a", 3.0), true);
+        assert_eq!(2, p.len());
+        assert_eq!(1, p.coalesced());
+        let a = p.pop().unwrap();
+        assert_eq!(("a", Value::Float(3.0)), (a.feed.as_str(), a.value));
+    }
+
+    #[test]
+    fn pending_keeps_every_point_under_budget() {
+        let mut p = Pending::new(Vec::new());
+        p.push(metric("a", 1.0), false);
+        p.push(metric("a", 2.0), false);
+        p.push(metric("a", 3.0), true);
+        assert_eq!(2, p.len());
+        assert_eq!(1, p.coalesced());
+        let values: Vec<Value> = drain(&mut p).into_iter().map(|m| m.value).collect();
+        assert_eq!(vec![Value::Float(1.0), Value::Float(3.0)], values);
+    }
+
+    #[test]
+    fn requeue_yields_to_newer_points() {
+        let mut p = Pending::new(Vec::new());
+        p.push(metric("a", 1.0), true);
+        p.push(metric("b", 2.0), true);
+        let a = p.pop().unwrap();
+        let b = p.pop().unwrap();
+        p.push(metric("a", 5.0), true);
+        p.requeue(a, true);
+        p.requeue(b, true);
+        assert_eq!(2, p.len());
+        assert_eq!(
+            Value::Float(5.0),
+            drain(&mut p).iter().find(|m| m.feed == "a").unwrap().value
+        );
+    }
+}
@@ -0,0 +1,37 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+//! Which sensors are currently producing readings, for sinks that report
+//! availability (e.g. Home Assistant).
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+static SENSORS: Mutex<BTreeMap<&'static str, bool>> = Mutex::new(BTreeMap::new());
+
+pub fn set(sensor: &'static str, available: bool) {
+    SENSORS.lock().unwrap().insert(sensor, available);
+}
+
+/// Every sensor seen so far and whether it is available, sorted by name.
+pub fn snapshot() -> Vec<(&'static str, bool)> {
+    SENSORS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(k, v)| (*k, *v))
+        .collect()
+}
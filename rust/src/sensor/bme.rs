@@ -14,102 +14,101 @@
 
 #![warn(clippy::all)]
 
+use super::{publish_climate, publish_pressure, Environment, Mean, Sensor};
 use crate::adafruit;
 use bme280::BME280;
-use crate::conversion;
 use embedded_hal::blocking::{delay, i2c};
 use log::debug;
+use std::error::Error;
+use std::fmt::Debug;
 use std::sync::mpsc;
-use std::time::{Duration, Instant};
 
-const UPDATE_PERIOD: Duration = Duration::from_secs(60);
+pub struct Bme280<I2C, D> {
+    bme: BME280<I2C, D>,
+    altitude: f32,
+    // Whether this is the primary temperature/humidity source.
+    primary: bool,
+    // Whether this provides mbr.pressure.
+    barometer: bool,
+    temperature: Mean,
+    humidity: Mean,
+    pressure: Mean,
+}
 
-pub struct State {
-    pub sensor_is_valid: bool,
-    pub last_abs_humidity: f32,
-    pub last_update: Instant,
-    pub temperature_sum: f32,
-    pub humidity_sum: f32,
-    pub pressure_sum: f32,
-    pub count: i32,
+impl<I2C, D, E> Bme280<I2C, D>
+where
+    I2C: i2c::Read<Error = E> + i2c::Write<Error = E> + i2c::WriteRead<Error = E>,
+    D: delay::DelayUs<u8> + delay::DelayMs<u8>,
+{
+    pub fn new(i2c: I2C, delay: D, altitude: f32, primary: bool, barometer: bool) -> Self {
+        Bme280 {
+            bme: BME280::new_secondary(i2c, delay),
+            altitude,
+            primary,
+            barometer,
+            temperature: Mean::default(),
+            humidity: Mean::default(),
+            pressure: Mean::default(),
+        }
+    }
 }
 
-pub fn poll<I2C, D, E>(
-    bme: &mut BME280<I2C, D>,
-    state: &mut State,
-    tx: &mpsc::Sender<adafruit::Metric>,
-) where
+impl<I2C, D, E> Sensor for Bme280<I2C, D>
+where
     I2C: i2c::Read<Error = E> + i2c::Write<Error = E> + i2c::WriteRead<Error = E>,
     D: delay::DelayUs<u8> + delay::DelayMs<u8>,
+    E: Debug,
 {
-    if let Ok(measurements) = bme.measure() {
+    fn name(&self) -> &'static str {
+        "bme280"
+    }
+
+    fn init(&mut self) -> Result<(), Box<dyn Error>> {
+        self.bme.init().map_err(|e| format!("{:?}", e))?;
+        Ok(())
+    }
+
+    fn sample(&mut self, _env: &mut Environment) -> Result<(), Box<dyn Error>> {
+        let measurements = self.bme.measure().map_err(|e| format!("{:?}", e))?;
         debug!(
             "BME: temp = {} humid = {} press = {}",
             measurements.temperature, measurements.humidity, measurements.pressure,
         );
-        state.temperature_sum += measurements.temperature;
-        state.humidity_sum += measurements.humidity;
-        state.pressure_sum += measurements.pressure;
-        state.count += 1;
+        self.temperature.add(measurements.temperature);
+        self.humidity.add(measurements.humidity);
+        self.pressure.add(measurements.pressure);
+        Ok(())
     }
 
-    let now = Instant::now();
-    if now.duration_since(state.last_update) > UPDATE_PERIOD {
-        if state.count > 0 {
-            let celsius = state.temperature_sum / state.count as f32;
-            let relative_humidity = state.humidity_sum / state.count as f32;
-            let raw_pressure_hpa = state.pressure_sum / state.count as f32 / 100.0;
+    fn flush(&mut self, env: &mut Environment, tx: &mpsc::Sender<adafruit::Metric>) {
+        let (celsius, relative_humidity, raw_pressure) = match (
+            self.temperature.take(),
+            self.humidity.take(),
+            self.pressure.take(),
+        ) {
+            (Some(t), Some(h), Some(p)) => (t, h, p),
+            _ => return,
+        };
+        let raw_pressure_hpa = raw_pressure / 100.0;
 
-            tx.send(adafruit::Metric {
-                feed: "mbr-bme280.temperature".into(),
-                value: celsius,
-            })
+        tx.send(adafruit::Metric::new("mbr-bme280.temperature", celsius))
             .unwrap();
-            tx.send(adafruit::Metric {
-                feed: "mbr-bme280.humidity".into(),
-                value: relative_humidity,
-            })
-            .unwrap();
-            tx.send(adafruit::Metric {
-                feed: "mbr-bme280.pressure".into(),
-                value: raw_pressure_hpa,
-            })
-            .unwrap();
-
-            let fahrenheit = conversion::celsius_to_fahrenheit(celsius);
-            state.last_abs_humidity =
-                conversion::relative_humidity_to_absolute(relative_humidity, celsius);
-            let sealevel_pressure = conversion::hpa_to_inhg(conversion::raw_pressure_to_sealevel(
-                raw_pressure_hpa,
-                celsius,
-            ));
+        tx.send(adafruit::Metric::new(
+            "mbr-bme280.humidity",
+            relative_humidity,
+        ))
+        .unwrap();
+        tx.send(adafruit::Metric::new(
+            "mbr-bme280.pressure",
+            raw_pressure_hpa,
+        ))
+        .unwrap();
 
-            tx.send(adafruit::Metric {
-                feed: "mbr.temperature".into(),
-                value: fahrenheit,
-            })
-            .unwrap();
-            tx.send(adafruit::Metric {
-                feed: "mbr.humidity".into(),
-                value: relative_humidity,
-            })
-            .unwrap();
-            tx.send(adafruit::Metric {
-                feed: "mbr.abs-humidity".into(),
-                value: state.last_abs_humidity,
-            })
-            .unwrap();
-            tx.send(adafruit::Metric {
-                feed: "mbr.pressure".into(),
-                value: sealevel_pressure,
-            })
-            .unwrap();
+        if self.primary {
+            publish_climate(celsius, relative_humidity, env, tx);
+        }
+        if self.barometer {
+            publish_pressure(raw_pressure_hpa, celsius, self.altitude, env, tx);
         }
-
-        state.temperature_sum = 0.0;
-        state.humidity_sum = 0.0;
-        state.pressure_sum = 0.0;
-        state.count = 0;
-        state.last_update = now;
     }
-}
\ No newline at end of file
+}
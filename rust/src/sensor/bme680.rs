@@ -0,0 +1,681 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+//! The Bosch BME680 and BME688: temperature, humidity, pressure, and the
+//! resistance of a heated metal-oxide gas sensor, which falls as VOCs rise.
+//! Measured in forced mode, with the floating-point compensation from
+//! Bosch's BME68x API.
+//!
+//! The air quality score is the one from Pimoroni's open-source bme680
+//! examples (indoor-air-quality.py). A quarter of it comes from how far
+//! humidity is from 40%, and three quarters from how far the gas resistance
+//! has fallen below its clean-air baseline. As in the original, the baseline
+//! is the mean over the last part of a 5 minute burn-in, and stays fixed
+//! after that, so a single high reading can't skew the scores that follow.
+//! 100 is the cleanest.
+
+use super::{publish_climate, publish_pressure, Bme680Settings, Environment, Mean, Sensor};
+use crate::adafruit;
+use embedded_hal::blocking::{delay, i2c};
+use log::{debug, info};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+const CHIP_ID: u8 = 0x61;
+const SOFT_RESET: u8 = 0xB6;
+
+const REG_FIELD_0: u8 = 0x1D;
+const REG_RES_HEAT_0: u8 = 0x5A;
+const REG_GAS_WAIT_0: u8 = 0x64;
+const REG_CTRL_GAS_1: u8 = 0x71;
+const REG_CTRL_HUM: u8 = 0x72;
+const REG_CTRL_MEAS: u8 = 0x74;
+const REG_CONFIG: u8 = 0x75;
+const REG_COEFF_1: u8 = 0x8A;
+const REG_CHIP_ID: u8 = 0xD0;
+const REG_RESET: u8 = 0xE0;
+const REG_COEFF_2: u8 = 0xE1;
+const REG_VARIANT_ID: u8 = 0xF0;
+const REG_COEFF_3: u8 = 0x00;
+
+// Oversampling ×8 temperature, ×4 pressure and ×2 humidity, and an IIR
+// filter of 3, as in Bosch's examples.
+const CTRL_MEAS_FORCED: u8 = 0b100 << 5 | 0b011 << 2 | 0b01;
+const CTRL_HUM: u8 = 0b010;
+const CONFIG: u8 = 0b010 << 2;
+// Runs the gas measurement with heater step 0.
+const RUN_GAS_BME680: u8 = 0x10;
+const RUN_GAS_BME688: u8 = 0x20;
+
+// (8 + 4 + 2) conversions of 1.963 ms, plus overhead, before the heater
+// starts.
+const TPH_MS: u16 = 35;
+const READY_POLLS: u32 = 5;
+const READY_POLL_MS: u16 = 10;
+
+const NEW_DATA: u8 = 0x80;
+const GAS_VALID: u8 = 0x20;
+const HEAT_STAB: u8 = 0x10;
+
+const BURN_IN: Duration = Duration::from_secs(5 * 60);
+const BURN_IN_SAMPLES: usize = 50;
+const HUMIDITY_BASELINE: f32 = 40.0;
+const HUMIDITY_WEIGHTING: f32 = 0.25;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Variant {
+    Bme680,
+    Bme688,
+}
+
+pub struct Bme680<I2C, D> {
+    i2c: I2C,
+    delay: D,
+    altitude: f32,
+    settings: Bme680Settings,
+    // Whether this is the primary temperature/humidity source, and so also
+    // provides mbr.pressure.
+    primary: bool,
+    variant: Variant,
+    calibration: Calibration,
+    // Heater step for the next sample.
+    step: usize,
+    // The last temperature, which the heater setting depends on.
+    ambient: f32,
+    air_quality: AirQuality,
+    temperature: Mean,
+    humidity: Mean,
+    pressure: Mean,
+    // One per heater step.
+    gas: Vec<Mean>,
+    score: Mean,
+}
+
+impl<I2C, D, E> Bme680<I2C, D>
+where
+    I2C: i2c::Write<Error = E> + i2c::WriteRead<Error = E>,
+    D: delay::DelayMs<u16>,
+    E: Debug,
+{
+    pub fn new(i2c: I2C, delay: D, altitude: f32, settings: Bme680Settings, primary: bool) -> Self {
+        let gas = settings
+            .heater_profile
+            .iter()
+            .map(|_| Mean::default())
+            .collect();
+        Bme680 {
+            i2c,
+            delay,
+            altitude,
+            settings,
+            primary,
+            variant: Variant::Bme680,
+            calibration: Calibration::default(),
+            step: 0,
+            ambient: 25.0,
+            air_quality: AirQuality::new(Instant::now()),
+            temperature: Mean::default(),
+            humidity: Mean::default(),
+            pressure: Mean::default(),
+            gas,
+            score: Mean::default(),
+        }
+    }
+
+    // Writes (register, value) pairs.
+    fn write(&mut self, pairs: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.i2c
+            .write(self.settings.address, pairs)
+            .map_err(|e| format!("{:?}", e))?;
+        Ok(())
+    }
+
+    fn read(&mut self, register: u8, buffer: &mut [u8]) -> Result<(), Box<dyn Error>> {
+        self.i2c
+            .write_read(self.settings.address, &[register], buffer)
+            .map_err(|e| format!("{:?}", e))?;
+        Ok(())
+    }
+
+    // Waits for the measurement started by the last forced-mode write.
+    fn read_field(&mut self) -> Result<[u8; 17], Box<dyn Error>> {
+        let mut field = [0; 17];
+        for _ in 0..READY_POLLS {
+            self.read(REG_FIELD_0, &mut field)?;
+            if field[0] & NEW_DATA != 0 {
+                return Ok(field);
+            }
+            self.delay.delay_ms(READY_POLL_MS);
+        }
+        Err("measurement not ready".into())
+    }
+}
+
+impl<I2C, D, E> Sensor for Bme680<I2C, D>
+where
+    I2C: i2c::Write<Error = E> + i2c::WriteRead<Error = E>,
+    D: delay::DelayMs<u16>,
+    E: Debug,
+{
+    fn name(&self) -> &'static str {
+        "bme680"
+    }
+
+    fn init(&mut self) -> Result<(), Box<dyn Error>> {
+        self.write(&[REG_RESET, SOFT_RESET])?;
+        self.delay.delay_ms(10);
+        let mut id = [0];
+        self.read(REG_CHIP_ID, &mut id)?;
+        if id[0] != CHIP_ID {
+            return Err(format!("unexpected chip ID {:#04x}", id[0]).into());
+        }
+        self.read(REG_VARIANT_ID, &mut id)?;
+        self.variant = if id[0] == 0 {
+            Variant::Bme680
+        } else {
+            Variant::Bme688
+        };
+
+        let mut coeff = [0; 42];
+        self.read(REG_COEFF_1, &mut coeff[..23])?;
+        self.read(REG_COEFF_2, &mut coeff[23..37])?;
+        self.read(REG_COEFF_3, &mut coeff[37..])?;
+        self.calibration = Calibration::parse(&coeff);
+
+        let run_gas = match self.variant {
+            Variant::Bme680 => RUN_GAS_BME680,
+            Variant::Bme688 => RUN_GAS_BME688,
+        };
+        // Humidity oversampling takes effect on the next ctrl_meas write.
+        self.write(&[
+            REG_CTRL_HUM,
+            CTRL_HUM,
+            REG_CONFIG,
+            CONFIG,
+            REG_CTRL_GAS_1,
+            run_gas,
+        ])?;
+        info!(
+            "{:?} heater profile {:?}",
+            self.variant, self.settings.heater_profile
+        );
+        self.step = 0;
+        // A baseline from before a re-init still holds for the same sensor.
+        if self.air_quality.baseline.is_none() {
+            self.air_quality = AirQuality::new(Instant::now());
+        }
+        Ok(())
+    }
+
+    fn sample(&mut self, _env: &mut Environment) -> Result<(), Box<dyn Error>> {
+        let step_index = self.step;
+        let step = self.settings.heater_profile[step_index];
+        self.step = (self.step + 1) % self.settings.heater_profile.len();
+
+        let res_heat = self.calibration.res_heat(step.temperature_c, self.ambient);
+        self.write(&[
+            REG_RES_HEAT_0,
+            res_heat,
+            REG_GAS_WAIT_0,
+            gas_wait(step.duration_ms),
+            REG_CTRL_MEAS,
+            CTRL_MEAS_FORCED,
+        ])?;
+        self.delay.delay_ms(TPH_MS + step.duration_ms);
+        let field = self.read_field()?;
+        let m = self.calibration.compensate(&field, self.variant);
+        debug!(
+            "BME680: temp = {} humid = {} press = {} gas = {:?} at {} °C",
+            m.temperature, m.humidity, m.pressure, m.gas, step.temperature_c
+        );
+        self.ambient = m.temperature;
+        self.temperature.add(m.temperature);
+        self.humidity.add(m.humidity);
+        self.pressure.add(m.pressure);
+        if let Some(gas) = m.gas {
+            self.gas[step_index].add(gas);
+            // The baseline only makes sense for one heater temperature.
+            if step_index == 0 {
+                if let Some(score) = self.air_quality.update(Instant::now(), gas, m.humidity) {
+                    self.score.add(score);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, env: &mut Environment, tx: &mpsc::Sender<adafruit::Metric>) {
+        let gas: Vec<_> = self.gas.iter_mut().map(Mean::take).collect();
+        let score = self.score.take();
+        let (celsius, relative_humidity, raw_pressure) = match (
+            self.temperature.take(),
+            self.humidity.take(),
+            self.pressure.take(),
+        ) {
+            (Some(t), Some(h), Some(p)) => (t, h, p),
+            _ => return,
+        };
+        let raw_pressure_hpa = raw_pressure / 100.0;
+
+        tx.send(adafruit::Metric::new("mbr-bme680.temperature", celsius))
+            .unwrap();
+        tx.send(adafruit::Metric::new(
+            "mbr-bme680.humidity",
+            relative_humidity,
+        ))
+        .unwrap();
+        tx.send(adafruit::Metric::new(
+            "mbr-bme680.pressure",
+            raw_pressure_hpa,
+        ))
+        .unwrap();
+        for (i, ohms) in gas.into_iter().enumerate() {
+            let feed = match i {
+                0 => "mbr-bme680.gas-resistance".to_string(),
+                _ => format!("mbr-bme680.gas-resistance-{}", i + 1),
+            };
+            if let Some(ohms) = ohms {
+                tx.send(adafruit::Metric::new(feed, ohms)).unwrap();
+            }
+        }
+        if let Some(score) = score {
+            tx.send(adafruit::Metric::new("mbr-bme680.air-quality", score))
+                .unwrap();
+        }
+
+        if self.primary {
+            publish_climate(celsius, relative_humidity, env, tx);
+            publish_pressure(raw_pressure_hpa, celsius, self.altitude, env, tx);
+        }
+    }
+}
+
+// A compensated measurement: °C, %RH, Pa, and Ω if the gas reading is good.
+#[derive(Debug)]
+struct Measurement {
+    temperature: f32,
+    humidity: f32,
+    pressure: f32,
+    gas: Option<f32>,
+}
+
+#[derive(Debug, Default)]
+struct Calibration {
+    t1: f32,
+    t2: f32,
+    t3: f32,
+    p1: f32,
+    p2: f32,
+    p3: f32,
+    p4: f32,
+    p5: f32,
+    p6: f32,
+    p7: f32,
+    p8: f32,
+    p9: f32,
+    p10: f32,
+    h1: f32,
+    h2: f32,
+    h3: f32,
+    h4: f32,
+    h5: f32,
+    h6: f32,
+    h7: f32,
+    gh1: f32,
+    gh2: f32,
+    gh3: f32,
+    res_heat_range: f32,
+    res_heat_val: f32,
+    range_sw_err: f32,
+}
+
+impl Calibration {
+    // From the three coefficient blocks, at 0x8A (23 bytes), 0xE1 (14) and
+    // 0x00 (5), in that order.
+    fn parse(c: &[u8; 42]) -> Calibration {
+        let u16_at = |msb: usize, lsb: usize| f32::from(u16::from_be_bytes([c[msb], c[lsb]]));
+        let i16_at = |msb: usize, lsb: usize| f32::from(i16::from_be_bytes([c[msb], c[lsb]]));
+        let i8_at = |i: usize| f32::from(c[i] as i8);
+        Calibration {
+            t1: u16_at(32, 31),
+            t2: i16_at(1, 0),
+            t3: i8_at(2),
+            p1: u16_at(5, 4),
+            p2: i16_at(7, 6),
+            p3: i8_at(8),
+            p4: i16_at(11, 10),
+            p5: i16_at(13, 12),
+            p6: i8_at(15),
+            p7: i8_at(14),
+            p8: i16_at(19, 18),
+            p9: i16_at(21, 20),
+            p10: f32::from(c[22]),
+            h1: f32::from(u16::from(c[25]) << 4 | u16::from(c[24] & 0x0F)),
+            h2: f32::from(u16::from(c[23]) << 4 | u16::from(c[24] >> 4)),
+            h3: i8_at(26),
+            h4: i8_at(27),
+            h5: i8_at(28),
+            h6: f32::from(c[29]),
+            h7: i8_at(30),
+            gh1: i8_at(35),
+            gh2: i16_at(34, 33),
+            gh3: i8_at(36),
+            res_heat_range: f32::from((c[39] & 0x30) >> 4),
+            res_heat_val: i8_at(37),
+            range_sw_err: f32::from((c[41] as i8) >> 4),
+        }
+    }
+
+    fn compensate(&self, field: &[u8; 17], variant: Variant) -> Measurement {
+        let adc20 = |i: usize| {
+            (u32::from(field[i]) << 12
+                | u32::from(field[i + 1]) << 4
+                | u32::from(field[i + 2]) >> 4) as f32
+        };
+        let (temperature, t_fine) = self.temperature(adc20(5));
+        let (gas_msb, gas_lsb) = match variant {
+            Variant::Bme680 => (field[13], field[14]),
+            Variant::Bme688 => (field[15], field[16]),
+        };
+        let gas_adc = u16::from(gas_msb) << 2 | u16::from(gas_lsb) >> 6;
+        let gas_range = gas_lsb & 0x0F;
+        let gas_ok = gas_lsb & GAS_VALID != 0 && gas_lsb & HEAT_STAB != 0;
+        Measurement {
+            temperature,
+            humidity: self.humidity(f32::from(u16::from_be_bytes([field[8], field[9]])), t_fine),
+            pressure: self.pressure(adc20(2), t_fine),
+            gas: gas_ok.then(|| match variant {
+                Variant::Bme680 => self.gas_resistance_bme680(gas_adc, gas_range),
+                Variant::Bme688 => gas_resistance_bme688(gas_adc, gas_range),
+            }),
+        }
+    }
+
+    // °C, and the fine temperature the other readings are compensated with.
+    fn temperature(&self, adc: f32) -> (f32, f32) {
+        let var1 = (adc / 16384.0 - self.t1 / 1024.0) * self.t2;
+        let var2 = adc / 131072.0 - self.t1 / 8192.0;
+        let t_fine = var1 + var2 * var2 * self.t3 * 16.0;
+        (t_fine / 5120.0, t_fine)
+    }
+
+    // Pa.
+    fn pressure(&self, adc: f32, t_fine: f32) -> f32 {
+        let var1 = t_fine / 2.0 - 64000.0;
+        let var2 = var1 * var1 * self.p6 / 131072.0 + var1 * self.p5 * 2.0;
+        let var2 = var2 / 4.0 + self.p4 * 65536.0;
+        let var1 = (self.p3 * var1 * var1 / 16384.0 + self.p2 * var1) / 524288.0;
+        let var1 = (1.0 + var1 / 32768.0) * self.p1;
+        if var1 == 0.0 {
+            return 0.0;
+        }
+        let p = (1048576.0 - adc - var2 / 4096.0) * 6250.0 / var1;
+        let var1 = self.p9 * p * p / 2147483648.0;
+        let var2 = p * self.p8 / 32768.0;
+        let var3 = (p / 256.0).powi(3) * self.p10 / 131072.0;
+        p + (var1 + var2 + var3 + self.p7 * 128.0) / 16.0
+    }
+
+    // %RH.
+    fn humidity(&self, adc: f32, t_fine: f32) -> f32 {
+        let t = t_fine / 5120.0;
+        let var1 = adc - (self.h1 * 16.0 + self.h3 / 2.0 * t);
+        let var2 = var1
+            * (self.h2 / 262144.0 * (1.0 + self.h4 / 16384.0 * t + self.h5 / 1048576.0 * t * t));
+        let var3 = self.h6 / 16384.0;
+        let var4 = self.h7 / 2097152.0;
+        (var2 + (var3 + var4 * t) * var2 * var2).clamp(0.0, 100.0)
+    }
+
+    // Ω, for the BME680's gas ADC.
+    fn gas_resistance_bme680(&self, adc: u16, range: u8) -> f32 {
+        const K1: [f32; 16] = [
+            0.0, 0.0, 0.0, 0.0, 0.0, -1.0, 0.0, -0.8, 0.0, 0.0, -0.2, -0.5, 0.0, -1.0, 0.0, 0.0,
+        ];
+        const K2: [f32; 16] = [
+            0.0, 0.0, 0.0, 0.0, 0.1, 0.7, 0.0, -0.8, -0.1, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0,
+        ];
+        let range = usize::from(range & 0x0F);
+        let var1 = 1340.0 + 5.0 * self.range_sw_err;
+        let var2 = var1 * (1.0 + K1[range] / 100.0);
+        let var3 = 1.0 + K2[range] / 100.0;
+        1.0 / (var3
+            * 0.000000125
+            * (1u32 << range) as f32
+            * ((f32::from(adc) - 512.0) / var2 + 1.0))
+    }
+
+    // The res_heat register value that heats the plate to `target` °C.
+    fn res_heat(&self, target: u16, ambient: f32) -> u8 {
+        let target = f32::from(target.min(400));
+        let var1 = self.gh1 / 16.0 + 49.0;
+        let var2 = self.gh2 / 32768.0 * 0.0005 + 0.00235;
+        let var3 = self.gh3 / 1024.0;
+        let var4 = var1 * (1.0 + var2 * target);
+        let var5 = var4 + var3 * ambient;
+        let res_heat = 3.4
+            * (var5
+                * (4.0 / (4.0 + self.res_heat_range))
+                * (1.0 / (1.0 + self.res_heat_val * 0.002))
+                - 25.0);
+        res_heat.clamp(0.0, 255.0) as u8
+    }
+}
+
+// Ω, for the BME688's gas ADC.
+fn gas_resistance_bme688(adc: u16, range: u8) -> f32 {
+    let var1 = 262144u32 >> (range & 0x0F);
+    let var2 = 4096 + 3 * (i32::from(adc) - 512);
+    1_000_000.0 * var1 as f32 / var2 as f32
+}
+
+// The gas_wait register value for a heating time: 6 bits of time and a 2-bit
+// multiplier of 1, 4, 16 or 64.
+fn gas_wait(mut ms: u16) -> u8 {
+    if ms >= 0xFC0 {
+        return 0xFF;
+    }
+    let mut factor = 0;
+    while ms > 0x3F {
+        ms /= 4;
+        factor += 1;
+    }
+    (ms + factor * 64) as u8
+}
+
+struct AirQuality {
+    started: Instant,
+    // The most recent gas readings during burn-in.
+    burn_in: VecDeque<f32>,
+    baseline: Option<f32>,
+}
+
+impl AirQuality {
+    fn new(now: Instant) -> AirQuality {
+        AirQuality {
+            started: now,
+            burn_in: VecDeque::new(),
+            baseline: None,
+        }
+    }
+
+    // The score for a reading, once burn-in is over.
+    fn update(&mut self, now: Instant, gas: f32, humidity: f32) -> Option<f32> {
+        let baseline = match self.baseline {
+            Some(baseline) => baseline,
+            None => {
+                self.burn_in.push_back(gas);
+                if self.burn_in.len() > BURN_IN_SAMPLES {
+                    self.burn_in.pop_front();
+                }
+                if now.duration_since(self.started) < BURN_IN {
+                    return None;
+                }
+                let baseline = self.burn_in.iter().sum::<f32>() / self.burn_in.len() as f32;
+                info!("BME680 gas baseline {:.0} Ω", baseline);
+                baseline
+            }
+        };
+        self.baseline = Some(baseline);
+        Some(air_quality_score(gas, baseline, humidity))
+    }
+}
+
+fn air_quality_score(gas: f32, gas_baseline: f32, humidity: f32) -> f32 {
+    let humidity_offset = humidity - HUMIDITY_BASELINE;
+    let humidity_score = if humidity_offset > 0.0 {
+        (100.0 - HUMIDITY_BASELINE - humidity_offset) / (100.0 - HUMIDITY_BASELINE)
+    } else {
+        (HUMIDITY_BASELINE + humidity_offset) / HUMIDITY_BASELINE
+    } * HUMIDITY_WEIGHTING
+        * 100.0;
+    let gas_score = if gas < gas_baseline {
+        gas / gas_baseline
+    } else {
+        1.0
+    } * (1.0 - HUMIDITY_WEIGHTING)
+        * 100.0;
+    humidity_score + gas_score
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heater_wait_is_encoded() {
+        // The datasheet's example: 100 ms is 25 × 4.
+        assert_eq!(0x59, gas_wait(100));
+        assert_eq!(0x25, gas_wait(0x25));
+        assert_eq!(0x65, gas_wait(150));
+        assert_eq!(0xFF, gas_wait(5000));
+    }
+
+    #[test]
+    fn gas_resistance_is_compensated() {
+        // Midscale on the most sensitive range.
+        let calibration = Calibration::default();
+        assert_eq!(8_000_000.0, calibration.gas_resistance_bme680(512, 0));
+        assert_eq!(64_000_000.0, gas_resistance_bme688(512, 0));
+        assert_eq!(62_500.0, gas_resistance_bme688(512, 10));
+        // Resistance falls as the ADC reading rises.
+        assert!(gas_resistance_bme688(800, 10) < gas_resistance_bme688(512, 10));
+    }
+
+    #[test]
+    fn air_quality_weighs_gas_and_humidity() {
+        assert_eq!(100.0, air_quality_score(50_000.0, 50_000.0, 40.0));
+        assert_eq!(100.0, air_quality_score(60_000.0, 50_000.0, 40.0));
+        assert_eq!(62.5, air_quality_score(25_000.0, 50_000.0, 40.0));
+        assert_eq!(87.5, air_quality_score(50_000.0, 50_000.0, 70.0));
+        assert_eq!(87.5, air_quality_score(50_000.0, 50_000.0, 20.0));
+    }
+
+    #[test]
+    fn air_quality_waits_for_burn_in() {
+        let start = Instant::now();
+        let mut aq = AirQuality::new(start);
+        for i in 0..100 {
+            let gas = if i < 50 { 1_000.0 } else { 40_000.0 };
+            assert_eq!(None, aq.update(start + Duration::from_secs(i), gas, 40.0));
+        }
+        // Only the last 50 readings make the baseline.
+        let after = start + BURN_IN;
+        assert_eq!(Some(100.0), aq.update(after, 40_000.0, 40.0));
+        assert_eq!(Some(40_000.0), aq.baseline);
+        assert_eq!(Some(62.5), aq.update(after, 20_000.0, 40.0));
+    }
+
+    #[test]
+    fn one_outlier_does_not_lower_later_scores() {
+        let start = Instant::now();
+        let mut aq = AirQuality::new(start);
+        aq.update(start, 40_000.0, 40.0);
+        let after = start + BURN_IN;
+        assert_eq!(Some(100.0), aq.update(after, 40_000.0, 40.0));
+        assert_eq!(Some(62.5), aq.update(after, 20_000.0, 40.0));
+        // A spike after a heater change or in a draft scores as clean...
+        assert_eq!(Some(100.0), aq.update(after, 400_000.0, 40.0));
+        // ...and leaves the scores after it as they were.
+        assert_eq!(Some(62.5), aq.update(after, 20_000.0, 40.0));
+        assert_eq!(Some(40_000.0), aq.baseline);
+    }
+
+    #[cfg(feature = "sim")]
+    #[test]
+    fn simulated_bme688_is_read() {
+        use super::super::{HeaterStep, BME680_DEFAULT_ADDRESS};
+        use crate::sensor::sim;
+
+        let config = crate::config::SimConfig {
+            temperature_swing_c: 0.,
+            humidity_swing_pct: 0.,
+            co2_spike_ppm: 0.,
+            ..Default::default()
+        };
+        let settings = Bme680Settings {
+            address: BME680_DEFAULT_ADDRESS,
+            heater_profile: vec![
+                HeaterStep {
+                    temperature_c: 320,
+                    duration_ms: 150,
+                },
+                HeaterStep {
+                    temperature_c: 200,
+                    duration_ms: 100,
+                },
+            ],
+        };
+        let bus = sim::Bus::new(config.waveforms()).with_bme680(BME680_DEFAULT_ADDRESS);
+        let mut bme = Bme680::new(bus, sim::Delay, 100.0, settings, true);
+        bme.init().unwrap();
+        assert_eq!(Variant::Bme688, bme.variant);
+        let mut env = Environment::default();
+        for _ in 0..3 {
+            bme.sample(&mut env).unwrap();
+        }
+
+        let (tx, rx) = mpsc::channel();
+        bme.flush(&mut env, &tx);
+        let sent: Vec<_> = rx
+            .try_iter()
+            .map(|m| (m.feed, m.value.to_string()))
+            .collect();
+        let value = |feed: &str| -> f32 {
+            sent.iter()
+                .find(|(f, _)| f == feed)
+                .unwrap_or_else(|| panic!("no {} in {:?}", feed, sent))
+                .1
+                .parse()
+                .unwrap()
+        };
+        assert!((value("mbr-bme680.temperature") - 21.0).abs() < 0.01);
+        assert!((value("mbr-bme680.humidity") - 45.0).abs() < 0.01);
+        assert!((value("mbr-bme680.pressure") - 1000.0).abs() < 0.01);
+        // The simulated room's clean air, within the ADC's resolution.
+        let gas = value("mbr-bme680.gas-resistance");
+        assert!((gas - 115_385.0).abs() < 500.0, "{}", gas);
+        value("mbr-bme680.gas-resistance-2");
+        // Still burning in.
+        assert!(!sent.iter().any(|(f, _)| f == "mbr-bme680.air-quality"));
+        value("mbr.temperature");
+        value("mbr.pressure");
+        assert_eq!(Some(1000.0), env.pressure_hpa.map(f32::round));
+    }
+}
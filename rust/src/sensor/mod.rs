@@ -16,32 +16,595 @@
 
 #[cfg(all(feature = "ftdi", feature = "rpi"))]
 compile_error!("feature \"ftdi\" and feature \"rpi\" cannot be enabled at the same time");
+#[cfg(all(feature = "sim", any(feature = "ftdi", feature = "rpi")))]
+compile_error!("feature \"sim\" cannot be enabled with feature \"ftdi\" or \"rpi\"");
 
 #[cfg(feature = "ftdi")]
 extern crate ftdi;
 
+pub mod availability;
 mod bme;
+mod bme680;
+mod ndir;
+mod pms;
+mod scd30;
+mod scd4x;
+mod sensirion;
+#[cfg(not(feature = "sim"))]
+mod serial;
 mod sgp;
+mod sht;
+#[cfg(feature = "sim")]
+pub mod sim;
 mod tsl;
+mod veml;
 
 use crate::adafruit;
-use bme280::BME280;
+use crate::backoff::Backoff;
+use crate::conversion;
+use crate::counters;
+use embedded_hal::blocking::i2c;
 #[cfg(feature = "ftdi")]
 use ftdi_embedded_hal as hal;
 #[cfg(feature = "rpi")]
 use linux_embedded_hal as hal;
-use log::{debug, error, info};
-use sgp30::Sgp30;
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use std::error::Error;
+use std::fmt::{self, Debug};
+use std::path::{Path, PathBuf};
 use std::sync::{mpsc, Arc, Condvar, Mutex};
 use std::time::{Duration, Instant};
 
 const DEFAULT_ABS_HUMIDITY: f32 = 10.5;
-const SENSOR_PERIOD: Duration = Duration::from_millis(1000);
+const OFFLINE_AFTER_ERRORS: u32 = 5;
+const INITIAL_REINIT_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_REINIT_BACKOFF: Duration = Duration::from_secs(10 * 60);
 
 #[derive(Debug)]
 pub struct CallParams {
     pub shutdown: Arc<(Mutex<bool>, Condvar)>,
     pub tx: mpsc::Sender<adafruit::Metric>,
+    pub altitude: f32,
+    pub sample_period: Duration,
+    pub update_period: Duration,
+    pub sgp30_warmup: Duration,
+    pub sgp30_baseline_file: Option<PathBuf>,
+    // Which sensor's readings become mbr.temperature and mbr.humidity and
+    // drive the SGP30's humidity compensation.
+    pub climate_source: ClimateSource,
+    pub bme280: bool,
+    pub bme680: Option<Bme680Settings>,
+    // Which sensor's readings become mbr.lux and mbr.lux-db.
+    pub light_source: LightSource,
+    // Darker readings are published as this, so that mbr.lux-db is finite.
+    pub dark_floor_lux: f32,
+    pub tsl2591: bool,
+    pub veml7700: bool,
+    // Optional temperature/humidity sensors.
+    pub sht4x: Option<ShtSettings>,
+    pub sht3x: Option<ShtSettings>,
+    // Optional NDIR CO₂ sensors; not polled unless configured.
+    pub scd4x: Option<Co2Calibration>,
+    pub scd30: Option<Co2Calibration>,
+    // Which sensor's readings become mbr.pm1, mbr.pm25 and mbr.pm10.
+    pub particulate_source: ParticulateSource,
+    // Optional particulate matter sensors.
+    pub pmsa003i: bool,
+    pub pms5003_port: Option<PathBuf>,
+    #[cfg(feature = "sim")]
+    pub sim: sim::Waveforms,
+}
+
+/// A device on the I2C bus. `init` is called once, `sample` every sample
+/// period and `flush` every update period.
+pub trait Sensor {
+    /// Name used in logs and for availability, e.g. "bme280".
+    fn name(&self) -> &'static str;
+
+    fn init(&mut self) -> Result<(), Box<dyn Error>>;
+
+    /// Takes one reading and adds it to the running aggregate.
+    fn sample(&mut self, env: &mut Environment) -> Result<(), Box<dyn Error>>;
+
+    /// Sends the aggregate since the last flush, then starts a new one.
+    fn flush(&mut self, env: &mut Environment, tx: &mpsc::Sender<adafruit::Metric>);
+
+    /// Problems the sensor can see for itself, beyond failed reads.
+    fn health(&self) -> Health {
+        Health::Ok
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Health {
+    Ok,
+    // Recent reads failed, but not enough of them to give up on the device.
+    Degraded,
+    // Not initialized; the registry keeps retrying `init` with backoff.
+    Offline,
+}
+
+impl Health {
+    // Published as `mbr-<sensor>.health` on every transition.
+    fn code(self) -> i64 {
+        match self {
+            Health::Ok => 0,
+            Health::Degraded => 1,
+            Health::Offline => 2,
+        }
+    }
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Health::Ok => "healthy",
+            Health::Degraded => "degraded",
+            Health::Offline => "offline",
+        })
+    }
+}
+
+/// Readings shared between sensors, e.g. the SGP30 compensates for the
+/// humidity measured by the BME280.
+#[derive(Debug)]
+pub struct Environment {
+    pub abs_humidity: f32,
+    // Station pressure, for the CO₂ sensors' pressure compensation.
+    pub pressure_hpa: Option<f32>,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Environment {
+            abs_humidity: DEFAULT_ABS_HUMIDITY,
+            pressure_hpa: None,
+        }
+    }
+}
+
+/// Calibration of an NDIR CO₂ sensor (SCD4x or SCD30).
+#[derive(Debug, Clone, Default)]
+pub struct Co2Calibration {
+    // Turns automatic self-calibration on or off; None leaves it as it is.
+    pub automatic_self_calibration: Option<bool>,
+    // Recalibrates to this CO₂ level once the sensor has settled after init,
+    // e.g. 420 ppm when it is known to be in fresh air.
+    pub forced_recalibration_ppm: Option<u16>,
+}
+
+/// Settings for an SHT4x or SHT3x temperature/humidity sensor.
+#[derive(Debug, Clone)]
+pub struct ShtSettings {
+    pub address: u8,
+    // How often to pulse the heater while the air is humid; never if None.
+    pub heater_interval: Option<Duration>,
+}
+
+/// One step of the BME680's gas heater profile.
+#[derive(Debug, Clone, Copy)]
+pub struct HeaterStep {
+    pub temperature_c: u16,
+    pub duration_ms: u16,
+}
+
+/// Settings for a BME680 or BME688.
+#[derive(Debug, Clone)]
+pub struct Bme680Settings {
+    pub address: u8,
+    // Each sample heats the gas sensor to the next step's temperature.
+    pub heater_profile: Vec<HeaterStep>,
+}
+
+/// Where a BME680 answers unless its SDO pin is grounded (then 0x76).
+pub const BME680_DEFAULT_ADDRESS: u8 = 0x77;
+
+/// The sensors that can provide mbr.temperature and mbr.humidity.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClimateSource {
+    Bme280,
+    Bme680,
+    Sht4x,
+    Sht3x,
+}
+
+impl ClimateSource {
+    // The sensor's name, as in its feeds and availability.
+    pub fn sensor_name(self) -> &'static str {
+        match self {
+            ClimateSource::Bme280 => "bme280",
+            ClimateSource::Bme680 => "bme680",
+            ClimateSource::Sht4x => "sht4x",
+            ClimateSource::Sht3x => "sht3x",
+        }
+    }
+}
+
+/// The sensors that can provide mbr.lux and mbr.lux-db.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LightSource {
+    Tsl2591,
+    Veml7700,
+}
+
+impl LightSource {
+    // The sensor's name, as in its feeds and availability.
+    pub fn sensor_name(self) -> &'static str {
+        match self {
+            LightSource::Tsl2591 => "tsl2591",
+            LightSource::Veml7700 => "veml7700",
+        }
+    }
+}
+
+/// The sensors that can provide mbr.pm1, mbr.pm25 and mbr.pm10.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ParticulateSource {
+    Pmsa003i,
+    Pms5003,
+}
+
+impl ParticulateSource {
+    // The sensor's name, as in its feeds and availability.
+    pub fn sensor_name(self) -> &'static str {
+        match self {
+            ParticulateSource::Pmsa003i => "pmsa003i",
+            ParticulateSource::Pms5003 => "pms5003",
+        }
+    }
+}
+
+/// The sensor behind mbr.pressure: the BME680 if it is the climate source,
+/// otherwise the BME280.
+pub fn pressure_source(climate_source: ClimateSource) -> ClimateSource {
+    match climate_source {
+        ClimateSource::Bme680 => ClimateSource::Bme680,
+        _ => ClimateSource::Bme280,
+    }
+}
+
+/// Publishes the station pressure as mbr.pressure, corrected to sea level,
+/// and shares it with the CO₂ sensors.
+pub fn publish_pressure(
+    raw_pressure_hpa: f32,
+    celsius: f32,
+    altitude: f32,
+    env: &mut Environment,
+    tx: &mpsc::Sender<adafruit::Metric>,
+) {
+    env.pressure_hpa = Some(raw_pressure_hpa);
+    let sealevel_pressure = conversion::hpa_to_inhg(conversion::raw_pressure_to_sealevel(
+        raw_pressure_hpa,
+        celsius,
+        altitude,
+    ));
+    tx.send(adafruit::Metric::new("mbr.pressure", sealevel_pressure))
+        .unwrap();
+}
+
+/// Publishes the primary temperature and humidity as the mbr.* feeds, and
+/// shares the absolute humidity with the other sensors.
+pub fn publish_climate(
+    celsius: f32,
+    relative_humidity: f32,
+    env: &mut Environment,
+    tx: &mpsc::Sender<adafruit::Metric>,
+) {
+    env.abs_humidity = conversion::relative_humidity_to_absolute(relative_humidity, celsius);
+    tx.send(adafruit::Metric::new(
+        "mbr.temperature",
+        conversion::celsius_to_fahrenheit(celsius),
+    ))
+    .unwrap();
+    tx.send(adafruit::Metric::new("mbr.humidity", relative_humidity))
+        .unwrap();
+    tx.send(adafruit::Metric::new("mbr.abs-humidity", env.abs_humidity))
+        .unwrap();
+}
+
+/// Publishes the primary illuminance as mbr.lux, and in decibels as
+/// mbr.lux-db. Readings below `dark_floor_lux`, including zero, are raised
+/// to it.
+pub fn publish_light(lux: f32, dark_floor_lux: f32, tx: &mpsc::Sender<adafruit::Metric>) {
+    let lux = lux.max(dark_floor_lux);
+    tx.send(adafruit::Metric::new("mbr.lux", lux)).unwrap();
+    tx.send(adafruit::Metric::new("mbr.lux-db", 10. * lux.log10()))
+        .unwrap();
+}
+
+/// Running mean of the samples since the last flush.
+#[derive(Debug, Default)]
+pub struct Mean {
+    sum: f32,
+    count: u32,
+}
+
+impl Mean {
+    pub fn add(&mut self, value: f32) {
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// The mean so far, if there were any samples, and resets.
+    pub fn take(&mut self) -> Option<f32> {
+        let mean = (self.count > 0).then(|| self.sum / self.count as f32);
+        *self = Mean::default();
+        mean
+    }
+}
+
+struct Entry<'a> {
+    sensor: Box<dyn Sensor + 'a>,
+    // None until the first init attempt.
+    health: Option<Health>,
+    consecutive_errors: u32,
+    backoff: Backoff,
+    next_init: Instant,
+}
+
+impl<'a> Entry<'a> {
+    fn init(&mut self, now: Instant, tx: &mpsc::Sender<adafruit::Metric>) {
+        let name = self.sensor.name();
+        match self.sensor.init() {
+            Ok(()) => {
+                self.backoff.reset();
+                self.consecutive_errors = 0;
+                self.set_health(Health::Ok, tx);
+            }
+            Err(e) => {
+                counters::inc("sensor_init_failures");
+                let delay = self.backoff.next_delay(None);
+                self.next_init = now + delay;
+                if self.health == Some(Health::Offline) {
+                    debug!("{} init failed: {}; retrying in {:?}", name, e, delay);
+                } else {
+                    error!("{} init failed: {}; retrying in {:?}", name, e, delay);
+                }
+                self.set_health(Health::Offline, tx);
+            }
+        }
+    }
+
+    fn sample(&mut self, now: Instant, env: &mut Environment, tx: &mpsc::Sender<adafruit::Metric>) {
+        let health = match self.sensor.sample(env) {
+            Ok(()) => {
+                self.consecutive_errors = 0;
+                self.sensor.health()
+            }
+            Err(e) => {
+                counters::inc("sensor_read_errors");
+                self.consecutive_errors += 1;
+                debug!("{} sample failed: {}", self.sensor.name(), e);
+                if self.consecutive_errors >= OFFLINE_AFTER_ERRORS {
+                    // Re-initialize on the next sample, then back off.
+                    self.next_init = now;
+                    Health::Offline
+                } else {
+                    Health::Degraded
+                }
+            }
+        };
+        self.set_health(health, tx);
+    }
+
+    fn set_health(&mut self, health: Health, tx: &mpsc::Sender<adafruit::Metric>) {
+        if self.health == Some(health) {
+            return;
+        }
+        let name = self.sensor.name();
+        match (self.health, health) {
+            (None, Health::Ok) => info!("{} initialized", name),
+            (Some(_), Health::Ok) => info!("{} recovered", name),
+            (_, Health::Degraded) => warn!(
+                "{} degraded after {} failed reads",
+                name, self.consecutive_errors
+            ),
+            (_, Health::Offline) => warn!("{} offline", name),
+        }
+        self.health = Some(health);
+        availability::set(name, health != Health::Offline);
+        tx.send(adafruit::Metric::new(
+            format!("mbr-{}.health", name),
+            health.code(),
+        ))
+        .unwrap();
+    }
+}
+
+/// The sensors being polled. Each one's health follows its recent reads:
+/// a failed read makes it degraded, `OFFLINE_AFTER_ERRORS` in a row take it
+/// offline, and offline sensors are re-initialized with backoff until they
+/// come back.
+pub struct Registry<'a> {
+    entries: Vec<Entry<'a>>,
+    env: Environment,
+    tx: mpsc::Sender<adafruit::Metric>,
+}
+
+impl<'a> Registry<'a> {
+    pub fn new(sensors: Vec<Box<dyn Sensor + 'a>>, tx: mpsc::Sender<adafruit::Metric>) -> Self {
+        let now = Instant::now();
+        Registry {
+            entries: sensors
+                .into_iter()
+                .map(|sensor| Entry {
+                    sensor,
+                    health: None,
+                    consecutive_errors: 0,
+                    backoff: Backoff::new(INITIAL_REINIT_BACKOFF, MAX_REINIT_BACKOFF),
+                    next_init: now,
+                })
+                .collect(),
+            env: Environment::default(),
+            tx,
+        }
+    }
+
+    pub fn init(&mut self) {
+        let now = Instant::now();
+        for entry in &mut self.entries {
+            entry.init(now, &self.tx);
+        }
+    }
+
+    /// Samples every sensor in registration order, and retries `init` on
+    /// offline sensors that are due.
+    pub fn sample(&mut self) {
+        let now = Instant::now();
+        for entry in &mut self.entries {
+            if entry.health != Some(Health::Offline) {
+                entry.sample(now, &mut self.env, &self.tx);
+            } else if now >= entry.next_init {
+                entry.init(now, &self.tx);
+            }
+        }
+    }
+
+    /// Flushes every sensor, including ones that went offline since the
+    /// last flush and still hold readings.
+    pub fn flush(&mut self) {
+        for entry in &mut self.entries {
+            entry.sensor.flush(&mut self.env, &self.tx);
+        }
+    }
+
+    pub fn shutdown(&mut self) {
+        for entry in &self.entries {
+            availability::set(entry.sensor.name(), false);
+        }
+    }
+}
+
+/// Every sensor that may be attached, in sampling order. New devices are
+/// added here.
+fn sensors<'a, I2C, E>(
+    bus: &'a shared_bus::BusManagerSimple<I2C>,
+    params: &CallParams,
+) -> Vec<Box<dyn Sensor + 'a>>
+where
+    I2C: i2c::Read<Error = E> + i2c::Write<Error = E> + i2c::WriteRead<Error = E> + 'a,
+    E: Debug + 'a,
+{
+    let barometer = pressure_source(params.climate_source);
+    let mut sensors: Vec<Box<dyn Sensor + 'a>> = Vec::new();
+    if params.bme280 {
+        sensors.push(Box::new(bme::Bme280::new(
+            bus.acquire_i2c(),
+            delay(),
+            params.altitude,
+            params.climate_source == ClimateSource::Bme280,
+            barometer == ClimateSource::Bme280,
+        )));
+    }
+    if let Some(settings) = &params.bme680 {
+        sensors.push(Box::new(bme680::Bme680::new(
+            bus.acquire_i2c(),
+            delay(),
+            params.altitude,
+            settings.clone(),
+            params.climate_source == ClimateSource::Bme680,
+        )));
+    }
+    sensors.push(Box::new(sgp::Sgp30::new(
+        bus.acquire_i2c(),
+        delay(),
+        params.sgp30_warmup,
+        params.sgp30_baseline_file.clone(),
+    )));
+    if params.tsl2591 {
+        sensors.push(Box::new(tsl::Tsl2591::new(
+            bus.acquire_i2c(),
+            delay(),
+            params.light_source == LightSource::Tsl2591,
+            params.dark_floor_lux,
+        )));
+    }
+    if params.veml7700 {
+        sensors.push(Box::new(veml::Veml7700::new(
+            bus.acquire_i2c(),
+            params.light_source == LightSource::Veml7700,
+            params.dark_floor_lux,
+        )));
+    }
+    for (model, source, settings) in [
+        (sht::Model::Sht4x, ClimateSource::Sht4x, &params.sht4x),
+        (sht::Model::Sht3x, ClimateSource::Sht3x, &params.sht3x),
+    ] {
+        if let Some(settings) = settings {
+            sensors.push(Box::new(sht::Sht::new(
+                bus.acquire_i2c(),
+                delay(),
+                model,
+                settings.clone(),
+                params.climate_source == source,
+            )));
+        }
+    }
+    if let Some(calibration) = &params.scd4x {
+        sensors.push(Box::new(scd4x::Scd4x::new(
+            bus.acquire_i2c(),
+            delay(),
+            calibration.clone(),
+        )));
+    }
+    if let Some(calibration) = &params.scd30 {
+        sensors.push(Box::new(scd30::Scd30::new(
+            bus.acquire_i2c(),
+            delay(),
+            calibration.clone(),
+        )));
+    }
+    if params.pmsa003i {
+        sensors.push(Box::new(pms::Pmsa003i::new(
+            bus.acquire_i2c(),
+            params.particulate_source == ParticulateSource::Pmsa003i,
+        )));
+    }
+    if let Some(path) = &params.pms5003_port {
+        sensors.push(Box::new(pms::Pms5003::new(
+            serial_port(path, params),
+            params.particulate_source == ParticulateSource::Pms5003,
+        )));
+    }
+    sensors
+}
+
+#[cfg(feature = "ftdi")]
+fn delay() -> hal::Delay {
+    hal::Delay::default()
+}
+
+#[cfg(feature = "rpi")]
+fn delay() -> hal::Delay {
+    hal::Delay
+}
+
+#[cfg(feature = "sim")]
+fn delay() -> sim::Delay {
+    sim::Delay
+}
+
+// Opens the PMS5003's serial port; the same for the ftdi and rpi builds.
+#[cfg(not(feature = "sim"))]
+fn serial_port(
+    path: &Path,
+    _params: &CallParams,
+) -> impl FnMut() -> Result<serial::Port, Box<dyn Error>> {
+    let path = path.to_owned();
+    move || serial::Port::open(&path)
+}
+
+#[cfg(feature = "sim")]
+fn serial_port(
+    _path: &Path,
+    params: &CallParams,
+) -> impl FnMut() -> Result<sim::Serial, Box<dyn Error>> {
+    let waveforms = params.sim.clone();
+    move || Ok(sim::Serial::new(waveforms.clone()))
 }
 
 pub fn sensor_updater(params: CallParams) {
@@ -58,126 +621,35 @@ pub fn sensor_updater(params: CallParams) {
     };
 
     #[cfg(feature = "ftdi")]
-    let (i2c, delay) = (
-        ftdi_hal.i2c().expect("Unable to find FTDI I2C bus."),
-        hal::Delay::default(),
-    );
+    let i2c = ftdi_hal.i2c().expect("Unable to find FTDI I2C bus.");
 
     #[cfg(feature = "rpi")]
-    let (i2c, delay) = (
-        hal::I2cdev::new("/dev/i2c-1").expect("Unable to find RPI I2C-1 bus."),
-        hal::Delay,
-    );
-
-    let i2c = shared_bus::BusManagerSimple::new(i2c);
-    let mut bme = BME280::new_secondary(i2c.acquire_i2c(), delay);
-    let mut bme_state = bme::State {
-        sensor_is_valid: false,
-        last_abs_humidity: DEFAULT_ABS_HUMIDITY,
-        last_update: Instant::now(),
-        temperature_sum: 0.0,
-        humidity_sum: 0.0,
-        pressure_sum: 0.0,
-        count: 0,
-    };
-    match bme.init() {
-        Ok(()) => {
-            info!("BME280 initialized");
-            bme_state.sensor_is_valid = true;
-        }
-        Err(e) => error!("BME280 not found: {:?}", e),
-    };
+    let i2c = hal::I2cdev::new("/dev/i2c-1").expect("Unable to find RPI I2C-1 bus.");
 
-    #[cfg(feature = "rpi")]
-    let delay = hal::Delay;
-    let sgp30_address = 0x58;
-    let mut sgp = Sgp30::new(i2c.acquire_i2c(), sgp30_address, delay);
-    let mut sgp_state = sgp::State {
-        sensor_is_valid: false,
-        abs_humidity: DEFAULT_ABS_HUMIDITY,
-        last_update: Instant::now(),
-        co2_sum: 0.0,
-        co2_count: 0,
-        tvoc_sum: 0.0,
-        tvoc_count: 0,
-        raw_h2_sum: 0.0,
-        raw_ethanol_sum: 0.0,
-        raw_count: 0,
-    };
-    match sgp.init() {
-        Ok(()) => {
-            info!("SGP30 initialized");
-            sgp_state.sensor_is_valid = true;
-        }
-        Err(e) => error!("SGP30 not found: {:?}", e),
+    #[cfg(feature = "sim")]
+    let i2c = match &params.bme680 {
+        Some(settings) => sim::Bus::new(params.sim.clone()).with_bme680(settings.address),
+        None => sim::Bus::new(params.sim.clone()),
     };
 
-    #[cfg(feature = "rpi")]
-    let delay = hal::Delay;
-    let mut tsl_state = tsl::State {
-        sensor_is_valid: true,
-        delay,
-        integ_time: tsl2591::IntegrationTimes::_200MS,
-        gain: tsl2591::Gain::MED,
-        last_update: Instant::now(),
-        lux_sum: 0.0,
-        full_spectrum_sum: 0.0,
-        infrared_sum: 0.0,
-        count: 0,
-    };
-    let mut tsl = match tsl2591::Driver::new_define_integration(i2c.acquire_i2c(), tsl_state.integ_time, tsl_state.gain) {
-        Ok(mut t) => {
-            match t.enable() {
-                Ok(()) => {}
-                Err(e) => {
-                    tsl_state.sensor_is_valid = false;
-                    error!("TSL2591 not enabled: {:?}", e);
-                }
-            };
-            // match t.set_timing(Some(tsl_state.integ_time)) {
-            //     Ok(()) => {}
-            //     Err(e) => {
-            //         tsl_state.sensor_is_valid = false;
-            //         error!("TSL2591 timing not set: {:?}", e);
-            //     }
-            // };
-            match t.set_gain(Some(tsl_state.gain)) {
-                Ok(()) => {}
-                Err(e) => {
-                    tsl_state.sensor_is_valid = false;
-                    error!("TSL2591 gain not set: {:?}", e);
-                }
-            };
-            Some(t)
-        }
-        Err(e) => {
-            tsl_state.sensor_is_valid = false;
-            error!("TSL2591 not found: {:?}", e);
-            None
-        }
-    };
-    if tsl_state.sensor_is_valid {
-        info!("TSL2591 initialized");
-    }
+    let bus = shared_bus::BusManagerSimple::new(i2c);
+    let mut registry = Registry::new(sensors(&bus, &params), params.tx.clone());
+    registry.init();
 
+    let mut last_flush = Instant::now();
     loop {
         let last_update = Instant::now();
 
-        if bme_state.sensor_is_valid {
-            bme::poll(&mut bme, &mut bme_state, &params.tx);
-        }
-        if sgp_state.sensor_is_valid {
-            sgp_state.abs_humidity = bme_state.last_abs_humidity;
-            sgp::poll(&mut sgp, &mut sgp_state, &params.tx);
-        }
-        if tsl_state.sensor_is_valid {
-            if let Some(t) = tsl.as_mut() {
-                tsl::poll(t, &mut tsl_state, &params.tx);
-            }
+        registry.sample();
+        if last_update.duration_since(last_flush) > params.update_period {
+            registry.flush();
+            last_flush = last_update;
         }
 
         // Wait for next sensor period, or shutdown signal.
-        let wait_time = SENSOR_PERIOD - Instant::now().duration_since(last_update);
+        let wait_time = params
+            .sample_period
+            .saturating_sub(Instant::now().duration_since(last_update));
         let (lock, cvar) = &*params.shutdown;
         let shutdown = cvar
             .wait_timeout_while(lock.lock().unwrap(), wait_time, |&mut shutdown| !shutdown)
@@ -186,5 +658,254 @@ pub fn sensor_updater(params: CallParams) {
             break;
         }
     }
+    registry.shutdown();
     info!("sensor_updater finished");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Init and sample results are consumed in order; once they run out,
+    // init fails and reads NACK.
+    struct Fake {
+        name: &'static str,
+        inits: Vec<bool>,
+        samples: Vec<Result<f32, &'static str>>,
+        mean: Mean,
+    }
+
+    impl Sensor for Fake {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn init(&mut self) -> Result<(), Box<dyn Error>> {
+            if !self.inits.is_empty() && self.inits.remove(0) {
+                Ok(())
+            } else {
+                Err("no ACK".into())
+            }
+        }
+
+        fn sample(&mut self, env: &mut Environment) -> Result<(), Box<dyn Error>> {
+            if self.samples.is_empty() {
+                return Err("NACK".into());
+            }
+            let value = self.samples.remove(0)?;
+            env.abs_humidity = value;
+            self.mean.add(value);
+            Ok(())
+        }
+
+        fn flush(&mut self, _env: &mut Environment, tx: &mpsc::Sender<adafruit::Metric>) {
+            if let Some(mean) = self.mean.take() {
+                tx.send(adafruit::Metric::new(self.name, mean)).unwrap();
+            }
+        }
+    }
+
+    fn fake(
+        name: &'static str,
+        inits: &[bool],
+        samples: &[Result<f32, &'static str>],
+    ) -> Box<Fake> {
+        Box::new(Fake {
+            name,
+            inits: inits.to_vec(),
+            samples: samples.to_vec(),
+            mean: Mean::default(),
+        })
+    }
+
+    fn available(name: &str) -> Option<bool> {
+        availability::snapshot()
+            .into_iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, a)| a)
+    }
+
+    fn sent(rx: &mpsc::Receiver<adafruit::Metric>) -> Vec<(String, String)> {
+        rx.try_iter()
+            .map(|m| (m.feed, m.value.to_string()))
+            .collect()
+    }
+
+    fn metric(feed: &str, value: &str) -> (String, String) {
+        (feed.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn mean_averages_and_resets() {
+        let mut mean = Mean::default();
+        assert_eq!(mean.take(), None);
+        mean.add(1.0);
+        mean.add(2.0);
+        assert_eq!(mean.take(), Some(1.5));
+        assert_eq!(mean.take(), None);
+    }
+
+    #[test]
+    fn light_is_published_above_the_dark_floor() {
+        let (tx, rx) = mpsc::channel();
+        publish_light(0., 0.001, &tx);
+        publish_light(100., 0.001, &tx);
+        let values: Vec<_> = rx.try_iter().map(|m| (m.feed, m.value)).collect();
+        assert_eq!(
+            values,
+            [
+                ("mbr.lux".to_string(), adafruit::Value::from(0.001f32)),
+                ("mbr.lux-db".to_string(), adafruit::Value::from(-30f32)),
+                ("mbr.lux".to_string(), adafruit::Value::from(100f32)),
+                ("mbr.lux-db".to_string(), adafruit::Value::from(20f32)),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_reads_degrade_a_sensor_until_it_reads_again() {
+        let (tx, rx) = mpsc::channel();
+        let mut registry = Registry::new(
+            vec![fake(
+                "fake-flaky",
+                &[true],
+                &[Ok(1.0), Err("NACK"), Ok(3.0)],
+            )],
+            tx,
+        );
+        registry.init();
+        registry.sample();
+        assert_eq!(registry.env.abs_humidity, 1.0);
+        registry.sample();
+        assert_eq!(available("fake-flaky"), Some(true));
+        registry.sample();
+        registry.flush();
+        assert_eq!(
+            sent(&rx),
+            vec![
+                metric("mbr-fake-flaky.health", "0"),
+                metric("mbr-fake-flaky.health", "1"),
+                metric("mbr-fake-flaky.health", "0"),
+                metric("fake-flaky", "2"),
+            ]
+        );
+
+        registry.shutdown();
+        assert_eq!(available("fake-flaky"), Some(false));
+    }
+
+    #[test]
+    fn offline_sensors_are_reinitialized() {
+        let (tx, rx) = mpsc::channel();
+        let mut registry = Registry::new(
+            vec![fake("fake-cable", &[false, true, false, true], &[Ok(1.0)])],
+            tx,
+        );
+        for entry in &mut registry.entries {
+            entry.backoff = Backoff::new(Duration::ZERO, Duration::ZERO);
+        }
+
+        // Missing at startup, found on the first retry.
+        registry.init();
+        assert_eq!(available("fake-cable"), Some(false));
+        registry.sample();
+        assert_eq!(available("fake-cable"), Some(true));
+
+        // One good read, then it drops off the bus.
+        for _ in 0..OFFLINE_AFTER_ERRORS + 1 {
+            registry.sample();
+        }
+        assert_eq!(available("fake-cable"), Some(false));
+        registry.sample();
+        registry.sample();
+        assert_eq!(available("fake-cable"), Some(true));
+
+        assert_eq!(
+            sent(&rx),
+            vec![
+                metric("mbr-fake-cable.health", "2"),
+                metric("mbr-fake-cable.health", "0"),
+                metric("mbr-fake-cable.health", "1"),
+                metric("mbr-fake-cable.health", "2"),
+                metric("mbr-fake-cable.health", "0"),
+            ]
+        );
+    }
+
+    #[test]
+    fn reinit_backs_off() {
+        let (tx, _rx) = mpsc::channel();
+        let mut registry = Registry::new(vec![fake("fake-absent", &[], &[])], tx);
+        registry.init();
+        let next_init = registry.entries[0].next_init;
+        assert!(next_init > Instant::now());
+        registry.sample();
+        assert_eq!(registry.entries[0].next_init, next_init);
+    }
+
+    #[cfg(feature = "sim")]
+    #[test]
+    fn home_assistant_shows_every_simulated_feed() {
+        let (tx, rx) = mpsc::channel();
+        let params = CallParams {
+            shutdown: Arc::new((Mutex::new(false), Condvar::new())),
+            tx: tx.clone(),
+            altitude: 100.,
+            sample_period: Duration::from_secs(1),
+            update_period: Duration::from_secs(60),
+            sgp30_warmup: Duration::ZERO,
+            sgp30_baseline_file: None,
+            climate_source: ClimateSource::Sht4x,
+            bme280: true,
+            bme680: Some(Bme680Settings {
+                address: 0x76,
+                heater_profile: vec![
+                    HeaterStep {
+                        temperature_c: 320,
+                        duration_ms: 150,
+                    },
+                    HeaterStep {
+                        temperature_c: 200,
+                        duration_ms: 100,
+                    },
+                ],
+            }),
+            light_source: LightSource::Veml7700,
+            dark_floor_lux: 0.0002,
+            tsl2591: true,
+            veml7700: true,
+            sht4x: Some(ShtSettings {
+                address: 0x44,
+                heater_interval: None,
+            }),
+            sht3x: None,
+            scd4x: Some(Co2Calibration::default()),
+            scd30: Some(Co2Calibration::default()),
+            particulate_source: ParticulateSource::Pms5003,
+            pmsa003i: true,
+            pms5003_port: Some(PathBuf::from("/dev/null")),
+            sim: crate::config::SimConfig::default().waveforms(),
+        };
+        let bus =
+            shared_bus::BusManagerSimple::new(sim::Bus::new(params.sim.clone()).with_bme680(0x76));
+        let mut registry = Registry::new(sensors(&bus, &params), tx);
+        registry.init();
+        registry.sample();
+        // Long enough for a whole PMS5003 frame.
+        std::thread::sleep(Duration::from_millis(1100));
+        registry.sample();
+        registry.flush();
+
+        let feeds: std::collections::BTreeSet<String> = rx.try_iter().map(|m| m.feed).collect();
+        for feed in [
+            "mbr.pm25",
+            "mbr-bme680.gas-resistance-2",
+            "mbr-scd30.health",
+        ] {
+            assert!(feeds.contains(feed), "{} not sent", feed);
+        }
+        for feed in &feeds {
+            assert!(crate::homeassistant::entity(feed).is_some(), "{}", feed);
+        }
+    }
+}
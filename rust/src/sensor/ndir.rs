@@ -0,0 +1,213 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+//! What the SCD4x and SCD30 drivers share: when to pass on the ambient
+//! pressure, the one forced recalibration per process, and averaging the
+//! readings into the mbr-<sensor>.* feeds. The drivers only speak their
+//! sensor's commands.
+
+use super::{Co2Calibration, Environment, Mean};
+use crate::adafruit;
+use log::{debug, warn};
+use std::error::Error;
+use std::ops::RangeInclusive;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+pub struct Ndir {
+    // For logs, e.g. "SCD4x".
+    model: &'static str,
+    // The sensor's name, e.g. "scd4x".
+    name: &'static str,
+    calibration: Co2Calibration,
+    // How long to measure after init before a forced recalibration.
+    pub(super) settling: Duration,
+    // The ambient pressures the sensor accepts, in hPa.
+    pressure_range: RangeInclusive<u16>,
+    started: Instant,
+    // Set once per process, not by started(), so a re-init after a bus
+    // dropout doesn't re-zero the sensor in whatever air it is in by then.
+    frc_pending: bool,
+    pressure_sent: Option<u16>,
+    co2: Mean,
+    temperature: Mean,
+    humidity: Mean,
+}
+
+impl Ndir {
+    pub fn new(
+        model: &'static str,
+        name: &'static str,
+        calibration: Co2Calibration,
+        settling: Duration,
+        pressure_range: RangeInclusive<u16>,
+    ) -> Ndir {
+        let frc_pending = calibration.forced_recalibration_ppm.is_some();
+        Ndir {
+            model,
+            name,
+            calibration,
+            settling,
+            pressure_range,
+            started: Instant::now(),
+            frc_pending,
+            pressure_sent: None,
+            co2: Mean::default(),
+            temperature: Mean::default(),
+            humidity: Mean::default(),
+        }
+    }
+
+    pub fn automatic_self_calibration(&self) -> Option<bool> {
+        self.calibration.automatic_self_calibration
+    }
+
+    /// Notes that the sensor has (re)started measuring, without pressure
+    /// compensation.
+    pub fn started(&mut self) {
+        self.started = Instant::now();
+        self.pressure_sent = None;
+    }
+
+    /// The ambient pressure to send, if it is known, in range and not
+    /// already sent.
+    pub fn pressure_to_send(&self, env: &Environment) -> Option<u16> {
+        let hpa = env.pressure_hpa.map(|p| p.round() as u16)?;
+        (self.pressure_range.contains(&hpa) && self.pressure_sent != Some(hpa)).then_some(hpa)
+    }
+
+    pub fn pressure_sent(&mut self, hpa: u16) {
+        debug!("{} ambient pressure {} hPa", self.model, hpa);
+        self.pressure_sent = Some(hpa);
+    }
+
+    /// The level to recalibrate to, once the sensor has settled after init,
+    /// and only the first time it is asked.
+    pub fn recalibration_due(&mut self) -> Option<u16> {
+        if !self.frc_pending || self.started.elapsed() < self.settling {
+            return None;
+        }
+        self.frc_pending = false;
+        self.calibration.forced_recalibration_ppm
+    }
+
+    /// Logs the outcome of a forced recalibration to `ppm`, with the
+    /// correction applied if the sensor reports one.
+    pub fn recalibrated(&self, ppm: u16, result: Result<Option<i32>, Box<dyn Error>>) {
+        match result {
+            Ok(correction) => warn!(
+                "{} recalibrated to {} ppm{}; remove forced_recalibration_ppm before the \
+                 next restart",
+                self.model,
+                ppm,
+                correction
+                    .map(|c| format!(", correction {} ppm", c))
+                    .unwrap_or_default()
+            ),
+            Err(e) => warn!("{} not recalibrated: {}", self.model, e),
+        }
+    }
+
+    /// Adds a reading of CO₂ (ppm), temperature (°C) and relative humidity
+    /// (%).
+    pub fn add(&mut self, co2: f32, temperature: f32, humidity: f32) {
+        debug!(
+            "{}: CO₂ = {} ppm, temp = {}, humid = {}",
+            self.model, co2, temperature, humidity
+        );
+        self.co2.add(co2);
+        self.temperature.add(temperature);
+        self.humidity.add(humidity);
+    }
+
+    /// Publishes the means since the last flush, if there were readings.
+    pub fn flush(&mut self, tx: &mpsc::Sender<adafruit::Metric>) {
+        let (co2, temperature, humidity) = match (
+            self.co2.take(),
+            self.temperature.take(),
+            self.humidity.take(),
+        ) {
+            (Some(c), Some(t), Some(h)) => (c, t, h),
+            _ => return,
+        };
+        for (quantity, value) in [
+            ("co2", co2),
+            ("temperature", temperature),
+            ("humidity", humidity),
+        ] {
+            let feed = format!("mbr-{}.{}", self.name, quantity);
+            tx.send(adafruit::Metric::new(feed, value)).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pressure_is_sent_when_it_changes() {
+        let mut ndir = Ndir::new(
+            "SCD4x",
+            "scd4x",
+            Co2Calibration::default(),
+            Duration::ZERO,
+            700..=1200,
+        );
+        let at = |hpa| Environment {
+            pressure_hpa: Some(hpa),
+            ..Environment::default()
+        };
+        assert_eq!(None, ndir.pressure_to_send(&Environment::default()));
+        assert_eq!(None, ndir.pressure_to_send(&at(650.)));
+        assert_eq!(Some(1001), ndir.pressure_to_send(&at(1001.4)));
+        ndir.pressure_sent(1001);
+        assert_eq!(None, ndir.pressure_to_send(&at(1000.6)));
+        // A restart loses it.
+        ndir.started();
+        assert_eq!(Some(1001), ndir.pressure_to_send(&at(1001.4)));
+    }
+
+    #[test]
+    fn means_are_published_per_sensor() {
+        let mut ndir = Ndir::new(
+            "SCD30",
+            "scd30",
+            Co2Calibration::default(),
+            Duration::ZERO,
+            700..=1400,
+        );
+        let (tx, rx) = mpsc::channel();
+        ndir.flush(&tx);
+        assert!(rx.try_recv().is_err());
+
+        ndir.add(500., 20., 40.);
+        ndir.add(600., 22., 50.);
+        ndir.flush(&tx);
+        let sent: Vec<_> = rx
+            .try_iter()
+            .map(|m| (m.feed, m.value.to_string()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("mbr-scd30.co2".to_owned(), "550".to_owned()),
+                ("mbr-scd30.temperature".to_owned(), "21".to_owned()),
+                ("mbr-scd30.humidity".to_owned(), "45".to_owned()),
+            ],
+            sent
+        );
+    }
+}
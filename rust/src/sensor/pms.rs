@@ -0,0 +1,419 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+//! Plantower particulate matter sensors: the PMSA003I on the I2C bus and the
+//! PMS5003 on a serial port. Both send the same 32-byte frame about once a
+//! second.
+
+use super::{Environment, Mean, Sensor};
+use crate::adafruit;
+use crate::counters;
+use embedded_hal::blocking::i2c;
+use embedded_hal::serial;
+use log::debug;
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+const PMSA003I_ADDRESS: u8 = 0x12;
+
+const FRAME_LEN: usize = 32;
+const START: [u8; 2] = [0x42, 0x4D];
+// A PMS5003 that has sent nothing for this long is treated as a failed read.
+const SERIAL_TIMEOUT: Duration = Duration::from_secs(5);
+// Bytes read from the serial port per sample, so a babbling port can't stall
+// the sensor loop.
+const MAX_SERIAL_BYTES: usize = 4 * FRAME_LEN;
+
+// Particle count feeds, by minimum diameter.
+const COUNT_FEEDS: [&str; 6] = [
+    "particles-03um",
+    "particles-05um",
+    "particles-10um",
+    "particles-25um",
+    "particles-50um",
+    "particles-100um",
+];
+
+// One reading. Mass concentrations are µg/m³ under atmospheric conditions,
+// and counts are particles per 0.1 L of air.
+#[derive(Debug, PartialEq)]
+struct Frame {
+    pm1: u16,
+    pm25: u16,
+    pm10: u16,
+    counts: [u16; 6],
+}
+
+fn parse_frame(bytes: &[u8]) -> Result<Frame, String> {
+    if bytes.len() != FRAME_LEN || bytes[..2] != START {
+        return Err("no start of frame".into());
+    }
+    let word = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]);
+    if usize::from(word(2)) != FRAME_LEN - 4 {
+        return Err(format!("bad frame length {}", word(2)));
+    }
+    let sum = bytes[..30].iter().map(|&b| u16::from(b)).sum::<u16>();
+    if sum != word(30) {
+        return Err(format!(
+            "bad checksum {:#06x}, expected {:#06x}",
+            word(30),
+            sum
+        ));
+    }
+    // Words 4-8 are the same concentrations at factory (CF=1) conditions.
+    Ok(Frame {
+        pm1: word(10),
+        pm25: word(12),
+        pm10: word(14),
+        counts: [word(16), word(18), word(20), word(22), word(24), word(26)],
+    })
+}
+
+// Finds frames in a byte stream that may start mid-frame or drop bytes,
+// resyncing on the start characters after a bad frame.
+#[derive(Default)]
+struct FrameReader {
+    buf: Vec<u8>,
+}
+
+impl FrameReader {
+    fn push(&mut self, byte: u8) -> Option<Frame> {
+        self.buf.push(byte);
+        loop {
+            // Drop anything that can't be the start of a frame.
+            while !self.buf.is_empty() && !START.starts_with(&self.buf[..self.buf.len().min(2)]) {
+                self.buf.remove(0);
+            }
+            if self.buf.len() < FRAME_LEN {
+                return None;
+            }
+            match parse_frame(&self.buf) {
+                Ok(frame) => {
+                    self.buf.clear();
+                    return Some(frame);
+                }
+                Err(e) => {
+                    counters::inc("pms_bad_frames");
+                    debug!("PMS5003 frame dropped: {}", e);
+                    self.buf.remove(0);
+                }
+            }
+        }
+    }
+}
+
+// Running means of every value in the frames, published under `prefix`,
+// and the mass concentrations also as mbr.* if `primary`.
+struct Readings {
+    prefix: &'static str,
+    primary: bool,
+    pm1: Mean,
+    pm25: Mean,
+    pm10: Mean,
+    counts: [Mean; 6],
+}
+
+impl Readings {
+    fn new(prefix: &'static str, primary: bool) -> Self {
+        Readings {
+            prefix,
+            primary,
+            pm1: Mean::default(),
+            pm25: Mean::default(),
+            pm10: Mean::default(),
+            counts: Default::default(),
+        }
+    }
+
+    fn add(&mut self, frame: &Frame) {
+        debug!(
+            "{}: PM1.0 = {} PM2.5 = {} PM10 = {}",
+            self.prefix, frame.pm1, frame.pm25, frame.pm10
+        );
+        self.pm1.add(frame.pm1.into());
+        self.pm25.add(frame.pm25.into());
+        self.pm10.add(frame.pm10.into());
+        for (mean, &count) in self.counts.iter_mut().zip(&frame.counts) {
+            mean.add(count.into());
+        }
+    }
+
+    fn flush(&mut self, tx: &mpsc::Sender<adafruit::Metric>) {
+        for (quantity, mean) in [
+            ("pm1", &mut self.pm1),
+            ("pm25", &mut self.pm25),
+            ("pm10", &mut self.pm10),
+        ] {
+            if let Some(value) = mean.take() {
+                tx.send(adafruit::Metric::new(
+                    format!("{}.{}", self.prefix, quantity),
+                    value,
+                ))
+                .unwrap();
+                if self.primary {
+                    tx.send(adafruit::Metric::new(format!("mbr.{}", quantity), value))
+                        .unwrap();
+                }
+            }
+        }
+        for (feed, mean) in COUNT_FEEDS.iter().zip(&mut self.counts) {
+            if let Some(value) = mean.take() {
+                tx.send(adafruit::Metric::new(
+                    format!("{}.{}", self.prefix, feed),
+                    value,
+                ))
+                .unwrap();
+            }
+        }
+    }
+}
+
+pub struct Pmsa003i<I2C> {
+    i2c: I2C,
+    readings: Readings,
+}
+
+impl<I2C> Pmsa003i<I2C> {
+    pub fn new(i2c: I2C, primary: bool) -> Self {
+        Pmsa003i {
+            i2c,
+            readings: Readings::new("mbr-pmsa003i", primary),
+        }
+    }
+}
+
+impl<I2C, E> Pmsa003i<I2C>
+where
+    I2C: i2c::Read<Error = E>,
+    E: Debug,
+{
+    fn read(&mut self) -> Result<Frame, Box<dyn Error>> {
+        let mut bytes = [0; FRAME_LEN];
+        self.i2c
+            .read(PMSA003I_ADDRESS, &mut bytes)
+            .map_err(|e| format!("{:?}", e))?;
+        Ok(parse_frame(&bytes)?)
+    }
+}
+
+impl<I2C, E> Sensor for Pmsa003i<I2C>
+where
+    I2C: i2c::Read<Error = E>,
+    E: Debug,
+{
+    fn name(&self) -> &'static str {
+        "pmsa003i"
+    }
+
+    fn init(&mut self) -> Result<(), Box<dyn Error>> {
+        self.read()?;
+        Ok(())
+    }
+
+    fn sample(&mut self, _env: &mut Environment) -> Result<(), Box<dyn Error>> {
+        let frame = self.read()?;
+        self.readings.add(&frame);
+        Ok(())
+    }
+
+    fn flush(&mut self, _env: &mut Environment, tx: &mpsc::Sender<adafruit::Metric>) {
+        self.readings.flush(tx);
+    }
+}
+
+// `open` opens the serial port at 9600 baud, 8N1, and is called again on
+// every re-init.
+pub struct Pms5003<S, F> {
+    open: F,
+    port: Option<S>,
+    reader: FrameReader,
+    last_frame: Instant,
+    readings: Readings,
+}
+
+impl<S, F> Pms5003<S, F> {
+    pub fn new(open: F, primary: bool) -> Self {
+        Pms5003 {
+            open,
+            port: None,
+            reader: FrameReader::default(),
+            last_frame: Instant::now(),
+            readings: Readings::new("mbr-pms5003", primary),
+        }
+    }
+}
+
+impl<S, F> Sensor for Pms5003<S, F>
+where
+    S: serial::Read<u8>,
+    S::Error: Debug,
+    F: FnMut() -> Result<S, Box<dyn Error>>,
+{
+    fn name(&self) -> &'static str {
+        "pms5003"
+    }
+
+    fn init(&mut self) -> Result<(), Box<dyn Error>> {
+        self.port = Some((self.open)()?);
+        self.reader = FrameReader::default();
+        self.last_frame = Instant::now();
+        Ok(())
+    }
+
+    fn sample(&mut self, _env: &mut Environment) -> Result<(), Box<dyn Error>> {
+        let port = self.port.as_mut().ok_or("not initialized")?;
+        for _ in 0..MAX_SERIAL_BYTES {
+            let byte = match port.read() {
+                Ok(byte) => byte,
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(e)) => return Err(format!("{:?}", e).into()),
+            };
+            if let Some(frame) = self.reader.push(byte) {
+                self.readings.add(&frame);
+                self.last_frame = Instant::now();
+            }
+        }
+        if self.last_frame.elapsed() > SERIAL_TIMEOUT {
+            return Err(format!("no frame for {:?}", self.last_frame.elapsed()).into());
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, _env: &mut Environment, tx: &mpsc::Sender<adafruit::Metric>) {
+        self.readings.flush(tx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A frame with the given atmospheric PM values and counts.
+    fn frame_bytes(pm: [u16; 3], counts: [u16; 6]) -> Vec<u8> {
+        let mut words = vec![28];
+        words.extend_from_slice(&pm);
+        words.extend_from_slice(&pm);
+        words.extend_from_slice(&counts);
+        words.push(0);
+        let mut bytes = START.to_vec();
+        for w in words {
+            bytes.extend_from_slice(&w.to_be_bytes());
+        }
+        let sum = bytes.iter().map(|&b| u16::from(b)).sum::<u16>();
+        bytes.extend_from_slice(&sum.to_be_bytes());
+        bytes
+    }
+
+    #[test]
+    fn frames_are_parsed_and_checked() {
+        let bytes = frame_bytes([4, 7, 9], [1200, 350, 60, 8, 2, 1]);
+        assert_eq!(
+            Ok(Frame {
+                pm1: 4,
+                pm25: 7,
+                pm10: 9,
+                counts: [1200, 350, 60, 8, 2, 1],
+            }),
+            parse_frame(&bytes)
+        );
+
+        let mut corrupt = bytes.clone();
+        corrupt[13] ^= 0x01;
+        assert!(parse_frame(&corrupt).unwrap_err().contains("checksum"));
+        let mut short = bytes;
+        short[3] = 20;
+        assert!(parse_frame(&short).unwrap_err().contains("length"));
+    }
+
+    #[test]
+    fn reader_resyncs_after_noise_and_bad_frames() {
+        let good = frame_bytes([1, 2, 3], [0; 6]);
+        let mut bad = frame_bytes([9, 9, 9], [0; 6]);
+        bad[31] ^= 0xFF;
+
+        // Starts mid-frame, then a corrupt frame, a stray start byte, and two
+        // good frames.
+        let mut stream = good[10..].to_vec();
+        stream.extend_from_slice(&bad);
+        stream.push(0x42);
+        stream.extend_from_slice(&good);
+        stream.extend_from_slice(&good);
+
+        let mut reader = FrameReader::default();
+        let frames: Vec<Frame> = stream.into_iter().filter_map(|b| reader.push(b)).collect();
+        assert_eq!(2, frames.len());
+        assert!(frames.iter().all(|f| f.pm25 == 2));
+    }
+
+    #[cfg(feature = "sim")]
+    #[test]
+    fn simulated_sensors_are_read() {
+        use crate::sensor::sim;
+
+        let waveforms = crate::config::SimConfig::default().waveforms();
+        let (tx, rx) = mpsc::channel();
+        let mut env = Environment::default();
+
+        let mut pmsa = Pmsa003i::new(sim::Bus::new(waveforms.clone()), true);
+        pmsa.init().unwrap();
+        pmsa.sample(&mut env).unwrap();
+        pmsa.flush(&mut env, &tx);
+        assert_eq!(12, rx.try_iter().count());
+
+        // The port opens partway through a frame; the next one is whole. Not
+        // the particulate source, so only its own feeds.
+        let mut pms = Pms5003::new(|| Ok(sim::Serial::new(waveforms.clone())), false);
+        pms.init().unwrap();
+        pms.sample(&mut env).unwrap();
+        pms.flush(&mut env, &tx);
+        assert_eq!(0, rx.try_iter().count());
+        std::thread::sleep(Duration::from_millis(1100));
+        pms.sample(&mut env).unwrap();
+        pms.flush(&mut env, &tx);
+        let feeds: Vec<String> = rx.try_iter().map(|m| m.feed).collect();
+        assert_eq!(9, feeds.len());
+        assert!(feeds.iter().all(|f| f.starts_with("mbr-pms5003.")));
+    }
+
+    #[test]
+    fn means_are_published_per_sensor_and_by_the_source_as_mbr_feeds() {
+        let (tx, rx) = mpsc::channel();
+        let mut readings = Readings::new("mbr-pms5003", true);
+        for pm25 in [10, 20] {
+            readings.add(&parse_frame(&frame_bytes([5, pm25, 30], [100; 6])).unwrap());
+        }
+        readings.flush(&tx);
+        let sent: Vec<_> = rx
+            .try_iter()
+            .map(|m| (m.feed, m.value.to_string()))
+            .collect();
+        assert_eq!(12, sent.len());
+        assert!(sent.contains(&("mbr-pms5003.pm25".to_string(), "15".to_string())));
+        assert!(sent.contains(&("mbr.pm25".to_string(), "15".to_string())));
+        assert!(sent.contains(&("mbr-pms5003.particles-100um".to_string(), "100".to_string())));
+        readings.flush(&tx);
+        assert_eq!(0, rx.try_iter().count());
+
+        let mut readings = Readings::new("mbr-pmsa003i", false);
+        readings.add(&parse_frame(&frame_bytes([5, 10, 30], [100; 6])).unwrap());
+        readings.flush(&tx);
+        let sent: Vec<_> = rx.try_iter().map(|m| m.feed).collect();
+        assert_eq!(9, sent.len());
+        assert!(!sent.contains(&"mbr.pm25".to_string()));
+    }
+}
@@ -0,0 +1,178 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+//! The Sensirion SCD30 NDIR CO₂ sensor, in continuous measurement mode (a
+//! new reading every 2 s).
+
+use super::ndir::Ndir;
+use super::sensirion;
+use super::{Co2Calibration, Environment, Sensor};
+use crate::adafruit;
+use embedded_hal::blocking::{delay, i2c};
+use log::info;
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::mpsc;
+use std::time::Duration;
+
+const ADDRESS: u8 = 0x61;
+
+const TRIGGER_CONTINUOUS_MEASUREMENT: u16 = 0x0010;
+const STOP_CONTINUOUS_MEASUREMENT: u16 = 0x0104;
+const SET_MEASUREMENT_INTERVAL: u16 = 0x4600;
+const GET_DATA_READY_STATUS: u16 = 0x0202;
+const READ_MEASUREMENT: u16 = 0x0300;
+const SET_AUTOMATIC_SELF_CALIBRATION: u16 = 0x5306;
+const SET_FORCED_RECALIBRATION: u16 = 0x5204;
+const READ_FIRMWARE_VERSION: u16 = 0xD100;
+
+const MEASUREMENT_INTERVAL_SECS: u16 = 2;
+// The datasheet asks for at least 2 minutes of continuous measurement at
+// the target concentration before a forced recalibration.
+const FRC_SETTLING: Duration = Duration::from_secs(2 * 60);
+// Ambient pressure compensation accepts 700-1400 mbar; 0 turns it off.
+const PRESSURE_RANGE_HPA: std::ops::RangeInclusive<u16> = 700..=1400;
+
+pub struct Scd30<I2C, D> {
+    i2c: I2C,
+    delay: D,
+    ndir: Ndir,
+}
+
+impl<I2C, D, E> Scd30<I2C, D>
+where
+    I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+    D: delay::DelayMs<u16>,
+    E: Debug,
+{
+    pub fn new(i2c: I2C, delay: D, calibration: Co2Calibration) -> Self {
+        Scd30 {
+            i2c,
+            delay,
+            ndir: Ndir::new(
+                "SCD30",
+                "scd30",
+                calibration,
+                FRC_SETTLING,
+                PRESSURE_RANGE_HPA,
+            ),
+        }
+    }
+
+    fn command(&mut self, command: u16, args: &[u16]) -> Result<(), Box<dyn Error>> {
+        sensirion::write(&mut self.i2c, ADDRESS, command, args).map_err(|e| format!("{:?}", e))?;
+        Ok(())
+    }
+
+    // The SCD30 needs 3 ms between a command and reading its response.
+    fn read(&mut self, command: u16, words: &mut [u16]) -> Result<(), Box<dyn Error>> {
+        self.command(command, &[])?;
+        self.delay.delay_ms(3);
+        sensirion::read(&mut self.i2c, ADDRESS, words).map_err(|e| format!("{:?}", e))?;
+        Ok(())
+    }
+
+    // Starts measuring, with pressure compensation if the pressure is known.
+    fn start(&mut self, pressure_hpa: Option<u16>) -> Result<(), Box<dyn Error>> {
+        self.command(
+            TRIGGER_CONTINUOUS_MEASUREMENT,
+            &[pressure_hpa.unwrap_or_default()],
+        )
+    }
+}
+
+impl<I2C, D, E> Sensor for Scd30<I2C, D>
+where
+    I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+    D: delay::DelayMs<u16>,
+    E: Debug,
+{
+    fn name(&self) -> &'static str {
+        "scd30"
+    }
+
+    fn init(&mut self) -> Result<(), Box<dyn Error>> {
+        self.command(STOP_CONTINUOUS_MEASUREMENT, &[])?;
+        let mut version = [0];
+        self.read(READ_FIRMWARE_VERSION, &mut version)?;
+        info!("SCD30 firmware {}.{}", version[0] >> 8, version[0] & 0xFF);
+        self.command(SET_MEASUREMENT_INTERVAL, &[MEASUREMENT_INTERVAL_SECS])?;
+        if let Some(asc) = self.ndir.automatic_self_calibration() {
+            self.command(SET_AUTOMATIC_SELF_CALIBRATION, &[asc.into()])?;
+            info!(
+                "SCD30 automatic self-calibration {}",
+                if asc { "on" } else { "off" }
+            );
+        }
+        self.start(None)?;
+        self.ndir.started();
+        Ok(())
+    }
+
+    fn sample(&mut self, env: &mut Environment) -> Result<(), Box<dyn Error>> {
+        if let Some(hpa) = self.ndir.pressure_to_send(env) {
+            self.start(Some(hpa))?;
+            self.ndir.pressure_sent(hpa);
+        }
+
+        if let Some(ppm) = self.ndir.recalibration_due() {
+            // The SCD30 does not report the correction it applied.
+            let result = self
+                .command(SET_FORCED_RECALIBRATION, &[ppm])
+                .map(|()| None);
+            self.ndir.recalibrated(ppm, result);
+        }
+
+        let mut ready = [0];
+        self.read(GET_DATA_READY_STATUS, &mut ready)?;
+        if ready[0] != 1 {
+            // No new reading since the last one.
+            return Ok(());
+        }
+        let mut words = [0; 6];
+        self.read(READ_MEASUREMENT, &mut words)?;
+        let (co2, temperature, humidity) = decode(&words);
+        self.ndir.add(co2, temperature, humidity);
+        Ok(())
+    }
+
+    fn flush(&mut self, _env: &mut Environment, tx: &mpsc::Sender<adafruit::Metric>) {
+        self.ndir.flush(tx);
+    }
+}
+
+// CO₂ (ppm), temperature (°C) and relative humidity (%), as big-endian
+// floats each split across two words.
+fn decode(words: &[u16; 6]) -> (f32, f32, f32) {
+    let float = |i: usize| f32::from_bits(u32::from(words[i]) << 16 | u32::from(words[i + 1]));
+    (float(0), float(2), float(4))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measurements_are_decoded() {
+        // 439.09 ppm, 27.24 °C and 48.81 %RH, the example from the interface
+        // description.
+        let (co2, temperature, humidity) =
+            decode(&[0x43DB, 0x8C2E, 0x41D9, 0xE7FF, 0x4243, 0x3A1B]);
+        assert!((co2 - 439.09).abs() < 0.01, "{}", co2);
+        assert!((temperature - 27.24).abs() < 0.01, "{}", temperature);
+        assert!((humidity - 48.81).abs() < 0.01, "{}", humidity);
+    }
+}
@@ -0,0 +1,278 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+//! Sensirion SCD40/SCD41 NDIR CO₂ sensors, in periodic measurement mode (a
+//! new reading every 5 s).
+
+use super::ndir::Ndir;
+use super::sensirion;
+use super::{Co2Calibration, Environment, Sensor};
+use crate::adafruit;
+use embedded_hal::blocking::{delay, i2c};
+use log::info;
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::mpsc;
+use std::time::Duration;
+
+const ADDRESS: u8 = 0x62;
+
+const START_PERIODIC_MEASUREMENT: u16 = 0x21B1;
+const READ_MEASUREMENT: u16 = 0xEC05;
+const STOP_PERIODIC_MEASUREMENT: u16 = 0x3F86;
+const SET_AMBIENT_PRESSURE: u16 = 0xE000;
+const PERFORM_FORCED_RECALIBRATION: u16 = 0x362F;
+const SET_AUTOMATIC_SELF_CALIBRATION: u16 = 0x2416;
+const GET_DATA_READY_STATUS: u16 = 0xE4B8;
+const GET_SERIAL_NUMBER: u16 = 0x3682;
+
+// The datasheet asks for at least 3 minutes of measurements at the target
+// concentration before a forced recalibration.
+const FRC_SETTLING: Duration = Duration::from_secs(3 * 60);
+// Ambient pressure compensation accepts 700-1200 hPa.
+const PRESSURE_RANGE_HPA: std::ops::RangeInclusive<u16> = 700..=1200;
+
+pub struct Scd4x<I2C, D> {
+    i2c: I2C,
+    delay: D,
+    ndir: Ndir,
+}
+
+impl<I2C, D, E> Scd4x<I2C, D>
+where
+    I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+    D: delay::DelayMs<u16>,
+    E: Debug,
+{
+    pub fn new(i2c: I2C, delay: D, calibration: Co2Calibration) -> Self {
+        Scd4x {
+            i2c,
+            delay,
+            ndir: Ndir::new(
+                "SCD4x",
+                "scd4x",
+                calibration,
+                FRC_SETTLING,
+                PRESSURE_RANGE_HPA,
+            ),
+        }
+    }
+
+    // Sends a command and waits its execution time.
+    fn command(&mut self, command: u16, args: &[u16], wait_ms: u16) -> Result<(), Box<dyn Error>> {
+        sensirion::write(&mut self.i2c, ADDRESS, command, args).map_err(|e| format!("{:?}", e))?;
+        self.delay.delay_ms(wait_ms);
+        Ok(())
+    }
+
+    fn read(&mut self, command: u16, words: &mut [u16]) -> Result<(), Box<dyn Error>> {
+        self.command(command, &[], 1)?;
+        sensirion::read(&mut self.i2c, ADDRESS, words).map_err(|e| format!("{:?}", e))?;
+        Ok(())
+    }
+
+    // Most settings can only be changed while the sensor is idle.
+    fn stop(&mut self) -> Result<(), Box<dyn Error>> {
+        self.command(STOP_PERIODIC_MEASUREMENT, &[], 500)
+    }
+
+    fn start(&mut self) -> Result<(), Box<dyn Error>> {
+        self.command(START_PERIODIC_MEASUREMENT, &[], 1)
+    }
+
+    // Returns the correction applied, in ppm.
+    fn forced_recalibration(&mut self, ppm: u16) -> Result<i32, Box<dyn Error>> {
+        self.stop()?;
+        self.command(PERFORM_FORCED_RECALIBRATION, &[ppm], 400)?;
+        let mut correction = [0];
+        sensirion::read(&mut self.i2c, ADDRESS, &mut correction).map_err(|e| format!("{:?}", e))?;
+        self.start()?;
+        if correction[0] == 0xFFFF {
+            return Err("forced recalibration failed".into());
+        }
+        Ok(i32::from(correction[0]) - 0x8000)
+    }
+}
+
+impl<I2C, D, E> Sensor for Scd4x<I2C, D>
+where
+    I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+    D: delay::DelayMs<u16>,
+    E: Debug,
+{
+    fn name(&self) -> &'static str {
+        "scd4x"
+    }
+
+    fn init(&mut self) -> Result<(), Box<dyn Error>> {
+        // It may still be measuring from before a restart.
+        self.stop()?;
+        let mut serial = [0; 3];
+        self.read(GET_SERIAL_NUMBER, &mut serial)?;
+        info!(
+            "SCD4x serial {:04x}{:04x}{:04x}",
+            serial[0], serial[1], serial[2]
+        );
+        if let Some(asc) = self.ndir.automatic_self_calibration() {
+            self.command(SET_AUTOMATIC_SELF_CALIBRATION, &[asc.into()], 1)?;
+            info!(
+                "SCD4x automatic self-calibration {}",
+                if asc { "on" } else { "off" }
+            );
+        }
+        self.start()?;
+        self.ndir.started();
+        Ok(())
+    }
+
+    fn sample(&mut self, env: &mut Environment) -> Result<(), Box<dyn Error>> {
+        if let Some(hpa) = self.ndir.pressure_to_send(env) {
+            self.command(SET_AMBIENT_PRESSURE, &[hpa], 1)?;
+            self.ndir.pressure_sent(hpa);
+        }
+
+        if let Some(ppm) = self.ndir.recalibration_due() {
+            let result = self.forced_recalibration(ppm).map(Some);
+            self.ndir.recalibrated(ppm, result);
+            return Ok(());
+        }
+
+        let mut status = [0];
+        self.read(GET_DATA_READY_STATUS, &mut status)?;
+        if status[0] & 0x07FF == 0 {
+            // No new reading since the last one.
+            return Ok(());
+        }
+        let mut words = [0; 3];
+        self.read(READ_MEASUREMENT, &mut words)?;
+        let (co2, temperature, humidity) = decode(&words);
+        self.ndir.add(co2, temperature, humidity);
+        Ok(())
+    }
+
+    fn flush(&mut self, _env: &mut Environment, tx: &mpsc::Sender<adafruit::Metric>) {
+        self.ndir.flush(tx);
+    }
+}
+
+// CO₂ (ppm), temperature (°C) and relative humidity (%).
+fn decode(words: &[u16; 3]) -> (f32, f32, f32) {
+    (
+        f32::from(words[0]),
+        -45. + 175. * f32::from(words[1]) / 65535.,
+        100. * f32::from(words[2]) / 65535.,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measurements_are_decoded() {
+        // The example from the datasheet.
+        let (co2, temperature, humidity) = decode(&[0x01F4, 0x6667, 0x5EB9]);
+        assert_eq!(500., co2);
+        assert!((temperature - 25.).abs() < 0.01, "{}", temperature);
+        assert!((humidity - 37.).abs() < 0.01, "{}", humidity);
+    }
+
+    // Passes everything through to the simulated bus, noting the commands
+    // sent to the SCD4x.
+    #[cfg(feature = "sim")]
+    struct Recorder {
+        bus: crate::sensor::sim::Bus,
+        commands: std::rc::Rc<std::cell::RefCell<Vec<u16>>>,
+    }
+
+    #[cfg(feature = "sim")]
+    impl i2c::Write for Recorder {
+        type Error = crate::sensor::sim::Error;
+
+        fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+            if address == ADDRESS && bytes.len() >= 2 {
+                let command = u16::from_be_bytes([bytes[0], bytes[1]]);
+                self.commands.borrow_mut().push(command);
+            }
+            self.bus.write(address, bytes)
+        }
+    }
+
+    #[cfg(feature = "sim")]
+    impl i2c::Read for Recorder {
+        type Error = crate::sensor::sim::Error;
+
+        fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+            self.bus.read(address, buffer)
+        }
+    }
+
+    #[cfg(feature = "sim")]
+    #[test]
+    fn recalibration_is_forced_once_per_process() {
+        use crate::sensor::{sim, Registry};
+
+        let config = crate::config::SimConfig {
+            co2_spike_ppm: 0.,
+            ..Default::default()
+        };
+        let commands = std::rc::Rc::default();
+        let bus = Recorder {
+            bus: sim::Bus::new(config.waveforms()),
+            commands: std::rc::Rc::clone(&commands),
+        };
+        let calibration = Co2Calibration {
+            automatic_self_calibration: Some(false),
+            forced_recalibration_ppm: Some(420),
+        };
+        let mut scd = Scd4x::new(bus, sim::Delay, calibration);
+        scd.ndir.settling = Duration::ZERO;
+        let (tx, _rx) = mpsc::channel();
+        let mut registry = Registry::new(vec![Box::new(scd)], tx);
+
+        // However often the registry re-inits it, as after a bus dropout.
+        for _ in 0..2 {
+            registry.init();
+            registry.sample();
+            registry.sample();
+        }
+        let sent = commands.borrow();
+        let count = |command| sent.iter().filter(|&&c| c == command).count();
+        assert_eq!(2, count(SET_AUTOMATIC_SELF_CALIBRATION));
+        assert_eq!(1, count(PERFORM_FORCED_RECALIBRATION));
+    }
+
+    #[cfg(feature = "sim")]
+    #[test]
+    fn simulated_sensor_reports_the_correction() {
+        use crate::sensor::sim;
+
+        let config = crate::config::SimConfig {
+            co2_spike_ppm: 0.,
+            ..Default::default()
+        };
+        let mut scd = Scd4x::new(
+            sim::Bus::new(config.waveforms()),
+            sim::Delay,
+            Co2Calibration::default(),
+        );
+        scd.init().unwrap();
+
+        // The simulated sensor reads 30 ppm high in a 450 ppm room.
+        assert_eq!(-60, scd.forced_recalibration(420).unwrap());
+        assert_eq!(0, scd.forced_recalibration(420).unwrap());
+    }
+}
@@ -0,0 +1,83 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+//! Framing shared by Sensirion's I2C sensors: 16-bit commands, and 16-bit
+//! words each followed by a CRC.
+
+use embedded_hal::blocking::i2c;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    I2c(E),
+    // A word read back did not match its CRC.
+    Crc,
+}
+
+// Sensirion's CRC-8: polynomial 0x31, initial value 0xFF.
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0xFFu8;
+    for &b in data {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// Sends a command and its arguments, each with its CRC.
+pub fn write<I2C, E>(i2c: &mut I2C, address: u8, command: u16, args: &[u16]) -> Result<(), Error<E>>
+where
+    I2C: i2c::Write<Error = E>,
+{
+    let mut bytes = command.to_be_bytes().to_vec();
+    for arg in args {
+        let word = arg.to_be_bytes();
+        bytes.extend_from_slice(&word);
+        bytes.push(crc8(&word));
+    }
+    i2c.write(address, &bytes).map_err(Error::I2c)
+}
+
+// Reads the response to the last command, checking every word's CRC.
+pub fn read<I2C, E>(i2c: &mut I2C, address: u8, words: &mut [u16]) -> Result<(), Error<E>>
+where
+    I2C: i2c::Read<Error = E>,
+{
+    let mut bytes = vec![0; words.len() * 3];
+    i2c.read(address, &mut bytes).map_err(Error::I2c)?;
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks(3)) {
+        if crc8(&chunk[..2]) != chunk[2] {
+            return Err(Error::Crc);
+        }
+        *word = u16::from_be_bytes([chunk[0], chunk[1]]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc_matches_the_datasheet() {
+        assert_eq!(0x92, crc8(&[0xBE, 0xEF]));
+    }
+}
@@ -0,0 +1,64 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+//! A serial port opened through the OS rather than the HAL, so that the
+//! ftdi build, whose HAL only has the FTDI chip's I2C, can read a PMS5003 on
+//! the host's own port just as the rpi build does.
+
+use embedded_hal::serial;
+use serial_core::SerialPort;
+use std::error::Error;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::Duration;
+
+pub struct Port(serial_unix::TTYPort);
+
+impl Port {
+    /// Opens `path` at 9600 baud 8N1, as the Plantower sensors use.
+    pub fn open(path: &Path) -> Result<Port, Box<dyn Error>> {
+        let mut port = serial_unix::TTYPort::open(path)?;
+        port.reconfigure(&|settings| {
+            settings.set_baud_rate(serial_core::Baud9600)?;
+            settings.set_char_size(serial_core::Bits8);
+            settings.set_parity(serial_core::ParityNone);
+            settings.set_stop_bits(serial_core::Stop1);
+            settings.set_flow_control(serial_core::FlowNone);
+            Ok(())
+        })?;
+        // Reads return WouldBlock once nothing arrives for this long.
+        port.set_timeout(Duration::from_millis(10))?;
+        Ok(Port(port))
+    }
+}
+
+impl serial::Read<u8> for Port {
+    type Error = io::Error;
+
+    fn read(&mut self) -> nb::Result<u8, io::Error> {
+        let mut byte = [0];
+        match self.0.read(&mut byte) {
+            Ok(1) => Ok(byte[0]),
+            Ok(_) => Err(nb::Error::WouldBlock),
+            Err(e) => match e.kind() {
+                io::ErrorKind::WouldBlock
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::Interrupted => Err(nb::Error::WouldBlock),
+                _ => Err(nb::Error::Other(e)),
+            },
+        }
+    }
+}
@@ -14,101 +14,275 @@
 
 #![warn(clippy::all)]
 
+use super::{Environment, Mean, Sensor};
 use crate::adafruit;
+use chrono::{DateTime, Utc};
 use embedded_hal::blocking::{delay, i2c};
-use log::debug;
-use sgp30::Sgp30;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sgp30::Baseline;
+use std::error::Error;
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use std::time::{Duration, Instant};
 
-const UPDATE_PERIOD: Duration = Duration::from_secs(60);
-
-pub struct State {
-    pub sensor_is_valid: bool,
-    pub abs_humidity: f32,
-    pub last_update: Instant,
-    pub co2_sum: f32,
-    pub co2_count: i32,
-    pub tvoc_sum: f32,
-    pub tvoc_count: i32,
-    pub raw_h2_sum: f32,
-    pub raw_ethanol_sum: f32,
-    pub raw_count: i32,
+const ADDRESS: u8 = 0x58;
+// From the datasheet: a new sensor needs 12 hours to establish a baseline
+// worth saving, it should be saved hourly, and a saved baseline older than
+// 7 days must not be restored.
+const NEW_BASELINE_DELAY: Duration = Duration::from_secs(12 * 60 * 60);
+const BASELINE_SAVE_PERIOD: Duration = Duration::from_secs(60 * 60);
+const BASELINE_MAX_AGE: chrono::Duration = chrono::Duration::days(7);
+
+// The baseline as saved to disk.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct SavedBaseline {
+    co2eq: u16,
+    tvoc: u16,
+    saved_at: DateTime<Utc>,
+}
+
+pub struct Sgp30<I2C, D> {
+    sgp: sgp30::Sgp30<I2C, D>,
+    warmup: Duration,
+    // Until then CO₂eq and TVOC are read but not published.
+    warm_until: Option<Instant>,
+    baseline_file: Option<PathBuf>,
+    // When the baseline next needs saving; not before it is established.
+    next_baseline_save: Instant,
+    co2: Mean,
+    tvoc: Mean,
+    raw_h2: Mean,
+    raw_ethanol: Mean,
 }
 
-pub fn poll<I2C, D, E>(
-    sgp: &mut Sgp30<I2C, D>,
-    state: &mut State,
-    tx: &mpsc::Sender<adafruit::Metric>,
-) where
+impl<I2C, D, E> Sgp30<I2C, D>
+where
     I2C: i2c::Read<Error = E> + i2c::Write<Error = E> + i2c::WriteRead<Error = E>,
     D: delay::DelayUs<u16> + delay::DelayMs<u16>,
 {
-    if let Ok(humidity) = sgp30::Humidity::from_f32(state.abs_humidity) {
-        let _ = sgp.set_humidity(Some(&humidity));
-    }
-    let measurements = sgp.measure().unwrap_or(sgp30::Measurement {
-        co2eq_ppm: 0,
-        tvoc_ppb: 0,
-    });
-    let raw = sgp
-        .measure_raw_signals()
-        .unwrap_or(sgp30::RawSignals { h2: 0, ethanol: 0 });
-
-    if measurements.co2eq_ppm != 400 {
-        debug!("SGP: CO₂eq = {}", measurements.co2eq_ppm);
-        state.co2_sum += measurements.co2eq_ppm as f32;
-        state.co2_count += 1;
+    pub fn new(i2c: I2C, delay: D, warmup: Duration, baseline_file: Option<PathBuf>) -> Self {
+        Sgp30 {
+            sgp: sgp30::Sgp30::new(i2c, ADDRESS, delay),
+            warmup,
+            warm_until: None,
+            baseline_file,
+            next_baseline_save: Instant::now() + NEW_BASELINE_DELAY,
+            co2: Mean::default(),
+            tvoc: Mean::default(),
+            raw_h2: Mean::default(),
+            raw_ethanol: Mean::default(),
+        }
     }
-    if measurements.tvoc_ppb != 0 {
-        debug!("TVOC = {} ppb", measurements.tvoc_ppb);
-        state.tvoc_sum += measurements.tvoc_ppb as f32;
-        state.tvoc_count += 1;
+}
+
+impl<I2C, D, E> Sensor for Sgp30<I2C, D>
+where
+    I2C: i2c::Read<Error = E> + i2c::Write<Error = E> + i2c::WriteRead<Error = E>,
+    D: delay::DelayUs<u16> + delay::DelayMs<u16>,
+    E: Debug,
+{
+    fn name(&self) -> &'static str {
+        "sgp30"
     }
-    if raw.h2 > 0 || raw.ethanol > 0 {
-        state.raw_h2_sum += raw.h2 as f32;
-        state.raw_ethanol_sum += raw.ethanol as f32;
-        state.raw_count += 1;
+
+    fn init(&mut self) -> Result<(), Box<dyn Error>> {
+        let serial = self.sgp.serial().map_err(|e| format!("{:?}", e))?;
+        let features = self.sgp.get_feature_set().map_err(|e| format!("{:?}", e))?;
+        if features.product_type != sgp30::ProductType::Sgp30 {
+            return Err(format!("unexpected product type {:?}", features.product_type).into());
+        }
+        // The self-test resets the baseline, so it has to come before init.
+        if !self.sgp.selftest().map_err(|e| format!("{:?}", e))? {
+            return Err("self-test failed".into());
+        }
+        info!(
+            "SGP30 serial {}, feature set version {:#04x}, self-test passed",
+            serial
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>(),
+            features.product_version
+        );
+
+        // Not init(), which does nothing the second time, e.g. after the
+        // sensor dropped off the bus and lost power.
+        self.sgp.force_init().map_err(|e| format!("{:?}", e))?;
+        self.warm_until = Some(Instant::now() + self.warmup);
+        self.next_baseline_save = Instant::now() + NEW_BASELINE_DELAY;
+
+        let path = match &self.baseline_file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        match load_baseline(path, Utc::now()) {
+            Ok(Some(baseline)) => {
+                self.sgp
+                    .set_baseline(&baseline)
+                    .map_err(|e| format!("baseline not restored: {:?}", e))?;
+                info!("SGP30 baseline restored from {}", path.display());
+                self.next_baseline_save = Instant::now() + BASELINE_SAVE_PERIOD;
+            }
+            Ok(None) => info!(
+                "SGP30 has no recent baseline in {}; saving one in {:?}",
+                path.display(),
+                NEW_BASELINE_DELAY
+            ),
+            Err(e) => warn!("SGP30 baseline in {} ignored: {}", path.display(), e),
+        }
+        Ok(())
     }
 
-    let now = Instant::now();
-    if now.duration_since(state.last_update) > UPDATE_PERIOD {
-        if state.co2_count > 0 {
-            tx.send(adafruit::Metric {
-                feed: "mbr-sgp30.co2".into(),
-                value: state.co2_sum / state.co2_count as f32,
-            })
-            .unwrap();
+    fn sample(&mut self, env: &mut Environment) -> Result<(), Box<dyn Error>> {
+        if let Ok(humidity) = sgp30::Humidity::from_f32(env.abs_humidity) {
+            let _ = self.sgp.set_humidity(Some(&humidity));
         }
+        let measurements = self.sgp.measure().map_err(|e| format!("{:?}", e))?;
+        let raw = self
+            .sgp
+            .measure_raw_signals()
+            .map_err(|e| format!("{:?}", e))?;
 
-        if state.tvoc_count > 0 {
-            tx.send(adafruit::Metric {
-                feed: "mbr-sgp30.tvoc".into(),
-                value: state.tvoc_sum / state.tvoc_count as f32,
-            })
-            .unwrap();
+        debug!(
+            "SGP: CO₂eq = {} ppm, TVOC = {} ppb",
+            measurements.co2eq_ppm, measurements.tvoc_ppb
+        );
+        // The raw signals don't depend on the baseline, so they are
+        // published during warm-up too.
+        self.raw_h2.add(raw.h2 as f32);
+        self.raw_ethanol.add(raw.ethanol as f32);
+        if self.warm_until.is_some_and(|t| Instant::now() >= t) {
+            info!("SGP30 warmed up");
+            self.warm_until = None;
+        }
+        if self.warm_until.is_none() {
+            self.co2.add(measurements.co2eq_ppm as f32);
+            self.tvoc.add(measurements.tvoc_ppb as f32);
         }
 
-        if state.raw_count > 0 {
-            tx.send(adafruit::Metric {
-                feed: "mbr-sgp30.raw-h2".into(),
-                value: state.raw_h2_sum / state.raw_count as f32,
-            })
-            .unwrap();
-            tx.send(adafruit::Metric {
-                feed: "mbr-sgp30.raw-ethanol".into(),
-                value: state.raw_ethanol_sum / state.raw_count as f32,
-            })
-            .unwrap();
+        if let Some(path) = &self.baseline_file {
+            let now = Instant::now();
+            if now >= self.next_baseline_save {
+                self.next_baseline_save = now + BASELINE_SAVE_PERIOD;
+                let baseline = self.sgp.get_baseline().map_err(|e| format!("{:?}", e))?;
+                match save_baseline(path, &baseline, Utc::now()) {
+                    Ok(()) => debug!("SGP30 baseline saved: {:?}", baseline),
+                    Err(e) => warn!("SGP30 baseline not saved to {}: {}", path.display(), e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, _env: &mut Environment, tx: &mpsc::Sender<adafruit::Metric>) {
+        if let Some(co2) = self.co2.take() {
+            tx.send(adafruit::Metric::new("mbr-sgp30.co2", co2))
+                .unwrap();
         }
+        if let Some(tvoc) = self.tvoc.take() {
+            tx.send(adafruit::Metric::new("mbr-sgp30.tvoc", tvoc))
+                .unwrap();
+        }
+        if let (Some(h2), Some(ethanol)) = (self.raw_h2.take(), self.raw_ethanol.take()) {
+            tx.send(adafruit::Metric::new("mbr-sgp30.raw-h2", h2))
+                .unwrap();
+            tx.send(adafruit::Metric::new("mbr-sgp30.raw-ethanol", ethanol))
+                .unwrap();
+        }
+    }
+}
+
+// The saved baseline, if there is one young enough to restore.
+fn load_baseline(path: &Path, now: DateTime<Utc>) -> Result<Option<Baseline>, Box<dyn Error>> {
+    let saved: SavedBaseline = match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let age = now - saved.saved_at;
+    if age < chrono::Duration::zero() || age >= BASELINE_MAX_AGE {
+        return Ok(None);
+    }
+    Ok(Some(Baseline {
+        co2eq: saved.co2eq,
+        tvoc: saved.tvoc,
+    }))
+}
+
+// Writes to a temporary file first so a crash never leaves half a baseline.
+fn save_baseline(path: &Path, baseline: &Baseline, now: DateTime<Utc>) -> io::Result<()> {
+    let saved = SavedBaseline {
+        co2eq: baseline.co2eq,
+        tvoc: baseline.tvoc,
+        saved_at: now,
+    };
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(&saved)?)?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::env;
+
+    fn temp_file(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!(
+            "iot-central-sgp-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn baselines_round_trip_until_they_expire() {
+        let path = temp_file("round-trip");
+        let saved_at = Utc.with_ymd_and_hms(2022, 10, 18, 6, 30, 0).unwrap();
+        let baseline = Baseline {
+            co2eq: 0x8a3c,
+            tvoc: 0x8e21,
+        };
+        assert_eq!(load_baseline(&path, saved_at).unwrap(), None);
+
+        save_baseline(&path, &baseline, saved_at).unwrap();
+        let day = chrono::Duration::days(1);
+        assert_eq!(
+            load_baseline(&path, saved_at + day * 6).unwrap(),
+            Some(baseline)
+        );
+        assert_eq!(load_baseline(&path, saved_at + day * 7).unwrap(), None);
+        assert_eq!(load_baseline(&path, saved_at - day).unwrap(), None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "sim")]
+    #[test]
+    fn co2_and_tvoc_wait_for_warm_up() {
+        use crate::sensor::sim;
+
+        let waveforms = crate::config::SimConfig::default().waveforms();
+        let (tx, rx) = mpsc::channel();
+        let mut env = Environment::default();
+        for (warmup, feeds) in [(Duration::from_secs(3600), 2), (Duration::ZERO, 4)] {
+            let mut sgp = Sgp30::new(sim::Bus::new(waveforms.clone()), sim::Delay, warmup, None);
+            sgp.init().unwrap();
+            sgp.sample(&mut env).unwrap();
+            sgp.flush(&mut env, &tx);
+            assert_eq!(feeds, rx.try_iter().count());
+        }
+    }
 
-        state.co2_sum = 0.0;
-        state.co2_count = 0;
-        state.tvoc_sum = 0.0;
-        state.tvoc_count = 0;
-        state.last_update = now;
-        state.raw_h2_sum = 0.0;
-        state.raw_ethanol_sum = 0.0;
-        state.raw_count = 0;
+    #[test]
+    fn corrupt_baselines_are_errors() {
+        let path = temp_file("corrupt");
+        fs::write(&path, "{\"co2eq\": 1").unwrap();
+        assert!(load_baseline(&path, Utc::now()).is_err());
+        fs::remove_file(&path).unwrap();
     }
 }
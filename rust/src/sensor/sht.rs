@@ -0,0 +1,330 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+//! Sensirion SHT4x (SHT40/41/45) and SHT3x (SHT30/31/35) temperature and
+//! humidity sensors, measured single-shot at high repeatability. Both can
+//! pulse an on-chip heater to drive off condensation, which otherwise makes
+//! humidity readings creep upwards in damp air.
+
+use super::sensirion;
+use super::{publish_climate, Environment, Mean, Sensor, ShtSettings};
+use crate::adafruit;
+use crate::counters;
+use embedded_hal::blocking::{delay, i2c};
+use log::{debug, info};
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+// SHT4x commands are a single byte.
+const SHT4X_MEASURE_HIGH_PRECISION: u8 = 0xFD;
+const SHT4X_READ_SERIAL: u8 = 0x89;
+const SHT4X_SOFT_RESET: u8 = 0x94;
+// 200 mW for 1 s, then a measurement (which is discarded; it is hot).
+const SHT4X_HEATER_200MW_1S: u8 = 0x39;
+
+const SHT3X_MEASURE_HIGH_REPEATABILITY: u16 = 0x2400;
+const SHT3X_SOFT_RESET: u16 = 0x30A2;
+const SHT3X_HEATER_ENABLE: u16 = 0x306D;
+const SHT3X_HEATER_DISABLE: u16 = 0x3066;
+const SHT3X_READ_STATUS: u16 = 0xF32D;
+const SHT3X_CLEAR_STATUS: u16 = 0x3041;
+
+const HEATER_PULSE_MS: u16 = 1000;
+// Sensirion only recommends the heater in high humidity.
+const HEATER_MIN_HUMIDITY: f32 = 80.0;
+// Readings are skipped while the sensor cools down after a pulse.
+const HEATER_COOLDOWN: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    Sht4x,
+    Sht3x,
+}
+
+impl Model {
+    pub fn name(self) -> &'static str {
+        match self {
+            Model::Sht4x => "sht4x",
+            Model::Sht3x => "sht3x",
+        }
+    }
+}
+
+pub struct Sht<I2C, D> {
+    i2c: I2C,
+    delay: D,
+    model: Model,
+    settings: ShtSettings,
+    // Whether this is the primary temperature/humidity source.
+    primary: bool,
+    next_heat: Instant,
+    cooling_until: Instant,
+    last_humidity: f32,
+    temperature: Mean,
+    humidity: Mean,
+}
+
+impl<I2C, D, E> Sht<I2C, D>
+where
+    I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+    D: delay::DelayMs<u16>,
+    E: Debug,
+{
+    pub fn new(i2c: I2C, delay: D, model: Model, settings: ShtSettings, primary: bool) -> Self {
+        Sht {
+            i2c,
+            delay,
+            model,
+            settings,
+            primary,
+            next_heat: Instant::now(),
+            cooling_until: Instant::now(),
+            last_humidity: 0.0,
+            temperature: Mean::default(),
+            humidity: Mean::default(),
+        }
+    }
+
+    // Sends a command and waits its execution time.
+    fn command(&mut self, command: u16, wait_ms: u16) -> Result<(), Box<dyn Error>> {
+        let address = self.settings.address;
+        match self.model {
+            Model::Sht4x => self
+                .i2c
+                .write(address, &[command as u8])
+                .map_err(|e| format!("{:?}", e))?,
+            Model::Sht3x => sensirion::write(&mut self.i2c, address, command, &[])
+                .map_err(|e| format!("{:?}", e))?,
+        }
+        self.delay.delay_ms(wait_ms);
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        command: u16,
+        wait_ms: u16,
+        words: &mut [u16],
+    ) -> Result<(), Box<dyn Error>> {
+        self.command(command, wait_ms)?;
+        sensirion::read(&mut self.i2c, self.settings.address, words)
+            .map_err(|e| format!("{:?}", e))?;
+        Ok(())
+    }
+
+    fn heat(&mut self) -> Result<(), Box<dyn Error>> {
+        match self.model {
+            Model::Sht4x => {
+                let mut hot = [0; 2];
+                self.read(
+                    SHT4X_HEATER_200MW_1S.into(),
+                    HEATER_PULSE_MS + 100,
+                    &mut hot,
+                )
+            }
+            Model::Sht3x => {
+                self.command(SHT3X_HEATER_ENABLE, HEATER_PULSE_MS)?;
+                self.command(SHT3X_HEATER_DISABLE, 1)
+            }
+        }
+    }
+}
+
+impl<I2C, D, E> Sensor for Sht<I2C, D>
+where
+    I2C: i2c::Read<Error = E> + i2c::Write<Error = E>,
+    D: delay::DelayMs<u16>,
+    E: Debug,
+{
+    fn name(&self) -> &'static str {
+        self.model.name()
+    }
+
+    fn init(&mut self) -> Result<(), Box<dyn Error>> {
+        match self.model {
+            Model::Sht4x => {
+                self.command(SHT4X_SOFT_RESET.into(), 2)?;
+                let mut serial = [0; 2];
+                self.read(SHT4X_READ_SERIAL.into(), 1, &mut serial)?;
+                info!("SHT4x serial {:04x}{:04x}", serial[0], serial[1]);
+            }
+            Model::Sht3x => {
+                // The heater stays on through a soft reset if we were
+                // restarted mid-pulse.
+                self.command(SHT3X_SOFT_RESET, 2)?;
+                self.command(SHT3X_HEATER_DISABLE, 1)?;
+                let mut status = [0];
+                self.read(SHT3X_READ_STATUS, 1, &mut status)?;
+                debug!("SHT3x status {:04x}", status[0]);
+                self.command(SHT3X_CLEAR_STATUS, 1)?;
+            }
+        }
+        if let Some(interval) = self.settings.heater_interval {
+            info!(
+                "{} heater pulses every {:?} above {}%RH",
+                self.name(),
+                interval,
+                HEATER_MIN_HUMIDITY
+            );
+        }
+        self.next_heat = Instant::now();
+        self.cooling_until = Instant::now();
+        Ok(())
+    }
+
+    fn sample(&mut self, _env: &mut Environment) -> Result<(), Box<dyn Error>> {
+        let now = Instant::now();
+        if let Some(interval) = self.settings.heater_interval {
+            if now >= self.next_heat && self.last_humidity >= HEATER_MIN_HUMIDITY {
+                self.heat()?;
+                counters::inc("sht_heater_pulses");
+                debug!("{} heater pulsed at {}%RH", self.name(), self.last_humidity);
+                self.next_heat = now + interval;
+                self.cooling_until = Instant::now() + HEATER_COOLDOWN;
+            }
+        }
+        if Instant::now() < self.cooling_until {
+            return Ok(());
+        }
+
+        let mut words = [0; 2];
+        match self.model {
+            Model::Sht4x => self.read(SHT4X_MEASURE_HIGH_PRECISION.into(), 10, &mut words)?,
+            Model::Sht3x => self.read(SHT3X_MEASURE_HIGH_REPEATABILITY, 16, &mut words)?,
+        }
+        let (temperature, humidity) = decode(self.model, &words);
+        debug!(
+            "{}: temp = {} humid = {}",
+            self.name(),
+            temperature,
+            humidity
+        );
+        self.last_humidity = humidity;
+        self.temperature.add(temperature);
+        self.humidity.add(humidity);
+        Ok(())
+    }
+
+    fn flush(&mut self, env: &mut Environment, tx: &mpsc::Sender<adafruit::Metric>) {
+        let (celsius, relative_humidity) = match (self.temperature.take(), self.humidity.take()) {
+            (Some(t), Some(h)) => (t, h),
+            _ => return,
+        };
+        let name = self.name();
+        tx.send(adafruit::Metric::new(
+            format!("mbr-{}.temperature", name),
+            celsius,
+        ))
+        .unwrap();
+        tx.send(adafruit::Metric::new(
+            format!("mbr-{}.humidity", name),
+            relative_humidity,
+        ))
+        .unwrap();
+        if self.primary {
+            publish_climate(celsius, relative_humidity, env, tx);
+        }
+    }
+}
+
+// Temperature (°C) and relative humidity (%).
+fn decode(model: Model, words: &[u16; 2]) -> (f32, f32) {
+    let temperature = -45. + 175. * f32::from(words[0]) / 65535.;
+    let humidity = match model {
+        // The SHT4x range runs past 0-100% so that it doesn't clip early.
+        Model::Sht4x => -6. + 125. * f32::from(words[1]) / 65535.,
+        Model::Sht3x => 100. * f32::from(words[1]) / 65535.,
+    };
+    (temperature, humidity.clamp(0., 100.))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measurements_are_decoded() {
+        let (t, h) = decode(Model::Sht4x, &[0x6667, 0x8000]);
+        assert!((t - 25.).abs() < 0.01, "{}", t);
+        assert!((h - 56.5).abs() < 0.01, "{}", h);
+        let (t, h) = decode(Model::Sht3x, &[0x6667, 0x8000]);
+        assert!((t - 25.).abs() < 0.01, "{}", t);
+        assert!((h - 50.).abs() < 0.01, "{}", h);
+
+        // Out-of-range SHT4x humidity is cropped, as the datasheet says.
+        assert_eq!(100., decode(Model::Sht4x, &[0, 0xFFFF]).1);
+        assert_eq!(0., decode(Model::Sht4x, &[0, 0]).1);
+    }
+
+    #[cfg(feature = "sim")]
+    #[test]
+    fn heater_pulses_in_humid_air_and_primary_feeds_are_sent() {
+        use crate::sensor::sim;
+
+        let config = crate::config::SimConfig {
+            temperature_swing_c: 0.,
+            humidity_mean_pct: 90.,
+            humidity_swing_pct: 0.,
+            ..Default::default()
+        };
+        let settings = ShtSettings {
+            address: 0x44,
+            heater_interval: Some(Duration::from_secs(3600)),
+        };
+        for model in [Model::Sht4x, Model::Sht3x] {
+            let bus = sim::Bus::new(config.waveforms());
+            let mut sht = Sht::new(bus, sim::Delay, model, settings.clone(), true);
+            sht.init().unwrap();
+            let mut env = Environment::default();
+
+            // Nothing is known about the humidity until the first reading.
+            sht.sample(&mut env).unwrap();
+            assert!(
+                (sht.last_humidity - 90.).abs() < 0.01,
+                "{}",
+                sht.last_humidity
+            );
+            sht.sample(&mut env).unwrap();
+            assert!(sht.next_heat > Instant::now());
+            assert!(sht.cooling_until > Instant::now());
+            // Still cooling down.
+            sht.sample(&mut env).unwrap();
+
+            let (tx, rx) = mpsc::channel();
+            sht.flush(&mut env, &tx);
+            let feeds: Vec<_> = rx.try_iter().map(|m| m.feed).collect();
+            let name = model.name();
+            assert_eq!(
+                vec![
+                    format!("mbr-{}.temperature", name),
+                    format!("mbr-{}.humidity", name),
+                    "mbr.temperature".to_string(),
+                    "mbr.humidity".to_string(),
+                    "mbr.abs-humidity".to_string(),
+                ],
+                feeds
+            );
+            // 90% at 21 °C.
+            assert!(
+                (env.abs_humidity - 16.5).abs() < 0.1,
+                "{}",
+                env.abs_humidity
+            );
+        }
+    }
+}
@@ -0,0 +1,1180 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+//! A fake I2C bus for running without hardware (the "sim" feature). It answers
+//! at the BME280, SGP30, TSL2591, VEML7700, SCD4x, SCD30, PMSA003I and
+//! SHT4x/SHT3x addresses, and at the BME688's once it is placed with
+//! `with_bme680`, with their register maps and command sets, so the real
+//! drivers run unchanged, and the readings follow `Waveforms` over a simulated
+//! day. `Serial` plays a PMS5003 on a serial port.
+
+use super::sensirion::crc8;
+use super::veml;
+use chrono::{Local, Timelike};
+use embedded_hal::blocking::{delay, i2c};
+use embedded_hal::serial;
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const BME280_ADDRESS: u8 = 0x77;
+const SGP30_ADDRESS: u8 = 0x58;
+const TSL2591_ADDRESS: u8 = 0x29;
+const VEML7700_ADDRESS: u8 = 0x10;
+const SCD4X_ADDRESS: u8 = 0x62;
+const SCD30_ADDRESS: u8 = 0x61;
+const PMSA003I_ADDRESS: u8 = 0x12;
+const SHT_ADDRESS: u8 = 0x44;
+
+const DAY_SECS: f64 = 24. * 60. * 60.;
+
+// What the simulated room does over a day.
+#[derive(Debug, Clone)]
+pub struct Waveforms {
+    // °C; warmest at 15:00 and coolest at 03:00.
+    pub temperature_mean: f64,
+    pub temperature_swing: f64,
+    // %RH; lowest when it is warmest.
+    pub humidity_mean: f64,
+    pub humidity_swing: f64,
+    // hPa at the sensor.
+    pub pressure: f64,
+    // ppm, rising by up to `co2_spike` for `co2_spike_length` out of every
+    // `co2_spike_every`, as when someone is in the room.
+    pub co2_baseline: f64,
+    pub co2_spike: f64,
+    pub co2_spike_every: Duration,
+    pub co2_spike_length: Duration,
+    // ppb, following CO₂.
+    pub tvoc_baseline: f64,
+    // PM2.5 in µg/m³, also following CO₂ (as when cooking).
+    pub pm25_baseline: f64,
+    // Lux at midday and at night; the sun is up between the two hours (local
+    // time).
+    pub daylight: f64,
+    pub night_light: f64,
+    pub sunrise_hour: f64,
+    pub sunset_hour: f64,
+    // Simulated seconds per real second, e.g. 1440 for a day in a minute.
+    pub time_scale: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Conditions {
+    pub temperature: f64,
+    pub humidity: f64,
+    pub pressure: f64,
+    pub co2: f64,
+    pub tvoc: f64,
+    pub pm25: f64,
+    pub lux: f64,
+}
+
+impl Waveforms {
+    // The conditions `t` seconds after a local midnight.
+    pub fn at(&self, t: f64) -> Conditions {
+        let diurnal = (2. * PI * (t / DAY_SECS - 15. / 24.)).cos();
+
+        let every = self.co2_spike_every.as_secs_f64();
+        let length = self.co2_spike_length.as_secs_f64();
+        let into_spike = if every > 0. { t.rem_euclid(every) } else { t };
+        let spike = if into_spike < length {
+            (PI * into_spike / length).sin()
+        } else {
+            0.
+        };
+        let co2 = self.co2_baseline + self.co2_spike * spike;
+
+        let hour = t.rem_euclid(DAY_SECS) / 3600.;
+        let lux = if hour > self.sunrise_hour && hour < self.sunset_hour {
+            let day = (hour - self.sunrise_hour) / (self.sunset_hour - self.sunrise_hour);
+            self.night_light + self.daylight * (PI * day).sin()
+        } else {
+            self.night_light
+        };
+
+        Conditions {
+            temperature: self.temperature_mean + self.temperature_swing * diurnal,
+            humidity: self.humidity_mean - self.humidity_swing * diurnal,
+            pressure: self.pressure,
+            co2,
+            tvoc: self.tvoc_baseline * co2 / self.co2_baseline,
+            pm25: self.pm25_baseline * co2 / self.co2_baseline,
+            lux,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    // Nothing answered at this address.
+    NoDevice(u8),
+    // The device refused the transfer, e.g. an unknown command or bad CRC.
+    Nack(u8),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NoDevice(address) => write!(f, "no device at {:#04x}", address),
+            Error::Nack(address) => write!(f, "NACK from {:#04x}", address),
+        }
+    }
+}
+
+// The simulated bus, with every sensor attached.
+pub struct Bus {
+    waveforms: Waveforms,
+    started: Instant,
+    // Seconds after local midnight when the bus was created.
+    origin: f64,
+    bme: Bme280,
+    bme680: Bme680,
+    // Where the BME688 answers, in place of the BME280 if that is 0x77.
+    bme680_address: Option<u8>,
+    sgp: Sgp30,
+    tsl: Tsl2591,
+    veml: Veml7700,
+    scd4x: Scd4x,
+    scd30: Scd30,
+    sht: Sht,
+}
+
+impl Bus {
+    pub fn new(waveforms: Waveforms) -> Bus {
+        Bus {
+            waveforms,
+            started: Instant::now(),
+            origin: Local::now().num_seconds_from_midnight().into(),
+            bme: Bme280::new(),
+            bme680: Bme680::new(),
+            bme680_address: None,
+            sgp: Sgp30::new(),
+            tsl: Tsl2591::new(),
+            veml: Veml7700::new(),
+            scd4x: Scd4x::new(),
+            scd30: Scd30::new(),
+            sht: Sht::new(),
+        }
+    }
+
+    /// Adds a BME688 at `address`, as `[sensor.bme680]` would have it.
+    pub fn with_bme680(mut self, address: u8) -> Bus {
+        self.bme680_address = Some(address);
+        self
+    }
+
+    fn now(&self) -> Conditions {
+        let elapsed = self.started.elapsed().as_secs_f64() * self.waveforms.time_scale;
+        self.waveforms.at(self.origin + elapsed)
+    }
+}
+
+impl i2c::Write for Bus {
+    type Error = Error;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Error> {
+        let now = self.now();
+        let ok = match address {
+            a if Some(a) == self.bme680_address => self.bme680.write(bytes, &now),
+            BME280_ADDRESS => self.bme.write(bytes, &now),
+            SGP30_ADDRESS => self.sgp.write(bytes, &now),
+            TSL2591_ADDRESS => self.tsl.write(bytes),
+            VEML7700_ADDRESS => self.veml.write(bytes),
+            SCD4X_ADDRESS => self.scd4x.write(bytes, &now),
+            SCD30_ADDRESS => self.scd30.write(bytes, &now),
+            // Nothing to configure.
+            PMSA003I_ADDRESS => true,
+            SHT_ADDRESS => self.sht.write(bytes, &now),
+            _ => return Err(Error::NoDevice(address)),
+        };
+        ok.then_some(()).ok_or(Error::Nack(address))
+    }
+}
+
+impl i2c::Read for Bus {
+    type Error = Error;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        let now = self.now();
+        let ok = match address {
+            a if Some(a) == self.bme680_address => self.bme680.read(buffer),
+            BME280_ADDRESS => self.bme.read(buffer, &now),
+            SGP30_ADDRESS => respond(&mut self.sgp.response, buffer),
+            TSL2591_ADDRESS => self.tsl.read(buffer, &now),
+            VEML7700_ADDRESS => self.veml.read(buffer, &now),
+            SCD4X_ADDRESS => respond(&mut self.scd4x.response, buffer),
+            SCD30_ADDRESS => respond(&mut self.scd30.response, buffer),
+            PMSA003I_ADDRESS if buffer.len() == 32 => {
+                buffer.copy_from_slice(&pms_frame(&now));
+                true
+            }
+            SHT_ADDRESS => respond(&mut self.sht.response, buffer),
+            _ => return Err(Error::NoDevice(address)),
+        };
+        ok.then_some(()).ok_or(Error::Nack(address))
+    }
+}
+
+impl i2c::WriteRead for Bus {
+    type Error = Error;
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Error> {
+        i2c::Write::write(self, address, bytes)?;
+        i2c::Read::read(self, address, buffer)
+    }
+}
+
+// A PMS5003 streaming a frame a second. Like a real serial port, it is
+// opened partway through a frame.
+pub struct Serial {
+    waveforms: Waveforms,
+    started: Instant,
+    origin: f64,
+    next_frame: Instant,
+    pending: VecDeque<u8>,
+}
+
+impl Serial {
+    pub fn new(waveforms: Waveforms) -> Serial {
+        let mut pending: VecDeque<u8> = pms_frame(&waveforms.at(0.)).into();
+        pending.drain(..10);
+        Serial {
+            waveforms,
+            started: Instant::now(),
+            origin: Local::now().num_seconds_from_midnight().into(),
+            next_frame: Instant::now() + PMS_INTERVAL,
+            pending,
+        }
+    }
+}
+
+const PMS_INTERVAL: Duration = Duration::from_secs(1);
+
+impl serial::Read<u8> for Serial {
+    type Error = Error;
+
+    fn read(&mut self) -> nb::Result<u8, Error> {
+        if self.pending.is_empty() && Instant::now() >= self.next_frame {
+            let elapsed = self.started.elapsed().as_secs_f64() * self.waveforms.time_scale;
+            let now = self.waveforms.at(self.origin + elapsed);
+            self.pending.extend(pms_frame(&now));
+            self.next_frame += PMS_INTERVAL;
+        }
+        self.pending.pop_front().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+// A Plantower frame for the conditions, with a rough indoor size
+// distribution around the PM2.5 level.
+fn pms_frame(now: &Conditions) -> [u8; 32] {
+    let pm25 = now.pm25.max(0.);
+    let pm = [0.7 * pm25, pm25, 1.2 * pm25].map(|v| v.round() as u16);
+    let counts =
+        [150., 45., 8., 1., 0.3, 0.1].map(|per_ugm3: f64| (per_ugm3 * pm25).round() as u16);
+    let mut words = vec![0x424D, 28];
+    words.extend_from_slice(&pm);
+    words.extend_from_slice(&pm);
+    words.extend_from_slice(&counts);
+    words.push(0);
+    let mut frame = [0; 32];
+    for (i, w) in words.iter().enumerate() {
+        frame[2 * i..2 * i + 2].copy_from_slice(&w.to_be_bytes());
+    }
+    let sum = frame[..30].iter().map(|&b| u16::from(b)).sum::<u16>();
+    frame[30..].copy_from_slice(&sum.to_be_bytes());
+    frame
+}
+
+// Really sleeps, so the sensor loop keeps its usual pace.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Delay;
+
+impl delay::DelayMs<u8> for Delay {
+    fn delay_ms(&mut self, ms: u8) {
+        thread::sleep(Duration::from_millis(ms.into()));
+    }
+}
+
+impl delay::DelayMs<u16> for Delay {
+    fn delay_ms(&mut self, ms: u16) {
+        thread::sleep(Duration::from_millis(ms.into()));
+    }
+}
+
+impl delay::DelayUs<u8> for Delay {
+    fn delay_us(&mut self, us: u8) {
+        thread::sleep(Duration::from_micros(us.into()));
+    }
+}
+
+impl delay::DelayUs<u16> for Delay {
+    fn delay_us(&mut self, us: u16) {
+        thread::sleep(Duration::from_micros(us.into()));
+    }
+}
+
+// BME280 registers. The calibration is chosen so that the driver's
+// compensation formulas reduce to T = raw / 8192 - 40, P = (2^20 - raw) / 8
+// and H = raw / 64.
+struct Bme280 {
+    regs: [u8; 256],
+    pointer: u8,
+}
+
+const BME280_CHIP_ID: u8 = 0xD0;
+const BME280_RESET: u8 = 0xE0;
+const BME280_CTRL_HUM: u8 = 0xF2;
+const BME280_CTRL_MEAS: u8 = 0xF4;
+const BME280_CONFIG: u8 = 0xF5;
+const BME280_DATA: u8 = 0xF7;
+
+impl Bme280 {
+    fn new() -> Bme280 {
+        let mut regs = [0; 256];
+        regs[BME280_CHIP_ID as usize] = 0x60;
+        // dig_T1 = 20480, dig_T2 = 10240, dig_P1 = 50000; everything else 0.
+        regs[0x88..0x8E].copy_from_slice(&[0x00, 0x50, 0x00, 0x28, 0x00, 0x00]);
+        regs[0x8E..0x90].copy_from_slice(&50000u16.to_le_bytes());
+        // dig_H2 = 1024.
+        regs[0xE1..0xE3].copy_from_slice(&1024u16.to_le_bytes());
+        Bme280 { regs, pointer: 0 }
+    }
+
+    // A lone register address sets up a read; otherwise the bytes are
+    // (register, value) pairs.
+    fn write(&mut self, bytes: &[u8], now: &Conditions) -> bool {
+        match bytes {
+            [] => false,
+            [reg] => {
+                self.pointer = *reg;
+                true
+            }
+            _ if bytes.len().is_multiple_of(2) => {
+                for pair in bytes.chunks(2) {
+                    self.set(pair[0], pair[1], now);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn set(&mut self, reg: u8, value: u8, now: &Conditions) {
+        match reg {
+            BME280_RESET if value == 0xB6 => {
+                for r in [BME280_CTRL_HUM, BME280_CTRL_MEAS, BME280_CONFIG] {
+                    self.regs[r as usize] = 0;
+                }
+            }
+            BME280_CTRL_HUM | BME280_CONFIG => self.regs[reg as usize] = value,
+            BME280_CTRL_MEAS => {
+                let mode = value & 0x03;
+                if mode != 0 {
+                    self.measure(now);
+                }
+                // Forced mode measures once and goes back to sleep.
+                self.regs[reg as usize] = if mode == 0x03 { value } else { value & !0x03 };
+            }
+            // Everything else is read-only.
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, buffer: &mut [u8], now: &Conditions) -> bool {
+        let normal_mode = self.regs[BME280_CTRL_MEAS as usize] & 0x03 == 0x03;
+        if normal_mode && self.pointer >= BME280_DATA {
+            self.measure(now);
+        }
+        for (i, b) in buffer.iter_mut().enumerate() {
+            *b = *self.regs.get(self.pointer as usize + i).unwrap_or(&0);
+        }
+        true
+    }
+
+    fn measure(&mut self, now: &Conditions) {
+        let t = ((now.temperature + 40.) * 8192.)
+            .round()
+            .clamp(0., 0xF_FFFF as f64) as u32;
+        let p = (1_048_576. - now.pressure * 100. * 8.)
+            .round()
+            .clamp(0., 0xF_FFFF as f64) as u32;
+        let h = (now.humidity.clamp(0., 100.) * 64.).round() as u32;
+        let data = [
+            (p >> 12) as u8,
+            (p >> 4) as u8,
+            ((p & 0x0F) << 4) as u8,
+            (t >> 12) as u8,
+            (t >> 4) as u8,
+            ((t & 0x0F) << 4) as u8,
+            (h >> 8) as u8,
+            h as u8,
+        ];
+        let start = BME280_DATA as usize;
+        self.regs[start..start + data.len()].copy_from_slice(&data);
+    }
+}
+
+// A BME688's registers. As for the BME280, the calibration reduces the
+// compensation to T = raw / 8192 - 40 and P = (2^20 - raw) / 8, and here
+// H = raw / 128. Gas resistance falls as TVOC rises.
+struct Bme680 {
+    regs: [u8; 256],
+    pointer: u8,
+}
+
+const BME680_FIELD_0: u8 = 0x1D;
+const BME680_CTRL_GAS_1: u8 = 0x71;
+const BME680_CTRL_MEAS: u8 = 0x74;
+const BME680_RESET: u8 = 0xE0;
+// Resistance in clean air, with no TVOC.
+const BME680_CLEAN_AIR_OHMS: f64 = 150_000.;
+
+impl Bme680 {
+    fn new() -> Bme680 {
+        let mut regs = [0; 256];
+        regs[0xD0] = 0x61;
+        // Variant: BME688.
+        regs[0xF0] = 0x01;
+        // par_t2 = 10240, par_t1 = 20480, par_p1 = 50000, par_h2 = 2048;
+        // everything else 0.
+        regs[0x8A..0x8C].copy_from_slice(&10240u16.to_le_bytes());
+        regs[0xE9..0xEB].copy_from_slice(&20480u16.to_le_bytes());
+        regs[0x8E..0x90].copy_from_slice(&50000u16.to_le_bytes());
+        regs[0xE1] = (2048 >> 4) as u8;
+        Bme680 { regs, pointer: 0 }
+    }
+
+    // A lone register address sets up a read; otherwise the bytes are
+    // (register, value) pairs.
+    fn write(&mut self, bytes: &[u8], now: &Conditions) -> bool {
+        match bytes {
+            [] => false,
+            [reg] => {
+                self.pointer = *reg;
+                true
+            }
+            _ if bytes.len().is_multiple_of(2) => {
+                for pair in bytes.chunks(2) {
+                    self.set(pair[0], pair[1], now);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn set(&mut self, reg: u8, value: u8, now: &Conditions) {
+        match reg {
+            BME680_RESET if value == 0xB6 => {
+                self.regs[BME680_CTRL_GAS_1 as usize] = 0;
+                self.regs[BME680_CTRL_MEAS as usize] = 0;
+            }
+            // Forced mode measures once and goes back to sleep.
+            BME680_CTRL_MEAS if value & 0x03 == 0x01 => {
+                self.measure(now);
+                self.regs[reg as usize] = value & !0x03;
+            }
+            // Calibration, IDs and data are read-only.
+            0x50..=0x75 => self.regs[reg as usize] = value,
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> bool {
+        for (i, b) in buffer.iter_mut().enumerate() {
+            *b = *self.regs.get(self.pointer as usize + i).unwrap_or(&0);
+        }
+        true
+    }
+
+    fn measure(&mut self, now: &Conditions) {
+        let t = ((now.temperature + 40.) * 8192.)
+            .round()
+            .clamp(0., 0xF_FFFF as f64) as u32;
+        let p = (1_048_576. - now.pressure * 100. * 8.)
+            .round()
+            .clamp(0., 0xF_FFFF as f64) as u32;
+        let h = (now.humidity.clamp(0., 100.) * 128.).round() as u16;
+        let mut field = [0u8; 17];
+        field[0] = 0x80;
+        field[2..5].copy_from_slice(&[(p >> 12) as u8, (p >> 4) as u8, ((p & 0x0F) << 4) as u8]);
+        field[5..8].copy_from_slice(&[(t >> 12) as u8, (t >> 4) as u8, ((t & 0x0F) << 4) as u8]);
+        field[8..10].copy_from_slice(&h.to_be_bytes());
+        if self.regs[BME680_CTRL_GAS_1 as usize] & 0x20 != 0 {
+            // The BME688's formula is R = 10^6 (2^18 >> range) / (4096 + 3
+            // (adc - 512)); pick the range that keeps adc within 10 bits.
+            let ohms = BME680_CLEAN_AIR_OHMS / (1. + now.tvoc / 100.);
+            let (adc, range) = (0..16)
+                .map(|range| {
+                    let scale = f64::from(262_144u32 >> range);
+                    ((1e6 * scale / ohms - 4096.) / 3. + 512., range)
+                })
+                .find(|(adc, _)| (0. ..=1023.).contains(adc))
+                .unwrap_or((1023., 15));
+            let adc = adc.round() as u16;
+            // gas_valid and heat_stab.
+            field[15..17]
+                .copy_from_slice(&[(adc >> 2) as u8, ((adc & 0x03) << 6) as u8 | 0x30 | range]);
+        }
+        let start = BME680_FIELD_0 as usize;
+        self.regs[start..start + field.len()].copy_from_slice(&field);
+    }
+}
+
+// SGP30 commands. Every word on the wire is followed by its CRC.
+struct Sgp30 {
+    // When InitAirQuality was sent.
+    initialized: Option<Instant>,
+    // CO₂eq and TVOC, in the order GetBaseline returns them.
+    baseline: [u16; 2],
+    humidity: u16,
+    response: Vec<u16>,
+}
+
+// For the first 15 s after init the SGP30 reports exactly 400 ppm / 0 ppb.
+const SGP30_WARM_UP: Duration = Duration::from_secs(15);
+
+impl Sgp30 {
+    fn new() -> Sgp30 {
+        Sgp30 {
+            initialized: None,
+            baseline: [0x8973, 0x8AAE],
+            humidity: 0,
+            response: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8], now: &Conditions) -> bool {
+        let (command, args) = match parse_command(bytes) {
+            Some(c) => c,
+            None => return false,
+        };
+        self.response = match (command, args.as_slice()) {
+            // GetSerial
+            (0x3682, []) => vec![0x0000, 0x0123, 0x4567],
+            // SelfTest
+            (0x2032, []) => vec![0xD400],
+            // InitAirQuality
+            (0x2003, []) => {
+                self.initialized = Some(Instant::now());
+                Vec::new()
+            }
+            // MeasureAirQuality
+            (0x2008, []) => match self.initialized {
+                Some(t) if t.elapsed() >= SGP30_WARM_UP => vec![
+                    now.co2.round().clamp(400., 60000.) as u16,
+                    now.tvoc.round().clamp(0., 60000.) as u16,
+                ],
+                _ => vec![400, 0],
+            },
+            // MeasureRawSignals: s = s_ref - 512 ln(c / c_ref), roughly.
+            (0x2050, []) => {
+                let gas = (1. + now.tvoc / 1000.).ln();
+                vec![
+                    (13_500. - 512. * gas).round() as u16,
+                    (18_500. - 512. * gas).round() as u16,
+                ]
+            }
+            // GetBaseline
+            (0x2015, []) => self.baseline.to_vec(),
+            // SetBaseline takes TVOC first.
+            (0x201E, [tvoc, co2eq]) => {
+                self.baseline = [*co2eq, *tvoc];
+                Vec::new()
+            }
+            // SetHumidity
+            (0x2061, [humidity]) => {
+                self.humidity = *humidity;
+                Vec::new()
+            }
+            // GetFeatureSet: product type 0, version 0x22.
+            (0x202F, []) => vec![0x0022],
+            _ => return false,
+        };
+        true
+    }
+}
+
+// A Sensirion command and its arguments, if every argument's CRC matches.
+fn parse_command(bytes: &[u8]) -> Option<(u16, Vec<u16>)> {
+    if bytes.len() < 2 || !(bytes.len() - 2).is_multiple_of(3) {
+        return None;
+    }
+    let command = u16::from_be_bytes([bytes[0], bytes[1]]);
+    let mut args = Vec::new();
+    for word in bytes[2..].chunks(3) {
+        if crc8(&word[..2]) != word[2] {
+            return None;
+        }
+        args.push(u16::from_be_bytes([word[0], word[1]]));
+    }
+    Some((command, args))
+}
+
+// Reads out the response to the last Sensirion command, each word followed by
+// its CRC.
+fn respond(response: &mut Vec<u16>, buffer: &mut [u8]) -> bool {
+    let mut bytes = Vec::new();
+    for word in response.drain(..) {
+        let w = word.to_be_bytes();
+        bytes.extend_from_slice(&w);
+        bytes.push(crc8(&w));
+    }
+    if bytes.len() < buffer.len() {
+        return false;
+    }
+    buffer.copy_from_slice(&bytes[..buffer.len()]);
+    true
+}
+
+// SCD4x commands. It measures every 5 s once started, and only takes
+// settings while idle.
+struct Scd4x {
+    // When measurement started or the last reading was read out.
+    measuring_since: Option<Instant>,
+    automatic_self_calibration: bool,
+    pressure: u16,
+    // How far off true CO₂ its readings are; forced recalibration fixes it.
+    offset: f64,
+    response: Vec<u16>,
+}
+
+const SCD4X_INTERVAL: Duration = Duration::from_secs(5);
+
+impl Scd4x {
+    fn new() -> Scd4x {
+        Scd4x {
+            measuring_since: None,
+            automatic_self_calibration: true,
+            pressure: 1013,
+            offset: 30.,
+            response: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8], now: &Conditions) -> bool {
+        let (command, args) = match parse_command(bytes) {
+            Some(c) => c,
+            None => return false,
+        };
+        let idle = self.measuring_since.is_none();
+        self.response = match (command, args.as_slice()) {
+            // StartPeriodicMeasurement
+            (0x21B1, []) if idle => {
+                self.measuring_since = Some(Instant::now());
+                Vec::new()
+            }
+            // StopPeriodicMeasurement
+            (0x3F86, []) => {
+                self.measuring_since = None;
+                Vec::new()
+            }
+            // GetSerialNumber
+            (0x3682, []) if idle => vec![0x0A1B, 0x2C3D, 0x4E5F],
+            // GetDataReadyStatus: any of the low 11 bits set means ready.
+            (0xE4B8, []) => match self.measuring_since {
+                Some(t) if t.elapsed() >= SCD4X_INTERVAL => vec![0x8006],
+                _ => vec![0x8000],
+            },
+            // ReadMeasurement
+            (0xEC05, []) if !idle => {
+                self.measuring_since = Some(Instant::now());
+                vec![
+                    (now.co2 + self.offset).round().clamp(0., 40000.) as u16,
+                    ((now.temperature + 45.) * 65535. / 175.).round() as u16,
+                    (now.humidity.clamp(0., 100.) * 65535. / 100.).round() as u16,
+                ]
+            }
+            // SetAmbientPressure, in hPa.
+            (0xE000, [hpa]) => {
+                self.pressure = *hpa;
+                Vec::new()
+            }
+            // PerformForcedRecalibration returns the correction + 0x8000.
+            (0x362F, [target]) if idle => {
+                let correction = f64::from(*target) - (now.co2 + self.offset);
+                self.offset += correction;
+                vec![(0x8000 as f64 + correction.round()) as u16]
+            }
+            // SetAutomaticSelfCalibrationEnabled
+            (0x2416, [enabled]) if idle => {
+                self.automatic_self_calibration = *enabled != 0;
+                Vec::new()
+            }
+            _ => return false,
+        };
+        true
+    }
+}
+
+// SCD30 commands. Readings are floats, and it keeps measuring while settings
+// change.
+struct Scd30 {
+    measuring_since: Option<Instant>,
+    interval: Duration,
+    automatic_self_calibration: bool,
+    // In mbar; 0 when compensation is off.
+    pressure: u16,
+    offset: f64,
+    response: Vec<u16>,
+}
+
+impl Scd30 {
+    fn new() -> Scd30 {
+        Scd30 {
+            measuring_since: None,
+            interval: Duration::from_secs(2),
+            automatic_self_calibration: false,
+            pressure: 0,
+            offset: -25.,
+            response: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8], now: &Conditions) -> bool {
+        let (command, args) = match parse_command(bytes) {
+            Some(c) => c,
+            None => return false,
+        };
+        self.response = match (command, args.as_slice()) {
+            // TriggerContinuousMeasurement, with the ambient pressure.
+            (0x0010, [mbar]) => {
+                self.pressure = *mbar;
+                self.measuring_since.get_or_insert_with(Instant::now);
+                Vec::new()
+            }
+            // StopContinuousMeasurement
+            (0x0104, []) => {
+                self.measuring_since = None;
+                Vec::new()
+            }
+            // SetMeasurementInterval
+            (0x4600, [secs]) if (2..=1800).contains(secs) => {
+                self.interval = Duration::from_secs((*secs).into());
+                Vec::new()
+            }
+            // GetDataReadyStatus
+            (0x0202, []) => match self.measuring_since {
+                Some(t) if t.elapsed() >= self.interval => vec![1],
+                _ => vec![0],
+            },
+            // ReadMeasurement
+            (0x0300, []) if self.measuring_since.is_some() => {
+                self.measuring_since = Some(Instant::now());
+                [
+                    (now.co2 + self.offset) as f32,
+                    now.temperature as f32,
+                    now.humidity.clamp(0., 100.) as f32,
+                ]
+                .iter()
+                .flat_map(|f| {
+                    let bits = f.to_bits();
+                    [(bits >> 16) as u16, bits as u16]
+                })
+                .collect()
+            }
+            // (De-)ActivateAutomaticSelfCalibration
+            (0x5306, [enabled]) => {
+                self.automatic_self_calibration = *enabled != 0;
+                Vec::new()
+            }
+            // SetForcedRecalibrationValue
+            (0x5204, [target]) if (400..=2000).contains(target) => {
+                self.offset = f64::from(*target) - now.co2;
+                Vec::new()
+            }
+            // ReadFirmwareVersion: 3.66.
+            (0xD100, []) => vec![0x0342],
+            _ => return false,
+        };
+        true
+    }
+}
+
+// An SHT4x or SHT3x, whichever the driver talks to: SHT4x commands are one
+// byte and SHT3x commands two. Its readings are exact.
+struct Sht {
+    // SHT3x status register; bit 13 is set while the heater is on.
+    status: u16,
+    response: Vec<u16>,
+}
+
+const SHT3X_STATUS_HEATER: u16 = 1 << 13;
+// How much warmer the SHT4x reads at the end of a heater pulse.
+const SHT_HEATER_RISE: f64 = 20.;
+
+impl Sht {
+    fn new() -> Sht {
+        Sht {
+            status: 0,
+            response: Vec::new(),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8], now: &Conditions) -> bool {
+        let measurement = |rise: f64, sht4x: bool| {
+            let humidity = now.humidity.clamp(0., 100.);
+            vec![
+                ((now.temperature + rise + 45.) * 65535. / 175.).round() as u16,
+                if sht4x {
+                    ((humidity + 6.) * 65535. / 125.).round() as u16
+                } else {
+                    (humidity * 65535. / 100.).round() as u16
+                },
+            ]
+        };
+        if let [command] = bytes {
+            self.response = match command {
+                // MeasureHighPrecision
+                0xFD => measurement(0., true),
+                // ReadSerial
+                0x89 => vec![0x1234, 0x5678],
+                // SoftReset
+                0x94 => Vec::new(),
+                // Heater at 200 mW for 1 s, then a measurement.
+                0x39 => measurement(SHT_HEATER_RISE, true),
+                _ => return false,
+            };
+            return true;
+        }
+        let (command, args) = match parse_command(bytes) {
+            Some(c) => c,
+            None => return false,
+        };
+        self.response = match (command, args.as_slice()) {
+            // MeasureSingleShot, high repeatability, no clock stretching
+            (0x2400, []) => measurement(0., false),
+            // SoftReset leaves the heater as it is.
+            (0x30A2, []) => Vec::new(),
+            // HeaterEnable
+            (0x306D, []) => {
+                self.status |= SHT3X_STATUS_HEATER;
+                Vec::new()
+            }
+            // HeaterDisable
+            (0x3066, []) => {
+                self.status &= !SHT3X_STATUS_HEATER;
+                Vec::new()
+            }
+            // ReadStatus
+            (0xF32D, []) => vec![self.status],
+            // ClearStatus
+            (0x3041, []) => Vec::new(),
+            _ => return false,
+        };
+        true
+    }
+}
+
+// TSL2591 registers. Channel 0 sees visible and infrared light, channel 1
+// infrared only; counts scale with gain and integration time.
+struct Tsl2591 {
+    regs: [u8; 32],
+    pointer: u8,
+}
+
+const TSL2591_COMMAND: u8 = 0x80;
+const TSL2591_SPECIAL_FUNCTION: u8 = 0x60;
+const TSL2591_ENABLE: u8 = 0x00;
+const TSL2591_CONTROL: u8 = 0x01;
+const TSL2591_ID: u8 = 0x12;
+const TSL2591_STATUS: u8 = 0x13;
+const TSL2591_C0DATAL: u8 = 0x14;
+
+// Share of channel 0 that is infrared.
+const TSL2591_IR_RATIO: f64 = 0.2;
+
+impl Tsl2591 {
+    fn new() -> Tsl2591 {
+        let mut regs = [0; 32];
+        regs[TSL2591_ID as usize] = 0x50;
+        Tsl2591 { regs, pointer: 0 }
+    }
+
+    // The first byte is a command: the register, with the command bit set.
+    // Any further bytes go to consecutive registers.
+    fn write(&mut self, bytes: &[u8]) -> bool {
+        let (command, values) = match bytes.split_first() {
+            Some((c, v)) if c & TSL2591_COMMAND != 0 => (c, v),
+            _ => return false,
+        };
+        if command & TSL2591_SPECIAL_FUNCTION == TSL2591_SPECIAL_FUNCTION {
+            // Interrupt handling; nothing to do.
+            return true;
+        }
+        self.pointer = command & 0x1F;
+        for (i, &v) in values.iter().enumerate() {
+            let reg = (self.pointer as usize + i) & 0x1F;
+            if reg <= TSL2591_CONTROL as usize {
+                self.regs[reg] = v;
+            }
+        }
+        let enabled = self.regs[TSL2591_ENABLE as usize] & 0x03 == 0x03;
+        self.regs[TSL2591_STATUS as usize] = enabled.into();
+        true
+    }
+
+    fn read(&mut self, buffer: &mut [u8], now: &Conditions) -> bool {
+        let (ch0, ch1) = self.counts(now);
+        let start = TSL2591_C0DATAL as usize;
+        self.regs[start..start + 2].copy_from_slice(&ch0.to_le_bytes());
+        self.regs[start + 2..start + 4].copy_from_slice(&ch1.to_le_bytes());
+        for (i, b) in buffer.iter_mut().enumerate() {
+            *b = self.regs[(self.pointer as usize + i) & 0x1F];
+        }
+        true
+    }
+
+    // The inverse of the lux formula in sensor::tsl.
+    fn counts(&self, now: &Conditions) -> (u16, u16) {
+        if self.regs[TSL2591_ENABLE as usize] & 0x03 != 0x03 {
+            return (0, 0);
+        }
+        let control = self.regs[TSL2591_CONTROL as usize];
+        let atime = 100. * f64::from((control & 0x07).min(5) + 1);
+        let again = match control & 0x30 {
+            0x00 => 1.,
+            0x10 => 25.,
+            0x20 => 428.,
+            _ => 9876.,
+        };
+        // The ADC tops out early at the shortest integration time.
+        let max = if atime == 100. { 37888. } else { 65535. };
+        let ch0 = now.lux * atime * again / 53. / (1. - 1.7 * TSL2591_IR_RATIO);
+        let ch1 = ch0 * TSL2591_IR_RATIO;
+        (ch0.round().min(max) as u16, ch1.round().min(max) as u16)
+    }
+}
+
+// VEML7700 registers: 16 bits each, little-endian. It starts shut down.
+struct Veml7700 {
+    regs: [u16; 8],
+    pointer: u8,
+}
+
+const VEML7700_ALS_CONF_0: usize = 0;
+const VEML7700_ALS: u8 = 4;
+const VEML7700_WHITE: u8 = 5;
+// White light has more in it than the eye sees.
+const VEML7700_WHITE_RATIO: f64 = 1.25;
+
+impl Veml7700 {
+    fn new() -> Veml7700 {
+        let mut regs = [0; 8];
+        regs[VEML7700_ALS_CONF_0] = 0x0001;
+        regs[7] = 0xC481;
+        Veml7700 { regs, pointer: 0 }
+    }
+
+    // A register, then its new value if it is being written.
+    fn write(&mut self, bytes: &[u8]) -> bool {
+        match bytes {
+            [reg] if *reg < 8 => self.pointer = *reg,
+            [reg @ 0..=3, lsb, msb] => self.regs[*reg as usize] = u16::from_le_bytes([*lsb, *msb]),
+            _ => return false,
+        }
+        true
+    }
+
+    fn read(&mut self, buffer: &mut [u8], now: &Conditions) -> bool {
+        let value = match self.pointer {
+            VEML7700_ALS => self.counts(now.lux),
+            VEML7700_WHITE => self.counts(now.lux * VEML7700_WHITE_RATIO),
+            reg => self.regs[reg as usize],
+        };
+        match buffer {
+            [lsb, msb] => [*lsb, *msb] = value.to_le_bytes(),
+            _ => return false,
+        }
+        true
+    }
+
+    // The inverse of sensor::veml's lux formula, including its correction
+    // for the sensor's non-linearity above 1000 lx.
+    fn counts(&self, lux: f64) -> u16 {
+        let conf = self.regs[VEML7700_ALS_CONF_0];
+        if conf & 0x0001 != 0 {
+            return 0;
+        }
+        let gain = match (conf >> 11) & 0x03 {
+            0b00 => 1.,
+            0b01 => 2.,
+            0b10 => 0.125,
+            _ => 0.25,
+        };
+        let ms = match (conf >> 6) & 0x0F {
+            0b1100 => 25.,
+            0b1000 => 50.,
+            0b0000 => 100.,
+            0b0001 => 200.,
+            0b0010 => 400.,
+            _ => 800.,
+        };
+        let mut raw = lux;
+        if lux > 1000. {
+            // Bisect, as the correction only goes one way.
+            let (mut low, mut high) = (0., lux);
+            for _ in 0..60 {
+                raw = (low + high) / 2.;
+                if f64::from(veml::correct(raw as f32)) < lux {
+                    low = raw;
+                } else {
+                    high = raw;
+                }
+            }
+        }
+        let resolution = 0.0036 * (800. / ms) * (2. / gain);
+        (raw / resolution).round().min(65535.) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bme280::BME280;
+    use sgp30::Sgp30;
+
+    fn waveforms() -> Waveforms {
+        Waveforms {
+            temperature_mean: 21.,
+            temperature_swing: 3.,
+            humidity_mean: 45.,
+            humidity_swing: 10.,
+            pressure: 1000.,
+            co2_baseline: 450.,
+            co2_spike: 1000.,
+            co2_spike_every: Duration::from_secs(2 * 3600),
+            co2_spike_length: Duration::from_secs(1800),
+            tvoc_baseline: 30.,
+            pm25_baseline: 5.,
+            daylight: 500.,
+            night_light: 0.5,
+            sunrise_hour: 7.,
+            sunset_hour: 19.,
+            time_scale: 1.,
+        }
+    }
+
+    // A bus frozen at `hour` o'clock.
+    fn bus_at(hour: f64) -> Bus {
+        let mut w = waveforms();
+        w.time_scale = 0.;
+        let mut bus = Bus::new(w);
+        bus.origin = hour * 3600.;
+        bus
+    }
+
+    #[test]
+    fn waveforms_follow_the_day() {
+        let w = waveforms();
+        let afternoon = w.at(15. * 3600.);
+        let night = w.at(3. * 3600.);
+        assert!((afternoon.temperature - 24.).abs() < 1e-9);
+        assert!((night.temperature - 18.).abs() < 1e-9);
+        assert!(afternoon.humidity < night.humidity);
+        assert!((w.at(13. * 3600.).lux - 500.5).abs() < 1e-9);
+        assert_eq!(0.5, night.lux);
+
+        // Spikes peak halfway through, then settle back to the baseline.
+        assert!((w.at(900.).co2 - 1450.).abs() < 1e-9);
+        assert_eq!(450., w.at(3600.).co2);
+        assert!((w.at(2. * 3600. + 900.).tvoc - 30. * 1450. / 450.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn bme280_driver_reads_the_waveform() {
+        let mut bme = BME280::new_secondary(bus_at(15.), Delay);
+        bme.init().unwrap();
+        let m = bme.measure().unwrap();
+        assert!((m.temperature - 24.).abs() < 0.01, "{}", m.temperature);
+        assert!((m.humidity - 35.).abs() < 0.05, "{}", m.humidity);
+        assert!((m.pressure - 100_000.).abs() < 1., "{}", m.pressure);
+    }
+
+    #[test]
+    fn bme688_answers_where_it_is_placed() {
+        let chip_id = |bus: &mut Bus, address| {
+            let mut id = [0];
+            i2c::WriteRead::write_read(bus, address, &[BME280_CHIP_ID], &mut id).map(|_| id[0])
+        };
+        let mut bus = bus_at(0.);
+        assert_eq!(0x60, chip_id(&mut bus, 0x77).unwrap());
+        assert!(chip_id(&mut bus, 0x76).is_err());
+
+        let mut bus = bus_at(0.).with_bme680(super::super::BME680_DEFAULT_ADDRESS);
+        assert_eq!(0x61, chip_id(&mut bus, 0x77).unwrap());
+        let mut bus = bus_at(0.).with_bme680(0x76);
+        assert_eq!(0x61, chip_id(&mut bus, 0x76).unwrap());
+        assert_eq!(0x60, chip_id(&mut bus, 0x77).unwrap());
+    }
+
+    #[test]
+    fn sgp30_driver_sees_warm_up_then_readings() {
+        let mut sgp = Sgp30::new(bus_at(0.25), SGP30_ADDRESS, Delay);
+        assert_eq!([0, 0, 0x01, 0x23, 0x45, 0x67], sgp.serial().unwrap());
+        assert!(sgp.selftest().unwrap());
+        assert_eq!(0x22, sgp.get_feature_set().unwrap().product_version);
+        sgp.init().unwrap();
+        let m = sgp.measure().unwrap();
+        assert_eq!((400, 0), (m.co2eq_ppm, m.tvoc_ppb));
+
+        // Past warm-up, at the top of a CO₂ spike.
+        let mut bus = sgp.destroy();
+        bus.sgp.initialized = Some(Instant::now() - SGP30_WARM_UP);
+        let mut buf = [0; 6];
+        i2c::WriteRead::write_read(&mut bus, SGP30_ADDRESS, &[0x20, 0x08], &mut buf).unwrap();
+        assert_eq!([0x05, 0xAA], buf[0..2]);
+        assert_eq!(crc8(&buf[0..2]), buf[2]);
+        assert_eq!(97, u16::from_be_bytes([buf[3], buf[4]]));
+    }
+
+    #[test]
+    fn sgp30_rejects_bad_crc() {
+        let mut bus = bus_at(0.);
+        let w = 0x1234u16.to_be_bytes();
+        let good = [0x20, 0x61, w[0], w[1], crc8(&w)];
+        assert!(i2c::Write::write(&mut bus, SGP30_ADDRESS, &good).is_ok());
+        let bad = [0x20, 0x61, w[0], w[1], crc8(&w) ^ 1];
+        assert!(i2c::Write::write(&mut bus, SGP30_ADDRESS, &bad).is_err());
+        assert!(i2c::Write::write(&mut bus, 0x42, &good).is_err());
+    }
+
+    #[test]
+    fn tsl2591_counts_scale_with_gain_and_time() {
+        let mut tsl = tsl2591::Driver::new_define_integration(
+            bus_at(13.),
+            tsl2591::IntegrationTimes::_200MS,
+            tsl2591::Gain::LOW,
+        )
+        .unwrap();
+        tsl.enable().unwrap();
+        tsl.set_gain(Some(tsl2591::Gain::LOW)).unwrap();
+        let (ch0, ch1) = tsl.get_channel_data(&mut Delay).unwrap();
+        // The driver puts the low byte first.
+        let (ch0, ch1) = (ch0.swap_bytes(), ch1.swap_bytes());
+        let expected = 500.5 * 200. / 53. / (1. - 1.7 * TSL2591_IR_RATIO);
+        assert_eq!(expected.round() as u16, ch0);
+        assert_eq!((expected * TSL2591_IR_RATIO).round() as u16, ch1);
+
+        // Far too bright for MED gain: both channels clip.
+        tsl.set_gain(Some(tsl2591::Gain::MED)).unwrap();
+        let (ch0, _) = tsl.get_channel_data(&mut Delay).unwrap();
+        assert_eq!(0xFFFF, ch0);
+    }
+}
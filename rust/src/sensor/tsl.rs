@@ -14,139 +14,242 @@
 
 #![warn(clippy::all)]
 
+//! The ams TSL2591 light sensor. Gain and integration time step together
+//! through a ladder of every combination, so it ranges from a dark room (the
+//! maximum gain for 600 ms) to daylight (the low gain for 100 ms).
+
+use super::{publish_light, Environment, Mean, Sensor};
 use crate::adafruit;
+use crate::counters;
 use embedded_hal::blocking::{delay, i2c};
-use log::{debug, error};
+use log::debug;
+use std::error::Error;
+use std::fmt::Debug;
 use std::sync::mpsc;
 use std::time::{Duration, Instant};
 use tsl2591::{Gain, IntegrationTimes};
 
-const UPDATE_PERIOD: Duration = Duration::from_secs(60);
+const ADDRESS: u8 = 0x29;
+const COMMAND: u8 = 0xA0;
+const CONTROL: u8 = 0x01;
+
+// Each gain is 17-25 times the one below it, more than the whole range of
+// integration times, so stepping through every time at each gain in turn
+// orders the ladder by sensitivity.
+const GAINS: [Gain; 4] = [Gain::LOW, Gain::MED, Gain::HIGH, Gain::MAX];
+const TIMES: [IntegrationTimes; 6] = [
+    IntegrationTimes::_100MS,
+    IntegrationTimes::_200MS,
+    IntegrationTimes::_300MS,
+    IntegrationTimes::_400MS,
+    IntegrationTimes::_500MS,
+    IntegrationTimes::_600MS,
+];
+const RUNGS: usize = GAINS.len() * TIMES.len();
+// The medium gain for 200 ms.
+const START: usize = TIMES.len() + 1;
+
+// Step up below MIN_THRESHOLD counts, and down above MAX_SHARE of full
+// scale. The band between is wider than any step, so a change of setting
+// can't land outside it and step straight back.
+const MIN_THRESHOLD: u16 = 1_000;
+const MAX_SHARE: f32 = 0.75;
+
+pub struct Tsl2591<I2C, D> {
+    i2c: I2C,
+    delay: D,
+    tsl: Option<tsl2591::Driver<I2C>>,
+    // Index into the ladder; see `setting`.
+    rung: usize,
+    // Readings before this may still be from the previous setting.
+    ready_at: Instant,
+    // Whether this provides mbr.lux.
+    primary: bool,
+    // The least mbr.lux published; see `publish_light`.
+    dark_floor_lux: f32,
+    lux: Mean,
+    full_spectrum: Mean,
+    infrared: Mean,
+    // Samples since the last flush that were too bright to measure.
+    saturated: u32,
+}
 
-pub struct State<D>
+impl<I2C, D, E> Tsl2591<I2C, D>
 where
-    D: delay::DelayUs<u8> + delay::DelayMs<u8>,
+    I2C: i2c::Write<Error = E>,
+    E: Debug,
 {
-    pub sensor_is_valid: bool,
-    pub delay: D,
-    pub integ_time: IntegrationTimes,
-    pub gain: Gain,
-    pub last_update: Instant,
-    pub lux_sum: f32,
-    pub full_spectrum_sum: f32,
-    pub infrared_sum: f32,
-    pub count: i32,
+    pub fn new(i2c: I2C, delay: D, primary: bool, dark_floor_lux: f32) -> Self {
+        Tsl2591 {
+            i2c,
+            delay,
+            tsl: None,
+            rung: START,
+            ready_at: Instant::now(),
+            primary,
+            dark_floor_lux,
+            lux: Mean::default(),
+            full_spectrum: Mean::default(),
+            infrared: Mean::default(),
+            saturated: 0,
+        }
+    }
+
+    // The driver's set_gain() and set_timing() each write the other half of
+    // the control register as it was when the driver was made, so both are
+    // written here at once. The first full integration at the new setting is
+    // over within two integration times.
+    fn configure(&mut self) -> Result<(), Box<dyn Error>> {
+        let (gain, integ_time) = setting(self.rung);
+        self.i2c
+            .write(ADDRESS, &[COMMAND | CONTROL, integ_time as u8 | gain as u8])
+            .map_err(|e| format!("setting not changed: {:?}", e))?;
+        self.ready_at =
+            Instant::now() + 2 * Duration::from_millis(integration_ms(integ_time).into());
+        Ok(())
+    }
 }
 
-pub fn poll<I2C, D, E>(
-    tsl: &mut tsl2591::Driver<I2C>,
-    state: &mut State<D>,
-    tx: &mpsc::Sender<adafruit::Metric>,
-) where
-    I2C: i2c::Read<Error = E> + i2c::Write<Error = E> + i2c::WriteRead<Error = E>,
+impl<I2C, D, E> Sensor for Tsl2591<I2C, D>
+where
+    I2C: i2c::Write<Error = E> + i2c::WriteRead<Error = E> + Clone,
     D: delay::DelayUs<u8> + delay::DelayMs<u8>,
+    E: Debug,
 {
-    let (ch_0, ch_1) = tsl
-        .get_channel_data(&mut state.delay)
-        .unwrap_or((0xFFFF, 0xFFFF));
-    let lux = calculate_lux(state, ch_0, ch_1);
-
-    if !lux.is_nan() {
-        debug!("TSL2591: lux = {}", lux);
-        state.lux_sum += lux;
-        state.full_spectrum_sum += ch_0 as f32 / gain_factor(state.gain);
-        state.infrared_sum += ch_1 as f32 / gain_factor(state.gain);
-        state.count += 1;
-    }
-
-    let gain_before = state.gain;
-    adjust_gain(state, ch_0, ch_1);
-    if state.gain as u8 != gain_before as u8 {
-        match tsl.set_gain(Some(state.gain)) {
-            Ok(_) => debug!("TSL2591 gain: {}", gain_factor(state.gain)),
-            Err(_) => error!("TSL2591 set_gain() failed"),
-        };
+    fn name(&self) -> &'static str {
+        "tsl2591"
     }
 
-    let now = Instant::now();
-    if now.duration_since(state.last_update) > UPDATE_PERIOD {
-        if state.count > 0 {
-            tx.send(adafruit::Metric {
-                feed: "mbr-tsl2591.lux".into(),
-                value: state.lux_sum / state.count as f32,
-            })
-            .unwrap();
-            tx.send(adafruit::Metric {
-                feed: "mbr-tsl2591.full-spectrum".into(),
-                value: state.full_spectrum_sum / state.count as f32,
-            })
+    fn init(&mut self) -> Result<(), Box<dyn Error>> {
+        self.rung = START;
+        let (gain, integ_time) = setting(self.rung);
+        let mut tsl = tsl2591::Driver::new_define_integration(self.i2c.clone(), integ_time, gain)
+            .map_err(|e| format!("{:?}", e))?;
+        tsl.enable().map_err(|e| format!("not enabled: {:?}", e))?;
+        self.tsl = Some(tsl);
+        self.configure()
+    }
+
+    fn sample(&mut self, _env: &mut Environment) -> Result<(), Box<dyn Error>> {
+        if Instant::now() < self.ready_at {
+            return Ok(());
+        }
+        let tsl = self.tsl.as_mut().ok_or("not initialized")?;
+        // The driver reads the little-endian channel registers as big-endian.
+        let (ch_0, ch_1) = tsl
+            .get_channel_data(&mut self.delay)
+            .map(|(ch_0, ch_1)| (ch_0.swap_bytes(), ch_1.swap_bytes()))
+            .map_err(|e| format!("{:?}", e))?;
+        let (gain, integ_time) = setting(self.rung);
+        match calculate_lux(integ_time, gain, ch_0, ch_1) {
+            Some(lux) => {
+                debug!("TSL2591: lux = {}", lux);
+                self.lux.add(lux);
+                self.full_spectrum.add(ch_0 as f32 / gain_factor(gain));
+                self.infrared.add(ch_1 as f32 / gain_factor(gain));
+            }
+            None => {
+                // Left out of the means; the ladder steps down for the next.
+                debug!("TSL2591: saturated, ch_0 = {}, ch_1 = {}", ch_0, ch_1);
+                self.saturated += 1;
+                counters::inc("tsl2591_saturated_samples");
+            }
+        }
+
+        let rung = adjust_setting(self.rung, ch_0, ch_1);
+        if rung != self.rung {
+            self.rung = rung;
+            self.configure()?;
+            let (gain, integ_time) = setting(rung);
+            debug!(
+                "TSL2591 gain: {}, integration time: {} ms",
+                gain_factor(gain),
+                integration_ms(integ_time)
+            );
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, _env: &mut Environment, tx: &mpsc::Sender<adafruit::Metric>) {
+        let saturated = std::mem::take(&mut self.saturated);
+        let means = match (
+            self.lux.take(),
+            self.full_spectrum.take(),
+            self.infrared.take(),
+        ) {
+            (Some(l), Some(f), Some(i)) => Some((l, f, i)),
+            _ => None,
+        };
+        if means.is_none() && saturated == 0 {
+            return;
+        }
+        // Sent even when every sample saturated and there is nothing else,
+        // so that glare shows up as more than a gap.
+        tx.send(adafruit::Metric::new(
+            "mbr-tsl2591.saturated",
+            i64::from(saturated),
+        ))
+        .unwrap();
+        let (lux, full_spectrum, infrared) = match means {
+            Some(means) => means,
+            None => return,
+        };
+        let (gain, integ_time) = setting(self.rung);
+
+        tx.send(adafruit::Metric::new("mbr-tsl2591.lux", lux))
             .unwrap();
-            tx.send(adafruit::Metric {
-                feed: "mbr-tsl2591.infrared".into(),
-                value: state.infrared_sum / state.count as f32,
-            })
+        tx.send(adafruit::Metric::new(
+            "mbr-tsl2591.full-spectrum",
+            full_spectrum,
+        ))
+        .unwrap();
+        tx.send(adafruit::Metric::new("mbr-tsl2591.infrared", infrared))
             .unwrap();
-            tx.send(adafruit::Metric {
-                feed: "mbr-tsl2591.gain".into(),
-                value: gain_factor(state.gain) as f32,
-            })
+        tx.send(adafruit::Metric::new("mbr-tsl2591.gain", gain_factor(gain)))
             .unwrap();
+        tx.send(adafruit::Metric::new(
+            "mbr-tsl2591.integration-time",
+            i64::from(integration_ms(integ_time)),
+        ))
+        .unwrap();
 
-            tx.send(adafruit::Metric {
-                feed: "mbr.lux".into(),
-                value: state.lux_sum / state.count as f32,
-            })
-            .unwrap();
-            tx.send(adafruit::Metric {
-                feed: "mbr.lux-db".into(),
-                value: 10. * (state.lux_sum / state.count as f32).log10(),
-            })
-            .unwrap();
+        if self.primary {
+            publish_light(lux, self.dark_floor_lux, tx);
         }
-
-        state.lux_sum = 0.0;
-        state.full_spectrum_sum = 0.0;
-        state.infrared_sum = 0.0;
-        state.count = 0;
-        state.last_update = now;
     }
 }
 
-fn adjust_gain<D>(state: &mut State<D>, ch_0: u16, ch_1: u16)
-where
-    D: delay::DelayUs<u8> + delay::DelayMs<u8>,
-{
-    const MIN_THRESHOLD: u16 = 1_000;
-    const MAX_THRESHOLD: u16 = 50_000;
-
-    if ch_0 == 0xFFFF || ch_1 == 0xFFFF {
-        // Lower the gain if we are clipping.
-        state.gain = next_gain_down(state.gain);
-    } else if ch_0 == 0 || ch_1 == 0 {
-        // Raise the gain if we have no signal.
-        state.gain = next_gain_up(state.gain);
-    } else if ch_0 < MIN_THRESHOLD && ch_1 < MIN_THRESHOLD {
-        // Raise the gain to get more resolution.
-        state.gain = next_gain_up(state.gain);
-    } else if ch_0 > MAX_THRESHOLD && ch_1 > MAX_THRESHOLD {
-        // Lower the gain to avoid clipping.
-        state.gain = next_gain_down(state.gain);
-    }
+// The gain and integration time at a rung of the ladder, from least to most
+// sensitive.
+fn setting(rung: usize) -> (Gain, IntegrationTimes) {
+    (GAINS[rung / TIMES.len()], TIMES[rung % TIMES.len()])
 }
 
-fn next_gain_up(gain: Gain) -> Gain {
-    match gain {
-        Gain::LOW => Gain::MED,
-        Gain::MED => Gain::HIGH,
-        _ => Gain::MAX,
+// The next rung of the ladder. Channel 0 sees visible and infrared light,
+// so it alone says whether there is enough signal; channel 1 can read zero
+// under LED lighting.
+fn adjust_setting(rung: usize, ch_0: u16, ch_1: u16) -> usize {
+    let (_, integ_time) = setting(rung);
+    let max_threshold = MAX_SHARE * f32::from(full_scale(integ_time));
+
+    if f32::from(ch_0.max(ch_1)) > max_threshold {
+        // Lower the sensitivity if we are clipping, or about to.
+        rung.saturating_sub(1)
+    } else if ch_0 < MIN_THRESHOLD {
+        // Raise it if we have no signal, or too little resolution.
+        (rung + 1).min(RUNGS - 1)
+    } else {
+        rung
     }
 }
 
-fn next_gain_down(gain: Gain) -> Gain {
-    match gain {
-        Gain::MAX => Gain::HIGH,
-        Gain::HIGH => Gain::MED,
-        _ => Gain::LOW,
+// The most either channel can count: the MAX COUNT column of the ATIME table
+// under the datasheet's Control Register (0x01), 37888 at 100 ms and 65535
+// at 200 ms and longer.
+fn full_scale(integ_time: IntegrationTimes) -> u16 {
+    match integ_time {
+        IntegrationTimes::_100MS => 37_888,
+        _ => 0xFFFF,
     }
 }
 
@@ -159,38 +262,219 @@ fn gain_factor(gain: Gain) -> f32 {
     }
 }
 
-fn calculate_lux<D>(state: &State<D>, ch_0: u16, ch_1: u16) -> f32
-where
-    D: delay::DelayUs<u8> + delay::DelayMs<u8>,
-{
+fn integration_ms(integ_time: IntegrationTimes) -> u16 {
+    match integ_time {
+        IntegrationTimes::_100MS => 100,
+        IntegrationTimes::_200MS => 200,
+        IntegrationTimes::_300MS => 300,
+        IntegrationTimes::_400MS => 400,
+        IntegrationTimes::_500MS => 500,
+        IntegrationTimes::_600MS => 600,
+    }
+}
+
+// Lux from the channel counts, or None if either channel is saturated: the
+// counts then only say that it is brighter than the setting can measure.
+fn calculate_lux(integ_time: IntegrationTimes, gain: Gain, ch_0: u16, ch_1: u16) -> Option<f32> {
     const TSL2591_LUX_DF: f32 = 53.;
     const CH1_IR_COEFF: f32 = 1.7; // For subtracting IR from full spectrum.
     const CH1_VISIBLE_COEFF: f32 = 1.0; // For estimating visible from IR.
-    const OVERFLOW: u16 = 0xFFFF;
 
-    if (ch_0 == OVERFLOW) && (ch_1 == OVERFLOW) {
-        // Signal an overflow.
-        return f32::NAN;
+    let full_scale = full_scale(integ_time);
+    if ch_0 >= full_scale || ch_1 >= full_scale {
+        return None;
     }
 
-    let a_time = match state.integ_time {
-        IntegrationTimes::_100MS => 100.,
-        IntegrationTimes::_200MS => 200.,
-        IntegrationTimes::_300MS => 300.,
-        IntegrationTimes::_400MS => 400.,
-        IntegrationTimes::_500MS => 500.,
-        IntegrationTimes::_600MS => 600.,
-    };
-
-    let a_gain = gain_factor(state.gain);
+    let a_time = f32::from(integration_ms(integ_time));
+    let a_gain = gain_factor(gain);
 
-    let lux = if ch_0 != OVERFLOW && ch_0 as f32 > CH1_IR_COEFF * ch_1 as f32 {
+    let lux = if ch_0 as f32 > CH1_IR_COEFF * ch_1 as f32 {
         ch_0 as f32 - CH1_IR_COEFF * ch_1 as f32
-    } else if ch_1 != OVERFLOW {
-        ch_1 as f32 * CH1_VISIBLE_COEFF
     } else {
-        f32::NAN
+        ch_1 as f32 * CH1_VISIBLE_COEFF
     };
 
-    lux * TSL2591_LUX_DF / a_time / a_gain
+    Some(lux * TSL2591_LUX_DF / a_time / a_gain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sensitivity(rung: usize) -> f32 {
+        let (gain, integ_time) = setting(rung);
+        gain_factor(gain) * f32::from(integration_ms(integ_time))
+    }
+
+    #[test]
+    fn ladder_covers_every_setting_in_order() {
+        assert_eq!(24, RUNGS);
+        assert_eq!(Gain::MED as u8, setting(START).0 as u8);
+        assert_eq!(200, integration_ms(setting(START).1));
+        assert_eq!(100. * 1., sensitivity(0));
+        assert_eq!(600. * 9876., sensitivity(RUNGS - 1));
+        for rung in 1..RUNGS {
+            assert!(sensitivity(rung) > sensitivity(rung - 1), "{}", rung);
+        }
+    }
+
+    #[test]
+    fn steps_cannot_cross_the_hysteresis_band() {
+        for rung in 1..RUNGS {
+            let step = sensitivity(rung) / sensitivity(rung - 1);
+            let (_, below) = setting(rung - 1);
+            let (_, above) = setting(rung);
+            // Just too dim below stays under the threshold above...
+            let up = f32::from(MIN_THRESHOLD) * step;
+            assert!(up < MAX_SHARE * f32::from(full_scale(above)), "{}", rung);
+            // ...and just too bright above has enough signal below.
+            let down = MAX_SHARE * f32::from(full_scale(above)) / step;
+            assert!(down > f32::from(MIN_THRESHOLD), "{}", rung);
+            assert!(down < MAX_SHARE * f32::from(full_scale(below)), "{}", rung);
+        }
+    }
+
+    #[test]
+    fn setting_follows_the_signal() {
+        assert_eq!(START - 1, adjust_setting(START, 0xFFFF, 0xFFFF));
+        assert_eq!(START - 1, adjust_setting(START, 50_000, 10_000));
+        assert_eq!(START + 1, adjust_setting(START, 0, 0));
+        assert_eq!(START + 1, adjust_setting(START, 999, 200));
+        assert_eq!(START, adjust_setting(START, 20_000, 4_000));
+        // No infrared, as under LEDs.
+        assert_eq!(START, adjust_setting(START, 20_000, 0));
+        // 100 ms tops out early.
+        assert_eq!(START - 2, adjust_setting(START - 1, 30_000, 6_000));
+        assert_eq!(0, adjust_setting(0, 0xFFFF, 0xFFFF));
+        assert_eq!(RUNGS - 1, adjust_setting(RUNGS - 1, 0, 0));
+    }
+
+    #[test]
+    fn lux_at_every_setting() {
+        for rung in 0..RUNGS {
+            let (gain, integ_time) = setting(rung);
+            let full_scale = full_scale(integ_time);
+            // Either channel saturated, or both.
+            assert_eq!(
+                None,
+                calculate_lux(integ_time, gain, full_scale, 0),
+                "{}",
+                rung
+            );
+            assert_eq!(
+                None,
+                calculate_lux(integ_time, gain, 1_000, full_scale),
+                "{}",
+                rung
+            );
+            assert_eq!(
+                None,
+                calculate_lux(integ_time, gain, 0xFFFF, 0xFFFF),
+                "{}",
+                rung
+            );
+            // No light at all.
+            assert_eq!(Some(0.), calculate_lux(integ_time, gain, 0, 0), "{}", rung);
+            // Infrared subtracted, then scaled by the setting.
+            let lux = calculate_lux(integ_time, gain, 1_000, 200).unwrap();
+            let expected = (1_000. - 1.7 * 200.) * 53. / sensitivity(rung);
+            assert!((lux - expected).abs() <= expected * 1e-6, "{}", rung);
+            // Mostly infrared: channel 1 stands in for the visible light.
+            let lux = calculate_lux(integ_time, gain, 1_000, 800).unwrap();
+            let expected = 800. * 53. / sensitivity(rung);
+            assert!((lux - expected).abs() <= expected * 1e-6, "{}", rung);
+            // Just short of full scale still counts.
+            let lux = calculate_lux(integ_time, gain, full_scale - 1, 0).unwrap();
+            assert!(lux.is_finite() && lux > 0., "{}", rung);
+        }
+    }
+
+    #[cfg(feature = "sim")]
+    #[test]
+    fn simulated_darkness_and_glare() {
+        use crate::sensor::sim;
+
+        for (room_lux, rung, expected) in [(0., RUNGS - 1, Some(0.001)), (100_000., 0, None)] {
+            let config = crate::config::SimConfig {
+                daylight_lux: 0.,
+                night_lux: room_lux,
+                ..Default::default()
+            };
+            let bus = shared_bus::BusManagerSimple::new(sim::Bus::new(config.waveforms()));
+            let mut tsl = Tsl2591::new(bus.acquire_i2c(), sim::Delay, true, 0.001);
+            tsl.init().unwrap();
+            tsl.rung = rung;
+            tsl.configure().unwrap();
+            tsl.ready_at = Instant::now();
+            let mut env = Environment::default();
+            tsl.sample(&mut env).unwrap();
+            let (tx, rx) = mpsc::channel();
+            tsl.flush(&mut env, &tx);
+            let metrics: Vec<_> = rx.try_iter().collect();
+            let lux = metrics.iter().find(|m| m.feed == "mbr.lux");
+            let lux_db = metrics.iter().find(|m| m.feed == "mbr.lux-db");
+            match expected {
+                // Clamped, so its level is -30 dB rather than -inf.
+                Some(floor) => {
+                    assert_eq!(adafruit::Value::from(floor), lux.unwrap().value);
+                    assert_eq!(adafruit::Value::from(-30f32), lux_db.unwrap().value);
+                    let saturated = metrics.iter().find(|m| m.feed == "mbr-tsl2591.saturated");
+                    assert_eq!(adafruit::Value::Int(0), saturated.unwrap().value);
+                }
+                // Saturated, even at the least sensitive setting.
+                None => {
+                    assert_eq!(1, metrics.len());
+                    assert_eq!("mbr-tsl2591.saturated", metrics[0].feed);
+                    assert_eq!(adafruit::Value::Int(1), metrics[0].value);
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "sim")]
+    #[test]
+    fn simulated_sensor_settles_on_a_setting() {
+        use crate::sensor::sim;
+
+        // A dark bedroom needs the most sensitive setting, and an overcast
+        // day one of the least.
+        for (room_lux, rung) in [(0.005, RUNGS - 1), (5_000., 2)] {
+            let config = crate::config::SimConfig {
+                daylight_lux: 0.,
+                night_lux: room_lux,
+                ..Default::default()
+            };
+            let bus = shared_bus::BusManagerSimple::new(sim::Bus::new(config.waveforms()));
+            let mut tsl = Tsl2591::new(bus.acquire_i2c(), sim::Delay, true, 0.0002);
+            tsl.init().unwrap();
+            let mut env = Environment::default();
+            for _ in 0..RUNGS {
+                tsl.ready_at = Instant::now();
+                tsl.sample(&mut env).unwrap();
+            }
+            assert_eq!(rung, tsl.rung);
+
+            // Only the readings at the final setting.
+            tsl.lux.take();
+            tsl.ready_at = Instant::now();
+            tsl.sample(&mut env).unwrap();
+            let (tx, rx) = mpsc::channel();
+            tsl.flush(&mut env, &tx);
+            let metrics: Vec<_> = rx.try_iter().collect();
+            let value = |feed| {
+                metrics
+                    .iter()
+                    .find(|m| m.feed == feed)
+                    .unwrap()
+                    .value
+                    .to_string()
+                    .parse::<f64>()
+                    .unwrap()
+            };
+            let lux = value("mbr.lux");
+            assert!((lux - room_lux).abs() < room_lux * 0.01, "{}", lux);
+            let ms = integration_ms(setting(rung).1);
+            assert_eq!(f64::from(ms), value("mbr-tsl2591.integration-time"));
+        }
+    }
 }
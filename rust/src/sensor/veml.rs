@@ -0,0 +1,343 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+//! The Vishay VEML7700 ambient light sensor. Gain and integration time step
+//! through the ladder from Vishay's application note ("Designing the
+//! VEML7700 Into an Application"), with thresholds like those of
+//! `tsl::adjust_setting`. Readings above 1000 lx get the app note's
+//! non-linearity correction.
+
+use super::{publish_light, Environment, Mean, Sensor};
+use crate::adafruit;
+use embedded_hal::blocking::i2c;
+use log::debug;
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+const ADDRESS: u8 = 0x10;
+
+const ALS_CONF_0: u8 = 0x00;
+const POWER_SAVING: u8 = 0x03;
+const ALS: u8 = 0x04;
+const WHITE: u8 = 0x05;
+const ID: u8 = 0x07;
+const DEVICE_ID: u8 = 0x81;
+
+// Lux per count at gain 2 and 800 ms; it scales inversely with both.
+const RESOLUTION: f32 = 0.0036;
+const CORRECTION_ABOVE_LUX: f32 = 1000.0;
+
+const MIN_THRESHOLD: u16 = 1_000;
+const MAX_THRESHOLD: u16 = 50_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Gain {
+    Eighth,
+    Quarter,
+    One,
+    Two,
+}
+
+impl Gain {
+    fn factor(self) -> f32 {
+        match self {
+            Gain::Eighth => 0.125,
+            Gain::Quarter => 0.25,
+            Gain::One => 1.,
+            Gain::Two => 2.,
+        }
+    }
+
+    // ALS_GAIN, bits 12:11 of ALS_CONF_0.
+    fn bits(self) -> u16 {
+        match self {
+            Gain::One => 0b00,
+            Gain::Two => 0b01,
+            Gain::Eighth => 0b10,
+            Gain::Quarter => 0b11,
+        }
+    }
+}
+
+// ALS_IT, bits 9:6 of ALS_CONF_0.
+fn integration_time_bits(ms: u16) -> u16 {
+    match ms {
+        25 => 0b1100,
+        50 => 0b1000,
+        100 => 0b0000,
+        200 => 0b0001,
+        400 => 0b0010,
+        _ => 0b0011,
+    }
+}
+
+// Gain and integration time (ms) from least to most sensitive, in the app
+// note's order: start at 1/8 and 100 ms, shorten the integration time if
+// that is too bright, and otherwise raise the gain before lengthening it.
+const LADDER: [(Gain, u16); 9] = [
+    (Gain::Eighth, 25),
+    (Gain::Eighth, 50),
+    (Gain::Eighth, 100),
+    (Gain::Quarter, 100),
+    (Gain::One, 100),
+    (Gain::Two, 100),
+    (Gain::Two, 200),
+    (Gain::Two, 400),
+    (Gain::Two, 800),
+];
+const START: usize = 2;
+
+pub struct Veml7700<I2C> {
+    i2c: I2C,
+    // Whether this provides mbr.lux.
+    primary: bool,
+    // The least mbr.lux published; see `publish_light`.
+    dark_floor_lux: f32,
+    // Index into LADDER.
+    setting: usize,
+    // Readings before this may still be from the previous setting.
+    ready_at: Instant,
+    lux: Mean,
+    white: Mean,
+}
+
+impl<I2C, E> Veml7700<I2C>
+where
+    I2C: i2c::Write<Error = E> + i2c::WriteRead<Error = E>,
+    E: Debug,
+{
+    pub fn new(i2c: I2C, primary: bool, dark_floor_lux: f32) -> Self {
+        Veml7700 {
+            i2c,
+            primary,
+            dark_floor_lux,
+            setting: START,
+            ready_at: Instant::now(),
+            lux: Mean::default(),
+            white: Mean::default(),
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u16) -> Result<(), Box<dyn Error>> {
+        let [lsb, msb] = value.to_le_bytes();
+        self.i2c
+            .write(ADDRESS, &[register, lsb, msb])
+            .map_err(|e| format!("{:?}", e))?;
+        Ok(())
+    }
+
+    fn read(&mut self, register: u8) -> Result<u16, Box<dyn Error>> {
+        let mut bytes = [0; 2];
+        self.i2c
+            .write_read(ADDRESS, &[register], &mut bytes)
+            .map_err(|e| format!("{:?}", e))?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    // Powers on with the current setting. The first full integration at the
+    // new setting is over within two integration times.
+    fn configure(&mut self) -> Result<(), Box<dyn Error>> {
+        let (gain, ms) = LADDER[self.setting];
+        self.write(
+            ALS_CONF_0,
+            gain.bits() << 11 | integration_time_bits(ms) << 6,
+        )?;
+        self.ready_at = Instant::now() + 2 * Duration::from_millis(ms.into());
+        Ok(())
+    }
+}
+
+impl<I2C, E> Sensor for Veml7700<I2C>
+where
+    I2C: i2c::Write<Error = E> + i2c::WriteRead<Error = E>,
+    E: Debug,
+{
+    fn name(&self) -> &'static str {
+        "veml7700"
+    }
+
+    fn init(&mut self) -> Result<(), Box<dyn Error>> {
+        let id = self.read(ID)?;
+        if id & 0xFF != u16::from(DEVICE_ID) {
+            return Err(format!("unexpected device ID {:#06x}", id).into());
+        }
+        self.write(POWER_SAVING, 0)?;
+        self.setting = START;
+        self.configure()
+    }
+
+    fn sample(&mut self, _env: &mut Environment) -> Result<(), Box<dyn Error>> {
+        if Instant::now() < self.ready_at {
+            return Ok(());
+        }
+        let als = self.read(ALS)?;
+        let white = self.read(WHITE)?;
+        let (gain, ms) = LADDER[self.setting];
+        if als != 0xFFFF {
+            let lux = calculate_lux(gain, ms, als);
+            debug!("VEML7700: lux = {}", lux);
+            self.lux.add(lux);
+            self.white.add(calculate_lux(gain, ms, white));
+        }
+
+        let setting = adjust_setting(self.setting, als);
+        if setting != self.setting {
+            self.setting = setting;
+            self.configure()?;
+            debug!("VEML7700 setting: {:?}", LADDER[setting]);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, _env: &mut Environment, tx: &mpsc::Sender<adafruit::Metric>) {
+        let (lux, white) = match (self.lux.take(), self.white.take()) {
+            (Some(l), Some(w)) => (l, w),
+            _ => return,
+        };
+        let (gain, ms) = LADDER[self.setting];
+
+        tx.send(adafruit::Metric::new("mbr-veml7700.lux", lux))
+            .unwrap();
+        tx.send(adafruit::Metric::new("mbr-veml7700.white", white))
+            .unwrap();
+        tx.send(adafruit::Metric::new("mbr-veml7700.gain", gain.factor()))
+            .unwrap();
+        tx.send(adafruit::Metric::new(
+            "mbr-veml7700.integration-time",
+            i64::from(ms),
+        ))
+        .unwrap();
+
+        if self.primary {
+            publish_light(lux, self.dark_floor_lux, tx);
+        }
+    }
+}
+
+// The next rung of LADDER, by the same rules as `tsl::adjust_setting`.
+fn adjust_setting(setting: usize, als: u16) -> usize {
+    if als == 0xFFFF || als > MAX_THRESHOLD {
+        // Clipping, or about to.
+        setting.saturating_sub(1)
+    } else if als < MIN_THRESHOLD {
+        // No signal, or too little resolution.
+        (setting + 1).min(LADDER.len() - 1)
+    } else {
+        setting
+    }
+}
+
+fn calculate_lux(gain: Gain, ms: u16, counts: u16) -> f32 {
+    let resolution = RESOLUTION * (800. / f32::from(ms)) * (2. / gain.factor());
+    let lux = f32::from(counts) * resolution;
+    if lux > CORRECTION_ABOVE_LUX {
+        correct(lux)
+    } else {
+        lux
+    }
+}
+
+// The app note's polynomial for the sensor's non-linearity in bright light.
+pub(super) fn correct(lux: f32) -> f32 {
+    let lux = f64::from(lux);
+    (6.0135e-13 * lux.powi(4) - 9.3924e-9 * lux.powi(3) + 8.1488e-5 * lux.powi(2) + 1.0023 * lux)
+        as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ladder_gets_more_sensitive() {
+        let sensitivity = |(gain, ms): (Gain, u16)| gain.factor() * f32::from(ms);
+        for pair in LADDER.windows(2) {
+            let step = sensitivity(pair[1]) / sensitivity(pair[0]);
+            // Small enough steps that MIN_THRESHOLD doesn't step straight
+            // past MAX_THRESHOLD.
+            assert!(step > 1. && step <= 4., "{:?}", pair);
+        }
+    }
+
+    #[test]
+    fn setting_steps_like_the_tsl2591() {
+        assert_eq!(START - 1, adjust_setting(START, 0xFFFF));
+        assert_eq!(START - 1, adjust_setting(START, 50_001));
+        assert_eq!(START + 1, adjust_setting(START, 0));
+        assert_eq!(START + 1, adjust_setting(START, 999));
+        assert_eq!(START, adjust_setting(START, 20_000));
+        assert_eq!(0, adjust_setting(0, 0xFFFF));
+        assert_eq!(LADDER.len() - 1, adjust_setting(LADDER.len() - 1, 0));
+    }
+
+    #[test]
+    fn lux_is_scaled_and_corrected() {
+        // The datasheet's resolutions.
+        assert_eq!(0.0036, calculate_lux(Gain::Two, 800, 1));
+        assert!((calculate_lux(Gain::Eighth, 25, 1) - 1.8432).abs() < 1e-6);
+        assert!((calculate_lux(Gain::One, 100, 10_000) - 576.).abs() < 1e-3);
+        // Corrected above 1000 lx: at 10,000 raw lux it reads a third low.
+        let lux = calculate_lux(Gain::Eighth, 100, 21_701);
+        assert!((lux - 14_793.).abs() < 5., "{}", lux);
+    }
+
+    #[cfg(feature = "sim")]
+    #[test]
+    fn simulated_sensor_settles_on_a_setting() {
+        use crate::sensor::sim;
+
+        // A dim room needs the most sensitive setting; bright daylight (read
+        // through the non-linearity) is fine where the app note starts.
+        for (room_lux, setting) in [(5., LADDER.len() - 1), (20_000., START)] {
+            let config = crate::config::SimConfig {
+                daylight_lux: 0.,
+                night_lux: room_lux,
+                ..Default::default()
+            };
+            let mut veml = Veml7700::new(sim::Bus::new(config.waveforms()), true, 0.0002);
+            veml.init().unwrap();
+            let mut env = Environment::default();
+            for _ in 0..10 {
+                veml.ready_at = Instant::now();
+                veml.sample(&mut env).unwrap();
+            }
+            assert_eq!(setting, veml.setting);
+
+            // Only the readings at the final setting.
+            veml.lux.take();
+            veml.white.take();
+            veml.ready_at = Instant::now();
+            veml.sample(&mut env).unwrap();
+            let (tx, rx) = mpsc::channel();
+            veml.flush(&mut env, &tx);
+            let lux = rx
+                .try_iter()
+                .find(|m| m.feed == "mbr.lux")
+                .unwrap()
+                .value
+                .to_string()
+                .parse::<f32>()
+                .unwrap();
+            assert!(
+                (lux - room_lux as f32).abs() < room_lux as f32 / 100.,
+                "{}",
+                lux
+            );
+        }
+    }
+}
@@ -0,0 +1,295 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+use crate::adafruit::Metric;
+use crate::counters;
+
+use log::{debug, error, info, warn};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+
+// Metrics buffered per sink before the dispatcher starts dropping them for
+// that sink; a few minutes' worth at the usual rates.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// A destination for metrics. Each sink runs on its own thread, so it may
+/// block on I/O without holding up the others.
+pub trait Sink: Send {
+    /// Short name for log messages.
+    fn name(&self) -> &'static str;
+
+    /// Accepts a metric. Delivery may be deferred until `flush`.
+    fn send(&mut self, metric: Metric);
+
+    /// Delivers whatever is due. Returns when it should be called again, or
+    /// None if nothing is outstanding.
+    fn flush(&mut self) -> Option<Instant>;
+
+    /// Makes a final delivery attempt before the program exits.
+    fn shutdown(&mut self);
+}
+
+/// A feed name taken apart: "mbr-bme280.temperature" is location "mbr",
+/// sensor "bme280" and quantity "temperature"; "weather.temp" has no sensor.
+#[derive(Debug, PartialEq)]
+pub struct FeedName<'a> {
+    pub location: &'a str,
+    pub sensor: Option<&'a str>,
+    pub quantity: &'a str,
+}
+
+impl<'a> FeedName<'a> {
+    pub fn parse(feed: &'a str) -> FeedName<'a> {
+        let (group, quantity) = feed.split_once('.').unwrap_or((feed, "value"));
+        let (location, sensor) = match group.split_once('-') {
+            Some((l, s)) => (l, Some(s)),
+            None => (group, None),
+        };
+        FeedName {
+            location,
+            sensor,
+            quantity,
+        }
+    }
+}
+
+struct Output {
+    name: &'static str,
+    tx: mpsc::SyncSender<Metric>,
+    thread: thread::JoinHandle<()>,
+    dropped: u64,
+}
+
+/// Copies every metric from `rx` to each sink until all producers hang up,
+/// then shuts the sinks down. A sink that falls behind loses metrics rather
+/// than stalling the rest. Metrics that are NaN or infinite go to none of
+/// them.
+pub fn dispatcher(rx: mpsc::Receiver<Metric>, sinks: Vec<Box<dyn Sink>>) {
+    info!("dispatcher starting with {} sinks", sinks.len());
+    let mut outputs: Vec<Output> = sinks
+        .into_iter()
+        .map(|sink| {
+            let name = sink.name();
+            let (tx, sink_rx) = mpsc::sync_channel(QUEUE_CAPACITY);
+            let thread = thread::spawn(move || run(sink, sink_rx));
+            Output {
+                name,
+                tx,
+                thread,
+                dropped: 0,
+            }
+        })
+        .collect();
+
+    for m in rx {
+        if !m.value.is_finite() {
+            counters::inc("sink_metrics_not_finite");
+            warn!("{} is {:?}; not sent", m.feed, m.value);
+            continue;
+        }
+        for o in &mut outputs {
+            match o.tx.try_send(m.clone()) {
+                Ok(()) => {}
+                Err(mpsc::TrySendError::Full(_)) => {
+                    o.dropped += 1;
+                    counters::inc("sink_metrics_dropped");
+                    // Don't flood the log while a sink is stuck.
+                    if o.dropped.is_power_of_two() {
+                        warn!("{} sink is behind; {} metrics dropped", o.name, o.dropped);
+                    }
+                }
+                Err(mpsc::TrySendError::Disconnected(_)) => {
+                    o.dropped += 1;
+                    counters::inc("sink_metrics_dropped");
+                }
+            }
+        }
+    }
+
+    for o in outputs {
+        drop(o.tx);
+        info!("Waiting for {} sink...", o.name);
+        if o.thread.join().is_err() {
+            error!("{} sink panicked", o.name);
+        }
+        if o.dropped > 0 {
+            warn!("{} sink dropped {} metrics in total", o.name, o.dropped);
+        }
+    }
+    info!("dispatcher finished");
+}
+
+fn run(mut sink: Box<dyn Sink>, rx: mpsc::Receiver<Metric>) {
+    info!("{} sink starting", sink.name());
+    // None when there is nothing to deliver; otherwise when to try next.
+    let mut wake: Option<Instant> = None;
+    loop {
+        let received = match wake {
+            None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            Some(t) => rx.recv_timeout(t.saturating_duration_since(Instant::now())),
+        };
+        match received {
+            Ok(m) => {
+                debug!("{} received {:?}", sink.name(), m);
+                sink.send(m);
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+        wake = sink.flush();
+    }
+    sink.shutdown();
+    info!("{} sink finished", sink.name());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    // Records everything it sees.
+    struct Recorder {
+        seen: Arc<Mutex<Vec<String>>>,
+        shut_down: Arc<Mutex<bool>>,
+    }
+
+    impl Sink for Recorder {
+        fn name(&self) -> &'static str {
+            "recorder"
+        }
+
+        fn send(&mut self, metric: Metric) {
+            self.seen.lock().unwrap().push(metric.feed);
+        }
+
+        fn flush(&mut self) -> Option<Instant> {
+            None
+        }
+
+        fn shutdown(&mut self) {
+            *self.shut_down.lock().unwrap() = true;
+        }
+    }
+
+    // Blocks on its first metric until released.
+    struct Stuck {
+        release: mpsc::Receiver<()>,
+    }
+
+    impl Sink for Stuck {
+        fn name(&self) -> &'static str {
+            "stuck"
+        }
+
+        fn send(&mut self, _metric: Metric) {
+            let _ = self.release.recv();
+        }
+
+        fn flush(&mut self) -> Option<Instant> {
+            None
+        }
+
+        fn shutdown(&mut self) {}
+    }
+
+    fn metric(feed: &str) -> Metric {
+        Metric::new(feed, 1.0)
+    }
+
+    #[test]
+    fn feed_names_are_taken_apart() {
+        assert_eq!(
+            FeedName {
+                location: "mbr",
+                sensor: Some("bme280"),
+                quantity: "temperature"
+            },
+            FeedName::parse("mbr-bme280.temperature")
+        );
+        assert_eq!(
+            FeedName {
+                location: "finance",
+                sensor: None,
+                quantity: "binance-btcusdt"
+            },
+            FeedName::parse("finance.binance-btcusdt")
+        );
+        assert_eq!("value", FeedName::parse("bare").quantity);
+    }
+
+    #[test]
+    fn non_finite_metrics_are_not_sent() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sinks: Vec<Box<dyn Sink>> = vec![Box::new(Recorder {
+            seen: seen.clone(),
+            shut_down: Arc::new(Mutex::new(false)),
+        })];
+        let (tx, rx) = mpsc::channel();
+        for (feed, value) in [
+            ("mbr.lux", 0.0),
+            ("mbr.lux-db", f32::NEG_INFINITY),
+            ("mbr-tsl2591.lux", f32::NAN),
+            ("mbr.temperature", 70.0),
+        ] {
+            tx.send(Metric::new(feed, value)).unwrap();
+        }
+        drop(tx);
+        dispatcher(rx, sinks);
+        assert_eq!(vec!["mbr.lux", "mbr.temperature"], *seen.lock().unwrap());
+    }
+
+    #[test]
+    fn stuck_sink_does_not_block_others() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let shut_down = Arc::new(Mutex::new(false));
+        let (release_tx, release) = mpsc::channel();
+        let sinks: Vec<Box<dyn Sink>> = vec![
+            Box::new(Stuck { release }),
+            Box::new(Recorder {
+                seen: seen.clone(),
+                shut_down: shut_down.clone(),
+            }),
+        ];
+        let (tx, rx) = mpsc::channel();
+        let dispatcher = thread::spawn(move || dispatcher(rx, sinks));
+
+        // Enough to overflow the stuck sink's queue, sent in steps the
+        // recorder can keep up with.
+        let wait_for = |n: usize| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while seen.lock().unwrap().len() < n {
+                assert!(Instant::now() < deadline, "recorder was held up");
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+        let total = QUEUE_CAPACITY + 100;
+        for i in 0..total {
+            tx.send(metric(&format!("f{}", i))).unwrap();
+            if i % 100 == 99 {
+                wait_for(i + 1);
+            }
+        }
+        wait_for(total);
+        assert_eq!("f0", seen.lock().unwrap()[0]);
+
+        drop(tx);
+        drop(release_tx);
+        dispatcher.join().unwrap();
+        assert!(*shut_down.lock().unwrap());
+    }
+}
@@ -0,0 +1,584 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+//! Durable outbound queue for metrics.
+//!
+//! Metrics are appended as JSON lines to numbered segment files in a directory.
+//! The sender takes them from the front in order and commits them once they
+//! are delivered. A cursor file records how far the commits have got, so
+//! anything undelivered, including metrics taken but not yet committed, is
+//! replayed in order after a restart. The cursor is saved in batches, so a
+//! crash may replay a few metrics that were already sent.
+
+use crate::adafruit;
+use crate::counters;
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const SEGMENT_EXTENSION: &str = "seg";
+const CURSOR_FILE: &str = "cursor";
+// The cursor is saved after this many commits or this long, whichever is
+// first, as well as at segment boundaries and on close.
+const CURSOR_SAVE_COMMITS: u32 = 100;
+const CURSOR_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    // Total size of all segments; the oldest segments are dropped beyond this.
+    pub max_bytes: u64,
+    // Metrics older than this are discarded instead of sent.
+    pub max_age: Duration,
+    // A new segment is started once the current one reaches this size.
+    pub segment_bytes: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stats {
+    pub dropped_overflow: u64,
+    pub dropped_expired: u64,
+    pub dropped_corrupt: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    // Enqueue time, in seconds since the Unix epoch.
+    t: u64,
+    m: adafruit::Metric,
+}
+
+#[derive(Debug)]
+struct Segment {
+    id: u64,
+    bytes: u64,
+}
+
+#[derive(Debug)]
+pub struct Spool {
+    dir: PathBuf,
+    limits: Limits,
+    // Oldest first; the last segment is the one being appended to.
+    segments: VecDeque<Segment>,
+    writer: File,
+    // Offset in the front segment of the first uncommitted record.
+    cursor: u64,
+    // The segment and offset of the next record to take.
+    read_id: u64,
+    read_offset: u64,
+    reader: Option<BufReader<File>>,
+    // The next record and the offset just past it, once read.
+    pending: Option<(adafruit::Metric, u64)>,
+    // Where each record taken but not yet committed ends, oldest first.
+    taken: VecDeque<(u64, u64)>,
+    // Commits since the cursor was last saved, and when that was.
+    unsaved_commits: u32,
+    cursor_saved_at: Instant,
+    stats: Stats,
+}
+
+impl Spool {
+    pub fn open(dir: &Path, limits: Limits) -> io::Result<Spool> {
+        fs::create_dir_all(dir)?;
+
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            if let Some(id) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+
+        let (cursor_id, cursor_offset) = read_cursor(dir).unwrap_or((0, 0));
+        let mut segments = VecDeque::new();
+        for id in ids {
+            let path = segment_path(dir, id);
+            if id < cursor_id {
+                // Already fully sent before the last shutdown.
+                fs::remove_file(path)?;
+                continue;
+            }
+            let bytes = fs::metadata(&path)?.len();
+            segments.push_back(Segment { id, bytes });
+        }
+        let cursor = match segments.front() {
+            Some(s) if s.id == cursor_id => cursor_offset,
+            _ => 0,
+        };
+
+        // Always append to a fresh segment, so a record torn by a crash never
+        // gets glued to a new one.
+        let next_id = segments.back().map_or(cursor_id, |s| s.id) + 1;
+        let writer = create_segment(dir, next_id)?;
+        segments.push_back(Segment {
+            id: next_id,
+            bytes: 0,
+        });
+
+        let read_id = segments.front().unwrap().id;
+        let spool = Spool {
+            dir: dir.to_owned(),
+            limits,
+            segments,
+            writer,
+            cursor,
+            read_id,
+            read_offset: cursor,
+            reader: None,
+            pending: None,
+            taken: VecDeque::new(),
+            unsaved_commits: 0,
+            cursor_saved_at: Instant::now(),
+            stats: Stats::default(),
+        };
+        info!(
+            "Spool opened at {} with {} bytes queued",
+            spool.dir.display(),
+            spool.queued_bytes()
+        );
+        Ok(spool)
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    // Approximate number of bytes not yet delivered.
+    pub fn queued_bytes(&self) -> u64 {
+        let total: u64 = self.segments.iter().map(|s| s.bytes).sum();
+        total - self.cursor
+    }
+
+    /// Appends a metric to the end of the queue.
+    pub fn push(&mut self, metric: &adafruit::Metric) -> io::Result<()> {
+        let record = Record {
+            t: unix_now(),
+            m: metric.clone(),
+        };
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        let len = line.len() as u64;
+
+        let back = self.segments.back().expect("spool has no write segment");
+        if back.bytes > 0 && back.bytes + len > self.limits.segment_bytes {
+            let id = back.id + 1;
+            self.writer = create_segment(&self.dir, id)?;
+            self.segments.push_back(Segment { id, bytes: 0 });
+            debug!("Spool started segment {}", id);
+        }
+
+        self.writer.write_all(line.as_bytes())?;
+        self.segments.back_mut().unwrap().bytes += len;
+        self.enforce_max_bytes()
+    }
+
+    /// Returns the oldest metric not yet taken, if any, without taking it.
+    pub fn front(&mut self) -> io::Result<Option<adafruit::Metric>> {
+        loop {
+            if let Some((m, _)) = &self.pending {
+                return Ok(Some(m.clone()));
+            }
+            if self.reader.is_none() {
+                let mut f = File::open(segment_path(&self.dir, self.read_id))?;
+                f.seek(SeekFrom::Start(self.read_offset))?;
+                self.reader = Some(BufReader::new(f));
+            }
+
+            let mut line = String::new();
+            let n = self.reader.as_mut().unwrap().read_line(&mut line)? as u64;
+            if n == 0 {
+                let next_id = match self.segments.iter().find(|s| s.id > self.read_id) {
+                    Some(s) => s.id,
+                    None => return Ok(None),
+                };
+                self.read_id = next_id;
+                self.read_offset = 0;
+                self.reader = None;
+                if self.taken.is_empty() && self.advance_cursor(next_id, 0)? {
+                    self.save_cursor()?;
+                }
+                continue;
+            }
+            let next_offset = self.read_offset + n;
+            if !line.ends_with('\n') {
+                // Torn write from a crash; only possible in a closed segment.
+                self.stats.dropped_corrupt += 1;
+                counters::inc("spool_dropped_corrupt");
+                self.read_offset = next_offset;
+                continue;
+            }
+
+            match serde_json::from_str::<Record>(&line) {
+                Ok(r) if unix_now().saturating_sub(r.t) > self.limits.max_age.as_secs() => {
+                    self.stats.dropped_expired += 1;
+                    counters::inc("spool_dropped_expired");
+                    debug!("Spool dropped expired metric {:?}", r.m);
+                    self.read_offset = next_offset;
+                }
+                Ok(r) => self.pending = Some((r.m, next_offset)),
+                Err(e) => {
+                    self.stats.dropped_corrupt += 1;
+                    counters::inc("spool_dropped_corrupt");
+                    warn!("Spool dropped corrupt record: {}", e);
+                    self.read_offset = next_offset;
+                }
+            }
+        }
+    }
+
+    /// Takes the metric last returned by `front()` for sending. It stays in
+    /// the spool until committed.
+    pub fn take(&mut self) {
+        if let Some((_, next_offset)) = self.pending.take() {
+            self.read_offset = next_offset;
+            self.taken.push_back((self.read_id, next_offset));
+        }
+    }
+
+    /// Marks the `n` oldest taken metrics as delivered.
+    pub fn commit(&mut self, n: usize) -> io::Result<()> {
+        let mut segments_removed = false;
+        for _ in 0..n {
+            let (id, offset) = match self.taken.pop_front() {
+                Some(end) => end,
+                None => break,
+            };
+            segments_removed |= self.advance_cursor(id, offset)?;
+            self.unsaved_commits += 1;
+        }
+        if self.taken.is_empty() {
+            // Past any records skipped since.
+            segments_removed |= self.advance_cursor(self.read_id, self.read_offset)?;
+        }
+        if segments_removed
+            || self.unsaved_commits >= CURSOR_SAVE_COMMITS
+            || self.cursor_saved_at.elapsed() >= CURSOR_SAVE_INTERVAL
+        {
+            self.save_cursor()?;
+        }
+        Ok(())
+    }
+
+    /// Puts the `n` most recently taken metrics back, to be taken again.
+    pub fn untake(&mut self, n: usize) {
+        for _ in 0..n {
+            self.taken.pop_back();
+        }
+        let committed = (self.front_id(), self.cursor);
+        let (id, offset) = self
+            .taken
+            .back()
+            .copied()
+            .map_or(committed, |t| t.max(committed));
+        self.read_id = id;
+        self.read_offset = offset;
+        self.reader = None;
+        self.pending = None;
+    }
+
+    /// Saves the cursor if any commits since the last save haven't been.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsaved_commits > 0 {
+            self.save_cursor()?;
+        }
+        Ok(())
+    }
+
+    fn front_id(&self) -> u64 {
+        self.segments.front().expect("spool has no segment").id
+    }
+
+    // Moves the cursor forward to an offset in a segment, removing the
+    // segments before it. Returns whether any were removed.
+    fn advance_cursor(&mut self, id: u64, offset: u64) -> io::Result<bool> {
+        if (id, offset) <= (self.front_id(), self.cursor) {
+            return Ok(false);
+        }
+        let mut removed = false;
+        while self.front_id() < id {
+            let s = self.segments.pop_front().unwrap();
+            fs::remove_file(segment_path(&self.dir, s.id))?;
+            removed = true;
+        }
+        self.cursor = offset;
+        Ok(removed)
+    }
+
+    fn save_cursor(&mut self) -> io::Result<()> {
+        write_cursor(&self.dir, self.front_id(), self.cursor)?;
+        self.unsaved_commits = 0;
+        self.cursor_saved_at = Instant::now();
+        Ok(())
+    }
+
+    // Drops the oldest segment, delivered or not.
+    fn remove_front(&mut self) -> io::Result<()> {
+        let s = self.segments.pop_front().expect("spool has no segment");
+        fs::remove_file(segment_path(&self.dir, s.id))?;
+        self.cursor = 0;
+        if self.read_id == s.id {
+            self.read_id = self.front_id();
+            self.read_offset = 0;
+            self.reader = None;
+            self.pending = None;
+        }
+        self.save_cursor()
+    }
+
+    fn enforce_max_bytes(&mut self) -> io::Result<()> {
+        while self.segments.len() > 1
+            && self.segments.iter().map(|s| s.bytes).sum::<u64>() > self.limits.max_bytes
+        {
+            let id = self.front_id();
+            let dropped = count_records(&segment_path(&self.dir, id), self.cursor)?;
+            self.stats.dropped_overflow += dropped;
+            counters::add("spool_dropped_overflow", dropped);
+            warn!(
+                "Spool is over {} bytes; dropped {} unsent metrics",
+                self.limits.max_bytes, dropped
+            );
+            self.remove_front()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            warn!("Spool cursor not saved: {}", e);
+        }
+    }
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", id, SEGMENT_EXTENSION))
+}
+
+fn create_segment(dir: &Path, id: u64) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, id))
+}
+
+fn count_records(path: &Path, offset: u64) -> io::Result<u64> {
+    let mut f = File::open(path)?;
+    f.seek(SeekFrom::Start(offset))?;
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf)?;
+    Ok(buf.iter().filter(|&&b| b == b'\n').count() as u64)
+}
+
+fn read_cursor(dir: &Path) -> Option<(u64, u64)> {
+    let text = fs::read_to_string(dir.join(CURSOR_FILE)).ok()?;
+    let mut parts = text.split_whitespace();
+    let id = parts.next()?.parse().ok()?;
+    let offset = parts.next()?.parse().ok()?;
+    Some((id, offset))
+}
+
+fn write_cursor(dir: &Path, id: u64, offset: u64) -> io::Result<()> {
+    // Write-then-rename so a crash never leaves a half-written cursor, with
+    // the file synced before the rename and the directory after it.
+    let tmp = dir.join(format!("{}.tmp", CURSOR_FILE));
+    let mut f = File::create(&tmp)?;
+    writeln!(f, "{} {}", id, offset)?;
+    f.sync_all()?;
+    fs::rename(tmp, dir.join(CURSOR_FILE))?;
+    File::open(dir)?.sync_all()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    const LIMITS: Limits = Limits {
+        max_bytes: 1 << 20,
+        max_age: Duration::from_secs(3600),
+        segment_bytes: 100,
+    };
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            env::temp_dir().join(format!("iot-central-spool-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn metric(i: i32) -> adafruit::Metric {
+        adafruit::Metric::new(format!("test.feed-{}", i), i64::from(i))
+    }
+
+    fn drain(spool: &mut Spool) -> Vec<String> {
+        let mut feeds = Vec::new();
+        while let Some(m) = spool.front().unwrap() {
+            feeds.push(m.feed);
+            spool.take();
+            spool.commit(1).unwrap();
+        }
+        feeds
+    }
+
+    #[test]
+    fn replays_in_order_across_segments() {
+        let dir = temp_dir("order");
+        let mut spool = Spool::open(&dir, LIMITS).unwrap();
+        for i in 0..10 {
+            spool.push(&metric(i)).unwrap();
+        }
+        assert!(spool.segments.len() > 1);
+        let feeds = drain(&mut spool);
+        let expected: Vec<String> = (0..10).map(|i| metric(i).feed).collect();
+        assert_eq!(expected, feeds);
+        assert_eq!(1, spool.segments.len());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unsent_metrics_survive_reopen() {
+        let dir = temp_dir("reopen");
+        let mut spool = Spool::open(&dir, LIMITS).unwrap();
+        for i in 0..5 {
+            spool.push(&metric(i)).unwrap();
+        }
+        for _ in 0..2 {
+            spool.front().unwrap();
+            spool.take();
+            spool.commit(1).unwrap();
+        }
+        drop(spool);
+
+        let mut spool = Spool::open(&dir, LIMITS).unwrap();
+        spool.push(&metric(5)).unwrap();
+        let expected: Vec<String> = (2..6).map(|i| metric(i).feed).collect();
+        assert_eq!(expected, drain(&mut spool));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn taken_metrics_are_replayed_until_committed() {
+        let dir = temp_dir("taken");
+        let mut spool = Spool::open(&dir, LIMITS).unwrap();
+        for i in 0..4 {
+            spool.push(&metric(i)).unwrap();
+        }
+        for _ in 0..3 {
+            spool.front().unwrap();
+            spool.take();
+        }
+        // The last is put back and comes up next.
+        spool.untake(1);
+        assert_eq!(Some(metric(2).feed), spool.front().unwrap().map(|m| m.feed));
+        spool.commit(1).unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(&dir, LIMITS).unwrap();
+        let expected: Vec<String> = (1..4).map(|i| metric(i).feed).collect();
+        assert_eq!(expected, drain(&mut spool));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cursor_is_saved_in_batches() {
+        let dir = temp_dir("batches");
+        let limits = Limits {
+            segment_bytes: 1 << 20,
+            ..LIMITS
+        };
+        let mut spool = Spool::open(&dir, limits).unwrap();
+        for i in 0..CURSOR_SAVE_COMMITS as i32 + 1 {
+            spool.push(&metric(i)).unwrap();
+        }
+        spool.front().unwrap();
+        spool.take();
+        spool.commit(1).unwrap();
+        assert_eq!(None, read_cursor(&dir));
+        for _ in 1..CURSOR_SAVE_COMMITS {
+            spool.front().unwrap();
+            spool.take();
+            spool.commit(1).unwrap();
+        }
+        let saved = read_cursor(&dir).unwrap();
+        assert_eq!(spool.cursor, saved.1);
+
+        // The rest is saved on close.
+        spool.front().unwrap();
+        spool.take();
+        spool.commit(1).unwrap();
+        drop(spool);
+        assert!(read_cursor(&dir).unwrap().1 > saved.1);
+        let mut spool = Spool::open(&dir, limits).unwrap();
+        assert!(drain(&mut spool).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn oldest_segments_are_dropped_over_max_bytes() {
+        let dir = temp_dir("overflow");
+        let limits = Limits {
+            max_bytes: 250,
+            ..LIMITS
+        };
+        let mut spool = Spool::open(&dir, limits).unwrap();
+        for i in 0..20 {
+            spool.push(&metric(i)).unwrap();
+        }
+        let remaining = drain(&mut spool);
+        assert_eq!(20, remaining.len() as u64 + spool.stats().dropped_overflow);
+        assert!(spool.stats().dropped_overflow > 0);
+        assert_eq!(Some(&metric(19).feed), remaining.last());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn expired_metrics_are_dropped() {
+        let dir = temp_dir("expired");
+        let mut spool = Spool::open(&dir, LIMITS).unwrap();
+        let stale = Record {
+            t: unix_now() - 7200,
+            m: metric(0),
+        };
+        let line = serde_json::to_string(&stale).unwrap() + "\n";
+        spool.writer.write_all(line.as_bytes()).unwrap();
+        spool.segments.back_mut().unwrap().bytes += line.len() as u64;
+        spool.push(&metric(1)).unwrap();
+
+        assert_eq!(vec![metric(1).feed], drain(&mut spool));
+        assert_eq!(1, spool.stats().dropped_expired);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+//  Copyright 2022 Google LLC
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      https://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+#![warn(clippy::all)]
+
+//! A minimal local MQTT 3.1.1 broker for tests. It accepts any client, acks
+//! QoS 1 publishes and subscriptions, answers pings, publishes a client's
+//! last will when its connection drops, and records everything it receives.
+//! Tests can also publish to every connected client.
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Connect {
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub will: Option<Message>,
+}
+
+#[derive(Default)]
+struct State {
+    connects: Vec<Connect>,
+    publishes: VecDeque<Message>,
+    subscriptions: Vec<String>,
+    streams: Vec<TcpStream>,
+}
+
+pub struct Broker {
+    port: u16,
+    shared: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl Broker {
+    pub fn start() -> Broker {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let shared = Arc::new((Mutex::new(State::default()), Condvar::new()));
+        let accept_shared = shared.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(_) => return,
+                };
+                accept_shared
+                    .0
+                    .lock()
+                    .unwrap()
+                    .streams
+                    .push(stream.try_clone().unwrap());
+                let shared = accept_shared.clone();
+                thread::spawn(move || handle(stream, shared));
+            }
+        });
+        Broker { port, shared }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn connects(&self) -> Vec<Connect> {
+        self.shared.0.lock().unwrap().connects.clone()
+    }
+
+    /// Waits up to five seconds for the next message published to the broker.
+    pub fn next_publish(&self) -> Message {
+        let (lock, cvar) = &*self.shared;
+        let (mut state, timeout) = cvar
+            .wait_timeout_while(lock.lock().unwrap(), Duration::from_secs(5), |s| {
+                s.publishes.is_empty()
+            })
+            .unwrap();
+        assert!(!timeout.timed_out(), "no message was published");
+        state.publishes.pop_front().unwrap()
+    }
+
+    /// Topic filters subscribed to, across all connections, in order.
+    pub fn subscriptions(&self) -> Vec<String> {
+        self.shared.0.lock().unwrap().subscriptions.clone()
+    }
+
+    /// Sends a QoS 0 message to every connected client, subscribed or not.
+    pub fn publish(&self, topic: &str, payload: &[u8]) {
+        let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+        body.extend(topic.as_bytes());
+        body.extend(payload);
+        let mut packet = vec![0x30];
+        let mut len = body.len();
+        loop {
+            let byte = (len % 128) as u8;
+            len /= 128;
+            if len == 0 {
+                packet.push(byte);
+                break;
+            }
+            packet.push(byte | 0x80);
+        }
+        packet.extend(body);
+        for mut s in &self.shared.0.lock().unwrap().streams {
+            let _ = s.write_all(&packet);
+        }
+    }
+
+    /// Closes every client connection, as if the network went away.
+    pub fn drop_connections(&self) {
+        for s in self.shared.0.lock().unwrap().streams.drain(..) {
+            let _ = s.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+fn handle(mut stream: TcpStream, shared: Arc<(Mutex<State>, Condvar)>) {
+    let mut will = None;
+    let mut clean = false;
+    while let Some((header, body)) = read_packet(&mut stream) {
+        let reply: Vec<u8> = match header >> 4 {
+            1 => {
+                let connect = parse_connect(&body);
+                will = connect.will.clone();
+                shared.0.lock().unwrap().connects.push(connect);
+                vec![0x20, 0x02, 0x00, 0x00]
+            }
+            3 => {
+                let qos = (header >> 1) & 0x03;
+                let mut pos = 0;
+                let topic = read_string(&body, &mut pos);
+                let mut reply = Vec::new();
+                if qos > 0 {
+                    reply = vec![0x40, 0x02, body[pos], body[pos + 1]];
+                    pos += 2;
+                }
+                record(
+                    &shared,
+                    Message {
+                        topic,
+                        payload: body[pos..].to_vec(),
+                        retain: header & 0x01 != 0,
+                    },
+                );
+                reply
+            }
+            8 => {
+                // Grant QoS 0 for every filter.
+                let mut pos = 2;
+                let mut granted = Vec::new();
+                while pos < body.len() {
+                    let filter = read_string(&body, &mut pos);
+                    shared.0.lock().unwrap().subscriptions.push(filter);
+                    pos += 1;
+                    granted.push(0x00);
+                }
+                let mut reply = vec![0x90, 2 + granted.len() as u8, body[0], body[1]];
+                reply.extend(granted);
+                reply
+            }
+            12 => vec![0xD0, 0x00],
+            14 => {
+                clean = true;
+                break;
+            }
+            _ => Vec::new(),
+        };
+        if stream.write_all(&reply).is_err() {
+            break;
+        }
+    }
+    if !clean {
+        if let Some(will) = will {
+            record(&shared, will);
+        }
+    }
+}
+
+fn record(shared: &(Mutex<State>, Condvar), message: Message) {
+    shared.0.lock().unwrap().publishes.push_back(message);
+    shared.1.notify_all();
+}
+
+fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+    let mut byte = [0u8; 1];
+    stream.read_exact(&mut byte).ok()?;
+    let header = byte[0];
+    let mut len = 0usize;
+    let mut shift = 0;
+    loop {
+        stream.read_exact(&mut byte).ok()?;
+        len |= ((byte[0] & 0x7F) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).ok()?;
+    Some((header, body))
+}
+
+fn read_bytes(body: &[u8], pos: &mut usize) -> Vec<u8> {
+    let len = u16::from_be_bytes([body[*pos], body[*pos + 1]]) as usize;
+    let bytes = body[*pos + 2..*pos + 2 + len].to_vec();
+    *pos += 2 + len;
+    bytes
+}
+
+fn read_string(body: &[u8], pos: &mut usize) -> String {
+    String::from_utf8(read_bytes(body, pos)).unwrap()
+}
+
+fn parse_connect(body: &[u8]) -> Connect {
+    let mut pos = 0;
+    read_string(body, &mut pos); // Protocol name.
+    pos += 1; // Protocol level.
+    let flags = body[pos];
+    pos += 3; // Flags and keep alive.
+
+    let client_id = read_string(body, &mut pos);
+    let will = if flags & 0x04 != 0 {
+        let topic = read_string(body, &mut pos);
+        let payload = read_bytes(body, &mut pos);
+        Some(Message {
+            topic,
+            payload,
+            retain: flags & 0x20 != 0,
+        })
+    } else {
+        None
+    };
+    let username = (flags & 0x80 != 0).then(|| read_string(body, &mut pos));
+    let password = (flags & 0x40 != 0).then(|| read_string(body, &mut pos));
+    Connect {
+        client_id,
+        username,
+        password,
+        will,
+    }
+}
@@ -16,7 +16,7 @@
 
 use crate::adafruit;
 
-//use chrono::{offset::TimeZone, Local, Utc};
+use chrono::{TimeZone, Utc};
 use log::{debug, info};
 use serde::Deserialize;
 use std::sync::{mpsc, Arc, Condvar, Mutex};
@@ -26,6 +26,7 @@ use std::time::Duration;
 pub struct CallParams {
     pub shutdown: Arc<(Mutex<bool>, Condvar)>,
     pub tx: mpsc::Sender<adafruit::Metric>,
+    pub update_period: Duration,
     pub base_url: String,
     pub api_key: String,
     pub lat: String,
@@ -50,22 +51,23 @@ struct CurrentConditions {
     pressure: i32,
     // wind_speed: f32,
     // wind_deg: i16,
-    // weather: Vec<WeatherInfo>,
+    #[serde(default)]
+    weather: Vec<WeatherInfo>,
 }
 
-// #[derive(Deserialize, Debug, Default)]
-// struct WeatherInfo {
-//     id: i16,
-//     main: String,
-//     description: String,
-//     icon: String,
-// }
+#[derive(Deserialize, Debug, Default)]
+struct WeatherInfo {
+    // id: i16,
+    // main: String,
+    // e.g. "light rain".
+    description: String,
+    // icon: String,
+}
 
 pub fn weather_updater(params: CallParams) {
     info!("weather_updater starting");
     debug!("weather_updater parameters {:?}", params);
     let client = reqwest::blocking::Client::new();
-    let update_period = Duration::from_secs(10 * 60);
     loop {
         let url = format!(
             "{}?lat={}&lon={}&units={}&exclude=minutely,daily&appid={}",
@@ -78,27 +80,16 @@ pub fn weather_updater(params: CallParams) {
                 debug!("GET weather: {:?}", r.status());
                 let w: OneCallWeather = r.json().unwrap_or_default();
                 if w.current.utc_timestamp != 0 {
-                    params
-                        .tx
-                        .send(adafruit::Metric {
-                            feed: "weather.temp".into(),
-                            value: w.current.temperature,
-                        })
-                        .unwrap();
-                    params
-                        .tx
-                        .send(adafruit::Metric {
-                            feed: "weather.humidity".into(),
-                            value: w.current.humidity as f32,
-                        })
-                        .unwrap();
-                    params
-                        .tx
-                        .send(adafruit::Metric {
-                            feed: "weather.pressure".into(),
-                            value: w.current.pressure as f32,
-                        })
-                        .unwrap();
+                    let created_at = Utc.timestamp_opt(w.current.utc_timestamp, 0).single();
+                    let location = location(&params);
+                    for (feed, value) in readings(&w.current) {
+                        let mut m = adafruit::Metric::new(feed, value);
+                        if let Some(t) = created_at {
+                            m.created_at = t;
+                        }
+                        m.location = location;
+                        params.tx.send(m).unwrap();
+                    }
                 }
             }
             _ => {
@@ -109,9 +100,11 @@ pub fn weather_updater(params: CallParams) {
         // Wait for next update period, or  shutdown signal.
         let (lock, cvar) = &*params.shutdown;
         let shutdown = cvar
-            .wait_timeout_while(lock.lock().unwrap(), update_period, |&mut shutdown| {
-                !shutdown
-            })
+            .wait_timeout_while(
+                lock.lock().unwrap(),
+                params.update_period,
+                |&mut shutdown| !shutdown,
+            )
             .unwrap();
         if *shutdown.0 {
             break;
@@ -119,3 +112,57 @@ pub fn weather_updater(params: CallParams) {
     }
     info!("weather_updater finished");
 }
+
+// The feeds for the current conditions; the first listed condition is the
+// main one.
+fn readings(current: &CurrentConditions) -> Vec<(&'static str, adafruit::Value)> {
+    let mut readings = vec![
+        ("weather.temp", current.temperature.into()),
+        ("weather.humidity", f64::from(current.humidity).into()),
+        ("weather.pressure", f64::from(current.pressure).into()),
+    ];
+    if let Some(info) = current.weather.first() {
+        readings.push(("weather.description", info.description.as_str().into()));
+    }
+    readings
+}
+
+// Where the readings apply, if the configured coordinates are numbers.
+fn location(params: &CallParams) -> Option<adafruit::Location> {
+    Some(adafruit::Location {
+        lat: params.lat.parse().ok()?,
+        lon: params.lon.parse().ok()?,
+        ele: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn description_is_published_as_text() {
+        let w: OneCallWeather = serde_json::from_str(
+            r#"{"current": {"dt": 1666000000, "temp": 12.5, "humidity": 80,
+                "pressure": 1012, "weather": [{"id": 500, "main": "Rain",
+                "description": "light rain", "icon": "10d"}]}}"#,
+        )
+        .unwrap();
+        let r = readings(&w.current);
+        assert_eq!(4, r.len());
+        assert_eq!(
+            (
+                "weather.description",
+                adafruit::Value::Text("light rain".to_owned())
+            ),
+            r[3]
+        );
+
+        // No conditions listed, no description.
+        let w: OneCallWeather = serde_json::from_str(
+            r#"{"current": {"dt": 1666000000, "temp": 12.5, "humidity": 80, "pressure": 1012}}"#,
+        )
+        .unwrap();
+        assert_eq!(3, readings(&w.current).len());
+    }
+}